@@ -0,0 +1,346 @@
+//! Agent mode: once a plan's tasks have run, their outcomes go back to the
+//! model as tool results, and it answers with more tasks, withdraws ones
+//! still pending, or finishes. Every task it adds goes through the same
+//! allowlist and approval queue as the first plan's. A run ends when the
+//! model finishes or hits its iteration, token or wall-clock limit.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::executor::ExecutionResult;
+use crate::task::{Task, TaskDetail, TaskStatus};
+use crate::tools::{ToolCall, ToolResult};
+
+const DEFAULT_MAX_ITERATIONS: u32 = 10;
+const DEFAULT_MAX_TOKENS: u64 = 200_000;
+const DEFAULT_MAX_MINUTES: u64 = 15;
+
+/// When the model hears about finished tasks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Feedback {
+    /// Once every task of the plan has finished.
+    #[default]
+    Batch,
+    /// After each task, while the rest are still pending.
+    Task,
+}
+
+/// The `[agent]` config table.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AgentConfig {
+    /// Keep going after the first plan. Needs `plan_with_tools`.
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub feedback: Feedback,
+    /// Model replies per run, the first plan included.
+    pub max_iterations: Option<u32>,
+    /// Input and output tokens across the run.
+    pub max_tokens: Option<u64>,
+    /// Wall-clock time for the run, including time spent running tasks.
+    pub max_minutes: Option<u64>,
+}
+
+/// One prompt's agent run.
+#[derive(Debug)]
+pub struct AgentRun {
+    feedback: Feedback,
+    max_iterations: u32,
+    max_tokens: u64,
+    max_duration: Duration,
+    started: Instant,
+    iterations: u32,
+    tokens: u64,
+    /// Tool calls of the latest reply, not yet answered.
+    open_calls: Vec<ToolCall>,
+    /// Answers ready as soon as a call was handled: controls, and calls
+    /// whose tasks couldn't be added.
+    answers: HashMap<String, ToolResult>,
+    /// Tasks whose outcome the model has seen.
+    reported: HashSet<String>,
+    /// What the model concluded when it called `finish`.
+    conclusion: Option<String>,
+    /// A continuation request is in flight.
+    pub awaiting: bool,
+}
+
+impl AgentRun {
+    pub fn new(cfg: &AgentConfig) -> Self {
+        Self {
+            feedback: cfg.feedback,
+            max_iterations: cfg.max_iterations.unwrap_or(DEFAULT_MAX_ITERATIONS),
+            max_tokens: cfg.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            max_duration: Duration::from_secs(cfg.max_minutes.unwrap_or(DEFAULT_MAX_MINUTES) * 60),
+            started: Instant::now(),
+            iterations: 0,
+            tokens: 0,
+            open_calls: Vec::new(),
+            answers: HashMap::new(),
+            reported: HashSet::new(),
+            conclusion: None,
+            awaiting: false,
+        }
+    }
+
+    /// Count a reply from the model and remember the calls to answer.
+    pub fn record_reply(&mut self, tokens: u64, calls: &[ToolCall]) {
+        self.iterations += 1;
+        self.tokens += tokens;
+        self.open_calls = calls.to_vec();
+        self.answers.clear();
+        self.awaiting = false;
+    }
+
+    /// Which limit, if any, stops the run from asking the model again.
+    pub fn limit_reached(&self) -> Option<String> {
+        if self.iterations >= self.max_iterations {
+            Some(format!("{} iterations", self.max_iterations))
+        } else if self.tokens >= self.max_tokens {
+            Some(format!("{} tokens", self.max_tokens))
+        } else if self.started.elapsed() >= self.max_duration {
+            Some(format!("{} minutes", self.max_duration.as_secs() / 60))
+        } else {
+            None
+        }
+    }
+
+    /// Short progress line, e.g. "step 2/10, 4100 tokens".
+    pub fn progress(&self) -> String {
+        format!(
+            "step {}/{}, {} tokens",
+            self.iterations, self.max_iterations, self.tokens
+        )
+    }
+
+    /// Answer `call_id` now rather than from the task it proposed.
+    pub fn answer(&mut self, call_id: &str, content: impl Into<String>, is_error: bool) {
+        self.answers.insert(
+            call_id.to_string(),
+            ToolResult {
+                tool_use_id: call_id.to_string(),
+                task_id: None,
+                content: content.into(),
+                is_error,
+            },
+        );
+    }
+
+    pub fn conclude(&mut self, summary: String) {
+        self.conclusion = Some(summary);
+    }
+
+    pub fn conclusion(&self) -> Option<&str> {
+        self.conclusion.as_deref()
+    }
+
+    /// Whether finished tasks should be reported now, before the rest run.
+    pub fn feedback_due(&self, tasks: &[Task]) -> bool {
+        self.feedback == Feedback::Task
+            && !self.awaiting
+            && self.conclusion.is_none()
+            && tasks.iter().any(|task| {
+                task.tool_use_id.is_some()
+                    && task.status.is_settled()
+                    && !self.reported.contains(&task.id)
+            })
+    }
+
+    /// Answer the open calls from the tasks they proposed, and report tasks
+    /// from earlier plans that finished since. `results` is keyed by task
+    /// index, like `App::execution_results`. Returns `(results, later)`.
+    pub fn collect_results(
+        &mut self,
+        tasks: &[Task],
+        results: &HashMap<usize, ExecutionResult>,
+    ) -> (Vec<ToolResult>, Vec<ToolResult>) {
+        let mut answered = Vec::new();
+        for call in std::mem::take(&mut self.open_calls) {
+            if let Some(result) = self.answers.remove(&call.id) {
+                answered.push(result);
+                continue;
+            }
+            let task = tasks
+                .iter()
+                .enumerate()
+                .find(|(_, task)| task.tool_use_id.as_deref() == Some(call.id.as_str()));
+            answered.push(match task {
+                // Notes are recorded and dropped from the list straight away
+                None => ToolResult {
+                    tool_use_id: call.id,
+                    task_id: None,
+                    content: "Noted.".to_string(),
+                    is_error: false,
+                },
+                Some((_, task)) if !task.status.is_settled() => ToolResult {
+                    tool_use_id: call.id,
+                    task_id: None,
+                    content: format!("Not run yet ({}).", task.status_text()),
+                    is_error: false,
+                },
+                Some((idx, task)) => {
+                    self.reported.insert(task.id.clone());
+                    outcome(call.id, task, results.get(&idx))
+                }
+            });
+        }
+
+        let mut later = Vec::new();
+        for (idx, task) in tasks.iter().enumerate() {
+            let Some(call_id) = &task.tool_use_id else {
+                continue;
+            };
+            if task.status.is_settled() && self.reported.insert(task.id.clone()) {
+                later.push(outcome(call_id.clone(), task, results.get(&idx)));
+            }
+        }
+        (answered, later)
+    }
+}
+
+/// What became of `task`, as the model should hear it.
+fn outcome(tool_use_id: String, task: &Task, result: Option<&ExecutionResult>) -> ToolResult {
+    let mut content = format!("{}: {}", task.description, task.status_text());
+    if let Some(result) = result {
+        content.push_str(&format!("\nResult: {}", result.status_text()));
+        if !result.stdout.trim().is_empty() {
+            content.push_str(&format!("\nSTDOUT:\n{}", result.stdout));
+        }
+        if !result.stderr.trim().is_empty() {
+            content.push_str(&format!("\nSTDERR:\n{}", result.stderr));
+        }
+    }
+    if task.status == TaskStatus::Complete {
+        if let TaskDetail::FileEdit(edit) = &task.detail
+            && let Some(diff) = &edit.diff
+        {
+            content.push_str(&format!("\nChanged:\n{diff}"));
+        }
+        if let Some(summary) = task.detail.file_op_summary() {
+            content.push_str(&format!("\nDone: {summary}"));
+        }
+    }
+    if !task.annotations.is_empty() {
+        content.push_str(&format!("\n({})", task.annotations.join("; ")));
+    }
+    ToolResult {
+        tool_use_id,
+        task_id: Some(task.id.clone()),
+        content,
+        is_error: matches!(
+            task.status,
+            TaskStatus::Failed { .. } | TaskStatus::Cancelled
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::CommandTask;
+
+    fn command(call_id: &str, status: TaskStatus) -> Task {
+        let mut task = Task::new(
+            format!("task {call_id}"),
+            TaskDetail::Command(CommandTask {
+                shell: "/bin/sh".into(),
+                command: "true".into(),
+                cwd: None,
+                requires_root: false,
+                timeout_secs: None,
+                interactive: false,
+                env: Default::default(),
+            }),
+        );
+        task.status = status;
+        task.tool_use_id = Some(call_id.into());
+        task
+    }
+
+    fn call(id: &str) -> ToolCall {
+        ToolCall {
+            id: id.into(),
+            name: "run_command".into(),
+            input: serde_json::json!({}),
+        }
+    }
+
+    #[test]
+    fn each_task_is_reported_once_even_if_it_finishes_later() {
+        let cfg = AgentConfig {
+            feedback: Feedback::Task,
+            ..Default::default()
+        };
+        let mut run = AgentRun::new(&cfg);
+        let mut tasks = vec![
+            command("toolu_a", TaskStatus::Failed { exit_code: Some(1) }),
+            command("toolu_b", TaskStatus::Ready),
+        ];
+        run.record_reply(100, &[call("toolu_a"), call("toolu_b"), call("toolu_c")]);
+        run.answer("toolu_c", "Withdrawn.", false);
+        assert!(run.feedback_due(&tasks));
+
+        let results = HashMap::from([(
+            0,
+            ExecutionResult {
+                status: Some(1),
+                stdout: String::new(),
+                stderr: "no such unit".into(),
+                killed: None,
+                truncated: Vec::new(),
+            },
+        )]);
+        let (answered, later) = run.collect_results(&tasks, &results);
+        assert!(later.is_empty());
+        assert_eq!(answered.len(), 3);
+        assert!(answered[0].is_error);
+        assert!(answered[0].content.contains("no such unit"));
+        assert_eq!(answered[1].content, "Not run yet (ready).");
+        assert_eq!(answered[1].task_id, None);
+        assert_eq!(answered[2].content, "Withdrawn.");
+        assert!(!run.feedback_due(&tasks));
+
+        tasks[1].status = TaskStatus::Complete;
+        run.record_reply(50, &[]);
+        let (answered, later) = run.collect_results(&tasks, &HashMap::new());
+        assert!(answered.is_empty());
+        assert_eq!(later.len(), 1);
+        assert_eq!(later[0].tool_use_id, "toolu_b");
+        assert_eq!(run.progress(), "step 2/10, 150 tokens");
+    }
+
+    #[test]
+    fn cancelled_tasks_wait_for_the_user() {
+        let cfg = AgentConfig {
+            feedback: Feedback::Task,
+            ..Default::default()
+        };
+        let mut run = AgentRun::new(&cfg);
+        let tasks = vec![command("toolu_a", TaskStatus::Cancelled)];
+        run.record_reply(100, &[call("toolu_a")]);
+        assert!(!run.feedback_due(&tasks));
+        let (answered, later) = run.collect_results(&tasks, &HashMap::new());
+        assert!(later.is_empty());
+        assert_eq!(answered[0].content, "Not run yet (cancelled).");
+    }
+
+    #[test]
+    fn limits_stop_the_run() {
+        let cfg = AgentConfig {
+            max_iterations: Some(2),
+            max_tokens: Some(1000),
+            ..Default::default()
+        };
+        let mut run = AgentRun::new(&cfg);
+        run.record_reply(400, &[]);
+        assert_eq!(run.limit_reached(), None);
+        run.record_reply(700, &[]);
+        assert_eq!(run.limit_reached().as_deref(), Some("2 iterations"));
+
+        let mut run = AgentRun::new(&cfg);
+        run.record_reply(1000, &[]);
+        assert_eq!(run.limit_reached().as_deref(), Some("1000 tokens"));
+    }
+}
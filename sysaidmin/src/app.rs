@@ -1,20 +1,35 @@
 use std::collections::{HashMap, VecDeque};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{self, Receiver, TryRecvError};
 use std::thread;
 
-use anyhow::Error;
+use anyhow::{Error, anyhow};
 use chrono::Utc;
 use log::{debug, error, info, trace, warn};
 
+use crate::agent::AgentRun;
 use crate::allowlist::Allowlist;
-use crate::api::AnthropicClient;
-use crate::config::AppConfig;
+use crate::api::{AnthropicClient, PlanReply, Progress};
+use crate::config::{AppConfig, FailurePolicy};
 use crate::conversation::{ConversationEntry, ConversationLogger};
-use crate::executor::{ExecutionResult, Executor, FileEditOutcome};
+use crate::diff;
+use crate::edit::{self, StaleEdit};
+use crate::escalation::{Password, Readiness};
+use crate::executor::{
+    CancelToken, ExecutionResult, Executor, FileEditOutcome, KillReason, OutputLine,
+};
 use crate::parser;
+use crate::pty::InteractiveMode;
+use crate::retry::RetryNotice;
 use crate::session::SessionStore;
-use crate::task::{Task, TaskDetail, TaskStatus};
+use crate::task::{CommandTask, Task, TaskDetail, TaskStatus};
+use crate::tools::{Control, ToolCall};
+use crate::undo;
+use crate::validate::ValidationFailed;
+
+/// Live output lines kept per running command for display; the full text
+/// still arrives with the final `ExecutionResult`.
+const LIVE_OUTPUT_LINES: usize = 500;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum InputMode {
@@ -32,6 +47,7 @@ pub struct App {
     pub execution_results: HashMap<usize, ExecutionResult>, // task index -> execution result
     pub analysis_result: Option<String>,                    // Synthesis/analysis result from LLM
     pub analysis_scroll_offset: usize,                      // Scroll offset for analysis display
+    pub detail_scroll_offset: usize,                        // Scroll offset for details pane diffs
     pub is_loading_plan: bool,   // True when waiting for plan API response
     pub spinner_frame: usize,    // Current spinner animation frame
     last_prompt: Option<String>, // Store last prompt for synthesis detection
@@ -41,15 +57,71 @@ pub struct App {
     executor: Executor,
     session: SessionStore,
     approval_queue: VecDeque<usize>,
+    failure_prompt: Option<usize>, // failed task index awaiting continue/stop (FailurePolicy::Ask)
+    conflict_prompt: Option<usize>, // stale file edit index awaiting re-plan/skip
+    password_prompt: Option<usize>, // root task index waiting for a sudo password
+    password_input: String,         // typed sudo password; never logged or drawn
     conversation: ConversationLogger,
     plan_receiver: Option<Receiver<PlanResponse>>,
+    streamed_tasks: usize, // tasks at the end of the list previewed from a plan still arriving
+    analysis_receiver: Option<Receiver<AnalysisUpdate>>,
+    running_command: Option<RunningCommand>,
+    pending_handover: Option<Handover>, // interactive command waiting for the terminal
+    agent: Option<AgentRun>,            // agent mode: the current prompt's run
+    retry_notice: Option<RetryNotice>,  // the plan or analysis request is waiting to retry
+    escalation_receiver: Option<Receiver<EscalationCheck>>, // sudo probe or password check
+    root_task_waiting: Option<usize>, // root task index to run once the sudo probe answers
+    readiness: Option<Readiness>,     // fresh probe answer for the root task about to run
 }
 
 enum PlanResponse {
-    Success(String),
+    /// A task's tool call arrived while the rest of the plan streams in.
+    Partial(ToolCall),
+    Retrying(RetryNotice),
+    Success(PlanReply),
     Error(String),
 }
 
+/// The answer from running sudo on a background thread, since it can take
+/// as long as its PAM stack likes.
+enum EscalationCheck {
+    /// Whether root tasks can run; `start` is set when gating a new plan
+    /// from that index on.
+    Probed {
+        start: Option<usize>,
+        readiness: Readiness,
+    },
+    /// Whether sudo accepted the typed password.
+    PasswordChecked {
+        password: Password,
+        accepted: anyhow::Result<bool>,
+    },
+}
+
+enum AnalysisUpdate {
+    Text(String),
+    Retrying(RetryNotice),
+    Done(Result<String, String>),
+}
+
+/// A command task executing on a background thread.
+struct RunningCommand {
+    task_id: String,
+    description: String,
+    command: CommandTask,
+    cancel: CancelToken,
+    output: Vec<OutputLine>,
+    output_receiver: Receiver<OutputLine>,
+    result_receiver: Receiver<anyhow::Result<ExecutionResult>>,
+}
+
+/// An interactive command the TUI should step aside for.
+pub struct Handover {
+    task_id: String,
+    description: String,
+    command: CommandTask,
+}
+
 impl App {
     pub fn new(
         config: AppConfig,
@@ -65,9 +137,7 @@ impl App {
         );
 
         // Initialize conversation logger
-        let conversation_path = std::env::current_dir()
-            .unwrap_or_else(|_| PathBuf::from("."))
-            .join("sysaidmin.conversation.jsonl");
+        let conversation_path = crate::conversation::default_log_path();
         let conversation = ConversationLogger::new(conversation_path.clone()).unwrap_or_else(|e| {
             warn!("Failed to create conversation logger: {}", e);
             // Create a dummy logger that does nothing
@@ -89,6 +159,7 @@ impl App {
             execution_results: HashMap::new(),
             analysis_result: None,
             analysis_scroll_offset: 0,
+            detail_scroll_offset: 0,
             is_loading_plan: false,
             spinner_frame: 0,
             last_prompt: None,
@@ -98,8 +169,21 @@ impl App {
             executor,
             session,
             approval_queue: VecDeque::new(),
+            failure_prompt: None,
+            conflict_prompt: None,
+            password_prompt: None,
+            password_input: String::new(),
             conversation,
             plan_receiver: None,
+            streamed_tasks: 0,
+            analysis_receiver: None,
+            escalation_receiver: None,
+            root_task_waiting: None,
+            readiness: None,
+            running_command: None,
+            pending_handover: None,
+            agent: None,
+            retry_notice: None,
         }
     }
 
@@ -114,18 +198,30 @@ impl App {
             self.log("A plan is already running. Please wait for it to finish.");
             return;
         }
+        if self.running_command.is_some() {
+            warn!("Command still running - ignoring new prompt");
+            self.log("A task is still running. Please wait for it to finish.");
+            return;
+        }
+        if self.analysis_receiver.is_some() {
+            self.log("The analysis is still arriving. Please wait for it to finish.");
+            return;
+        }
         info!("Submitting prompt: {}", prompt);
         // Clear input immediately so user can see it's been submitted
         self.input.clear();
 
-        // Set loading state - spinner will show until plan is received
-        self.is_loading_plan = true;
-        self.spinner_frame = 0;
-
         self.log(format!("Requesting plan for: {}", prompt));
 
         // Store prompt for synthesis detection
         self.last_prompt = Some(prompt.clone());
+        self.request_plan(prompt);
+    }
+
+    fn request_plan(&mut self, prompt: String) {
+        // Set loading state - spinner will show until plan is received
+        self.is_loading_plan = true;
+        self.spinner_frame = 0;
         self.analysis_result = None; // Clear previous analysis
         self.analysis_scroll_offset = 0; // Reset scroll
 
@@ -142,16 +238,44 @@ impl App {
             prompt: prompt.clone(),
         });
 
-        // Spawn background thread to fetch plan so UI can continue animating spinner
+        self.agent = self
+            .config
+            .agent_mode()
+            .then(|| AgentRun::new(&self.config.agent));
+        self.spawn_plan_request(move |client, on_progress| {
+            client.plan(&prompt, &history, on_progress)
+        });
+    }
+
+    /// Run `request` on a background thread so the UI keeps animating the
+    /// spinner; its tool calls and reply arrive through `poll_plan_response`.
+    fn spawn_plan_request(
+        &mut self,
+        request: impl FnOnce(
+            &AnthropicClient,
+            &mut dyn FnMut(Progress),
+        ) -> anyhow::Result<PlanReply>
+        + Send
+        + 'static,
+    ) {
+        self.is_loading_plan = true;
         let (tx, rx) = mpsc::channel();
         self.plan_receiver = Some(rx);
         let client = self.client.clone();
-        let history_clone = history.clone();
         thread::spawn(move || {
-            trace!("Background thread: calling API client.plan()");
-            let result = client.plan(&prompt, &history_clone);
+            trace!("Background thread: requesting plan");
+            let progress_tx = tx.clone();
+            let mut on_progress = move |progress| {
+                let message = match progress {
+                    Progress::ToolCall(call) => PlanResponse::Partial(call),
+                    Progress::Retrying(notice) => PlanResponse::Retrying(notice),
+                    Progress::Text(_) => return,
+                };
+                let _ = progress_tx.send(message);
+            };
+            let result = request(&client, &mut on_progress);
             let message = match result {
-                Ok(response_text) => PlanResponse::Success(response_text),
+                Ok(reply) => PlanResponse::Success(reply),
                 Err(err) => {
                     let formatted = format_error_chain(&err);
                     error!("Plan request failed in background thread: {}", formatted);
@@ -169,121 +293,566 @@ impl App {
             return;
         };
 
-        match rx.try_recv() {
-            Ok(PlanResponse::Success(response_text)) => {
-                self.is_loading_plan = false;
-                self.handle_plan_response(response_text);
+        loop {
+            match rx.try_recv() {
+                Ok(PlanResponse::Partial(call)) => {
+                    self.retry_notice = None;
+                    self.preview_streamed_call(&call);
+                    continue;
+                }
+                Ok(PlanResponse::Retrying(notice)) => {
+                    self.note_retry(notice);
+                    continue;
+                }
+                Ok(PlanResponse::Success(reply)) => {
+                    self.retry_notice = None;
+                    self.is_loading_plan = false;
+                    self.discard_streamed_tasks();
+                    self.handle_plan_response(reply);
+                }
+                Ok(PlanResponse::Error(err_msg)) => {
+                    self.retry_notice = None;
+                    self.is_loading_plan = false;
+                    self.discard_streamed_tasks();
+                    error!("Failed requesting plan: {}", err_msg);
+                    self.log(format!("Failed requesting plan: {}", err_msg));
+                    self.end_agent_after_failure();
+                }
+                Err(TryRecvError::Empty) => {
+                    // No response yet - store receiver for future polling
+                    self.plan_receiver = Some(rx);
+                }
+                Err(TryRecvError::Disconnected) => {
+                    self.retry_notice = None;
+                    self.is_loading_plan = false;
+                    self.discard_streamed_tasks();
+                    warn!("Plan request channel disconnected before response received");
+                    self.log("Plan request channel disconnected before response finished.");
+                    self.end_agent_after_failure();
+                }
             }
-            Ok(PlanResponse::Error(err_msg)) => {
-                self.is_loading_plan = false;
-                error!("Failed requesting plan: {}", err_msg);
-                self.log(format!("Failed requesting plan: {}", err_msg));
+            break;
+        }
+    }
+
+    /// Show a task from a plan that is still arriving. It is only a preview:
+    /// the whole plan replaces it, gated as usual, once the reply is in.
+    fn preview_streamed_call(&mut self, call: &ToolCall) {
+        if Control::from_call(call).is_some() {
+            return;
+        }
+        let shell = &self.config.default_shell;
+        match parser::plan_from_tool_calls(None, std::slice::from_ref(call), shell) {
+            Ok(plan) => {
+                self.streamed_tasks += plan.tasks.len();
+                self.tasks.extend(plan.tasks);
             }
+            Err(err) => debug!("Streamed tool call {} not previewed: {}", call.id, err),
+        }
+    }
+
+    fn discard_streamed_tasks(&mut self) {
+        let keep = self.tasks.len() - self.streamed_tasks;
+        self.tasks.truncate(keep);
+        self.streamed_tasks = 0;
+        self.selected = self.selected.min(self.tasks.len().saturating_sub(1));
+    }
+
+    fn is_streamed_task(&self, idx: usize) -> bool {
+        idx >= self.tasks.len() - self.streamed_tasks
+    }
+
+    /// Append analysis text as it streams in, and finish up once it's all there.
+    pub fn poll_analysis(&mut self) {
+        let Some(rx) = self.analysis_receiver.take() else {
+            return;
+        };
+        loop {
+            match rx.try_recv() {
+                Ok(AnalysisUpdate::Text(text)) => {
+                    self.retry_notice = None;
+                    self.analysis_result.get_or_insert_default().push_str(&text);
+                    continue;
+                }
+                Ok(AnalysisUpdate::Retrying(notice)) => {
+                    self.note_retry(notice);
+                    continue;
+                }
+                Ok(AnalysisUpdate::Done(Ok(analysis))) => {
+                    self.retry_notice = None;
+                    info!("Received synthesis result ({} chars)", analysis.len());
+                    self.show_analysis(analysis);
+                }
+                Ok(AnalysisUpdate::Done(Err(err))) => {
+                    self.retry_notice = None;
+                    self.analysis_failed(&err);
+                }
+                Err(TryRecvError::Empty) => self.analysis_receiver = Some(rx),
+                Err(TryRecvError::Disconnected) => {
+                    self.retry_notice = None;
+                    self.analysis_failed("synthesis thread exited before reporting a result");
+                }
+            }
+            break;
+        }
+    }
+
+    pub fn is_analysing(&self) -> bool {
+        self.analysis_receiver.is_some()
+    }
+
+    fn note_retry(&mut self, notice: RetryNotice) {
+        self.log(format!("API request failed; {}", notice));
+        self.retry_notice = Some(notice);
+    }
+
+    /// The retry the plan or analysis request is waiting on, for the spinner.
+    pub fn retry_status(&self) -> Option<String> {
+        self.retry_notice.as_ref().map(|notice| notice.to_string())
+    }
+
+    fn analysis_failed(&mut self, err: &str) {
+        error!("Synthesis failed: {}", err);
+        if self.analysis_result.as_ref().is_some_and(|text| !text.is_empty()) {
+            self.log(format!("Analysis incomplete: {}", err));
+        } else {
+            // Leave room for a later attempt
+            self.analysis_result = None;
+            self.log("All tasks completed successfully. (Synthesis unavailable)");
+        }
+    }
+
+    /// Drain streamed output from the running command and finish it once the
+    /// executor thread reports a result.
+    pub fn poll_command_output(&mut self) {
+        let Some(mut running) = self.running_command.take() else {
+            return;
+        };
+
+        running.output.extend(running.output_receiver.try_iter());
+        if running.output.len() > LIVE_OUTPUT_LINES {
+            let excess = running.output.len() - LIVE_OUTPUT_LINES;
+            running.output.drain(0..excess);
+        }
+
+        match running.result_receiver.try_recv() {
+            Ok(result) => self.finish_command(
+                &running.task_id,
+                &running.description,
+                &running.command,
+                result,
+            ),
             Err(TryRecvError::Empty) => {
-                // No response yet - store receiver for future polling
-                self.plan_receiver = Some(rx);
+                self.running_command = Some(running);
             }
             Err(TryRecvError::Disconnected) => {
-                self.is_loading_plan = false;
-                warn!("Plan request channel disconnected before response received");
-                self.log("Plan request channel disconnected before response finished.");
+                warn!("Command thread disconnected before reporting a result");
+                self.finish_command(
+                    &running.task_id,
+                    &running.description,
+                    &running.command,
+                    Err(anyhow!("command thread exited before reporting a result")),
+                );
             }
         }
     }
 
-    fn handle_plan_response(&mut self, response_text: String) {
-        info!("Received plan response ({} bytes)", response_text.len());
-        trace!(
-            "Response preview: {}",
-            response_text.chars().take(200).collect::<String>()
+    /// An interactive command that wants the terminal. The TUI must leave
+    /// its screen before calling `run_handover` with it.
+    pub fn take_handover(&mut self) -> Option<Handover> {
+        self.pending_handover.take()
+    }
+
+    /// Run an interactive command on the terminal and record what it showed.
+    pub fn run_handover(&mut self, handover: Handover) {
+        let result = self.executor.run_in_terminal(&handover.command);
+        self.finish_command(
+            &handover.task_id,
+            &handover.description,
+            &handover.command,
+            result,
         );
+    }
 
-        trace!("Parsing plan JSON");
-        match parser::parse_plan(&response_text, &self.config.default_shell) {
-            Ok(parsed) => {
-                info!("Plan parsed successfully: {} tasks", parsed.tasks.len());
-                self.summary = parsed.summary.clone();
-                self.tasks = parsed.tasks.clone();
-                self.selected = 0;
+    pub fn is_command_running(&self) -> bool {
+        self.running_command.is_some()
+    }
 
-                // Log plan to conversation (include full response for context)
-                let _ = self.conversation.log(ConversationEntry::Plan {
-                    timestamp: Utc::now().to_rfc3339(),
-                    summary: parsed.summary.clone(),
-                    task_count: parsed.tasks.len(),
-                    response: Some(response_text.clone()),
-                });
+    /// Ask the running command to stop. The executor kills its process group
+    /// and the partial result arrives through `poll_command_output`.
+    pub fn cancel_running(&mut self) -> bool {
+        let Some(running) = &self.running_command else {
+            return false;
+        };
+        if running.cancel.is_cancelled() {
+            return true;
+        }
+        info!("Cancelling running command: {}", running.command.command);
+        running.cancel.cancel();
+        let description = running.description.clone();
+        self.log(format!("Cancelling '{}'...", description));
+        true
+    }
 
-                info!("Evaluating {} tasks against allowlist", self.tasks.len());
-                let mut blocked_count = 0;
-                for (idx, task) in self.tasks.iter_mut().enumerate() {
-                    trace!("Evaluating task {}: {}", idx, task.description);
-                    match self.allowlist.evaluate(task) {
-                        Ok(status) => {
-                            debug!("Task {} status: {:?}", idx, status);
-                            task.status = status;
-                        }
-                        Err(err) => {
-                            debug!("Task {} blocked: {}", idx, err);
-                            task.status = TaskStatus::Blocked(err.to_string());
-                            blocked_count += 1;
-                        }
+    /// Undo the most recent file edit made in this session, or with `all`
+    /// every one of them, newest first.
+    pub fn undo_edits(&mut self, all: bool) {
+        if self.running_command.is_some() {
+            self.log("Wait for the running task to finish before undoing edits");
+            return;
+        }
+        let session_id = self
+            .executor
+            .backups()
+            .map(|store| store.session_id().to_string());
+        match undo::undo_edits(
+            &self.executor,
+            &self.conversation,
+            session_id.as_deref(),
+            all,
+        ) {
+            Ok(restored) if restored.is_empty() => {
+                self.log("No file edits to undo in this session");
+            }
+            Ok(restored) => {
+                for record in restored {
+                    if let Some(task) = self.tasks.iter_mut().find(|t| t.id == record.task_id) {
+                        task.annotations.push("edit undone".into());
                     }
-                }
-                if blocked_count > 0 {
-                    trace!("{} task(s) blocked by allowlist", blocked_count);
-                }
-
-                // Auto-complete Note tasks immediately and remove them from the list
-                let mut notes_to_remove = Vec::new();
-                for (idx, task) in self.tasks.iter_mut().enumerate() {
-                    if matches!(task.detail, TaskDetail::Note { .. })
-                        && matches!(task.status, TaskStatus::Ready | TaskStatus::Proposed)
-                    {
-                        info!("Auto-completing note task: {}", task.description);
-
-                        if let TaskDetail::Note { ref details } = task.detail {
-                            let _ = self.conversation.log(ConversationEntry::Note {
-                                timestamp: Utc::now().to_rfc3339(),
-                                task_id: task.id.clone(),
-                                description: task.description.clone(),
-                                details: details.clone(),
-                            });
-                        }
-
-                        notes_to_remove.push(idx);
+                    if record.created {
+                        self.log(format!(
+                            "Undid creation of {} (deleted it)",
+                            record.original.display()
+                        ));
+                    } else {
+                        self.log(format!(
+                            "Undid edit of {} (restored from {})",
+                            record.original.display(),
+                            record.backup.display()
+                        ));
                     }
                 }
+                self.persist_plan();
+            }
+            Err(err) => {
+                let formatted = format_error_chain(&err);
+                error!("Undo failed: {}", formatted);
+                self.log(format!("Undo failed: {}", formatted));
+            }
+        }
+    }
 
-                for &idx in notes_to_remove.iter().rev() {
-                    self.tasks.remove(idx);
-                    if self.selected >= idx && self.selected > 0 {
-                        self.selected -= 1;
-                    }
-                }
+    /// Output streamed so far for the task at `idx`, if that task is the one running.
+    pub fn live_output(&self, idx: usize) -> Option<&[OutputLine]> {
+        let running = self.running_command.as_ref()?;
+        let task = self.tasks.get(idx)?;
+        (task.id == running.task_id).then_some(running.output.as_slice())
+    }
 
-                self.sort_tasks_by_status();
+    fn handle_plan_response(&mut self, reply: PlanReply) {
+        info!(
+            "Received plan response ({} bytes, {} tool calls)",
+            reply.text.len(),
+            reply.tool_calls.len()
+        );
+        trace!(
+            "Response preview: {}",
+            reply.text.chars().take(200).collect::<String>()
+        );
 
-                trace!("Persisting plan");
-                self.persist_plan();
+        let continuing = self.agent.as_ref().is_some_and(|agent| agent.awaiting);
+        if let Some(agent) = &mut self.agent {
+            agent.record_reply(reply.tokens, &reply.tool_calls);
+        }
+        if continuing {
+            self.handle_agent_step(reply);
+            return;
+        }
+        if reply.tool_calls.is_empty() && self.agent.take().is_some() {
+            self.log("The model planned without tool calls; agent mode is off for this prompt.");
+        }
 
-                self.log("Plan created successfully.");
+        if self.adopt_plan(&reply, false) {
+            self.log("Plan created successfully.");
+            self.start_sequential_execution();
+        }
+    }
 
-                self.start_sequential_execution();
+    /// The next step of an agent run: new tasks join the end of the plan,
+    /// withdrawn ones are skipped, and a reply with no tool calls at all is
+    /// taken as the model's conclusion.
+    fn handle_agent_step(&mut self, reply: PlanReply) {
+        if reply.tool_calls.is_empty() {
+            let _ = self.conversation.log(ConversationEntry::Plan {
+                timestamp: Utc::now().to_rfc3339(),
+                summary: None,
+                task_count: 0,
+                response: Some(reply.text.clone()),
+                tool_calls: Vec::new(),
+            });
+            // With tasks still to run it's just carrying on
+            if self.first_pending_index().is_none()
+                && let Some(agent) = &mut self.agent
+            {
+                agent.conclude(reply.text);
             }
+            self.continue_sequential_execution();
+            return;
+        }
+
+        let before = self.tasks.len();
+        if !self.adopt_plan(&reply, true) {
+            // The model hears why and gets to try again, within its limits
+            self.agent_step();
+            return;
+        }
+        let added = self.tasks.len() - before;
+        if added > 0 {
+            self.log(format!("Agent added {} task(s).", added));
+        }
+        self.continue_sequential_execution();
+    }
+
+    /// Parse `reply` into tasks and gate them: allowlist, escalation and
+    /// edit previews. They replace the plan, or with `append`, join the end
+    /// of it. Agent-mode control calls are carried out here too. Returns
+    /// whether the reply could be used.
+    fn adopt_plan(&mut self, reply: &PlanReply, append: bool) -> bool {
+        let mut task_calls = Vec::new();
+        let mut controls = Vec::new();
+        for call in &reply.tool_calls {
+            match Control::from_call(call).filter(|_| self.agent.is_some()) {
+                Some(control) => controls.push((call.id.clone(), control)),
+                None => task_calls.push(call.clone()),
+            }
+        }
+        let summary = (!reply.text.is_empty()).then(|| reply.text.clone());
+
+        let parsed = if reply.tool_calls.is_empty() {
+            trace!("Parsing plan JSON");
+            parser::parse_plan(&reply.text, &self.config.default_shell)
+        } else if task_calls.is_empty() {
+            Ok(parser::ParsedPlan {
+                summary,
+                tasks: Vec::new(),
+            })
+        } else {
+            parser::plan_from_tool_calls(summary, &task_calls, &self.config.default_shell)
+        };
+        let parsed = match parsed {
+            Ok(parsed) => parsed,
             Err(err) => {
                 let formatted = format_error_chain(&err);
                 error!("Failed parsing plan: {}", formatted);
                 self.log(format!("Failed parsing plan: {}", formatted));
+                if append && let Some(agent) = &mut self.agent {
+                    let _ = self.conversation.log(ConversationEntry::Plan {
+                        timestamp: Utc::now().to_rfc3339(),
+                        summary: None,
+                        task_count: 0,
+                        response: Some(reply.text.clone()),
+                        tool_calls: reply.tool_calls.clone(),
+                    });
+                    for call in &reply.tool_calls {
+                        agent.answer(&call.id, format!("Not added: {formatted}"), true);
+                    }
+                }
+                return false;
+            }
+        };
+        info!("Plan parsed successfully: {} tasks", parsed.tasks.len());
+
+        let start = if append {
+            self.tasks.len()
+        } else {
+            self.summary = parsed.summary.clone();
+            self.tasks.clear();
+            self.selected = 0;
+            self.detail_scroll_offset = 0;
+            self.failure_prompt = None;
+            self.conflict_prompt = None;
+            0
+        };
+        self.tasks.extend(parsed.tasks.iter().cloned());
+
+        // Log plan to conversation (include full response for context)
+        let _ = self.conversation.log(ConversationEntry::Plan {
+            timestamp: Utc::now().to_rfc3339(),
+            summary: parsed.summary.clone(),
+            task_count: parsed.tasks.len(),
+            response: Some(reply.text.clone()),
+            tool_calls: reply.tool_calls.clone(),
+        });
+
+        info!("Evaluating {} tasks against allowlist", parsed.tasks.len());
+        let mut blocked_count = 0;
+        for (idx, task) in self.tasks.iter_mut().enumerate().skip(start) {
+            trace!("Evaluating task {}: {}", idx, task.description);
+            match self.allowlist.evaluate(task) {
+                Ok(status) => {
+                    debug!("Task {} status: {:?}", idx, status);
+                    task.status = status;
+                }
+                Err(err) => {
+                    debug!("Task {} blocked: {}", idx, err);
+                    task.status = TaskStatus::Blocked(err.to_string());
+                    blocked_count += 1;
+                }
+            }
+        }
+        if blocked_count > 0 {
+            trace!("{} task(s) blocked by allowlist", blocked_count);
+        }
+        self.check_escalation(start);
+        self.preview_file_edits(start);
+
+        // Auto-complete Note tasks immediately and remove them from the list
+        let mut notes_to_remove = Vec::new();
+        for (idx, task) in self.tasks.iter_mut().enumerate().skip(start) {
+            if matches!(task.detail, TaskDetail::Note { .. })
+                && matches!(task.status, TaskStatus::Ready | TaskStatus::Proposed)
+            {
+                info!("Auto-completing note task: {}", task.description);
+
+                if let TaskDetail::Note { ref details } = task.detail {
+                    let _ = self.conversation.log(ConversationEntry::Note {
+                        timestamp: Utc::now().to_rfc3339(),
+                        task_id: task.id.clone(),
+                        description: task.description.clone(),
+                        details: details.clone(),
+                    });
+                }
+
+                notes_to_remove.push(idx);
+            }
+        }
+
+        for &idx in notes_to_remove.iter().rev() {
+            self.tasks.remove(idx);
+            if self.selected >= idx && self.selected > 0 {
+                self.selected -= 1;
+            }
+        }
+
+        for (call_id, control) in controls {
+            self.apply_control(&call_id, control);
+        }
+
+        self.sort_tasks_by_status();
+
+        trace!("Persisting plan");
+        self.persist_plan();
+        true
+    }
+
+    fn apply_control(&mut self, call_id: &str, control: anyhow::Result<Control>) {
+        let Some(agent) = &mut self.agent else {
+            return;
+        };
+        match control {
+            Ok(Control::Finish { summary }) => {
+                agent.answer(call_id, "Done.", false);
+                agent.conclude(summary);
+            }
+            Ok(Control::WithdrawTask {
+                tool_use_id,
+                reason,
+            }) => {
+                let pending = self.tasks.iter().position(|task| {
+                    task.tool_use_id.as_deref() == Some(tool_use_id.as_str())
+                        && !task.status.is_finished()
+                        && task.status != TaskStatus::Running
+                });
+                let Some(idx) = pending else {
+                    agent.answer(call_id, "No pending task was proposed by that call.", true);
+                    return;
+                };
+                agent.answer(call_id, "Withdrawn.", false);
+                let task = &mut self.tasks[idx];
+                task.status = TaskStatus::Skipped;
+                task.annotations.push(match reason {
+                    Some(reason) => format!("withdrawn: {reason}"),
+                    None => "withdrawn".to_string(),
+                });
+                let description = task.description.clone();
+                self.approval_queue.retain(|queued| *queued != idx);
+                self.log(format!("Agent withdrew '{}'", description));
+            }
+            Err(err) => {
+                agent.answer(call_id, format_error_chain(&err), true);
             }
         }
     }
 
+    /// Report finished tasks to the model and ask for its next step, or end
+    /// the run if the model has concluded or a limit is reached.
+    fn agent_step(&mut self) {
+        if self.plan_receiver.is_some() {
+            return;
+        }
+        let Some(agent) = &mut self.agent else {
+            return;
+        };
+        if agent.awaiting {
+            return;
+        }
+
+        let (results, later) = agent.collect_results(&self.tasks, &self.execution_results);
+        if !results.is_empty() || !later.is_empty() {
+            let _ = self.conversation.log(ConversationEntry::ToolResults {
+                timestamp: Utc::now().to_rfc3339(),
+                results,
+                later,
+            });
+        }
+
+        if let Some(conclusion) = agent.conclusion().map(str::to_string) {
+            if self.first_pending_index().is_none() {
+                self.agent = None;
+                self.log("✓ Agent finished.");
+                self.show_analysis(conclusion);
+            }
+            return;
+        }
+        if let Some(limit) = agent.limit_reached() {
+            self.agent = None;
+            self.log(format!("Agent run stopped: reached its limit of {}.", limit));
+            self.check_and_synthesize_results();
+            return;
+        }
+
+        agent.awaiting = true;
+        let progress = agent.progress();
+        self.log(format!("Sending results back to the model ({})...", progress));
+        let history = self.conversation.load_history().unwrap_or_else(|e| {
+            warn!("Failed to load conversation history: {}", e);
+            vec![]
+        });
+        self.spawn_plan_request(move |client, on_progress| {
+            client.continue_plan(&history, on_progress)
+        });
+    }
+
+    /// A plan request failed; an agent run waiting on it can't go on.
+    fn end_agent_after_failure(&mut self) {
+        if self.agent.as_ref().is_some_and(|agent| agent.awaiting) {
+            self.agent = None;
+            self.log("Agent run stopped.");
+            self.continue_sequential_execution();
+        }
+    }
+
+    /// Progress of the agent run, while one is going.
+    pub fn agent_progress(&self) -> Option<String> {
+        self.agent.as_ref().map(AgentRun::progress)
+    }
+
     pub fn move_next(&mut self) {
         if self.tasks.is_empty() {
             return;
         }
         self.selected = (self.selected + 1).min(self.tasks.len() - 1);
+        self.detail_scroll_offset = 0;
     }
 
     pub fn move_prev(&mut self) {
@@ -291,6 +860,202 @@ impl App {
             return;
         }
         self.selected -= 1;
+        self.detail_scroll_offset = 0;
+    }
+
+    pub fn scroll_detail_up(&mut self, lines: usize) {
+        self.detail_scroll_offset = self.detail_scroll_offset.saturating_sub(lines);
+    }
+
+    pub fn scroll_detail_down(&mut self, lines: usize) {
+        self.detail_scroll_offset = self.detail_scroll_offset.saturating_add(lines);
+    }
+
+    /// Find out in the background whether root commands from `start` on can
+    /// run; `block_root_tasks` deals with the answer.
+    fn check_escalation(&mut self, start: usize) {
+        if self.config.dry_run || !self.tasks[start..].iter().any(needs_root) {
+            return;
+        }
+        self.spawn_escalation_probe(Some(start));
+    }
+
+    /// Ask the escalation method whether it can run commands as root on a
+    /// background thread; the answer arrives through `poll_escalation`. A
+    /// probe already on its way answers for this one too.
+    fn spawn_escalation_probe(&mut self, start: Option<usize>) {
+        if self.escalation_receiver.is_some() {
+            return;
+        }
+        let (tx, rx) = mpsc::channel();
+        self.escalation_receiver = Some(rx);
+        let escalation = self.executor.escalation().clone();
+        thread::spawn(move || {
+            trace!("Background thread: probing privilege escalation");
+            let readiness = escalation.readiness();
+            let _ = tx.send(EscalationCheck::Probed { start, readiness });
+        });
+    }
+
+    pub fn poll_escalation(&mut self) {
+        let Some(rx) = self.escalation_receiver.take() else {
+            return;
+        };
+        match rx.try_recv() {
+            Ok(EscalationCheck::Probed { start, readiness }) => {
+                if let Some(start) = start {
+                    self.block_root_tasks(start, &readiness);
+                }
+                if let Some(idx) = self.root_task_waiting.take() {
+                    self.selected = idx;
+                    if matches!(
+                        self.tasks.get(idx).map(|task| &task.status),
+                        Some(TaskStatus::Ready | TaskStatus::Proposed)
+                    ) {
+                        self.readiness = Some(readiness);
+                        self.execute_selected();
+                    } else {
+                        self.continue_sequential_execution();
+                    }
+                }
+            }
+            Ok(EscalationCheck::PasswordChecked { password, accepted }) => {
+                self.password_checked(password, accepted);
+            }
+            Err(TryRecvError::Empty) => self.escalation_receiver = Some(rx),
+            Err(TryRecvError::Disconnected) => {
+                warn!("Escalation check channel disconnected before answering");
+                self.log("Checking sudo failed unexpectedly.");
+                self.root_task_waiting = None;
+            }
+        }
+    }
+
+    /// Block root commands from `start` on up front when there is no way to
+    /// become root, rather than letting them fail or hang on a password prompt.
+    fn block_root_tasks(&mut self, start: usize, readiness: &Readiness) {
+        let start = start.min(self.tasks.len());
+        match readiness {
+            Readiness::Root | Readiness::Ready => {}
+            Readiness::NeedsPassword => {
+                self.log("Some tasks need root; sudo will ask for your password first.");
+            }
+            Readiness::Unavailable(reason) => {
+                let method = self.executor.escalation().method().label();
+                for task in self.tasks[start..].iter_mut().filter(|t| needs_root(t)) {
+                    task.status =
+                        TaskStatus::Blocked(format!("needs root, but {method} can't: {reason}"));
+                }
+                self.log(format!("Root tasks blocked: {}", reason));
+            }
+        }
+    }
+
+    pub fn is_asking_password(&self) -> bool {
+        self.password_prompt.is_some()
+    }
+
+    /// Whether sudo is still checking the submitted password.
+    pub fn is_checking_password(&self) -> bool {
+        self.password_prompt.is_some() && self.escalation_receiver.is_some()
+    }
+
+    /// Characters typed so far, for drawing a mask.
+    pub fn password_len(&self) -> usize {
+        self.password_input.chars().count()
+    }
+
+    pub fn push_password_char(&mut self, c: char) {
+        self.password_input.push(c);
+    }
+
+    pub fn pop_password_char(&mut self) {
+        self.password_input.pop();
+    }
+
+    /// Check the typed password with sudo on a background thread; if it is
+    /// right, the task that was waiting for it runs once the answer arrives.
+    pub fn submit_password(&mut self) {
+        if self.escalation_receiver.is_some() {
+            self.log("Still checking with sudo. Please wait.");
+            return;
+        }
+        let password = Password::new(std::mem::take(&mut self.password_input));
+        let (tx, rx) = mpsc::channel();
+        self.escalation_receiver = Some(rx);
+        let escalation = self.executor.escalation().clone();
+        thread::spawn(move || {
+            trace!("Background thread: checking sudo password");
+            let accepted = escalation.check_password(&password);
+            let _ = tx.send(EscalationCheck::PasswordChecked { password, accepted });
+        });
+    }
+
+    fn password_checked(&mut self, password: Password, accepted: anyhow::Result<bool>) {
+        match accepted {
+            Ok(true) => {
+                self.executor.set_sudo_password(password);
+                self.log("sudo password accepted.");
+                if let Some(idx) = self.password_prompt.take() {
+                    self.selected = idx;
+                    self.readiness = Some(Readiness::Ready);
+                    self.execute_selected();
+                }
+            }
+            Ok(false) => {
+                self.log("sudo rejected the password. Try again, or Esc to skip the task.");
+            }
+            Err(err) => {
+                error!("Could not check sudo password: {}", err);
+                self.log(format!("Could not check the password: {}", err));
+            }
+        }
+    }
+
+    /// Give up on the root task waiting for a password and move on.
+    pub fn cancel_password(&mut self) {
+        self.password_input.clear();
+        let Some(idx) = self.password_prompt.take() else {
+            return;
+        };
+        if let Some(task) = self.tasks.get_mut(idx) {
+            task.status = TaskStatus::Skipped;
+            let description = task.description.clone();
+            self.log(format!("✗ Skipped (no sudo password): '{}'", description));
+        }
+        self.persist_plan();
+        self.continue_sequential_execution();
+    }
+
+    /// Diff every file edit from `start` on against what is on disk now, so
+    /// the details pane shows what would change before anything is written.
+    /// An edit whose anchor can't be found is blocked up front. The contents
+    /// seen here are hashed so the edit can't later clobber a newer version.
+    fn preview_file_edits(&mut self, start: usize) {
+        for task in &mut self.tasks[start..] {
+            let TaskDetail::FileEdit(file_edit) = &mut task.detail else {
+                continue;
+            };
+            let Some(path) = file_edit.path.clone() else {
+                continue;
+            };
+            let preview = edit::read_current(Path::new(&path))
+                .map_err(Error::from)
+                .and_then(|current| {
+                    file_edit.planned = Some(edit::snapshot(current.as_deref()));
+                    let new_text = edit::apply(current.as_deref(), file_edit)?;
+                    Ok(diff::unified_diff(&path, current.as_deref(), &new_text))
+                });
+            match preview {
+                Ok(diff) => file_edit.diff = Some(diff),
+                Err(err) => {
+                    debug!("Edit of {} can't be applied: {}", path, err);
+                    if !matches!(task.status, TaskStatus::Blocked(_)) {
+                        task.status = TaskStatus::Blocked(format!("edit can't be applied: {err}"));
+                    }
+                }
+            }
+        }
     }
 
 
@@ -320,6 +1085,15 @@ impl App {
 
     pub fn execute_selected(&mut self) {
         info!("Executing selected task (index: {})", self.selected);
+        if self.running_command.is_some() {
+            warn!("A command is already running - not starting another");
+            self.log("A task is still running. Please wait for it to finish.");
+            return;
+        }
+        if self.is_streamed_task(self.selected) {
+            self.log("That task's plan is still arriving. Please wait for it to finish.");
+            return;
+        }
         let (detail, description) = {
             let Some(task) = self.tasks.get_mut(self.selected) else {
                 warn!("No task at selected index {}", self.selected);
@@ -348,65 +1122,60 @@ impl App {
 
         match detail {
             TaskDetail::Command(cmd) => {
-                info!("Running command: {} (shell: {})", cmd.command, cmd.shell);
-                trace!(
-                    "Command details: cwd={:?}, requires_root={}",
-                    cmd.cwd, cmd.requires_root
-                );
-                match self.executor.run_command(&cmd) {
-                    Ok(result) => {
-                        info!(
-                            "Command executed successfully: exit_code={}, stdout_len={}, stderr_len={}",
-                            result.status,
-                            result.stdout.len(),
-                            result.stderr.len()
-                        );
-
-                        // Store result for display
-                        self.execution_results.insert(self.selected, result.clone());
-
-                        // Log to conversation
-                        let _ = self.conversation.log(ConversationEntry::Command {
-                            timestamp: Utc::now().to_rfc3339(),
-                            task_id: task_id.clone(),
-                            description: description.clone(),
-                            command: cmd.command.clone(),
-                            shell: cmd.shell.clone(),
-                            exit_code: result.status,
-                            stdout: result.stdout.clone(),
-                            stderr: result.stderr.clone(),
-                        });
-
-                        self.mark_complete_with_log(
-                            format!("Executed '{}' exit {}", description, result.status),
-                            Some(result),
-                            None,
-                        );
-
-                        // After execution, continue to next task in sequence
-                        self.continue_sequential_execution();
-                    }
-                    Err(err) => {
-                        let formatted = format_error_chain(&err);
-                        error!("Command execution failed: {}", formatted);
-                        self.log(format!("Execution failed: {}", formatted));
-                        self.set_blocked(format!("execution failed: {}", formatted));
+                if cmd.interactive
+                    && !self.config.dry_run
+                    && self.executor.interactive_mode() == InteractiveMode::Handover
+                {
+                    // sudo asks on the terminal itself, so no password prompt here
+                    self.log(format!("Handing the terminal to '{}'.", cmd.command));
+                    self.pending_handover = Some(Handover {
+                        task_id,
+                        description,
+                        command: cmd,
+                    });
+                    return;
+                }
+                if cmd.requires_root && !self.config.dry_run {
+                    match self.readiness.take() {
+                        Some(Readiness::NeedsPassword) => {
+                            if let Some(task) = self.tasks.get_mut(self.selected) {
+                                task.status = TaskStatus::Ready;
+                            }
+                            self.password_prompt = Some(self.selected);
+                            self.log("sudo needs your password to run this task as root.");
+                            return;
+                        }
+                        Some(_) => {}
+                        None => {
+                            // Run it once the probe says whether sudo wants a password
+                            if let Some(task) = self.tasks.get_mut(self.selected) {
+                                task.status = TaskStatus::Ready;
+                            }
+                            self.root_task_waiting = Some(self.selected);
+                            self.spawn_escalation_probe(None);
+                            return;
+                        }
                     }
                 }
+                self.spawn_command(task_id, description, cmd);
             }
             TaskDetail::FileEdit(edit) => {
                 let path_str = edit.path.as_deref().unwrap_or("<no path>");
                 info!(
-                    "Applying file edit: {} ({} bytes)",
+                    "Applying file edit: {} ({}, {} bytes)",
                     path_str,
-                    edit.new_text.len()
+                    edit.operation.label(),
+                    edit.payload_len()
                 );
-                match self.executor.apply_file_edit(&edit) {
+                match self.executor.apply_file_edit(&edit, &task_id) {
                     Ok(outcome) => {
                         info!("File edit successful: {}", outcome.path.display());
                         if let Some(ref backup) = outcome.backup_path {
                             info!("Backup created: {}", backup.display());
                         }
+                        if let Some(validation) = &outcome.validation {
+                            self.log(format!("{} accepted the edit", validation.validator));
+                        }
 
                         // Log to conversation
                         let _ = self.conversation.log(ConversationEntry::FileEdit {
@@ -418,17 +1187,24 @@ impl App {
                                 .backup_path
                                 .as_ref()
                                 .map(|p| p.display().to_string()),
+                            diff: Some(outcome.diff.clone()),
                         });
+                        if let Some(task) = self.tasks.get_mut(self.selected)
+                            && let TaskDetail::FileEdit(edit) = &mut task.detail
+                        {
+                            edit.diff = Some(outcome.diff.clone());
+                        }
 
                         self.mark_complete_with_log(
                             format!(
-                                "Wrote {} (backup: {})",
+                                "Wrote {} (backup: {}; kept {})",
                                 outcome.path.display(),
                                 outcome
                                     .backup_path
                                     .as_ref()
                                     .map(|p| p.display().to_string())
-                                    .unwrap_or_else(|| "none".into())
+                                    .unwrap_or_else(|| "none".into()),
+                                outcome.kept.describe()
                             ),
                             None,
                             Some(outcome),
@@ -440,11 +1216,61 @@ impl App {
                     Err(err) => {
                         let formatted = format_error_chain(&err);
                         error!("File edit failed: {}", formatted);
+                        // Show what the validator objected to in the results pane
+                        if let Some(failed) = err.downcast_ref::<ValidationFailed>() {
+                            self.execution_results.insert(
+                                self.selected,
+                                ExecutionResult {
+                                    status: failed.validation.status,
+                                    stdout: String::new(),
+                                    stderr: failed.validation.output.clone(),
+                                    killed: None,
+                                    truncated: Vec::new(),
+                                },
+                            );
+                        }
+                        if let Some(stale) = err.downcast_ref::<StaleEdit>() {
+                            self.set_blocked(stale.to_string());
+                            self.conflict_prompt = Some(self.selected);
+                            return;
+                        }
                         self.log(format!("Edit failed: {}", formatted));
                         self.set_blocked(format!("edit failed: {}", formatted));
                     }
                 }
             }
+            op @ (TaskDetail::Delete { .. }
+            | TaskDetail::Move { .. }
+            | TaskDetail::Chmod { .. }
+            | TaskDetail::Chown { .. }
+            | TaskDetail::Mkdir { .. }
+            | TaskDetail::Symlink { .. }) => match self.executor.apply_file_op(&op, &task_id) {
+                Ok(outcome) => {
+                    let backup_path = outcome
+                        .backup_path
+                        .as_ref()
+                        .map(|p| p.display().to_string());
+                    let _ = self.conversation.log(ConversationEntry::FileOp {
+                        timestamp: Utc::now().to_rfc3339(),
+                        task_id: task_id.clone(),
+                        description: description.clone(),
+                        summary: outcome.summary.clone(),
+                        backup_path: backup_path.clone(),
+                    });
+                    let summary = match backup_path {
+                        Some(backup) => format!("Done: {} (backup: {})", outcome.summary, backup),
+                        None => format!("Done: {}", outcome.summary),
+                    };
+                    self.mark_complete_with_log(summary, None, None);
+                    self.continue_sequential_execution();
+                }
+                Err(err) => {
+                    let formatted = format_error_chain(&err);
+                    error!("File operation failed: {}", formatted);
+                    self.log(format!("File operation failed: {}", formatted));
+                    self.set_blocked(format!("file operation failed: {}", formatted));
+                }
+            },
             TaskDetail::Note { details } => {
                 info!("Processing note task: {}", details);
 
@@ -482,7 +1308,7 @@ impl App {
                     .iter()
                     .enumerate()
                     .skip(current_idx + 1)
-                    .find(|(_, t)| !matches!(t.status, TaskStatus::Complete));
+                    .find(|(_, t)| !t.status.is_finished());
 
                 if let Some((idx, _)) = next_incomplete {
                     self.selected = idx;
@@ -493,6 +1319,120 @@ impl App {
         }
     }
 
+    fn spawn_command(&mut self, task_id: String, description: String, cmd: CommandTask) {
+        info!("Running command: {} (shell: {})", cmd.command, cmd.shell);
+        trace!(
+            "Command details: cwd={:?}, requires_root={}",
+            cmd.cwd, cmd.requires_root
+        );
+
+        // Run on a background thread so the UI keeps drawing streamed output
+        let (output_tx, output_rx) = mpsc::channel();
+        let (result_tx, result_rx) = mpsc::channel();
+        let executor = self.executor.clone();
+        let thread_cmd = cmd.clone();
+        let cancel = CancelToken::new();
+        let thread_cancel = cancel.clone();
+        thread::spawn(move || {
+            trace!("Background thread: running command");
+            let result = executor.run_command(&thread_cmd, Some(output_tx), Some(thread_cancel));
+            if result_tx.send(result).is_err() {
+                warn!("Failed to send command result back to main thread");
+            }
+        });
+
+        self.running_command = Some(RunningCommand {
+            task_id,
+            description,
+            command: cmd,
+            cancel,
+            output: Vec::new(),
+            output_receiver: output_rx,
+            result_receiver: result_rx,
+        });
+    }
+
+    fn finish_command(
+        &mut self,
+        task_id: &str,
+        description: &str,
+        command: &CommandTask,
+        result: anyhow::Result<ExecutionResult>,
+    ) {
+        let Some(idx) = self.tasks.iter().position(|t| t.id == task_id) else {
+            warn!("Finished command's task {} is no longer in the plan", task_id);
+            return;
+        };
+        // The user may have moved the selection while the command ran
+        self.selected = idx;
+
+        match result {
+            Ok(result) => {
+                info!(
+                    "Command executed: {}, stdout_len={}, stderr_len={}",
+                    result.status_text(),
+                    result.stdout.len(),
+                    result.stderr.len()
+                );
+
+                // Store result for display
+                self.execution_results.insert(self.selected, result.clone());
+
+                // Log to conversation
+                let _ = self.conversation.log(ConversationEntry::Command {
+                    timestamp: Utc::now().to_rfc3339(),
+                    task_id: task_id.to_string(),
+                    description: description.to_string(),
+                    command: command.command.clone(),
+                    shell: command.shell.clone(),
+                    exit_code: result.status,
+                    killed: result.killed,
+                    stdout: result.stdout.clone(),
+                    stderr: result.stderr.clone(),
+                    truncated: result.truncated.clone(),
+                });
+                for cut in &result.truncated {
+                    let whole = match &cut.full_output {
+                        Some(path) => format!("full output in {}", path.display()),
+                        None => "full output not kept".to_string(),
+                    };
+                    self.log(format!(
+                        "{} cut by {} bytes; {}",
+                        cut.stream.label(),
+                        cut.omitted_bytes,
+                        whole
+                    ));
+                }
+
+                if result.killed == Some(KillReason::Cancelled) {
+                    // Leave the rest of the plan for the user to resume by hand
+                    self.mark_cancelled(description, result);
+                    return;
+                }
+
+                if !result.success() {
+                    self.mark_failed(description, result);
+                    return;
+                }
+
+                self.mark_complete_with_log(
+                    format!("Executed '{}' {}", description, result.status_text()),
+                    Some(result),
+                    None,
+                );
+
+                // After execution, continue to next task in sequence
+                self.continue_sequential_execution();
+            }
+            Err(err) => {
+                let formatted = format_error_chain(&err);
+                error!("Command execution failed: {}", formatted);
+                self.log(format!("Execution failed: {}", formatted));
+                self.set_blocked(format!("execution failed: {}", formatted));
+            }
+        }
+    }
+
     fn mark_complete_with_log(
         &mut self,
         summary: String,
@@ -505,7 +1445,7 @@ impl App {
         if let Some(task) = self.tasks.get_mut(self.selected) {
             task.status = TaskStatus::Complete;
             if let Some(result) = &exec {
-                task.annotations.push(format!("exit {}", result.status));
+                task.annotations.push(result.status_text());
             }
             if let Some(edit) = &edit {
                 task.annotations
@@ -523,6 +1463,7 @@ impl App {
                 self.selected = new_idx;
             }
 
+        self.persist_plan();
         self.log(summary);
         if let Some(result) = exec {
             if !result.stdout.trim().is_empty() {
@@ -534,6 +1475,69 @@ impl App {
         }
     }
 
+    fn mark_cancelled(&mut self, description: &str, result: ExecutionResult) {
+        if let Some(task) = self.tasks.get_mut(self.selected) {
+            task.status = TaskStatus::Cancelled;
+            task.annotations.push(result.status_text());
+        }
+        self.persist_plan();
+        self.log(format!(
+            "✗ Cancelled '{}'. Plan paused - select a task and press Enter to continue.",
+            description
+        ));
+        if !result.stdout.trim().is_empty() {
+            self.log(format!("partial stdout: {}", truncate(&result.stdout)));
+        }
+        if !result.stderr.trim().is_empty() {
+            self.log(format!("partial stderr: {}", truncate(&result.stderr)));
+        }
+    }
+
+    /// Record a failed command and apply the configured failure policy.
+    fn mark_failed(&mut self, description: &str, result: ExecutionResult) {
+        if let Some(task) = self.tasks.get_mut(self.selected) {
+            task.status = TaskStatus::Failed {
+                exit_code: result.status,
+            };
+            task.annotations.push(result.status_text());
+        }
+        self.persist_plan();
+        self.log(format!("✗ Failed '{}' ({})", description, result.status_text()));
+        if !result.stderr.trim().is_empty() {
+            self.log(format!("stderr: {}", truncate(&result.stderr)));
+        }
+
+        match self.config.failure_policy {
+            FailurePolicy::Continue => {
+                info!("Failure policy is continue - moving to next task");
+                self.continue_sequential_execution();
+            }
+            FailurePolicy::Stop => {
+                info!("Failure policy is stop - skipping remaining tasks");
+                self.stop_plan();
+            }
+            FailurePolicy::Ask => {
+                info!("Failure policy is ask - waiting for user");
+                self.failure_prompt = Some(self.selected);
+            }
+        }
+    }
+
+    /// Mark every task that hasn't run as skipped, then wrap up the plan.
+    fn stop_plan(&mut self) {
+        let mut skipped = 0;
+        for task in self.tasks.iter_mut() {
+            if !task.status.is_finished() && !matches!(task.status, TaskStatus::Running) {
+                task.status = TaskStatus::Skipped;
+                skipped += 1;
+            }
+        }
+        self.approval_queue.clear();
+        self.persist_plan();
+        self.log(format!("Plan stopped; {} remaining task(s) skipped.", skipped));
+        self.check_and_synthesize_results();
+    }
+
     fn set_blocked(&mut self, reason: String) {
         if let Some(task) = self.tasks.get_mut(self.selected) {
             task.status = TaskStatus::Blocked(reason.clone());
@@ -577,7 +1581,7 @@ impl App {
 
         // Then find any other incomplete task
         for (idx, task) in self.tasks.iter().enumerate() {
-            if !matches!(task.status, TaskStatus::Complete) {
+            if !task.status.is_finished() {
                 self.selected = idx;
                 return;
             }
@@ -589,6 +1593,14 @@ impl App {
 
     /// Check if prompt requests analysis/synthesis and trigger if needed
     fn check_and_synthesize_results(&mut self) {
+        // An agent run hears about the results and decides what comes next
+        if self.agent.is_some() {
+            if self.tasks.iter().all(|t| t.status.is_settled()) {
+                self.agent_step();
+            }
+            return;
+        }
+
         // Synthesize if:
         // 1. All executable tasks are complete
         // 2. We have execution results to analyze
@@ -598,7 +1610,10 @@ impl App {
         let has_executable_tasks = self
             .tasks
             .iter()
-            .any(|t| matches!(t.detail, TaskDetail::Command(_) | TaskDetail::FileEdit(_)));
+            .any(|t| {
+                matches!(t.detail, TaskDetail::Command(_) | TaskDetail::FileEdit(_))
+                    || t.detail.is_file_op()
+            });
 
         if !has_executable_tasks {
             debug!("No executable tasks to synthesize");
@@ -606,7 +1621,7 @@ impl App {
         }
 
         let all_complete = self.tasks.iter().all(|t| {
-            matches!(t.status, TaskStatus::Complete) || matches!(t.detail, TaskDetail::Note { .. })
+            t.status.is_settled() || matches!(t.detail, TaskDetail::Note { .. })
         });
 
         if !all_complete {
@@ -646,11 +1661,18 @@ impl App {
             if matches!(
                 task.detail,
                 TaskDetail::Command(_) | TaskDetail::FileEdit(_)
-            ) {
-                results_summary.push_str(&format!("Task {}: {}\n", idx + 1, task.description));
+            ) || task.detail.is_file_op()
+            {
+                results_summary.push_str(&format!(
+                    "Task {}: {} ({})\n",
+                    idx + 1,
+                    task.description,
+                    task.status_text()
+                ));
 
                 if let Some(exec_result) = self.execution_results.get(&idx) {
-                    results_summary.push_str(&format!("  Exit code: {}\n", exec_result.status));
+                    results_summary
+                        .push_str(&format!("  Result: {}\n", exec_result.status_text()));
                     if !exec_result.stdout.trim().is_empty() {
                         results_summary.push_str(&format!("  STDOUT:\n{}\n", exec_result.stdout));
                     }
@@ -662,6 +1684,9 @@ impl App {
                 if let TaskDetail::FileEdit(_) = task.detail {
                     results_summary.push_str("  File edit completed\n");
                 }
+                if let Some(summary) = task.detail.file_op_summary() {
+                    results_summary.push_str(&format!("  File operation: {}\n", summary));
+                }
 
                 results_summary.push('\n');
             }
@@ -677,36 +1702,70 @@ impl App {
             vec![]
         });
 
-        // Request synthesis (use a different system prompt for analysis)
-        match self.client.synthesize(&synthesis_prompt, &history) {
-            Ok(analysis) => {
-                info!("Received synthesis result ({} chars)", analysis.len());
-                self.analysis_result = Some(analysis.clone());
-                self.analysis_scroll_offset = 0; // Reset scroll when new analysis arrives
-                self.log("✓ Analysis complete. Review in Results pane (↑/↓ to scroll).");
-                self.log("Next: Ask a follow-up question or press 'r' to run more tasks.");
-
-                // Log analysis to conversation
-                let _ = self.conversation.log(ConversationEntry::Note {
-                    timestamp: Utc::now().to_rfc3339(),
-                    task_id: "synthesis".to_string(),
-                    description: "Analysis Result".to_string(),
-                    details: analysis,
-                });
-            }
-            Err(err) => {
-                let formatted = format_error_chain(&err);
-                error!("Synthesis failed: {}", formatted);
-                self.log("All tasks completed successfully. (Synthesis unavailable)");
+        // Request synthesis (use a different system prompt for analysis) on a
+        // background thread; the text streams into the Results pane
+        self.analysis_result = Some(String::new());
+        self.analysis_scroll_offset = 0;
+        let (tx, rx) = mpsc::channel();
+        self.analysis_receiver = Some(rx);
+        let client = self.client.clone();
+        thread::spawn(move || {
+            let progress_tx = tx.clone();
+            let mut on_progress = move |progress| {
+                let update = match progress {
+                    Progress::Text(text) => AnalysisUpdate::Text(text),
+                    Progress::Retrying(notice) => AnalysisUpdate::Retrying(notice),
+                    Progress::ToolCall(_) => return,
+                };
+                let _ = progress_tx.send(update);
+            };
+            let result = client
+                .synthesize(&synthesis_prompt, &history, &mut on_progress)
+                .map_err(|err| format_error_chain(&err));
+            if tx.send(AnalysisUpdate::Done(result)).is_err() {
+                warn!("Failed to send synthesis result back to main thread");
             }
-        }
+        });
+    }
+
+    /// Put `analysis` in the Results pane and the conversation log.
+    fn show_analysis(&mut self, analysis: String) {
+        self.analysis_result = Some(analysis.clone());
+        self.analysis_scroll_offset = 0; // Reset scroll when new analysis arrives
+        self.log("✓ Analysis complete. Review in Results pane (↑/↓ to scroll).");
+        self.log("Next: Ask a follow-up question or press 'r' to run more tasks.");
+
+        // Log analysis to conversation
+        let _ = self.conversation.log(ConversationEntry::Note {
+            timestamp: Utc::now().to_rfc3339(),
+            task_id: "synthesis".to_string(),
+            description: "Analysis Result".to_string(),
+            details: analysis,
+        });
     }
 
     pub fn has_pending_approval(&self) -> bool {
         !self.approval_queue.is_empty()
+            || self.failure_prompt.is_some()
+            || self.conflict_prompt.is_some()
     }
 
     pub fn pending_approval_message(&self) -> Option<String> {
+        if let Some(task) = self.failure_prompt.and_then(|idx| self.tasks.get(idx)) {
+            return Some(format!(
+                "Task '{}' {}.\nContinue with the rest of the plan?\nPress 'y' to continue, 'n' to stop.",
+                task.description,
+                task.status_text()
+            ));
+        }
+        if let Some(task) = self.conflict_prompt.and_then(|idx| self.tasks.get(idx))
+            && let TaskStatus::Blocked(reason) = &task.status
+        {
+            return Some(format!(
+                "Task '{}' was not applied.\n{}.\nPress 'y' to re-plan with the current contents, 'n' to skip it.",
+                task.description, reason
+            ));
+        }
         self.approval_queue
             .front()
             .and_then(|idx| self.tasks.get(*idx))
@@ -729,6 +1788,15 @@ impl App {
     }
 
     pub fn approve_current_blocked(&mut self) {
+        if self.failure_prompt.take().is_some() {
+            self.log("Continuing after failure.");
+            self.continue_sequential_execution();
+            return;
+        }
+        if let Some(idx) = self.conflict_prompt.take() {
+            self.replan_stale_edit(idx);
+            return;
+        }
         if let Some(idx) = self.approval_queue.pop_front()
             && idx < self.tasks.len() {
                 // Store selected task ID before status change
@@ -756,19 +1824,62 @@ impl App {
     }
 
     pub fn reject_current_blocked(&mut self) {
+        if self.failure_prompt.take().is_some() {
+            self.stop_plan();
+            return;
+        }
+        // Skipping a conflicting edit works like rejecting a blocked task
+        if let Some(idx) = self.conflict_prompt.take() {
+            self.approval_queue.push_front(idx);
+        }
         if let Some(idx) = self.approval_queue.pop_front() {
             let message = self
                 .tasks
                 .get(idx)
                 .map(|task| task.description.clone())
                 .unwrap_or_else(|| "unknown task".into());
-            self.log(format!("✗ Skipped: '{}' (remains blocked)", message));
+            if let Some(task) = self.tasks.get_mut(idx) {
+                task.status = TaskStatus::Skipped;
+            }
+            self.persist_plan();
+            self.log(format!("✗ Skipped: '{}'", message));
 
             // After rejecting, continue to next task in sequence
             self.continue_sequential_execution();
         }
     }
 
+    /// Ask for a fresh plan after the edit at `idx` found its file changed,
+    /// giving the model the file as it is now.
+    fn replan_stale_edit(&mut self, idx: usize) {
+        let Some(task) = self.tasks.get(idx) else {
+            return;
+        };
+        let TaskDetail::FileEdit(file_edit) = &task.detail else {
+            return;
+        };
+        let path = file_edit.path.clone().unwrap_or_default();
+        let contents = match edit::read_current(Path::new(&path)) {
+            Ok(Some(text)) => format!("Its current contents are:\n```\n{text}\n```"),
+            Ok(None) => "It no longer exists.".to_string(),
+            Err(err) => format!("It could not be read: {err}."),
+        };
+        let original = self.last_prompt.clone().unwrap_or_default();
+        let prompt = format!(
+            "{original}\n\nThe previous plan's edit of {path} was not applied because the file changed after the plan was made. {contents}\nPlan again against the file as it is now."
+        );
+
+        self.log(format!("Re-planning with the current contents of {}", path));
+        for task in self.tasks.iter_mut() {
+            if !task.status.is_finished() {
+                task.status = TaskStatus::Skipped;
+            }
+        }
+        self.approval_queue.clear();
+        self.persist_plan();
+        self.request_plan(prompt);
+    }
+
 
     /// Maintain tasks in original order - don't reorder by status
     /// This preserves the linear flow of the plan as tasks are completed
@@ -776,7 +1887,7 @@ impl App {
         // Keep tasks in their original order (by created_at)
         // This maintains the linear progression of the plan
         // Completed tasks stay in place, just marked as complete
-        self.tasks.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+        self.tasks.sort_by_key(|t| t.created_at);
     }
 
     /// Start sequential execution: check first task in order and either run it or wait for approval
@@ -800,7 +1911,10 @@ impl App {
                 TaskStatus::Running => {
                     self.log(format!("Waiting for running task: {}", description));
                 }
-                TaskStatus::Complete => {
+                TaskStatus::Complete
+                | TaskStatus::Failed { .. }
+                | TaskStatus::Skipped
+                | TaskStatus::Cancelled => {
                     // Should not happen, but fall back to continue logic
                     self.continue_sequential_execution();
                 }
@@ -813,6 +1927,19 @@ impl App {
 
     /// Continue sequential execution: after a task completes, move to next and execute
     fn continue_sequential_execution(&mut self) {
+        if self.first_pending_index().is_some()
+            && self
+                .agent
+                .as_ref()
+                .is_some_and(|agent| agent.feedback_due(&self.tasks))
+        {
+            self.agent_step();
+            if self.plan_receiver.is_some() {
+                // Carry on once the model has had its say
+                return;
+            }
+        }
+
         // Check if we should synthesize first
         self.check_and_synthesize_results();
 
@@ -832,7 +1959,10 @@ impl App {
                 TaskStatus::Running => {
                     self.log(format!("Waiting for running task: {}", description));
                 }
-                TaskStatus::Complete => {
+                TaskStatus::Complete
+                | TaskStatus::Failed { .. }
+                | TaskStatus::Skipped
+                | TaskStatus::Cancelled => {
                     // Should not happen, but try again on next tick
                 }
             }
@@ -847,11 +1977,17 @@ impl App {
         self.tasks
             .iter()
             .enumerate()
-            .find(|(_, t)| !matches!(t.status, TaskStatus::Complete))
+            .find(|(_, t)| !t.status.is_finished())
             .map(|(idx, _)| idx)
     }
 }
 
+/// A root command the allowlist hasn't already blocked.
+fn needs_root(task: &Task) -> bool {
+    matches!(&task.detail, TaskDetail::Command(cmd) if cmd.requires_root)
+        && !matches!(task.status, TaskStatus::Blocked(_))
+}
+
 fn format_error_chain(err: &Error) -> String {
     let mut parts = Vec::new();
     for cause in err.chain() {
@@ -0,0 +1,407 @@
+//! Backups of files sysaidmin is about to overwrite. Each backup keeps the
+//! original file name and carries the session id, a timestamp and a content
+//! hash, and `index.jsonl` records which task made it.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::fileops;
+
+const INDEX_FILE: &str = "index.jsonl";
+
+#[derive(Debug, Clone)]
+pub struct BackupStore {
+    dir: PathBuf,
+    session_id: String,
+    /// Backups kept per original file; 0 keeps them all. Backups made in
+    /// this session are never pruned, since undo may still need them.
+    retention: usize,
+}
+
+/// One line of the backup index.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupRecord {
+    pub created_at: DateTime<Utc>,
+    pub session_id: String,
+    pub task_id: String,
+    pub original: PathBuf,
+    /// Empty when `created` is set.
+    pub backup: PathBuf,
+    pub sha256: String,
+    /// The edit created the file, so there is nothing to restore and undo
+    /// deletes it instead.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub created: bool,
+    /// Hash of what sysaidmin wrote over the original, so undo can tell
+    /// whether the file was changed again afterwards. `None` when the
+    /// original was deleted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub written_sha256: Option<String>,
+    /// Permission bits and `(uid, gid)` of the original, so a deleted file
+    /// comes back as it was rather than as a fresh file owned by whoever
+    /// runs the undo.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<(u32, u32)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub undone_at: Option<DateTime<Utc>>,
+}
+
+impl BackupStore {
+    pub fn new(dir: PathBuf, session_id: impl Into<String>, retention: usize) -> Self {
+        Self {
+            dir,
+            session_id: session_id.into(),
+            retention,
+        }
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// Copy `path` into the backup directory if it exists, record it in the
+    /// index and prune older backups of the same file. `new_contents` is what
+    /// is about to replace it, or `None` if it is about to be deleted.
+    pub fn backup(
+        &self,
+        path: &Path,
+        task_id: &str,
+        new_contents: Option<&[u8]>,
+    ) -> Result<Option<BackupRecord>> {
+        if !path.is_file() {
+            debug!("Nothing to back up at {}", path.display());
+            return Ok(None);
+        }
+        self.create_dir()?;
+
+        let contents =
+            fs::read(path).with_context(|| format!("failed reading {}", path.display()))?;
+        let (mode, owner) = fs::metadata(path)
+            .map(|meta| mode_and_owner(&meta))
+            .unwrap_or_default();
+        let sha256 = sha256_hex(&contents);
+        let created_at = Utc::now();
+        let backup = self.unused_backup_path(path, created_at, &sha256);
+        // Backups often copy root-only files, so only the owner may read them.
+        fileops::private_file()
+            .write(true)
+            .create_new(true)
+            .open(&backup)
+            .and_then(|mut file| file.write_all(&contents))
+            .with_context(|| format!("failed writing backup {}", backup.display()))?;
+        info!("Backed up {} to {}", path.display(), backup.display());
+
+        let record = BackupRecord {
+            created_at,
+            session_id: self.session_id.clone(),
+            task_id: task_id.to_string(),
+            original: path.to_path_buf(),
+            backup,
+            sha256,
+            created: false,
+            written_sha256: new_contents.map(sha256_hex),
+            mode,
+            owner,
+            undone_at: None,
+        };
+        self.append_index(&record)?;
+        self.prune(path)?;
+        Ok(Some(record))
+    }
+
+    /// Record that `path`, which doesn't exist yet, is about to be created
+    /// with `new_contents`, so undo can remove it again.
+    pub fn record_created(
+        &self,
+        path: &Path,
+        task_id: &str,
+        new_contents: &[u8],
+    ) -> Result<BackupRecord> {
+        self.create_dir()?;
+        let record = BackupRecord {
+            created_at: Utc::now(),
+            session_id: self.session_id.clone(),
+            task_id: task_id.to_string(),
+            original: path.to_path_buf(),
+            backup: PathBuf::new(),
+            sha256: String::new(),
+            created: true,
+            written_sha256: Some(sha256_hex(new_contents)),
+            mode: None,
+            owner: None,
+            undone_at: None,
+        };
+        info!("Recorded creation of {}", path.display());
+        self.append_index(&record)?;
+        self.prune(path)?;
+        Ok(record)
+    }
+
+    /// Every backup still on record, oldest first.
+    pub fn index(&self) -> Result<Vec<BackupRecord>> {
+        let path = self.dir.join(INDEX_FILE);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let data = fs::read_to_string(&path)
+            .with_context(|| format!("failed reading {}", path.display()))?;
+        let mut records = Vec::new();
+        for line in data.lines().filter(|l| !l.trim().is_empty()) {
+            match serde_json::from_str(line) {
+                Ok(record) => records.push(record),
+                Err(err) => warn!("Skipping bad backup index line: {}", err),
+            }
+        }
+        Ok(records)
+    }
+
+    /// Backups that haven't been restored yet, newest first. Without a
+    /// session id, only the most recent session that made backups counts.
+    pub fn undoable(&self, session_id: Option<&str>) -> Result<Vec<BackupRecord>> {
+        let records = self.index()?;
+        let Some(session) = session_id
+            .map(str::to_string)
+            .or_else(|| records.last().map(|r| r.session_id.clone()))
+        else {
+            return Ok(Vec::new());
+        };
+        Ok(records
+            .into_iter()
+            .rev()
+            .filter(|r| r.session_id == session && r.undone_at.is_none())
+            .collect())
+    }
+
+    /// Record that `undone` has been rolled back.
+    pub fn mark_undone(&self, undone: &BackupRecord) -> Result<()> {
+        let mut records = self.index()?;
+        for record in records.iter_mut().filter(|r| {
+            r.original == undone.original
+                && r.created_at == undone.created_at
+                && r.backup == undone.backup
+        }) {
+            record.undone_at = Some(Utc::now());
+        }
+        self.write_index(&records)
+    }
+
+    /// The backup directory, private to its owner even if it already existed.
+    fn create_dir(&self) -> Result<()> {
+        fileops::private_dir(&self.dir)
+            .with_context(|| format!("failed to create backup dir {}", self.dir.display()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&self.dir, fs::Permissions::from_mode(0o700))
+                .with_context(|| format!("failed to restrict backup dir {}", self.dir.display()))?;
+        }
+        Ok(())
+    }
+
+    fn unused_backup_path(&self, path: &Path, at: DateTime<Utc>, sha256: &str) -> PathBuf {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "file".into());
+        let stem = format!(
+            "{name}.{}.{}.{}",
+            self.session_id,
+            at.format("%Y%m%dT%H%M%S%.3f"),
+            &sha256[..12]
+        );
+        let mut candidate = self.dir.join(format!("{stem}.bak"));
+        let mut n = 1;
+        while candidate.exists() {
+            candidate = self.dir.join(format!("{stem}-{n}.bak"));
+            n += 1;
+        }
+        candidate
+    }
+
+    fn append_index(&self, record: &BackupRecord) -> Result<()> {
+        let path = self.dir.join(INDEX_FILE);
+        let mut file = fileops::private_file()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("failed opening {}", path.display()))?;
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+        Ok(())
+    }
+
+    /// Drop the oldest backups of `original` beyond the retention count,
+    /// leaving this session's alone.
+    fn prune(&self, original: &Path) -> Result<()> {
+        if self.retention == 0 {
+            return Ok(());
+        }
+        let records = self.index()?;
+        let for_file = records.iter().filter(|r| r.original == original).count();
+        if for_file <= self.retention {
+            return Ok(());
+        }
+
+        let mut to_drop = for_file - self.retention;
+        let mut kept = Vec::with_capacity(records.len());
+        for record in records {
+            if to_drop > 0 && record.original == original && record.session_id != self.session_id {
+                to_drop -= 1;
+                debug!("Pruning backup {}", record.backup.display());
+                if record.created {
+                    continue;
+                }
+                if let Err(err) = fs::remove_file(&record.backup) {
+                    warn!("Could not remove {}: {}", record.backup.display(), err);
+                }
+            } else {
+                kept.push(record);
+            }
+        }
+
+        self.write_index(&kept)
+    }
+
+    fn write_index(&self, records: &[BackupRecord]) -> Result<()> {
+        let mut data = String::new();
+        for record in records {
+            data.push_str(&serde_json::to_string(record)?);
+            data.push('\n');
+        }
+        let path = self.dir.join(INDEX_FILE);
+        let tmp = self.dir.join(format!("{INDEX_FILE}.tmp"));
+        fileops::private_file()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)
+            .and_then(|mut file| file.write_all(data.as_bytes()))
+            .with_context(|| format!("failed writing {}", tmp.display()))?;
+        fs::rename(&tmp, &path).with_context(|| format!("failed replacing {}", path.display()))
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+#[cfg(unix)]
+fn mode_and_owner(meta: &fs::Metadata) -> (Option<u32>, Option<(u32, u32)>) {
+    use std::os::unix::fs::MetadataExt;
+    (Some(meta.mode() & 0o7777), Some((meta.uid(), meta.gid())))
+}
+
+#[cfg(not(unix))]
+fn mode_and_owner(_meta: &fs::Metadata) -> (Option<u32>, Option<(u32, u32)>) {
+    (None, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backups_keep_name_and_record_task() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("sshd_config");
+        fs::write(&file, "Port 22\n").unwrap();
+        let store = BackupStore::new(tmp.path().join("backups"), "20250101-000000", 5);
+
+        let record = store
+            .backup(&file, "task-1", Some(b"Port 2222\n".as_slice()))
+            .unwrap()
+            .unwrap();
+        let name = record
+            .backup
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        assert!(name.starts_with("sshd_config.20250101-000000."));
+        assert!(name.contains(&record.sha256[..12]));
+        assert_eq!(fs::read_to_string(&record.backup).unwrap(), "Port 22\n");
+        assert_eq!(store.index().unwrap(), vec![record.clone()]);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&record.backup), 0o600);
+            assert_eq!(mode(&tmp.path().join("backups")), 0o700);
+        }
+
+        let missing = store
+            .backup(&tmp.path().join("nope"), "task-2", Some(b"".as_slice()))
+            .unwrap();
+        assert!(missing.is_none());
+    }
+
+    #[test]
+    fn old_backups_are_rotated_out() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("app.conf");
+        let other = tmp.path().join("other.conf");
+        fs::write(&other, "x").unwrap();
+        let dir = tmp.path().join("backups");
+        let earlier = BackupStore::new(dir.clone(), "s1", 2);
+        let current = BackupStore::new(dir, "s2", 2);
+
+        earlier.backup(&other, "t0", Some(b"y".as_slice())).unwrap();
+        let mut made = Vec::new();
+        for i in 0..5 {
+            fs::write(&file, format!("v{i}")).unwrap();
+            let store = if i < 3 { &earlier } else { &current };
+            made.push(
+                store
+                    .backup(&file, &format!("t{}", i + 1), Some(b"next".as_slice()))
+                    .unwrap()
+                    .unwrap(),
+            );
+        }
+
+        // s1 kept all three of its own, then s2 pruned them down, but never
+        // its own backups, which undo may still need.
+        let index = current.index().unwrap();
+        let tasks: Vec<_> = index.iter().map(|r| r.task_id.as_str()).collect();
+        assert_eq!(tasks, ["t0", "t4", "t5"]);
+        assert!(!made[0].backup.exists());
+        assert!(!made[2].backup.exists());
+        assert_eq!(fs::read_to_string(&made[4].backup).unwrap(), "v4");
+    }
+
+    #[test]
+    fn undoable_lists_latest_session_newest_first() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("a.conf");
+        fs::write(&file, "a").unwrap();
+        let dir = tmp.path().join("backups");
+        let first = BackupStore::new(dir.clone(), "s1", 0);
+        let second = BackupStore::new(dir.clone(), "s2", 0);
+
+        first.backup(&file, "t1", Some(b"b".as_slice())).unwrap();
+        let older = second
+            .backup(&file, "t2", Some(b"c".as_slice()))
+            .unwrap()
+            .unwrap();
+        let newer = second
+            .backup(&file, "t3", Some(b"d".as_slice()))
+            .unwrap()
+            .unwrap();
+
+        let tasks = |records: Vec<BackupRecord>| -> Vec<String> {
+            records.into_iter().map(|r| r.task_id).collect()
+        };
+        assert_eq!(tasks(second.undoable(None).unwrap()), ["t3", "t2"]);
+        assert_eq!(tasks(first.undoable(Some("s1")).unwrap()), ["t1"]);
+
+        second.mark_undone(&newer).unwrap();
+        assert_eq!(second.undoable(None).unwrap(), vec![older]);
+    }
+}
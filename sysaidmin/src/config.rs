@@ -5,23 +5,78 @@ use anyhow::{Context, Result, anyhow};
 use log::{debug, info, trace, warn};
 use serde::Deserialize;
 
+use crate::agent::AgentConfig;
 use crate::allowlist::AllowlistConfig;
+use crate::environment::EnvironmentConfig;
+use crate::escalation::EscalationMethod;
+use crate::http::HttpConfig;
+use crate::limits::LimitsConfig;
+use crate::pty::InteractiveMode;
+use crate::retry::RetryConfig;
+use crate::validate::ValidatorConfig;
 
 const DEFAULT_MODEL: &str = "claude-4-5-sonnet";
 const DEFAULT_SHELL: &str = "/bin/bash";
 const DEFAULT_API_URL: &str = "https://api.anthropic.com/v1/messages";
+const DEFAULT_COMMAND_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_BACKUP_RETENTION: usize = 10;
+const DEFAULT_OUTPUT_MAX_BYTES: usize = 64 * 1024;
+const DEFAULT_OUTPUT_MAX_LINES: usize = 2000;
+
+/// What the sequential runner does after a command exits unsuccessfully.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Skip the rest of the plan.
+    #[default]
+    Stop,
+    /// Carry on with the next task.
+    Continue,
+    /// Ask the user whether to carry on.
+    Ask,
+}
 
 #[derive(Debug, Clone)]
 pub struct AppConfig {
     pub api_key: String,
     pub api_url: String,
     pub model: String,
+    /// Plan through tool calls; off for models without tool support, which
+    /// then answer with a JSON plan in text.
+    pub plan_with_tools: bool,
+    /// Stream responses, so tasks and analysis show up as they arrive.
+    pub stream_responses: bool,
+    /// How failed API requests are retried.
+    pub retry: RetryConfig,
+    /// Timeouts, proxy and certificates for API requests.
+    pub http: HttpConfig,
     pub default_shell: String,
     pub allowlist: AllowlistConfig,
     pub history_limit: usize,
     pub offline_mode: bool,
     pub dry_run: bool,
     pub session_root: PathBuf,
+    /// Default wall-clock limit for commands; 0 disables the limit.
+    pub command_timeout_secs: u64,
+    /// Command output kept per stream, beyond which only the head and tail
+    /// are kept and the rest is spilled to a file. 0 disables a cap.
+    pub output_max_bytes: usize,
+    pub output_max_lines: usize,
+    pub failure_policy: FailurePolicy,
+    /// Backups kept per edited file; older ones are pruned. 0 keeps them all.
+    pub backup_retention: usize,
+    /// Extra validators, tried before the built-in ones.
+    pub validators: Vec<ValidatorConfig>,
+    /// How `requires_root` commands become root.
+    pub escalation: EscalationMethod,
+    /// What to do with commands that need a terminal.
+    pub interactive_mode: InteractiveMode,
+    /// Resource limits and sandboxing for commands, by pattern.
+    pub limits: LimitsConfig,
+    /// What commands inherit from sysaidmin's environment and what is set.
+    pub environment: EnvironmentConfig,
+    /// Whether, and for how long, the model keeps planning from results.
+    pub agent: AgentConfig,
 }
 
 #[derive(Debug, Deserialize)]
@@ -29,12 +84,27 @@ struct FileConfig {
     anthropic_api_key: Option<String>,
     anthropic_api_url: Option<String>,
     anthropic_model: Option<String>,
+    plan_with_tools: Option<bool>,
+    stream_responses: Option<bool>,
+    retry: Option<RetryConfig>,
+    http: Option<HttpConfig>,
     default_shell: Option<String>,
     allowlist: Option<AllowlistConfig>,
     history_limit: Option<usize>,
     offline_mode: Option<bool>,
     dry_run: Option<bool>,
     session_dir: Option<String>,
+    command_timeout_secs: Option<u64>,
+    output_max_bytes: Option<usize>,
+    output_max_lines: Option<usize>,
+    failure_policy: Option<FailurePolicy>,
+    backup_retention: Option<usize>,
+    validators: Option<Vec<ValidatorConfig>>,
+    escalation: Option<EscalationMethod>,
+    interactive_mode: Option<InteractiveMode>,
+    limits: Option<LimitsConfig>,
+    environment: Option<EnvironmentConfig>,
+    agent: Option<AgentConfig>,
 }
 
 fn empty_file_config() -> FileConfig {
@@ -42,12 +112,27 @@ fn empty_file_config() -> FileConfig {
         anthropic_api_key: None,
         anthropic_api_url: None,
         anthropic_model: None,
+        plan_with_tools: None,
+        stream_responses: None,
+        retry: None,
+        http: None,
         default_shell: None,
         allowlist: None,
         history_limit: None,
         offline_mode: None,
         dry_run: None,
         session_dir: None,
+        command_timeout_secs: None,
+        output_max_bytes: None,
+        output_max_lines: None,
+        failure_policy: None,
+        backup_retention: None,
+        validators: None,
+        escalation: None,
+        interactive_mode: None,
+        limits: None,
+        environment: None,
+        agent: None,
     }
 }
 
@@ -71,6 +156,18 @@ impl AppConfig {
             .unwrap_or_else(|| DEFAULT_MODEL.to_string());
         info!("Model: {}", model);
 
+        let plan_with_tools = file_cfg.plan_with_tools.unwrap_or(true);
+        debug!("Plan with tools: {}", plan_with_tools);
+
+        let stream_responses = file_cfg.stream_responses.unwrap_or(true);
+        debug!("Stream responses: {}", stream_responses);
+
+        let retry = file_cfg.retry.unwrap_or_default();
+        debug!("API retries: {:?}", retry);
+
+        let http = file_cfg.http.unwrap_or_default().with_env(env_value)?;
+        debug!("HTTP: {}", http.describe());
+
         let default_shell = file_cfg
             .default_shell
             .unwrap_or_else(|| DEFAULT_SHELL.to_string());
@@ -94,6 +191,59 @@ impl AppConfig {
             warn!("Dry-run mode enabled");
         }
 
+        let command_timeout_secs = file_cfg
+            .command_timeout_secs
+            .unwrap_or(DEFAULT_COMMAND_TIMEOUT_SECS);
+        debug!("Command timeout: {}s", command_timeout_secs);
+
+        let output_max_bytes = file_cfg
+            .output_max_bytes
+            .unwrap_or(DEFAULT_OUTPUT_MAX_BYTES);
+        let output_max_lines = file_cfg
+            .output_max_lines
+            .unwrap_or(DEFAULT_OUTPUT_MAX_LINES);
+        debug!(
+            "Output caps: {} bytes, {} lines",
+            output_max_bytes, output_max_lines
+        );
+
+        let failure_policy = file_cfg.failure_policy.unwrap_or_default();
+        debug!("Failure policy: {:?}", failure_policy);
+
+        let backup_retention = file_cfg
+            .backup_retention
+            .unwrap_or(DEFAULT_BACKUP_RETENTION);
+        debug!("Backup retention: {}", backup_retention);
+
+        let validators = file_cfg.validators.unwrap_or_default();
+        debug!("Custom validators: {}", validators.len());
+
+        let escalation = file_cfg.escalation.unwrap_or_default();
+        debug!("Escalation method: {}", escalation.label());
+
+        let interactive_mode = file_cfg.interactive_mode.unwrap_or_default();
+        debug!("Interactive mode: {:?}", interactive_mode);
+
+        let limits = file_cfg.limits.unwrap_or_default();
+        debug!(
+            "Default limits: {:?}, {} rules",
+            limits.defaults,
+            limits.rules.len()
+        );
+
+        let environment = file_cfg.environment.unwrap_or_default();
+        debug!(
+            "Command environment: clean={}, locale={:?}",
+            environment.clean.unwrap_or(true),
+            environment.locale
+        );
+
+        let agent = file_cfg.agent.unwrap_or_default();
+        if agent.enabled && !plan_with_tools {
+            warn!("Agent mode needs plan_with_tools; it stays off");
+        }
+        debug!("Agent mode: {}, feedback: {:?}", agent.enabled, agent.feedback);
+
         trace!("Resolving session directory");
         let session_root = resolve_session_dir(file_cfg.session_dir.as_deref())?;
         info!("Session root: {}", session_root.display());
@@ -103,14 +253,34 @@ impl AppConfig {
             api_key,
             api_url,
             model,
+            plan_with_tools,
+            stream_responses,
+            retry,
+            http,
             default_shell,
             allowlist,
             history_limit,
             offline_mode,
             dry_run,
             session_root,
+            command_timeout_secs,
+            output_max_bytes,
+            output_max_lines,
+            failure_policy,
+            backup_retention,
+            validators,
+            escalation,
+            interactive_mode,
+            limits,
+            environment,
+            agent,
         })
     }
+
+    /// Agent mode feeds results back through tool calls, so it needs them.
+    pub fn agent_mode(&self) -> bool {
+        self.agent.enabled && self.plan_with_tools
+    }
 }
 
 fn read_file_config() -> Result<FileConfig> {
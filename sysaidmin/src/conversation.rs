@@ -4,6 +4,10 @@ use std::io::{BufRead, BufReader, Write};
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 
+use crate::executor::KillReason;
+use crate::output::Truncation;
+use crate::tools::{ToolCall, ToolResult};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ConversationEntry {
@@ -17,6 +21,9 @@ pub enum ConversationEntry {
         task_count: usize,
         #[serde(skip_serializing_if = "Option::is_none")]
         response: Option<String>, // Full JSON response for context
+        /// The tasks as proposed through tool calls, when the model used tools.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tool_calls: Vec<ToolCall>,
     },
     Command {
         timestamp: String,
@@ -24,9 +31,14 @@ pub enum ConversationEntry {
         description: String,
         command: String,
         shell: String,
-        exit_code: i32,
+        exit_code: Option<i32>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        killed: Option<KillReason>,
         stdout: String,
         stderr: String,
+        /// Streams cut by the output caps, and where their full output is.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        truncated: Vec<Truncation>,
     },
     FileEdit {
         timestamp: String,
@@ -34,6 +46,17 @@ pub enum ConversationEntry {
         description: String,
         path: String,
         backup_path: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        diff: Option<String>,
+    },
+    /// A delete, move, chmod, chown, mkdir or symlink task.
+    FileOp {
+        timestamp: String,
+        task_id: String,
+        description: String,
+        summary: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        backup_path: Option<String>,
     },
     Note {
         timestamp: String,
@@ -41,6 +64,42 @@ pub enum ConversationEntry {
         description: String,
         details: String,
     },
+    /// Agent mode's answer to the latest plan's tool calls, sent back to
+    /// the model once its tasks have run.
+    ToolResults {
+        timestamp: String,
+        /// One per tool call of the plan before it.
+        results: Vec<ToolResult>,
+        /// Tasks from earlier plans that finished since they were reported
+        /// as pending.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        later: Vec<ToolResult>,
+    },
+    /// A file edit that was undone by restoring its backup.
+    Rollback {
+        timestamp: String,
+        task_id: String,
+        path: String,
+        backup_path: String,
+    },
+}
+
+/// Human-readable outcome of a logged command, for feeding back to the model.
+pub fn describe_exit(exit_code: Option<i32>, killed: Option<KillReason>) -> String {
+    match (exit_code, killed) {
+        (_, Some(reason)) if reason.is_limit() => reason.label().to_string(),
+        (_, Some(reason)) => format!("{} (process group killed)", reason.label()),
+        (Some(code), None) => code.to_string(),
+        (None, None) => "none (killed by signal)".to_string(),
+    }
+}
+
+/// Where the conversation log lives: `sysaidmin.conversation.jsonl` in the
+/// working directory.
+pub fn default_log_path() -> PathBuf {
+    std::env::current_dir()
+        .unwrap_or_else(|_| PathBuf::from("."))
+        .join("sysaidmin.conversation.jsonl")
 }
 
 pub struct ConversationLogger {
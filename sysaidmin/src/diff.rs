@@ -0,0 +1,70 @@
+//! Unified diffs between a file on disk and the contents an edit would write.
+
+use diffy::DiffOptions;
+
+/// Lines of unchanged context around each hunk.
+const CONTEXT_LINES: usize = 3;
+
+/// Unified diff from `old` to `new`, labelled with `path`. Empty when the two
+/// are identical. `old` of `None` means the file doesn't exist yet.
+pub fn unified_diff(path: &str, old: Option<&str>, new: &str) -> String {
+    let before = old.unwrap_or("");
+    if old.is_some() && before == new {
+        return String::new();
+    }
+    let old_label = if old.is_some() {
+        format!("a{}", display_path(path))
+    } else {
+        "/dev/null".to_string()
+    };
+    DiffOptions::new()
+        .set_context_len(CONTEXT_LINES)
+        .set_original_filename(old_label)
+        .set_modified_filename(format!("b{}", display_path(path)))
+        .create_patch(before, new)
+        .to_string()
+}
+
+/// `(added, removed)` line counts of a unified diff.
+pub fn stats(diff: &str) -> (usize, usize) {
+    diff.lines()
+        .filter(|l| !l.starts_with("+++") && !l.starts_with("---"))
+        .fold((0, 0), |(add, del), line| match line.as_bytes().first() {
+            Some(b'+') => (add + 1, del),
+            Some(b'-') => (add, del + 1),
+            _ => (add, del),
+        })
+}
+
+fn display_path(path: &str) -> String {
+    if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{path}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_changed_lines_with_context() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\n";
+        let new = "a\nb\nc\nd\nE\nf\ng\nh\n";
+        let diff = unified_diff("/etc/x.conf", Some(old), new);
+        assert!(diff.starts_with("--- a/etc/x.conf\n+++ b/etc/x.conf\n"));
+        assert!(diff.contains("@@ -2,7 +2,7 @@"));
+        assert!(diff.contains("\n-e\n+E\n"));
+        assert_eq!(stats(&diff), (1, 1));
+
+        assert_eq!(unified_diff("/etc/x.conf", Some(old), old), "");
+    }
+
+    #[test]
+    fn new_files_diff_against_dev_null() {
+        let diff = unified_diff("/etc/new.conf", None, "one\ntwo\n");
+        assert!(diff.starts_with("--- /dev/null\n"));
+        assert_eq!(stats(&diff), (2, 0));
+    }
+}
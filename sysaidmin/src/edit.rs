@@ -0,0 +1,274 @@
+//! Turning a file edit operation plus the current file contents into the new
+//! contents. Nothing here touches the filesystem except `read_current`.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::backup::sha256_hex;
+use crate::task::{EditOperation, FileEditTask, PlannedContent};
+
+#[derive(Debug, thiserror::Error)]
+pub enum EditError {
+    #[error("{0} needs an existing file")]
+    MissingFile(&'static str),
+    #[error("anchor line '{0}' not found")]
+    AnchorNotFound(String),
+    #[error("anchor line '{0}' matches {1} lines; it must match exactly one")]
+    AmbiguousAnchor(String, usize),
+    #[error("search text not found")]
+    SearchNotFound,
+    #[error("search text occurs {0} times; it must occur exactly once")]
+    AmbiguousSearch(usize),
+    #[error("invalid regex '{0}': {1}")]
+    InvalidRegex(String, regex::Error),
+    #[error("regex '{0}' matches nothing")]
+    NoRegexMatch(String),
+    #[error("invalid patch: {0}")]
+    InvalidPatch(String),
+    #[error("patch does not apply: {0}")]
+    PatchFailed(String),
+}
+
+/// The file changed after the plan was made, so an edit planned against the
+/// old contents could overwrite someone else's change.
+#[derive(Debug, thiserror::Error)]
+#[error("conflict: {path} changed since the plan was made")]
+pub struct StaleEdit {
+    pub path: String,
+}
+
+/// Snapshot of file contents as read by `read_current`.
+pub fn snapshot(current: Option<&str>) -> PlannedContent {
+    match current {
+        Some(text) => PlannedContent::Sha256(sha256_hex(text.as_bytes())),
+        None => PlannedContent::Missing,
+    }
+}
+
+/// Contents of `path`, or `None` if it doesn't exist.
+pub fn read_current(path: &Path) -> io::Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(Some(text)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// New file contents after applying `edit` to `current` (`None` when the
+/// file doesn't exist yet).
+pub fn apply(current: Option<&str>, edit: &FileEditTask) -> Result<String, EditError> {
+    let op = &edit.operation;
+    let existing = || current.ok_or(EditError::MissingFile(op.label()));
+    match op {
+        EditOperation::Rewrite => Ok(edit.new_text.clone()),
+        EditOperation::Patch { patch } => {
+            let parsed = diffy::Patch::from_str(patch)
+                .map_err(|err| EditError::InvalidPatch(err.to_string()))?;
+            diffy::apply(current.unwrap_or(""), &parsed)
+                .map_err(|err| EditError::PatchFailed(err.to_string()))
+        }
+        EditOperation::Replace { search, replace } => {
+            let text = existing()?;
+            match text.matches(search.as_str()).count() {
+                0 => Err(EditError::SearchNotFound),
+                1 => Ok(text.replacen(search.as_str(), replace, 1)),
+                n => Err(EditError::AmbiguousSearch(n)),
+            }
+        }
+        EditOperation::InsertBefore { anchor, text } => {
+            insert_at_anchor(existing()?, anchor, text, false)
+        }
+        EditOperation::InsertAfter { anchor, text } => {
+            insert_at_anchor(existing()?, anchor, text, true)
+        }
+        EditOperation::Append { text } => {
+            let mut out = current.unwrap_or("").to_string();
+            if !out.is_empty() && !out.ends_with('\n') {
+                out.push('\n');
+            }
+            out.push_str(text);
+            Ok(out)
+        }
+        EditOperation::RegexReplace {
+            pattern,
+            replacement,
+        } => {
+            let text = existing()?;
+            let re =
+                Regex::new(pattern).map_err(|err| EditError::InvalidRegex(pattern.clone(), err))?;
+            if !re.is_match(text) {
+                return Err(EditError::NoRegexMatch(pattern.clone()));
+            }
+            Ok(re.replace_all(text, replacement.as_str()).into_owned())
+        }
+    }
+}
+
+/// Anchors match whole lines, ignoring surrounding whitespace, and must be
+/// unique so the insert can't land in the wrong place.
+fn insert_at_anchor(
+    current: &str,
+    anchor: &str,
+    text: &str,
+    after: bool,
+) -> Result<String, EditError> {
+    let lines: Vec<&str> = current.split_inclusive('\n').collect();
+    let matches: Vec<usize> = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| line.trim() == anchor.trim())
+        .map(|(idx, _)| idx)
+        .collect();
+    let idx = match matches.as_slice() {
+        [] => return Err(EditError::AnchorNotFound(anchor.to_string())),
+        [idx] => *idx,
+        many => {
+            return Err(EditError::AmbiguousAnchor(anchor.to_string(), many.len()));
+        }
+    };
+
+    let mut block = text.to_string();
+    if !block.ends_with('\n') {
+        block.push('\n');
+    }
+    let mut out = String::with_capacity(current.len() + block.len() + 1);
+    for (i, line) in lines.iter().enumerate() {
+        if i == idx && !after {
+            out.push_str(&block);
+        }
+        out.push_str(line);
+        if i == idx && after {
+            if !line.ends_with('\n') {
+                out.push('\n');
+            }
+            out.push_str(&block);
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONF: &str = "Port 22\nPermitRootLogin yes\nPasswordAuthentication yes\n";
+
+    fn edit(operation: EditOperation) -> FileEditTask {
+        FileEditTask {
+            path: Some("/etc/ssh/sshd_config".into()),
+            new_text: String::new(),
+            description: None,
+            operation,
+            diff: None,
+            planned: None,
+        }
+    }
+
+    #[test]
+    fn applies_each_operation() {
+        let replace = edit(EditOperation::Replace {
+            search: "PermitRootLogin yes".into(),
+            replace: "PermitRootLogin no".into(),
+        });
+        assert_eq!(
+            apply(Some(CONF), &replace).unwrap(),
+            "Port 22\nPermitRootLogin no\nPasswordAuthentication yes\n"
+        );
+
+        let before = edit(EditOperation::InsertBefore {
+            anchor: "  PermitRootLogin yes ".into(),
+            text: "# hardened".into(),
+        });
+        assert_eq!(
+            apply(Some(CONF), &before).unwrap(),
+            "Port 22\n# hardened\nPermitRootLogin yes\nPasswordAuthentication yes\n"
+        );
+
+        let after = edit(EditOperation::InsertAfter {
+            anchor: "Port 22".into(),
+            text: "Port 2222\n".into(),
+        });
+        assert!(
+            apply(Some(CONF), &after)
+                .unwrap()
+                .starts_with("Port 22\nPort 2222\nPermit")
+        );
+
+        let append = edit(EditOperation::Append {
+            text: "MaxAuthTries 3\n".into(),
+        });
+        assert_eq!(apply(Some("a"), &append).unwrap(), "a\nMaxAuthTries 3\n");
+        assert_eq!(apply(None, &append).unwrap(), "MaxAuthTries 3\n");
+
+        let regex = edit(EditOperation::RegexReplace {
+            pattern: r"(?m)^(\w+) yes$".into(),
+            replacement: "$1 no".into(),
+        });
+        assert_eq!(
+            apply(Some(CONF), &regex).unwrap(),
+            "Port 22\nPermitRootLogin no\nPasswordAuthentication no\n"
+        );
+
+        let patch = edit(EditOperation::Patch {
+            patch: "--- a/sshd_config\n+++ b/sshd_config\n@@ -1,3 +1,3 @@\n Port 22\n-PermitRootLogin yes\n+PermitRootLogin prohibit-password\n PasswordAuthentication yes\n".into(),
+        });
+        assert_eq!(
+            apply(Some(CONF), &patch).unwrap(),
+            "Port 22\nPermitRootLogin prohibit-password\nPasswordAuthentication yes\n"
+        );
+    }
+
+    #[test]
+    fn missing_anchors_fail_cleanly() {
+        let missing = edit(EditOperation::Replace {
+            search: "UsePAM yes".into(),
+            replace: "UsePAM no".into(),
+        });
+        assert!(matches!(
+            apply(Some(CONF), &missing),
+            Err(EditError::SearchNotFound)
+        ));
+
+        let twice = edit(EditOperation::Replace {
+            search: "yes".into(),
+            replace: "no".into(),
+        });
+        assert!(matches!(
+            apply(Some(CONF), &twice),
+            Err(EditError::AmbiguousSearch(2))
+        ));
+
+        let anchor = edit(EditOperation::InsertAfter {
+            anchor: "Port 2222".into(),
+            text: "x".into(),
+        });
+        assert!(matches!(
+            apply(Some(CONF), &anchor),
+            Err(EditError::AnchorNotFound(_))
+        ));
+        assert!(matches!(
+            apply(None, &anchor),
+            Err(EditError::MissingFile("insert_after"))
+        ));
+
+        let regex = edit(EditOperation::RegexReplace {
+            pattern: "^UsePAM".into(),
+            replacement: "".into(),
+        });
+        assert!(matches!(
+            apply(Some(CONF), &regex),
+            Err(EditError::NoRegexMatch(_))
+        ));
+
+        let stale_patch = edit(EditOperation::Patch {
+            patch: "--- a/x\n+++ b/x\n@@ -1,1 +1,1 @@\n-Port 2222\n+Port 22\n".into(),
+        });
+        assert!(matches!(
+            apply(Some(CONF), &stale_patch),
+            Err(EditError::PatchFailed(_))
+        ));
+    }
+}
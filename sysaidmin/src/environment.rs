@@ -0,0 +1,219 @@
+//! The environment executed commands run in. By default they see only a
+//! short list of sysaidmin's own variables, a fixed locale so output reads
+//! the same on every host, and no pager. sysaidmin's API keys never reach
+//! a command, whatever the configuration says.
+
+use std::collections::BTreeMap;
+use std::process::Command;
+
+use log::debug;
+use serde::Deserialize;
+
+/// Never passed on, even outside clean mode or when listed explicitly.
+const SECRET_VARS: &[&str] = &["SYSAIDMIN_API_KEY", "ANTHROPIC_API_KEY"];
+const DEFAULT_PASS_THROUGH: &[&str] = &[
+    "PATH", "HOME", "USER", "LOGNAME", "SHELL", "TERM", "TZ", "TMPDIR",
+];
+const DEFAULT_LOCALE: &str = "C";
+/// Set for every non-interactive command so nothing waits on a pager.
+const NO_PAGER: &[(&str, &str)] = &[("PAGER", "cat"), ("SYSTEMD_PAGER", "")];
+/// Variables a plan may never set for a task, even when the allowlist's
+/// `plan_env_vars` lists them: they change which program runs or what the
+/// shell does before the command, which the allowlist can't see.
+const PLAN_DENIED_VARS: &[&str] = &[
+    "PATH",
+    "IFS",
+    "ENV",
+    "BASH_ENV",
+    "SHELLOPTS",
+    "BASHOPTS",
+    "PROMPT_COMMAND",
+    "PS4",
+];
+const PLAN_DENIED_PREFIXES: &[&str] = &["LD_", "BASH_FUNC_"];
+
+/// The `[environment]` config table.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EnvironmentConfig {
+    /// Start commands from an empty environment plus `pass_through`.
+    /// On by default.
+    pub clean: Option<bool>,
+    /// Variables copied from sysaidmin's environment in clean mode.
+    pub pass_through: Option<Vec<String>>,
+    /// `LC_ALL` for every command; empty leaves the locale alone.
+    pub locale: Option<String>,
+    /// Extra variables set for every command.
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+}
+
+/// How to build a command's environment.
+#[derive(Debug, Clone)]
+pub struct EnvPolicy {
+    clean: bool,
+    pass_through: Vec<String>,
+    locale: Option<String>,
+    set: BTreeMap<String, String>,
+}
+
+impl Default for EnvPolicy {
+    fn default() -> Self {
+        Self::from_config(&EnvironmentConfig::default())
+    }
+}
+
+impl EnvPolicy {
+    pub fn from_config(cfg: &EnvironmentConfig) -> Self {
+        let pass_through = match &cfg.pass_through {
+            Some(names) => names.clone(),
+            None => DEFAULT_PASS_THROUGH.iter().map(|s| s.to_string()).collect(),
+        };
+        let locale = match cfg.locale.as_deref() {
+            Some("") => None,
+            Some(locale) => Some(locale.to_string()),
+            None => Some(DEFAULT_LOCALE.to_string()),
+        };
+        Self {
+            clean: cfg.clean.unwrap_or(true),
+            pass_through,
+            locale,
+            set: cfg.set.clone(),
+        }
+    }
+
+    /// The variables a command gets on top of what it inherits: the
+    /// locale, no pager unless it has a terminal, the configured extras,
+    /// then the task's own, each overriding the one before.
+    pub fn overrides(
+        &self,
+        task_env: &BTreeMap<String, String>,
+        interactive: bool,
+    ) -> Vec<(String, String)> {
+        let mut vars = BTreeMap::new();
+        if let Some(locale) = &self.locale {
+            vars.insert("LC_ALL".to_string(), locale.clone());
+        }
+        if !interactive {
+            for (name, value) in NO_PAGER {
+                vars.insert(name.to_string(), value.to_string());
+            }
+        }
+        vars.extend(self.set.clone());
+        vars.extend(task_env.clone());
+        vars.retain(|name, _| !is_secret(name));
+        vars.into_iter().collect()
+    }
+
+    /// Remove what this policy doesn't pass through from what `cmd` would
+    /// inherit, and set `overrides`. Variables are removed one by one
+    /// rather than with `env_clear`, which a pseudo-terminal's command
+    /// builder can't see.
+    pub fn apply(&self, cmd: &mut Command, overrides: &[(String, String)]) {
+        if self.clean {
+            for (name, _) in std::env::vars_os() {
+                let keep = name.to_str().is_some_and(|name| {
+                    !is_secret(name) && self.pass_through.iter().any(|p| p == name)
+                });
+                if !keep {
+                    cmd.env_remove(name);
+                }
+            }
+        } else {
+            for name in SECRET_VARS {
+                cmd.env_remove(name);
+            }
+        }
+        cmd.envs(overrides.iter().map(|(name, value)| (name, value)));
+        debug!(
+            "Command environment: clean={}, {} overrides",
+            self.clean,
+            overrides.len()
+        );
+    }
+}
+
+fn is_secret(name: &str) -> bool {
+    SECRET_VARS.contains(&name)
+}
+
+/// Whether `name` can be an environment variable name at all.
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+}
+
+/// Whether a plan could ever be allowed to set `name` for a task.
+pub fn plan_may_set(name: &str) -> bool {
+    is_valid_name(name)
+        && !is_secret(name)
+        && !PLAN_DENIED_VARS.contains(&name)
+        && !PLAN_DENIED_PREFIXES
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn task_variables_win_and_secrets_never_get_through() {
+        let cfg = EnvironmentConfig {
+            clean: Some(true),
+            pass_through: Some(vec!["PATH".into(), "ANTHROPIC_API_KEY".into()]),
+            locale: None,
+            set: BTreeMap::from([("SYSTEMD_PAGER".into(), "less".into())]),
+        };
+        let policy = EnvPolicy::from_config(&cfg);
+        let task_env = BTreeMap::from([
+            ("LC_ALL".to_string(), "en_US.UTF-8".to_string()),
+            ("SYSAIDMIN_API_KEY".to_string(), "leak".to_string()),
+        ]);
+        assert_eq!(
+            policy.overrides(&task_env, false),
+            vec![
+                ("LC_ALL".to_string(), "en_US.UTF-8".to_string()),
+                ("PAGER".to_string(), "cat".to_string()),
+                ("SYSTEMD_PAGER".to_string(), "less".to_string()),
+            ]
+        );
+        assert_eq!(
+            policy.overrides(&BTreeMap::new(), true),
+            vec![
+                ("LC_ALL".to_string(), "C".to_string()),
+                ("SYSTEMD_PAGER".to_string(), "less".to_string()),
+            ]
+        );
+
+        let mut cmd = Command::new("env");
+        policy.apply(&mut cmd, &[]);
+        let removed: Vec<_> = cmd
+            .get_envs()
+            .filter(|(_, value)| value.is_none())
+            .map(|(name, _)| name.to_string_lossy().to_string())
+            .collect();
+        assert!(!removed.contains(&"PATH".to_string()), "{removed:?}");
+        // cargo runs tests with CARGO_* set
+        assert!(removed.iter().any(|name| name.starts_with("CARGO")));
+    }
+
+    #[test]
+    fn plans_cannot_set_variables_that_change_what_runs() {
+        assert!(plan_may_set("DEBIAN_FRONTEND"));
+        assert!(plan_may_set("_x1"));
+        for name in [
+            "PATH",
+            "LD_PRELOAD",
+            "BASH_ENV",
+            "BASH_FUNC_ls%%",
+            "1X",
+            "A=B",
+            "",
+        ] {
+            assert!(!plan_may_set(name), "{name}");
+        }
+    }
+}
@@ -0,0 +1,369 @@
+//! Running `requires_root` commands through sudo, doas or run0 when
+//! sysaidmin itself isn't root. Nothing here ever reads from the terminal:
+//! a sudo password comes from the TUI and is written to sudo's stdin.
+
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result, anyhow};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use tempfile::NamedTempFile;
+
+/// How to become root for tasks that need it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EscalationMethod {
+    /// `sudo -n`, or `sudo -S` fed a password typed into the TUI.
+    #[default]
+    Sudo,
+    /// `doas -n`; needs a `nopass` or `persist` rule, as doas only reads
+    /// passwords from a terminal.
+    Doas,
+    /// systemd's `run0`, authorized through polkit.
+    Run0,
+    /// Never escalate; tasks that need root are blocked.
+    None,
+}
+
+impl EscalationMethod {
+    pub fn label(&self) -> &'static str {
+        match self {
+            EscalationMethod::Sudo => "sudo",
+            EscalationMethod::Doas => "doas",
+            EscalationMethod::Run0 => "run0",
+            EscalationMethod::None => "none",
+        }
+    }
+
+    /// The program and its flags for running without any prompt.
+    fn non_interactive(&self) -> Option<&'static [&'static str]> {
+        match self {
+            EscalationMethod::Sudo => Some(&["sudo", "-n"]),
+            EscalationMethod::Doas => Some(&["doas", "-n"]),
+            EscalationMethod::Run0 => Some(&["run0", "--no-ask-password"]),
+            EscalationMethod::None => None,
+        }
+    }
+}
+
+/// A sudo password. Never shown by `Debug`, so it can't end up in a log.
+#[derive(Clone)]
+pub struct Password(String);
+
+impl Password {
+    pub fn new(password: String) -> Self {
+        Self(password)
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Password(<redacted>)")
+    }
+}
+
+/// Whether `requires_root` commands can run right now.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Readiness {
+    /// sysaidmin is already root; commands run as they are.
+    Root,
+    /// The escalation method works without asking for anything.
+    Ready,
+    /// sudo wants a password, which the user can type into the TUI.
+    NeedsPassword,
+    /// There is no way to become root; such tasks must be blocked.
+    Unavailable(String),
+}
+
+/// Variables for a command run as root, in a file only the user can read,
+/// which the root shell sources before the command: the escalation method
+/// resets the environment, and on the command line the values would show
+/// in `ps`. Dropping it removes the file, so it must outlive the command.
+#[derive(Debug)]
+pub struct EnvFile(NamedTempFile);
+
+impl EnvFile {
+    fn write(env: &[(String, String)]) -> Result<Option<Self>> {
+        if env.is_empty() {
+            return Ok(None);
+        }
+        let mut file = tempfile::Builder::new()
+            .prefix("sysaidmin-env-")
+            .tempfile()
+            .context("failed creating a file for the command's environment")?;
+        for (name, value) in env {
+            writeln!(file, "export {name}={}", single_quote(value))?;
+        }
+        file.flush()?;
+        Ok(Some(Self(file)))
+    }
+
+    fn path(&self) -> &Path {
+        self.0.path()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Escalation {
+    method: EscalationMethod,
+    password: Option<Password>,
+}
+
+pub fn is_root() -> bool {
+    #[cfg(unix)]
+    {
+        unsafe { libc::geteuid() == 0 }
+    }
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+impl Escalation {
+    pub fn new(method: EscalationMethod) -> Self {
+        Self {
+            method,
+            password: None,
+        }
+    }
+
+    pub fn method(&self) -> EscalationMethod {
+        self.method
+    }
+
+    pub fn set_password(&mut self, password: Password) {
+        self.password = Some(password);
+    }
+
+    /// Find out whether root commands can run, by asking the escalation
+    /// method to run `true` without prompting.
+    pub fn readiness(&self) -> Readiness {
+        if is_root() {
+            return Readiness::Root;
+        }
+        let Some(base) = self.method.non_interactive() else {
+            return Readiness::Unavailable("privilege escalation is disabled".into());
+        };
+        if self.method == EscalationMethod::Sudo && self.password.is_some() {
+            return Readiness::Ready;
+        }
+
+        let output = Command::new(base[0])
+            .args(&base[1..])
+            .arg("true")
+            .env("LC_ALL", "C")
+            .stdin(Stdio::null())
+            .output();
+        let output = match output {
+            Ok(output) => output,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Readiness::Unavailable(format!("{} is not installed", base[0]));
+            }
+            Err(err) => {
+                return Readiness::Unavailable(format!("could not run {}: {err}", base[0]));
+            }
+        };
+        if output.status.success() {
+            return Readiness::Ready;
+        }
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        debug!("{} probe failed: {}", base[0], stderr);
+        match self.method {
+            EscalationMethod::Sudo if stderr.contains("password is required") => {
+                Readiness::NeedsPassword
+            }
+            EscalationMethod::Doas => Readiness::Unavailable(format!(
+                "doas needs a password, which it only reads from a terminal ({stderr})"
+            )),
+            _ => Readiness::Unavailable(stderr),
+        }
+    }
+
+    /// Ask sudo whether `password` is right, ignoring any cached credentials.
+    pub fn check_password(&self, password: &Password) -> Result<bool> {
+        let mut child = Command::new("sudo")
+            .args(["-S", "-k", "-v", "-p", ""])
+            .env("LC_ALL", "C")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .context("failed running sudo")?;
+        feed_password(&mut child, password);
+        let ok = child.wait().context("failed waiting for sudo")?.success();
+        info!(
+            "sudo password check: {}",
+            if ok { "accepted" } else { "rejected" }
+        );
+        Ok(ok)
+    }
+
+    /// A command that runs `shell -c command` as root with `env` set, the
+    /// password to write to its stdin before anything else, if sudo needs
+    /// one, and the file holding `env`.
+    pub fn command(
+        &self,
+        shell: &str,
+        command: &str,
+        env: &[(String, String)],
+    ) -> Result<(Command, Option<Password>, Option<EnvFile>)> {
+        let Some(base) = self.method.non_interactive() else {
+            return Err(anyhow!(
+                "task needs root but privilege escalation is disabled"
+            ));
+        };
+        let (mut cmd, password) = match (&self.password, self.method) {
+            // -k makes sudo read the password even when its cached
+            // credentials are still valid, so it never reaches the command
+            (Some(password), EscalationMethod::Sudo) => {
+                let mut cmd = Command::new("sudo");
+                cmd.args(["-S", "-k", "-p", ""]);
+                (cmd, Some(password.clone()))
+            }
+            _ => {
+                let mut cmd = Command::new(base[0]);
+                cmd.args(&base[1..]);
+                (cmd, None)
+            }
+        };
+        let env_file = push_target(&mut cmd, shell, command, env)?;
+        debug!("Escalating with {}", self.method.label());
+        Ok((cmd, password, env_file))
+    }
+
+    /// A command that runs `shell -c command` as root inside a terminal,
+    /// and the file holding `env`. With `prompt` the method may ask for a
+    /// password on that terminal; otherwise it must manage without one.
+    pub fn tty_command(
+        &self,
+        shell: &str,
+        command: &str,
+        env: &[(String, String)],
+        prompt: bool,
+    ) -> Result<(Command, Option<EnvFile>)> {
+        let Some(base) = self.method.non_interactive() else {
+            return Err(anyhow!(
+                "task needs root but privilege escalation is disabled"
+            ));
+        };
+        let mut cmd = Command::new(base[0]);
+        if !prompt {
+            cmd.args(&base[1..]);
+        }
+        let env_file = push_target(&mut cmd, shell, command, env)?;
+        debug!("Escalating with {} in a terminal", self.method.label());
+        Ok((cmd, env_file))
+    }
+}
+
+/// Append `-- shell -c command`, with the command first sourcing a file
+/// that exports `env`, if there is any.
+fn push_target(
+    cmd: &mut Command,
+    shell: &str,
+    command: &str,
+    env: &[(String, String)],
+) -> Result<Option<EnvFile>> {
+    let env_file = EnvFile::write(env)?;
+    cmd.arg("--").arg(shell).arg("-c");
+    match &env_file {
+        Some(file) => {
+            let path = single_quote(&file.path().to_string_lossy());
+            cmd.arg(format!(". {path} || exit 126\n{command}"))
+        }
+        None => cmd.arg(command),
+    };
+    Ok(env_file)
+}
+
+/// `value` in single quotes, for a POSIX shell to read back as it is.
+fn single_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Write `password` and a newline to the child's stdin, then close it so
+/// the command itself sees end of input.
+pub fn feed_password(child: &mut std::process::Child, password: &Password) {
+    if let Some(mut stdin) = child.stdin.take()
+        && let Err(err) = writeln!(stdin, "{}", password.0)
+    {
+        warn!("Failed to pass the password to sudo: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_never_show_up_in_debug_output() {
+        let mut escalation = Escalation::new(EscalationMethod::Sudo);
+        escalation.set_password(Password::new("hunter2".into()));
+        let shown = format!("{escalation:?}");
+        assert!(!shown.contains("hunter2"), "{shown}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wraps_commands_for_each_method() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let args = |escalation: &Escalation| {
+            let (cmd, password, _) = escalation.command("/bin/sh", "id -u", &[]).unwrap();
+            let mut words = vec![cmd.get_program().to_string_lossy().to_string()];
+            words.extend(cmd.get_args().map(|a| a.to_string_lossy().to_string()));
+            (words.join(" "), password.is_some())
+        };
+
+        let mut sudo = Escalation::new(EscalationMethod::Sudo);
+        assert_eq!(args(&sudo), ("sudo -n -- /bin/sh -c id -u".into(), false));
+        sudo.set_password(Password::new("pw".into()));
+        assert_eq!(
+            args(&sudo),
+            ("sudo -S -k -p  -- /bin/sh -c id -u".into(), true)
+        );
+
+        // Values stay off the command line, in a file the shell sources
+        let env = [
+            ("LC_ALL".to_string(), "C".to_string()),
+            ("MOTD".to_string(), "it's $HOME".to_string()),
+        ];
+        let (cmd, _, env_file) = sudo
+            .command("/bin/sh", "echo \"$LC_ALL $MOTD\"", &env)
+            .unwrap();
+        let words: Vec<_> = cmd.get_args().map(|a| a.to_string_lossy()).collect();
+        assert_eq!(words[4..7], ["--", "/bin/sh", "-c"]);
+        assert!(!words.iter().any(|word| word.contains("it's")), "{words:?}");
+        let output = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(words[7].as_ref())
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "C it's $HOME\n");
+        let env_file = env_file.unwrap();
+        let mode = std::fs::metadata(env_file.path())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let path = env_file.path().to_path_buf();
+        drop(env_file);
+        assert!(!path.exists());
+
+        let run0 = Escalation::new(EscalationMethod::Run0);
+        assert_eq!(
+            args(&run0),
+            ("run0 --no-ask-password -- /bin/sh -c id -u".into(), false)
+        );
+
+        let none = Escalation::new(EscalationMethod::None);
+        assert!(none.command("/bin/sh", "id -u", &[]).is_err());
+        if !is_root() {
+            assert!(matches!(none.readiness(), Readiness::Unavailable(_)));
+        }
+    }
+}
@@ -1,74 +1,404 @@
 use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result, anyhow};
 use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
 
-use crate::task::{CommandTask, FileEditTask};
+use crate::allowlist::Allowlist;
+use crate::backup::{self, BackupRecord, BackupStore};
+use crate::diff;
+use crate::edit::{self, StaleEdit};
+use crate::environment::EnvPolicy;
+use crate::escalation::{self, EnvFile, Escalation, Password};
+use crate::fileops;
+use crate::limits::{self, LimitPolicy};
+use crate::output::{CappedOutput, OutputCaps, Truncation};
+use crate::paths;
+use crate::pty::{self, InteractiveMode};
+use crate::task::{CommandTask, FileEditTask, TaskDetail};
+use crate::validate::{Validation, ValidatorRegistry};
+
+/// How often a running child is polled for exit, timeout and cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long a killed process group gets between SIGTERM and SIGKILL.
+const KILL_GRACE: Duration = Duration::from_secs(2);
 
 #[derive(Clone)]
 pub struct Executor {
     dry_run: bool,
+    default_timeout: Option<Duration>,
+    /// Re-checked right before a file edit is written.
+    allowlist: Option<Allowlist>,
+    /// Where files are copied before they are overwritten.
+    backups: Option<BackupStore>,
+    /// Checks a staged edit must pass before it is installed.
+    validators: ValidatorRegistry,
+    /// How `requires_root` commands become root when sysaidmin isn't.
+    escalation: Escalation,
+    /// Whether the UI hands the terminal to interactive tasks or snapshots them.
+    interactive_mode: InteractiveMode,
+    /// How much of each output stream a result keeps.
+    output_caps: OutputCaps,
+    /// Where output beyond the caps is written in full.
+    spill_dir: Option<PathBuf>,
+    /// Resource limits and sandboxing, by command.
+    limits: LimitPolicy,
+    /// What commands inherit from sysaidmin's environment and what is set.
+    environment: EnvPolicy,
 }
 
 #[derive(Clone)]
 pub struct ExecutionResult {
-    pub status: i32,
+    /// Exit code, or `None` when the process was terminated by a signal.
+    pub status: Option<i32>,
     pub stdout: String,
     pub stderr: String,
+    /// Set when sysaidmin killed the command rather than letting it exit.
+    pub killed: Option<KillReason>,
+    /// Streams cut down to their head and tail by the output caps.
+    pub truncated: Vec<Truncation>,
+}
+
+/// Why sysaidmin killed a command before it exited on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KillReason {
+    TimedOut,
+    Cancelled,
+    /// An interactive command was stopped once its screen was captured.
+    SnapshotTaken,
+    /// The kernel stopped the command at its CPU time limit.
+    CpuLimit,
+    /// The command seems to have died for want of memory under its memory
+    /// limit; see `ResourceLimits::kill_reason`.
+    MemoryLimit,
+}
+
+impl KillReason {
+    pub fn label(&self) -> &'static str {
+        match self {
+            KillReason::TimedOut => "timed out",
+            KillReason::Cancelled => "cancelled",
+            KillReason::SnapshotTaken => "snapshot taken",
+            KillReason::CpuLimit => "CPU time limit exceeded",
+            KillReason::MemoryLimit => "memory limit probably exceeded",
+        }
+    }
+
+    /// Whether a resource limit, rather than sysaidmin, ended the command.
+    pub fn is_limit(&self) -> bool {
+        matches!(self, KillReason::CpuLimit | KillReason::MemoryLimit)
+    }
+}
+
+/// Shared flag the UI sets to stop a command running on another thread.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    pub fn label(&self) -> &'static str {
+        match self {
+            OutputStream::Stdout => "stdout",
+            OutputStream::Stderr => "stderr",
+        }
+    }
+}
+
+/// One line of command output, delivered while the command is still running.
+#[derive(Debug, Clone)]
+pub struct OutputLine {
+    pub stream: OutputStream,
+    pub text: String,
+}
+
+impl ExecutionResult {
+    pub fn success(&self) -> bool {
+        match self.killed {
+            None => self.status == Some(0),
+            Some(KillReason::SnapshotTaken) => true,
+            Some(_) => false,
+        }
+    }
+
+    pub fn status_text(&self) -> String {
+        if let Some(reason) = self.killed {
+            return reason.label().to_string();
+        }
+        match self.status {
+            Some(code) => format!("exit {code}"),
+            None => "killed by signal".to_string(),
+        }
+    }
 }
 
 pub struct FileEditOutcome {
     pub path: PathBuf,
     pub backup_path: Option<PathBuf>,
+    /// Metadata carried over from the file that was replaced.
+    pub kept: KeptMetadata,
+    /// Unified diff of the change; empty when the contents were unchanged.
+    pub diff: String,
+    /// The validator that accepted the new contents, if one applied.
+    pub validation: Option<Validation>,
+}
+
+pub struct FileOpOutcome {
+    /// What was done, shell style, e.g. `chmod 0640 /etc/app.conf`.
+    pub summary: String,
+    /// Backup of a deleted file.
+    pub backup_path: Option<PathBuf>,
+}
+
+/// Which attributes of the original file survived an atomic replace.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeptMetadata {
+    pub mode: Option<u32>,
+    pub owner: Option<(u32, u32)>,
+    pub xattrs: Vec<String>,
+}
+
+impl KeptMetadata {
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(mode) = self.mode {
+            parts.push(format!("mode {:04o}", mode & 0o7777));
+        }
+        if let Some((uid, gid)) = self.owner {
+            parts.push(format!("owner {uid}:{gid}"));
+        }
+        if !self.xattrs.is_empty() {
+            parts.push(format!("xattrs {}", self.xattrs.join(",")));
+        }
+        if parts.is_empty() {
+            "nothing".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
 }
 
 impl Executor {
     pub fn new(dry_run: bool) -> Self {
         info!("Creating Executor (dry_run={})", dry_run);
-        Self { dry_run }
+        Self {
+            dry_run,
+            default_timeout: None,
+            allowlist: None,
+            backups: None,
+            validators: ValidatorRegistry::default(),
+            escalation: Escalation::default(),
+            interactive_mode: InteractiveMode::default(),
+            output_caps: OutputCaps::default(),
+            spill_dir: None,
+            limits: LimitPolicy::default(),
+            environment: EnvPolicy::default(),
+        }
+    }
+
+    pub fn with_environment(mut self, environment: EnvPolicy) -> Self {
+        self.environment = environment;
+        self
+    }
+
+    pub fn with_limits(mut self, limits: LimitPolicy) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Keep only the head and tail of output beyond `caps`, writing the
+    /// whole of it to a file in `spill_dir`.
+    pub fn with_output_caps(mut self, caps: OutputCaps, spill_dir: PathBuf) -> Self {
+        self.output_caps = caps;
+        self.spill_dir = Some(spill_dir);
+        self
+    }
+
+    pub fn with_interactive_mode(mut self, mode: InteractiveMode) -> Self {
+        self.interactive_mode = mode;
+        self
+    }
+
+    pub fn interactive_mode(&self) -> InteractiveMode {
+        self.interactive_mode
+    }
+
+    pub fn with_escalation(mut self, escalation: Escalation) -> Self {
+        self.escalation = escalation;
+        self
+    }
+
+    pub fn escalation(&self) -> &Escalation {
+        &self.escalation
     }
 
-    pub fn run_command(&self, task: &CommandTask) -> Result<ExecutionResult> {
+    /// Remember the sudo password the user typed, for later root commands.
+    pub fn set_sudo_password(&mut self, password: Password) {
+        self.escalation.set_password(password);
+    }
+
+    pub fn with_validators(mut self, validators: ValidatorRegistry) -> Self {
+        self.validators = validators;
+        self
+    }
+
+    /// Back up files into `store` before overwriting them.
+    pub fn with_backups(mut self, store: BackupStore) -> Self {
+        self.backups = Some(store);
+        self
+    }
+
+    pub fn backups(&self) -> Option<&BackupStore> {
+        self.backups.as_ref()
+    }
+
+    /// Re-check file edit paths against `allowlist` at write time, in case a
+    /// symlink was swapped in after the task was evaluated.
+    pub fn with_allowlist(mut self, allowlist: Allowlist) -> Self {
+        self.allowlist = Some(allowlist);
+        self
+    }
+
+    /// Set the timeout applied to commands that don't carry their own.
+    /// A value of 0 means commands may run indefinitely.
+    pub fn with_default_timeout(mut self, secs: u64) -> Self {
+        self.default_timeout = (secs > 0).then(|| Duration::from_secs(secs));
+        self
+    }
+
+    /// A plan's `timeout_secs` can shorten the configured timeout but never
+    /// lengthen or disable it; 0 from the plan means the default.
+    fn timeout_for(&self, task: &CommandTask) -> Option<Duration> {
+        let requested = task
+            .timeout_secs
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs);
+        match (requested, self.default_timeout) {
+            (Some(requested), Some(default)) => Some(requested.min(default)),
+            (requested, default) => requested.or(default),
+        }
+    }
+
+    /// Run a command, sending each output line to `sink` as it is produced.
+    /// The returned result still carries the complete stdout and stderr.
+    /// Setting `cancel` kills the command's process group. Interactive
+    /// commands can't have the terminal here, so they get a snapshot.
+    pub fn run_command(
+        &self,
+        task: &CommandTask,
+        sink: Option<Sender<OutputLine>>,
+        cancel: Option<CancelToken>,
+    ) -> Result<ExecutionResult> {
         info!("Running command: {} (shell: {})", task.command, task.shell);
         if self.dry_run {
-            warn!("DRY-RUN: Command would execute: {}", task.command);
-            return Ok(ExecutionResult {
-                status: 0,
-                stdout: format!("(dry-run) command would execute: {}", task.command),
-                stderr: String::new(),
-            });
+            return Ok(dry_run_result(task));
+        }
+        if task.interactive {
+            return self.run_snapshot(task, cancel.as_ref());
         }
 
         trace!(
             "Building command: shell={}, command={}",
             task.shell, task.command
         );
-        let mut cmd = Command::new(&task.shell);
-        cmd.arg("-c").arg(&task.command);
+        let env = self.environment.overrides(&task.env, false);
+        let (mut cmd, password, _env_file) = if task.requires_root && !escalation::is_root() {
+            self.escalation.command(&task.shell, &task.command, &env)?
+        } else {
+            let mut cmd = Command::new(&task.shell);
+            cmd.arg("-c").arg(&task.command);
+            (cmd, None, None)
+        };
+        self.environment.apply(&mut cmd, &env);
+        let limits = self.limits.for_command(&task.command);
+        if limits.sandboxed() && task.requires_root && !escalation::is_root() {
+            return Err(anyhow!(
+                "can't sandbox '{}': escalating to root doesn't work inside the sandbox",
+                task.command
+            ));
+        }
+        limits::apply(&mut cmd, &limits)?;
+        cmd.stdin(if password.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
         if let Some(cwd) = &task.cwd {
             info!("Setting working directory: {}", cwd);
             cmd.current_dir(cwd);
         }
+        // Run in a fresh process group so a timeout or cancel can take down
+        // everything the shell spawned, not just the shell itself.
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            cmd.process_group(0);
+        }
 
-        trace!("Executing command");
-        let output = cmd
-            .output()
+        trace!("Spawning command");
+        let mut child = cmd
+            .spawn()
             .with_context(|| format!("failed running shell command '{}'", task.command))?;
+        if let Some(password) = &password {
+            escalation::feed_password(&mut child, password);
+        }
+        let stdout_reader =
+            self.spawn_reader(child.stdout.take(), OutputStream::Stdout, sink.clone());
+        let stderr_reader = self.spawn_reader(child.stderr.take(), OutputStream::Stderr, sink);
+
+        let timeout = self.timeout_for(task);
+        debug!("Command timeout: {:?}", timeout);
+        let (exit_status, killed) = wait_for_exit(&mut child, timeout, cancel.as_ref())
+            .with_context(|| format!("failed waiting for shell command '{}'", task.command))?;
 
-        let status = output.status.code().unwrap_or_default();
-        let stdout_len = output.stdout.len();
-        let stderr_len = output.stderr.len();
+        let (stdout, stdout_cut) = join_reader(stdout_reader);
+        let (stderr, stderr_cut) = join_reader(stderr_reader);
+        let status = exit_status.code();
+        let killed = killed.or_else(|| limits.kill_reason(&exit_status, &stderr));
 
         info!(
-            "Command completed: exit_code={}, stdout_bytes={}, stderr_bytes={}",
-            status, stdout_len, stderr_len
+            "Command completed: exit_code={:?}, killed={:?}, stdout_bytes={}, stderr_bytes={}",
+            status,
+            killed,
+            stdout.len(),
+            stderr.len()
         );
 
-        if status != 0 {
-            warn!("Command exited with non-zero status: {}", status);
-            let stderr_preview = String::from_utf8_lossy(&output.stderr)
+        if let Some(reason) = killed {
+            warn!("Command {}: {}", reason.label(), task.command);
+        } else if status != Some(0) {
+            warn!("Command exited with non-zero status: {:?}", status);
+            let stderr_preview = String::from_utf8_lossy(&stderr)
                 .chars()
                 .take(200)
                 .collect::<String>();
@@ -77,21 +407,136 @@ impl Executor {
 
         Ok(ExecutionResult {
             status,
-            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
-            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+            stdout: String::from_utf8_lossy(&stdout).to_string(),
+            stderr: String::from_utf8_lossy(&stderr).to_string(),
+            killed,
+            truncated: stdout_cut.into_iter().chain(stderr_cut).collect(),
+        })
+    }
+
+    /// Read `source` on its own thread, streaming each line to `sink` and
+    /// keeping what the output caps allow.
+    fn spawn_reader<R: Read + Send + 'static>(
+        &self,
+        source: Option<R>,
+        stream: OutputStream,
+        sink: Option<Sender<OutputLine>>,
+    ) -> Option<JoinHandle<(Vec<u8>, Option<Truncation>)>> {
+        let caps = self.output_caps;
+        let spill_dir = self.spill_dir.clone();
+        source.map(|source| {
+            thread::spawn(move || {
+                let mut reader = BufReader::new(source);
+                let mut output = CappedOutput::new(stream, caps, spill_dir);
+                let mut line = Vec::new();
+                loop {
+                    line.clear();
+                    // A line longer than a piece arrives in several
+                    let mut piece = reader.by_ref().take(caps.piece_len() as u64);
+                    match piece.read_until(b'\n', &mut line) {
+                        Ok(0) => break,
+                        Ok(_) => {
+                            if let Some(sink) = &sink {
+                                let text = String::from_utf8_lossy(&line)
+                                    .trim_end_matches(['\n', '\r'])
+                                    .to_string();
+                                // A closed receiver only means nobody is watching anymore.
+                                let _ = sink.send(OutputLine { stream, text });
+                            }
+                            output.push(&line);
+                        }
+                        Err(err) => {
+                            warn!("Failed reading command output: {}", err);
+                            break;
+                        }
+                    }
+                }
+                output.finish()
+            })
         })
     }
 
-    pub fn apply_file_edit(&self, edit: &FileEditTask) -> Result<FileEditOutcome> {
+    /// Hand the terminal to an interactive command until it exits. The
+    /// caller must have put the TUI aside first; see `pty::handover`.
+    pub fn run_in_terminal(&self, task: &CommandTask) -> Result<ExecutionResult> {
+        info!("Handing the terminal to: {}", task.command);
+        if self.dry_run {
+            return Ok(dry_run_result(task));
+        }
+        let (cmd, _env_file) = self.pty_command(task, true)?;
+        let capture = pty::handover(&cmd)
+            .with_context(|| format!("failed running '{}' in a terminal", task.command))?;
+        Ok(capture.into())
+    }
+
+    fn run_snapshot(
+        &self,
+        task: &CommandTask,
+        cancel: Option<&CancelToken>,
+    ) -> Result<ExecutionResult> {
+        let window = match self.timeout_for(task) {
+            Some(timeout) if task.timeout_secs.is_some_and(|secs| secs > 0) => timeout,
+            _ => pty::SNAPSHOT_WINDOW,
+        };
+        debug!("Snapshot window: {:?}", window);
+        let (cmd, _env_file) = self.pty_command(task, false)?;
+        let capture = pty::snapshot(&cmd, window, cancel)
+            .with_context(|| format!("failed running '{}' in a terminal", task.command))?;
+        Ok(capture.into())
+    }
+
+    /// The command for a pseudo-terminal run. A sudo password typed into
+    /// the TUI is never passed along; with `prompt` set the escalation
+    /// method asks on the terminal itself. The pty crate spawns the child
+    /// itself, so the shell sets the resource limits before the command.
+    /// The command has to finish before the `EnvFile` is dropped.
+    fn pty_command(&self, task: &CommandTask, prompt: bool) -> Result<(Command, Option<EnvFile>)> {
+        let limits = self.limits.for_command(&task.command);
+        if limits.sandboxed() {
+            return Err(anyhow!(
+                "'{}' needs a terminal, which can't be sandboxed",
+                task.command
+            ));
+        }
+        let command = match limits.shell_prefix() {
+            Some(prefix) => {
+                debug!("Applying limits in the shell: {:?}", limits);
+                format!("{prefix}{}", task.command)
+            }
+            None => task.command.clone(),
+        };
+        let env = self.environment.overrides(&task.env, true);
+        let (mut cmd, env_file) = if task.requires_root && !escalation::is_root() {
+            self.escalation
+                .tty_command(&task.shell, &command, &env, prompt)?
+        } else {
+            let mut cmd = Command::new(&task.shell);
+            cmd.arg("-c").arg(&command);
+            (cmd, None)
+        };
+        self.environment.apply(&mut cmd, &env);
+        if let Some(cwd) = &task.cwd {
+            cmd.current_dir(cwd);
+        }
+        Ok((cmd, env_file))
+    }
+
+    pub fn apply_file_edit(&self, edit: &FileEditTask, task_id: &str) -> Result<FileEditOutcome> {
         let path_str = edit.path.as_ref().ok_or_else(|| {
             error!("File edit task missing path");
             anyhow!("file edit missing path")
         })?;
-        let path = PathBuf::from(path_str);
+        let path = match &self.allowlist {
+            Some(allowlist) => allowlist
+                .check_file_path(path_str)
+                .map_err(|err| anyhow!("refusing to write {path_str}: {err}"))?,
+            None => paths::resolve(Path::new(path_str)),
+        };
         info!(
-            "Applying file edit: {} ({} bytes)",
+            "Applying file edit: {} ({}, {} bytes)",
             path.display(),
-            edit.new_text.len()
+            edit.operation.label(),
+            edit.payload_len()
         );
 
         if let Some(parent) = path.parent() {
@@ -100,58 +545,498 @@ impl Executor {
                 .with_context(|| format!("failed to create parent dirs for {}", path.display()))?;
         }
 
+        let current = edit::read_current(&path)
+            .with_context(|| format!("failed reading {}", path.display()))?;
+        if let Some(planned) = &edit.planned
+            && *planned != edit::snapshot(current.as_deref())
+        {
+            warn!("{} changed since the plan was made", path.display());
+            return Err(StaleEdit {
+                path: path.display().to_string(),
+            }
+            .into());
+        }
+        let new_text = edit::apply(current.as_deref(), edit).with_context(|| {
+            format!(
+                "{} edit of {} failed",
+                edit.operation.label(),
+                path.display()
+            )
+        })?;
+        let diff = diff::unified_diff(&path.to_string_lossy(), current.as_deref(), &new_text);
+
         if self.dry_run {
             warn!(
                 "DRY-RUN: Would write {} bytes to {}",
-                edit.new_text.len(),
+                new_text.len(),
                 path.display()
             );
             return Ok(FileEditOutcome {
                 path,
                 backup_path: None,
+                kept: KeptMetadata::default(),
+                diff,
+                validation: None,
             });
         }
 
-        trace!("Creating backup if file exists");
-        let backup_path = self.create_backup_if_exists(&path)?;
-        if let Some(ref backup) = backup_path {
-            info!("Backup created: {}", backup.display());
+        // The path was resolved above, so a symlink here was swapped in since
+        if paths::is_symlink(&path) {
+            return Err(anyhow!(
+                "{} became a symlink after it was checked; refusing to follow it",
+                path.display()
+            ));
+        }
+
+        trace!("Staging and validating new contents");
+        let staged = stage(&path, new_text.as_bytes())?;
+        let validation = self.validators.validate(&path, staged.path())?;
+
+        let backup_path = match &self.backups {
+            Some(store) if current.is_none() => {
+                store.record_created(&path, task_id, new_text.as_bytes())?;
+                None
+            }
+            Some(store) => store
+                .backup(&path, task_id, Some(new_text.as_bytes()))?
+                .map(|record| record.backup),
+            None => {
+                warn!(
+                    "No backup store configured; not backing up {}",
+                    path.display()
+                );
+                None
+            }
+        };
+
+        trace!("Installing file content");
+        let kept = staged.install(&path)?;
+
+        info!(
+            "File edit completed successfully: {} (kept {})",
+            path.display(),
+            kept.describe()
+        );
+
+        Ok(FileEditOutcome {
+            path,
+            backup_path,
+            kept,
+            diff,
+            validation,
+        })
+    }
+
+    /// Carry out a delete, move, chmod, chown, mkdir or symlink task. Every
+    /// path is re-checked against the allowlist, and a regular file is backed
+    /// up before it is deleted.
+    pub fn apply_file_op(&self, op: &TaskDetail, task_id: &str) -> Result<FileOpOutcome> {
+        let summary = op
+            .file_op_summary()
+            .ok_or_else(|| anyhow!("not a file operation"))?;
+        for path in op.file_op_paths() {
+            if let Some(allowlist) = &self.allowlist {
+                allowlist
+                    .check_file_path(&path)
+                    .map_err(|err| anyhow!("refusing to touch {path}: {err}"))?;
+            }
         }
+        info!("Applying file operation: {}", summary);
 
-        trace!("Writing file content");
-        fs::write(&path, edit.new_text.as_bytes())
-            .with_context(|| format!("failed writing {}", path.display()))?;
+        if self.dry_run {
+            warn!("DRY-RUN: Would run {}", summary);
+            return Ok(FileOpOutcome {
+                summary: format!("(dry-run) would {summary}"),
+                backup_path: None,
+            });
+        }
 
-        info!("File edit completed successfully: {}", path.display());
+        // Delete, move and symlink act on the directory entry itself; the
+        // rest act on what it points to, which is what the allowlist checked
+        let entry = |path: &str| paths::resolve_entry(Path::new(path));
+        let resolved = |path: &str| paths::resolve(Path::new(path));
+        let mut backup_path = None;
+        match op {
+            TaskDetail::Delete { path } => {
+                let path = entry(path);
+                if path.is_file() && !paths::is_symlink(&path) {
+                    match &self.backups {
+                        Some(store) => {
+                            backup_path = store
+                                .backup(&path, task_id, None)?
+                                .map(|record| record.backup);
+                        }
+                        None => warn!(
+                            "No backup store configured; not backing up {}",
+                            path.display()
+                        ),
+                    }
+                }
+                fileops::delete(&path)?;
+            }
+            TaskDetail::Move { path, to } => fileops::rename(&entry(path), &entry(to))?,
+            TaskDetail::Chmod { path, mode } => {
+                fileops::chmod(&resolved(path), fileops::parse_mode(mode)?)?;
+            }
+            TaskDetail::Chown { path, owner, group } => {
+                fileops::chown(&resolved(path), owner.as_deref(), group.as_deref())?
+            }
+            TaskDetail::Mkdir { path, mode } => {
+                let mode = mode.as_deref().map(fileops::parse_mode).transpose()?;
+                fileops::mkdir(&resolved(path), mode)?;
+            }
+            TaskDetail::Symlink { path, target } => {
+                fileops::symlink(Path::new(target), &entry(path))?
+            }
+            TaskDetail::Command(_) | TaskDetail::FileEdit(_) | TaskDetail::Note { .. } => {
+                unreachable!("file_op_summary is None for these")
+            }
+        }
 
-        Ok(FileEditOutcome { path, backup_path })
+        Ok(FileOpOutcome {
+            summary,
+            backup_path,
+        })
     }
 
-    fn create_backup_if_exists(&self, path: &Path) -> Result<Option<PathBuf>> {
-        if !path.exists() {
-            trace!("File does not exist, no backup needed: {}", path.display());
-            return Ok(None);
+    /// Put the backed-up contents of `record` back in place, or delete the
+    /// file if the edit created it, provided the file still holds exactly
+    /// what sysaidmin wrote.
+    pub fn undo_edit(&self, record: &BackupRecord) -> Result<()> {
+        let path = &record.original;
+        if record.created {
+            info!("Undoing creation of {}", path.display());
+        } else {
+            info!(
+                "Undoing edit of {} from {}",
+                path.display(),
+                record.backup.display()
+            );
+        }
+        if let Some(allowlist) = &self.allowlist {
+            allowlist
+                .check_file_path(&path.to_string_lossy())
+                .map_err(|err| anyhow!("refusing to restore {}: {err}", path.display()))?;
+        }
+        if paths::is_symlink(path) {
+            return Err(anyhow!(
+                "{} is now a symlink; refusing to restore through it",
+                path.display()
+            ));
+        }
+
+        match &record.written_sha256 {
+            Some(written) => {
+                let current = fs::read(path).with_context(|| {
+                    format!("{} is gone since sysaidmin wrote it", path.display())
+                })?;
+                if backup::sha256_hex(&current) != *written {
+                    return Err(anyhow!(
+                        "{} has changed since sysaidmin wrote it; not restoring",
+                        path.display()
+                    ));
+                }
+            }
+            None if fs::symlink_metadata(path).is_ok() => {
+                return Err(anyhow!(
+                    "{} was recreated since sysaidmin deleted it; not restoring",
+                    path.display()
+                ));
+            }
+            None => {}
+        }
+
+        if record.created {
+            if self.dry_run {
+                warn!("DRY-RUN: Would delete {}", path.display());
+                return Ok(());
+            }
+            fs::remove_file(path).with_context(|| format!("failed deleting {}", path.display()))?;
+        } else {
+            let contents = fs::read(&record.backup)
+                .with_context(|| format!("failed reading backup {}", record.backup.display()))?;
+            if self.dry_run {
+                warn!("DRY-RUN: Would restore {} from backup", path.display());
+                return Ok(());
+            }
+            let staged = stage(path, &contents)?;
+            if record.written_sha256.is_none() {
+                restore_metadata(path, record, staged.tmp.as_file());
+            }
+            staged.install(path)?;
         }
+        if let Some(store) = &self.backups {
+            store.mark_undone(record)?;
+        }
+        info!("Undid edit of {}", path.display());
+        Ok(())
+    }
+}
+
+fn dry_run_result(task: &CommandTask) -> ExecutionResult {
+    warn!("DRY-RUN: Command would execute: {}", task.command);
+    ExecutionResult {
+        status: Some(0),
+        stdout: format!("(dry-run) command would execute: {}", task.command),
+        stderr: String::new(),
+        killed: None,
+        truncated: Vec::new(),
+    }
+}
 
-        info!("File exists, creating backup: {}", path.display());
-        let backup = path.with_extension("sysaidmin.bak");
+impl From<pty::Capture> for ExecutionResult {
+    fn from(capture: pty::Capture) -> Self {
+        Self {
+            status: capture.status,
+            stdout: capture.screen,
+            stderr: String::new(),
+            killed: capture.killed,
+            truncated: Vec::new(),
+        }
+    }
+}
 
-        trace!("Reading original file");
-        let contents =
-            fs::read(path).with_context(|| format!("failed reading {}", path.display()))?;
+fn join_reader(
+    handle: Option<JoinHandle<(Vec<u8>, Option<Truncation>)>>,
+) -> (Vec<u8>, Option<Truncation>) {
+    handle
+        .and_then(|handle| handle.join().ok())
+        .unwrap_or_default()
+}
 
-        trace!("Writing backup file");
-        fs::write(&backup, contents)
-            .with_context(|| format!("failed writing backup {}", backup.display()))?;
+/// Wait for the child to exit, killing its process group once `timeout`
+/// elapses or `cancel` is set. Returns the exit status and, if sysaidmin
+/// killed the command, why.
+fn wait_for_exit(
+    child: &mut Child,
+    timeout: Option<Duration>,
+    cancel: Option<&CancelToken>,
+) -> Result<(ExitStatus, Option<KillReason>)> {
+    if timeout.is_none() && cancel.is_none() {
+        return Ok((child.wait()?, None));
+    }
 
-        info!("Backup created successfully: {}", backup.display());
-        Ok(Some(backup))
+    let started = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok((status, None));
+        }
+        let reason = if cancel.is_some_and(CancelToken::is_cancelled) {
+            Some(KillReason::Cancelled)
+        } else if timeout.is_some_and(|limit| started.elapsed() >= limit) {
+            Some(KillReason::TimedOut)
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            warn!(
+                "Command {} after {:?}, killing process group {}",
+                reason.label(),
+                started.elapsed(),
+                child.id()
+            );
+            kill_process_group(child);
+            return Ok((child.wait()?, Some(reason)));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// New contents written and synced next to the file they will replace, with
+/// its metadata already copied over, waiting to be validated and installed.
+struct StagedFile {
+    tmp: tempfile::NamedTempFile,
+    kept: KeptMetadata,
+}
+
+/// Files are replaced via a temp file in the same directory, so a crash
+/// leaves either the old file or the new one and never half of either. The
+/// rename replaces a symlink at `path` rather than writing through it.
+///
+/// The temp file keeps the target's name as a suffix, since some validators
+/// (`systemd-analyze verify`) judge a file by its extension.
+fn stage(path: &Path, contents: &[u8]) -> Result<StagedFile> {
+    let dir = parent_dir(path);
+    let original = fs::symlink_metadata(path)
+        .ok()
+        .filter(|meta| meta.is_file());
+    let suffix = path
+        .file_name()
+        .map(|name| format!("-{}", name.to_string_lossy()))
+        .unwrap_or_default();
+
+    let mut tmp = tempfile::Builder::new()
+        .prefix(".sysaidmin-")
+        .suffix(&suffix)
+        .tempfile_in(dir)
+        .with_context(|| format!("failed creating temp file in {}", dir.display()))?;
+    tmp.write_all(contents)
+        .with_context(|| format!("failed writing temp file for {}", path.display()))?;
+
+    let kept = match &original {
+        Some(meta) => copy_metadata(path, meta, tmp.as_file()),
+        None => {
+            set_new_file_mode(tmp.as_file());
+            KeptMetadata::default()
+        }
+    };
+
+    tmp.as_file()
+        .sync_all()
+        .with_context(|| format!("failed syncing temp file for {}", path.display()))?;
+    Ok(StagedFile { tmp, kept })
+}
+
+impl StagedFile {
+    fn path(&self) -> &Path {
+        self.tmp.path()
+    }
+
+    /// Rename the staged file onto `path`. Dropping a `StagedFile` without
+    /// installing it removes the temp file.
+    fn install(self, path: &Path) -> Result<KeptMetadata> {
+        self.tmp
+            .persist(path)
+            .map_err(|err| err.error)
+            .with_context(|| format!("failed renaming temp file onto {}", path.display()))?;
+        sync_dir(parent_dir(path));
+        Ok(self.kept)
+    }
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    path.parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+/// Copy ownership, permissions and extended attributes from the file being
+/// replaced. Each one is best effort: an unprivileged run can't chown to root,
+/// and that shouldn't stop the edit, only be missing from the report.
+#[cfg(unix)]
+fn copy_metadata(path: &Path, meta: &fs::Metadata, dest: &fs::File) -> KeptMetadata {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use xattr::FileExt;
+
+    let mut kept = KeptMetadata::default();
+
+    // Ownership first: chown clears setuid/setgid bits set by a later chmod
+    match std::os::unix::fs::fchown(dest, Some(meta.uid()), Some(meta.gid())) {
+        Ok(()) => kept.owner = Some((meta.uid(), meta.gid())),
+        Err(err) => warn!("Could not keep owner of {}: {}", path.display(), err),
+    }
+    match dest.set_permissions(fs::Permissions::from_mode(meta.mode())) {
+        Ok(()) => kept.mode = Some(meta.mode() & 0o7777),
+        Err(err) => warn!("Could not keep mode of {}: {}", path.display(), err),
+    }
+
+    match xattr::list(path) {
+        Ok(names) => {
+            for name in names {
+                let label = name.to_string_lossy().to_string();
+                let copied = xattr::get(path, &name).and_then(|value| match value {
+                    Some(value) => dest.set_xattr(&name, &value),
+                    None => Ok(()),
+                });
+                match copied {
+                    Ok(()) => kept.xattrs.push(label),
+                    Err(err) => warn!(
+                        "Could not keep xattr {} of {}: {}",
+                        label,
+                        path.display(),
+                        err
+                    ),
+                }
+            }
+        }
+        Err(err) => debug!("Could not list xattrs of {}: {}", path.display(), err),
+    }
+    kept
+}
+
+#[cfg(not(unix))]
+fn copy_metadata(path: &Path, meta: &fs::Metadata, dest: &fs::File) -> KeptMetadata {
+    if let Err(err) = dest.set_permissions(meta.permissions()) {
+        warn!("Could not keep permissions of {}: {}", path.display(), err);
+    }
+    KeptMetadata::default()
+}
+
+/// Give a file being restored after a delete the owner and mode recorded
+/// when it was backed up. Best effort, like `copy_metadata`.
+#[cfg(unix)]
+fn restore_metadata(path: &Path, record: &BackupRecord, dest: &fs::File) {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some((uid, gid)) = record.owner
+        && let Err(err) = std::os::unix::fs::fchown(dest, Some(uid), Some(gid))
+    {
+        warn!("Could not restore owner of {}: {}", path.display(), err);
+    }
+    if let Some(mode) = record.mode
+        && let Err(err) = dest.set_permissions(fs::Permissions::from_mode(mode))
+    {
+        warn!("Could not restore mode of {}: {}", path.display(), err);
+    }
+}
+
+#[cfg(not(unix))]
+fn restore_metadata(_path: &Path, _record: &BackupRecord, _dest: &fs::File) {}
+
+/// Temp files start out 0600; give new files the usual 0644 instead.
+#[cfg(unix)]
+fn set_new_file_mode(file: &fs::File) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Err(err) = file.set_permissions(fs::Permissions::from_mode(0o644)) {
+        warn!("Could not set mode of new file: {}", err);
+    }
+}
+
+#[cfg(not(unix))]
+fn set_new_file_mode(_file: &fs::File) {}
+
+/// Make the rename itself durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) {
+    if let Err(err) = fs::File::open(dir).and_then(|d| d.sync_all()) {
+        warn!("Could not sync directory {}: {}", dir.display(), err);
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) {}
+
+#[cfg(unix)]
+fn kill_process_group(child: &mut Child) {
+    // The child was spawned with process_group(0), so its pid is the pgid.
+    let pgid = child.id() as libc::pid_t;
+    // SAFETY: killpg has no memory-safety preconditions.
+    unsafe { libc::killpg(pgid, libc::SIGTERM) };
+
+    let deadline = Instant::now() + KILL_GRACE;
+    while Instant::now() < deadline {
+        if matches!(child.try_wait(), Ok(Some(_))) {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    // Even if the shell exited on SIGTERM, its descendants may not have.
+    // SAFETY: as above.
+    unsafe { libc::killpg(pgid, libc::SIGKILL) };
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(child: &mut Child) {
+    if let Err(err) = child.kill() {
+        warn!("Failed to kill child process {}: {}", child.id(), err);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::task::EditOperation;
 
     #[test]
     fn runs_echo_command() {
@@ -161,9 +1046,182 @@ mod tests {
             command: "echo hello-world".into(),
             cwd: None,
             requires_root: false,
+            timeout_secs: None,
+            interactive: false,
+            env: Default::default(),
         };
-        let result = executor.run_command(&task).expect("command runs");
+        let result = executor
+            .run_command(&task, None, None)
+            .expect("command runs");
         assert!(result.stdout.contains("hello-world"));
+        assert_eq!(result.status, Some(0));
+        assert!(result.success());
+    }
+
+    #[test]
+    fn commands_run_in_a_clean_environment() {
+        let executor = Executor::new(false);
+        let task = CommandTask {
+            shell: "/bin/bash".into(),
+            command: "env".into(),
+            cwd: None,
+            requires_root: false,
+            timeout_secs: None,
+            interactive: false,
+            env: [("DEBIAN_FRONTEND".to_string(), "noninteractive".to_string())].into(),
+        };
+        let result = executor
+            .run_command(&task, None, None)
+            .expect("command runs");
+        let vars: Vec<&str> = result.stdout.lines().collect();
+        for expected in [
+            "LC_ALL=C",
+            "PAGER=cat",
+            "SYSTEMD_PAGER=",
+            "DEBIAN_FRONTEND=noninteractive",
+        ] {
+            assert!(vars.contains(&expected), "{expected} missing: {vars:?}");
+        }
+        // cargo sets plenty of CARGO_* variables for the test binary
+        assert!(!vars.iter().any(|var| var.starts_with("CARGO")), "{vars:?}");
+    }
+
+    #[test]
+    fn streams_output_lines_while_capturing() {
+        let executor = Executor::new(false);
+        let task = CommandTask {
+            shell: "/bin/bash".into(),
+            command: "echo one; echo two >&2; echo three".into(),
+            cwd: None,
+            requires_root: false,
+            timeout_secs: None,
+            interactive: false,
+            env: Default::default(),
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        let result = executor
+            .run_command(&task, Some(tx), None)
+            .expect("command runs");
+        let lines: Vec<OutputLine> = rx.try_iter().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(
+            lines
+                .iter()
+                .any(|l| l.stream == OutputStream::Stderr && l.text == "two")
+        );
+        assert_eq!(result.stdout, "one\nthree\n");
+        assert_eq!(result.stderr, "two\n");
+    }
+
+    #[test]
+    fn huge_output_is_cut_to_head_and_tail() {
+        let tmp = tempfile::tempdir().unwrap();
+        let caps = OutputCaps {
+            max_bytes: 4096,
+            max_lines: 100,
+        };
+        let executor = Executor::new(false).with_output_caps(caps, tmp.path().to_path_buf());
+        let task = CommandTask {
+            shell: "/bin/bash".into(),
+            command: "seq 1 100000; echo short >&2".into(),
+            cwd: None,
+            requires_root: false,
+            timeout_secs: None,
+            interactive: false,
+            env: Default::default(),
+        };
+        let result = executor
+            .run_command(&task, None, None)
+            .expect("command runs");
+
+        assert_eq!(result.stderr, "short\n");
+        assert_eq!(result.truncated.len(), 1);
+        let cut = &result.truncated[0];
+        assert_eq!(cut.stream, OutputStream::Stdout);
+        assert_eq!(cut.omitted_lines, 99_900);
+        assert!(result.stdout.starts_with("1\n2\n"));
+        assert!(result.stdout.ends_with("99999\n100000\n"));
+        assert!(result.stdout.contains("[... 99900 lines"));
+        let full = fs::read_to_string(cut.full_output.as_ref().unwrap()).unwrap();
+        assert_eq!(full.lines().count(), 100_000);
+    }
+
+    #[test]
+    fn timeout_kills_whole_process_group() {
+        let executor = Executor::new(false).with_default_timeout(60);
+        let task = CommandTask {
+            shell: "/bin/bash".into(),
+            // The backgrounded sleep keeps stdout open, so the result only
+            // comes back if the whole group is killed.
+            command: "sleep 30 & sleep 30".into(),
+            cwd: None,
+            requires_root: false,
+            timeout_secs: Some(1),
+            interactive: false,
+            env: Default::default(),
+        };
+        let started = Instant::now();
+        let result = executor
+            .run_command(&task, None, None)
+            .expect("command runs");
+        assert_eq!(result.killed, Some(KillReason::TimedOut));
+        assert_eq!(result.status, None);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn plan_timeouts_cannot_exceed_the_configured_one() {
+        let executor = Executor::new(false).with_default_timeout(60);
+        let task = |timeout_secs| CommandTask {
+            shell: "/bin/sh".into(),
+            command: "tail -f /dev/null".into(),
+            cwd: None,
+            requires_root: false,
+            timeout_secs,
+            interactive: false,
+            env: Default::default(),
+        };
+        let minute = Some(Duration::from_secs(60));
+        assert_eq!(executor.timeout_for(&task(None)), minute);
+        assert_eq!(executor.timeout_for(&task(Some(0))), minute);
+        assert_eq!(executor.timeout_for(&task(Some(3600))), minute);
+        assert_eq!(
+            executor.timeout_for(&task(Some(5))),
+            Some(Duration::from_secs(5))
+        );
+        let unlimited = Executor::new(false);
+        assert_eq!(unlimited.timeout_for(&task(Some(0))), None);
+        assert_eq!(
+            unlimited.timeout_for(&task(Some(5))),
+            Some(Duration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn cancel_token_stops_command() {
+        let executor = Executor::new(false);
+        let task = CommandTask {
+            shell: "/bin/bash".into(),
+            command: "echo started; sleep 30".into(),
+            cwd: None,
+            requires_root: false,
+            timeout_secs: None,
+            interactive: false,
+            env: Default::default(),
+        };
+        let cancel = CancelToken::new();
+        let canceller = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            canceller.cancel();
+        });
+        let started = Instant::now();
+        let result = executor
+            .run_command(&task, None, Some(cancel))
+            .expect("command runs");
+        assert_eq!(result.killed, Some(KillReason::Cancelled));
+        assert!(result.stdout.contains("started"));
+        assert!(started.elapsed() < Duration::from_secs(10));
     }
 
     #[test]
@@ -172,15 +1230,176 @@ mod tests {
         let file = dir.path().join("test.conf");
         fs::write(&file, "old").unwrap();
 
-        let executor = Executor::new(false);
+        let store = BackupStore::new(dir.path().join("backups"), "test", 10);
+        let executor = Executor::new(false).with_backups(store);
         let task = FileEditTask {
             path: Some(file.to_string_lossy().to_string()),
             new_text: "new-content".into(),
             description: None,
+            operation: EditOperation::Rewrite,
+            diff: None,
+            planned: None,
         };
-        let outcome = executor.apply_file_edit(&task).expect("write works");
+        let outcome = executor
+            .apply_file_edit(&task, "test")
+            .expect("write works");
+        assert!(outcome.diff.contains("\n-old\n"));
+        assert!(outcome.diff.contains("\n+new-content\n"));
         assert_eq!(fs::read_to_string(outcome.path).unwrap(), "new-content");
-        assert!(outcome.backup_path.is_some());
+        let backup = outcome.backup_path.expect("backup made");
+        assert!(backup.starts_with(dir.path().join("backups")));
+        assert_eq!(fs::read_to_string(backup).unwrap(), "old");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_edits_recheck_symlinks_at_write_time() {
+        use crate::allowlist::AllowlistConfig;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        fs::create_dir(root.join("logs")).unwrap();
+        fs::write(root.join("secret"), "keep").unwrap();
+        let cfg = AllowlistConfig {
+            file_patterns: vec![format!("^{}/logs/", regex::escape(&root.to_string_lossy()))],
+            ..AllowlistConfig::default()
+        };
+        let executor = Executor::new(false).with_allowlist(Allowlist::from_config(cfg).unwrap());
+
+        // Swapped in after the plan was evaluated against a regular file
+        let link = root.join("logs/app.log");
+        std::os::unix::fs::symlink(root.join("secret"), &link).unwrap();
+        let task = FileEditTask {
+            path: Some(link.to_string_lossy().to_string()),
+            new_text: "pwned".into(),
+            description: None,
+            operation: EditOperation::Rewrite,
+            diff: None,
+            planned: None,
+        };
+        assert!(executor.apply_file_edit(&task, "test").is_err());
+        assert_eq!(fs::read_to_string(root.join("secret")).unwrap(), "keep");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_edits_are_atomic_and_keep_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("sshd_config");
+        fs::write(&file, "old").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+        // Not every filesystem supports user xattrs; only check them if it does
+        let has_xattr = xattr::set(&file, "user.sysaidmin.test", b"1").is_ok();
+
+        let executor = Executor::new(false);
+        let task = FileEditTask {
+            path: Some(file.to_string_lossy().to_string()),
+            new_text: "new".into(),
+            description: None,
+            operation: EditOperation::Rewrite,
+            diff: None,
+            planned: None,
+        };
+        let outcome = executor.apply_file_edit(&task, "test").unwrap();
+
+        assert_eq!(fs::read_to_string(&file).unwrap(), "new");
+        let mode = fs::metadata(&file).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode, 0o600);
+        assert_eq!(outcome.kept.mode, Some(0o600));
+        assert!(outcome.kept.describe().contains("mode 0600"));
+        if has_xattr {
+            assert_eq!(
+                xattr::get(&file, "user.sysaidmin.test").unwrap(),
+                Some(b"1".to_vec())
+            );
+            assert!(
+                outcome
+                    .kept
+                    .xattrs
+                    .contains(&"user.sysaidmin.test".to_string())
+            );
+        }
+
+        let leftovers: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name().to_string_lossy().starts_with(".sysaidmin-"))
+            .collect();
+        assert!(leftovers.is_empty());
+    }
+
+    #[test]
+    fn failed_validation_leaves_file_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("settings.json");
+        fs::write(&file, "{\"debug\": false}").unwrap();
+        let store = BackupStore::new(dir.path().join("backups"), "test", 10);
+        let executor = Executor::new(false).with_backups(store.clone());
+
+        let task = FileEditTask {
+            path: Some(file.to_string_lossy().to_string()),
+            new_text: "{\"debug\": tru".into(),
+            description: None,
+            operation: EditOperation::Rewrite,
+            diff: None,
+            planned: None,
+        };
+        let Err(err) = executor.apply_file_edit(&task, "test") else {
+            panic!("invalid JSON was installed");
+        };
+        let failed = err
+            .downcast_ref::<crate::validate::ValidationFailed>()
+            .expect("validation error");
+        assert_eq!(failed.validation.validator, "JSON syntax");
+
+        assert_eq!(fs::read_to_string(&file).unwrap(), "{\"debug\": false}");
+        assert!(store.index().unwrap().is_empty());
+        let names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        assert!(
+            !names.iter().any(|n| n.starts_with(".sysaidmin-")),
+            "{names:?}"
+        );
+    }
+
+    #[test]
+    fn edits_of_files_changed_since_planning_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("hosts");
+        fs::write(&file, "127.0.0.1 localhost\n").unwrap();
+        let executor = Executor::new(false);
+
+        let mut task = FileEditTask {
+            path: Some(file.to_string_lossy().to_string()),
+            new_text: String::new(),
+            description: None,
+            operation: EditOperation::Append {
+                text: "10.0.0.2 db\n".into(),
+            },
+            diff: None,
+            planned: Some(edit::snapshot(Some("127.0.0.1 localhost\n"))),
+        };
+        fs::write(&file, "127.0.0.1 localhost\n10.0.0.9 cache\n").unwrap();
+        let Err(err) = executor.apply_file_edit(&task, "test") else {
+            panic!("stale edit was applied");
+        };
+        assert!(err.downcast_ref::<StaleEdit>().is_some());
+        assert_eq!(
+            fs::read_to_string(&file).unwrap(),
+            "127.0.0.1 localhost\n10.0.0.9 cache\n"
+        );
+
+        task.planned = Some(edit::snapshot(Some(&fs::read_to_string(&file).unwrap())));
+        executor.apply_file_edit(&task, "test").unwrap();
+        assert!(
+            fs::read_to_string(&file)
+                .unwrap()
+                .ends_with("10.0.0.2 db\n")
+        );
     }
 
     #[test]
@@ -194,17 +1413,66 @@ mod tests {
             command: "echo hi".into(),
             cwd: None,
             requires_root: false,
+            timeout_secs: None,
+            interactive: false,
+            env: Default::default(),
         };
-        let result = executor.run_command(&cmd).expect("dry run command ok");
+        let result = executor
+            .run_command(&cmd, None, None)
+            .expect("dry run command ok");
         assert!(result.stdout.contains("dry-run"));
 
         let edit = FileEditTask {
             path: Some(file.to_string_lossy().to_string()),
             new_text: "data".into(),
             description: None,
+            operation: EditOperation::Rewrite,
+            diff: None,
+            planned: None,
         };
-        let outcome = executor.apply_file_edit(&edit).expect("dry run edit ok");
+        let outcome = executor
+            .apply_file_edit(&edit, "test")
+            .expect("dry run edit ok");
         assert!(outcome.backup_path.is_none());
         assert!(!outcome.path.exists());
+
+        let mkdir = TaskDetail::Mkdir {
+            path: dir.path().join("new").to_string_lossy().to_string(),
+            mode: None,
+        };
+        let outcome = executor
+            .apply_file_op(&mkdir, "test")
+            .expect("dry run mkdir ok");
+        assert!(outcome.summary.starts_with("(dry-run) would mkdir"));
+        assert!(!dir.path().join("new").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn deleted_files_are_backed_up_and_can_be_restored() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("old.service");
+        fs::write(&file, "[Unit]\n").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o600)).unwrap();
+        let store = BackupStore::new(dir.path().join("backups"), "test", 10);
+        let executor = Executor::new(false).with_backups(store.clone());
+
+        let delete = TaskDetail::Delete {
+            path: file.to_string_lossy().to_string(),
+        };
+        let outcome = executor.apply_file_op(&delete, "t1").unwrap();
+        assert!(!file.exists());
+        let backup = outcome.backup_path.expect("deleted file was backed up");
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "[Unit]\n");
+
+        let record = store.undoable(None).unwrap().remove(0);
+        assert_eq!(record.written_sha256, None);
+        assert_eq!(record.mode, Some(0o600));
+        executor.undo_edit(&record).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "[Unit]\n");
+        let mode = fs::metadata(&file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o600);
     }
 }
@@ -0,0 +1,237 @@
+//! Delete, move, chmod, chown, mkdir and symlink, done directly rather than
+//! through a shell so the allowlist can judge them by path. Nothing here is
+//! recursive and nothing replaces an existing entry.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{Context, Result, anyhow};
+use log::info;
+
+/// Parse an octal mode such as `"0640"` or `"755"`.
+pub fn parse_mode(mode: &str) -> Result<u32> {
+    let digits = mode.trim().trim_start_matches("0o");
+    match u32::from_str_radix(digits, 8) {
+        Ok(bits) if !digits.is_empty() && bits <= 0o7777 => Ok(bits),
+        _ => Err(anyhow!("invalid octal mode '{mode}'")),
+    }
+}
+
+/// Remove a file, a symlink (not what it points to) or an empty directory.
+pub fn delete(path: &Path) -> Result<()> {
+    let meta =
+        fs::symlink_metadata(path).with_context(|| format!("can't delete {}", path.display()))?;
+    if meta.is_dir() {
+        fs::remove_dir(path)
+            .with_context(|| format!("failed removing directory {}", path.display()))?;
+    } else {
+        fs::remove_file(path).with_context(|| format!("failed removing {}", path.display()))?;
+    }
+    info!("Deleted {}", path.display());
+    Ok(())
+}
+
+/// Rename `from` to `to`, refusing to replace anything already at `to`.
+pub fn rename(from: &Path, to: &Path) -> Result<()> {
+    if exists(to) {
+        return Err(anyhow!("{} already exists; not replacing it", to.display()));
+    }
+    fs::rename(from, to)
+        .with_context(|| format!("failed moving {} to {}", from.display(), to.display()))?;
+    info!("Moved {} to {}", from.display(), to.display());
+    Ok(())
+}
+
+/// Create `path` and any missing parents. `mode` applies to `path` itself.
+/// Returns false when the directory was already there.
+pub fn mkdir(path: &Path, mode: Option<u32>) -> Result<bool> {
+    if path.is_dir() {
+        if let Some(mode) = mode {
+            chmod(path, mode)?;
+        }
+        return Ok(false);
+    }
+    fs::create_dir_all(path).with_context(|| format!("failed creating {}", path.display()))?;
+    if let Some(mode) = mode {
+        chmod(path, mode)?;
+    }
+    info!("Created directory {}", path.display());
+    Ok(true)
+}
+
+/// Set the permission bits of `path`, returning the previous ones.
+#[cfg(unix)]
+pub fn chmod(path: &Path, mode: u32) -> Result<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    let old = fs::metadata(path)
+        .with_context(|| format!("can't chmod {}", path.display()))?
+        .permissions()
+        .mode()
+        & 0o7777;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .with_context(|| format!("failed chmod {:04o} {}", mode, path.display()))?;
+    info!("chmod {:04o} -> {:04o} {}", old, mode, path.display());
+    Ok(old)
+}
+
+#[cfg(not(unix))]
+pub fn chmod(path: &Path, _mode: u32) -> Result<u32> {
+    Err(anyhow!(
+        "chmod of {} is not supported on this platform",
+        path.display()
+    ))
+}
+
+/// Change the owner and/or group of `path`. Names are looked up in the
+/// user and group databases; numeric ids are used as they are.
+#[cfg(unix)]
+pub fn chown(path: &Path, owner: Option<&str>, group: Option<&str>) -> Result<()> {
+    let uid = owner.map(lookup_uid).transpose()?;
+    let gid = group.map(lookup_gid).transpose()?;
+    std::os::unix::fs::chown(path, uid, gid)
+        .with_context(|| format!("failed chown {}", path.display()))?;
+    info!("chown {:?}:{:?} {}", uid, gid, path.display());
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn chown(path: &Path, _owner: Option<&str>, _group: Option<&str>) -> Result<()> {
+    Err(anyhow!(
+        "chown of {} is not supported on this platform",
+        path.display()
+    ))
+}
+
+/// Create a symlink at `link` pointing to `target`, refusing to replace
+/// anything already at `link`.
+#[cfg(unix)]
+pub fn symlink(target: &Path, link: &Path) -> Result<()> {
+    if exists(link) {
+        return Err(anyhow!(
+            "{} already exists; not replacing it",
+            link.display()
+        ));
+    }
+    std::os::unix::fs::symlink(target, link)
+        .with_context(|| format!("failed linking {} to {}", link.display(), target.display()))?;
+    info!("Linked {} -> {}", link.display(), target.display());
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn symlink(_target: &Path, link: &Path) -> Result<()> {
+    Err(anyhow!(
+        "symlinks like {} are not supported on this platform",
+        link.display()
+    ))
+}
+
+/// Options for writing a file only its owner may read, for backups and
+/// captured output that may hold secrets.
+#[cfg(unix)]
+pub fn private_file() -> fs::OpenOptions {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut options = fs::OpenOptions::new();
+    options.mode(0o600);
+    options
+}
+
+#[cfg(not(unix))]
+pub fn private_file() -> fs::OpenOptions {
+    fs::OpenOptions::new()
+}
+
+/// Create `dir` and any missing parents, private to their owner.
+#[cfg(unix)]
+pub fn private_dir(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+}
+
+#[cfg(not(unix))]
+pub fn private_dir(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)
+}
+
+/// True when anything, a dangling symlink included, is at `path`.
+fn exists(path: &Path) -> bool {
+    match fs::symlink_metadata(path) {
+        Ok(_) => true,
+        Err(err) => err.kind() != io::ErrorKind::NotFound,
+    }
+}
+
+#[cfg(unix)]
+fn lookup_uid(name: &str) -> Result<u32> {
+    if let Ok(uid) = name.parse() {
+        return Ok(uid);
+    }
+    let cname = std::ffi::CString::new(name)?;
+    // getpwnam returns a pointer into static storage; only the uid is read
+    // from it, before anything else can call into the user database.
+    let entry = unsafe { libc::getpwnam(cname.as_ptr()) };
+    if entry.is_null() {
+        return Err(anyhow!("unknown user '{name}'"));
+    }
+    Ok(unsafe { (*entry).pw_uid })
+}
+
+#[cfg(unix)]
+fn lookup_gid(name: &str) -> Result<u32> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    let cname = std::ffi::CString::new(name)?;
+    // Same static-storage caveat as getpwnam above.
+    let entry = unsafe { libc::getgrnam(cname.as_ptr()) };
+    if entry.is_null() {
+        return Err(anyhow!("unknown group '{name}'"));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_octal_modes() {
+        assert_eq!(parse_mode("0640").unwrap(), 0o640);
+        assert_eq!(parse_mode("755").unwrap(), 0o755);
+        assert_eq!(parse_mode("0o2775").unwrap(), 0o2775);
+        assert!(parse_mode("").is_err());
+        assert!(parse_mode("0999").is_err());
+        assert!(parse_mode("17777").is_err());
+        assert!(parse_mode("rw-r--r--").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn operations_refuse_to_replace_existing_entries() {
+        let tmp = tempfile::tempdir().unwrap();
+        let a = tmp.path().join("a");
+        let b = tmp.path().join("b");
+        fs::write(&a, "a").unwrap();
+        fs::write(&b, "b").unwrap();
+
+        assert!(rename(&a, &b).is_err());
+        assert!(symlink(&a, &b).is_err());
+        assert_eq!(fs::read_to_string(&b).unwrap(), "b");
+
+        let link = tmp.path().join("link");
+        symlink(&a, &link).unwrap();
+        delete(&link).unwrap();
+        assert!(a.exists());
+
+        let dir = tmp.path().join("d/e");
+        assert!(mkdir(&dir, Some(0o750)).unwrap());
+        assert!(!mkdir(&dir, None).unwrap());
+        assert_eq!(chmod(&dir, 0o700).unwrap(), 0o750);
+        rename(&a, &dir.join("a")).unwrap();
+        assert!(delete(&tmp.path().join("d")).is_err());
+    }
+}
@@ -0,0 +1,544 @@
+//! Resource limits and sandboxing for executed commands: rlimits for CPU
+//! time, memory and open files, a nice and I/O priority, and on Linux
+//! optional mount and network namespaces. Everything is applied in the
+//! child between fork and exec, so sysaidmin itself is never limited, or
+//! for a pseudo-terminal run by the shell before it runs the command.
+
+#[cfg(unix)]
+use std::io;
+use std::process::{Command, ExitStatus};
+
+use anyhow::{Result, anyhow};
+use log::debug;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::executor::KillReason;
+
+/// Extra CPU seconds between SIGXCPU and the kernel's SIGKILL.
+#[cfg(unix)]
+const CPU_GRACE_SECS: u64 = 5;
+/// What allocators and libc print when an allocation fails under
+/// `RLIMIT_AS`: strerror(ENOMEM), glibc and gnulib's xalloc, and Rust.
+const OUT_OF_MEMORY_MESSAGES: &[&str] = &[
+    "Cannot allocate memory",
+    "memory exhausted",
+    "memory allocation of",
+];
+/// Dropped from the bounding set of sandboxed commands run as root, so they
+/// can't remount the filesystem writable or leave their namespaces.
+#[cfg(target_os = "linux")]
+const CAP_SYS_ADMIN: libc::c_ulong = 21;
+
+/// I/O scheduling class, as set by `ionice -c`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoClass {
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+/// Limits for one command. Unset fields leave the inherited value alone.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct ResourceLimits {
+    /// CPU seconds before the kernel stops the command with SIGXCPU.
+    pub cpu_secs: Option<u64>,
+    /// Address space cap, in MiB.
+    pub memory_mb: Option<u64>,
+    pub open_files: Option<u64>,
+    pub nice: Option<i32>,
+    pub io_class: Option<IoClass>,
+    /// Mount every filesystem read-only for the command (Linux). Run as
+    /// root, the command also loses CAP_SYS_ADMIN, since it could otherwise
+    /// remount them writable.
+    pub read_only: Option<bool>,
+    /// Give the command its own network namespace with only a loopback
+    /// device, which is down (Linux).
+    pub no_network: Option<bool>,
+}
+
+impl ResourceLimits {
+    /// Fields set here, and `fallback`'s for the rest.
+    fn or(&self, fallback: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            cpu_secs: self.cpu_secs.or(fallback.cpu_secs),
+            memory_mb: self.memory_mb.or(fallback.memory_mb),
+            open_files: self.open_files.or(fallback.open_files),
+            nice: self.nice.or(fallback.nice),
+            io_class: self.io_class.or(fallback.io_class),
+            read_only: self.read_only.or(fallback.read_only),
+            no_network: self.no_network.or(fallback.no_network),
+        }
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only.unwrap_or(false)
+    }
+
+    pub fn no_network(&self) -> bool {
+        self.no_network.unwrap_or(false)
+    }
+
+    /// Whether the command needs namespaces of its own.
+    pub fn sandboxed(&self) -> bool {
+        self.read_only() || self.no_network()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.sandboxed()
+            && self.cpu_secs.is_none()
+            && self.memory_mb.is_none()
+            && self.open_files.is_none()
+            && self.nice.is_none()
+            && self.io_class.is_none()
+    }
+
+    /// Why the command died, if it looks like one of these limits did it.
+    /// SIGXCPU is certain, but the memory limit is a guess: nothing says
+    /// which allocation failed, so a SIGKILL or SIGABRT, or an allocator's
+    /// complaint on stderr, under a memory cap is taken to be the cap.
+    pub fn kill_reason(&self, status: &ExitStatus, stderr: &[u8]) -> Option<KillReason> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            let signal = status.signal();
+            if self.cpu_secs.is_some() && signal == Some(libc::SIGXCPU) {
+                return Some(KillReason::CpuLimit);
+            }
+            if self.memory_mb.is_some() && matches!(signal, Some(libc::SIGKILL | libc::SIGABRT)) {
+                return Some(KillReason::MemoryLimit);
+            }
+        }
+        if self.memory_mb.is_some() && !status.success() {
+            let stderr = String::from_utf8_lossy(stderr);
+            if OUT_OF_MEMORY_MESSAGES
+                .iter()
+                .any(|message| stderr.contains(message))
+            {
+                return Some(KillReason::MemoryLimit);
+            }
+        }
+        None
+    }
+}
+
+impl ResourceLimits {
+    /// Shell commands that set these limits, other than the sandbox, for the
+    /// shell that then runs the command. This is for pseudo-terminal runs,
+    /// where nothing can be done between fork and exec; the shell exits with
+    /// 126 if a limit can't be set. `None` when there is nothing to set.
+    pub fn shell_prefix(&self) -> Option<String> {
+        let mut steps = Vec::new();
+        if let Some(secs) = self.cpu_secs {
+            steps.push(format!("ulimit -t {}", secs + CPU_GRACE_SECS));
+            steps.push(format!("ulimit -S -t {secs}"));
+        }
+        if let Some(mb) = self.memory_mb {
+            steps.push(format!("ulimit -v {}", mb.saturating_mul(1024)));
+        }
+        if let Some(files) = self.open_files {
+            steps.push(format!("ulimit -n {files}"));
+        }
+        if let Some(nice) = self.nice {
+            steps.push(format!("renice -n {nice} -p $$ >/dev/null"));
+        }
+        if let Some(class) = self.io_class {
+            let class = match class {
+                IoClass::Realtime => 1,
+                IoClass::BestEffort => 2,
+                IoClass::Idle => 3,
+            };
+            steps.push(format!("ionice -c {class} -p $$"));
+        }
+        (!steps.is_empty()).then(|| format!("{} || exit 126\n", steps.join(" && ")))
+    }
+}
+
+/// The `[limits]` config table: default limits, and rules that override
+/// them for commands matching a pattern.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct LimitsConfig {
+    #[serde(flatten)]
+    pub defaults: ResourceLimits,
+    #[serde(default)]
+    pub rules: Vec<LimitRuleConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LimitRuleConfig {
+    pub pattern: String,
+    #[serde(flatten)]
+    pub limits: ResourceLimits,
+}
+
+/// Limits by command; the first matching rule wins over the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct LimitPolicy {
+    defaults: ResourceLimits,
+    rules: Vec<(Regex, ResourceLimits)>,
+}
+
+impl LimitPolicy {
+    pub fn from_config(cfg: &LimitsConfig) -> Result<Self> {
+        let rules = cfg
+            .rules
+            .iter()
+            .map(|rule| {
+                let regex = Regex::new(&rule.pattern)
+                    .map_err(|err| anyhow!("invalid limits pattern '{}': {err}", rule.pattern))?;
+                Ok((regex, rule.limits.clone()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            defaults: cfg.defaults.clone(),
+            rules,
+        })
+    }
+
+    pub fn for_command(&self, command: &str) -> ResourceLimits {
+        let command = command.trim();
+        match self.rules.iter().find(|(regex, _)| regex.is_match(command)) {
+            Some((regex, limits)) => {
+                debug!("Limits rule '{}' applies to: {}", regex.as_str(), command);
+                limits.or(&self.defaults)
+            }
+            None => self.defaults.clone(),
+        }
+    }
+}
+
+/// Arrange for `limits` to be applied in the child `cmd` spawns.
+#[cfg(unix)]
+pub fn apply(cmd: &mut Command, limits: &ResourceLimits) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    if limits.is_empty() {
+        return Ok(());
+    }
+    #[cfg(not(target_os = "linux"))]
+    if limits.sandboxed() {
+        return Err(anyhow!(
+            "read-only and no-network sandboxes need Linux namespaces"
+        ));
+    }
+    debug!("Applying limits: {:?}", limits);
+    let setup = ChildSetup::new(limits);
+    // SAFETY: the closure only makes raw system calls on values prepared
+    // before the fork, which is async-signal-safe.
+    unsafe {
+        cmd.pre_exec(move || setup.run());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn apply(_cmd: &mut Command, limits: &ResourceLimits) -> Result<()> {
+    if limits.is_empty() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "resource limits are not supported on this platform"
+    ))
+}
+
+/// Everything the child needs, worked out before the fork so that nothing
+/// allocates after it.
+#[cfg(unix)]
+struct ChildSetup {
+    limits: ResourceLimits,
+    #[cfg(target_os = "linux")]
+    id_maps: Option<IdMaps>,
+}
+
+#[cfg(target_os = "linux")]
+struct IdMaps {
+    uid_map: String,
+    gid_map: String,
+}
+
+#[cfg(unix)]
+impl ChildSetup {
+    fn new(limits: &ResourceLimits) -> Self {
+        Self {
+            limits: limits.clone(),
+            // Without root, namespaces need a user namespace of their own,
+            // in which sysaidmin's user keeps its ids.
+            #[cfg(target_os = "linux")]
+            id_maps: (limits.sandboxed() && !crate::escalation::is_root()).then(|| {
+                // SAFETY: getuid and getgid cannot fail.
+                let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+                IdMaps {
+                    uid_map: format!("{uid} {uid} 1"),
+                    gid_map: format!("{gid} {gid} 1"),
+                }
+            }),
+        }
+    }
+
+    fn run(&self) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if self.limits.sandboxed() {
+            self.enter_namespaces()?;
+        }
+        if let Some(secs) = self.limits.cpu_secs {
+            set_rlimit(libc::RLIMIT_CPU, secs, secs + CPU_GRACE_SECS)?;
+        }
+        if let Some(mb) = self.limits.memory_mb {
+            let bytes = mb.saturating_mul(1024 * 1024);
+            set_rlimit(libc::RLIMIT_AS, bytes, bytes)?;
+        }
+        if let Some(files) = self.limits.open_files {
+            set_rlimit(libc::RLIMIT_NOFILE, files, files)?;
+        }
+        if let Some(nice) = self.limits.nice {
+            // SAFETY: plain system call.
+            check(unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) })?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(class) = self.limits.io_class {
+            const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+            const IOPRIO_CLASS_SHIFT: u32 = 13;
+            let (class, level) = match class {
+                IoClass::Realtime => (1, 4),
+                IoClass::BestEffort => (2, 4),
+                IoClass::Idle => (3, 0),
+            };
+            // SAFETY: plain system call.
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_ioprio_set,
+                    IOPRIO_WHO_PROCESS,
+                    0,
+                    (class << IOPRIO_CLASS_SHIFT) | level,
+                )
+            };
+            check(result as libc::c_int)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn enter_namespaces(&self) -> io::Result<()> {
+        let mut flags = 0;
+        if self.limits.read_only() {
+            flags |= libc::CLONE_NEWNS;
+        }
+        if self.limits.no_network() {
+            flags |= libc::CLONE_NEWNET;
+        }
+        if self.id_maps.is_some() {
+            flags |= libc::CLONE_NEWUSER;
+        }
+        // SAFETY: plain system call.
+        check(unsafe { libc::unshare(flags) })?;
+
+        if let Some(maps) = &self.id_maps {
+            write_proc(c"/proc/self/setgroups", b"deny")?;
+            write_proc(c"/proc/self/uid_map", maps.uid_map.as_bytes())?;
+            write_proc(c"/proc/self/gid_map", maps.gid_map.as_bytes())?;
+        }
+
+        if self.limits.read_only() {
+            // Keep the read-only remount from propagating back to the host
+            // SAFETY: plain system call on static strings.
+            check(unsafe {
+                libc::mount(
+                    std::ptr::null(),
+                    c"/".as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    std::ptr::null(),
+                )
+            })?;
+            let attr = libc::mount_attr {
+                attr_set: libc::MOUNT_ATTR_RDONLY,
+                attr_clr: 0,
+                propagation: 0,
+                userns_fd: 0,
+            };
+            // SAFETY: attr outlives the call and its size is passed along.
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_mount_setattr,
+                    libc::AT_FDCWD,
+                    c"/".as_ptr(),
+                    libc::AT_RECURSIVE,
+                    &attr as *const libc::mount_attr,
+                    std::mem::size_of::<libc::mount_attr>(),
+                )
+            };
+            check(result as libc::c_int)?;
+        }
+
+        // Without a user namespace the command is really root, which could
+        // undo all of the above; exec drops the capability for good
+        if self.id_maps.is_none() {
+            // SAFETY: plain system call.
+            check(unsafe { libc::prctl(libc::PR_CAPBSET_DROP, CAP_SYS_ADMIN, 0, 0, 0) })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(all(unix, not(all(target_os = "linux", target_env = "gnu"))))]
+type Resource = libc::c_int;
+
+/// Lower a limit, never asking for more than the current hard limit.
+#[cfg(unix)]
+fn set_rlimit(resource: Resource, soft: u64, hard: u64) -> io::Result<()> {
+    let mut current = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: current is a valid rlimit to write into.
+    check(unsafe { libc::getrlimit(resource, &mut current) })?;
+    let hard = hard.min(current.rlim_max);
+    let limit = libc::rlimit {
+        rlim_cur: soft.min(hard),
+        rlim_max: hard,
+    };
+    // SAFETY: limit is a valid rlimit.
+    check(unsafe { libc::setrlimit(resource, &limit) })
+}
+
+#[cfg(target_os = "linux")]
+fn write_proc(path: &std::ffi::CStr, contents: &[u8]) -> io::Result<()> {
+    // SAFETY: path is NUL-terminated and contents is valid for its length.
+    unsafe {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        check(fd)?;
+        let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+        libc::close(fd);
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_override_the_defaults_field_by_field() {
+        let cfg: LimitsConfig = toml::from_str(
+            r#"
+            cpu_secs = 60
+            nice = 10
+
+            [[rules]]
+            pattern = "^(ps|df|ss)\\b"
+            read_only = true
+            no_network = true
+            nice = 5
+            "#,
+        )
+        .unwrap();
+        let policy = LimitPolicy::from_config(&cfg).unwrap();
+
+        let ps = policy.for_command("ps aux");
+        assert_eq!(ps.cpu_secs, Some(60));
+        assert_eq!(ps.nice, Some(5));
+        assert!(ps.read_only() && ps.no_network());
+
+        let other = policy.for_command("systemctl restart nginx");
+        assert_eq!(other.nice, Some(10));
+        assert!(!other.sandboxed());
+    }
+
+    #[test]
+    fn limits_apply_to_the_child_only() {
+        let limits = ResourceLimits {
+            open_files: Some(64),
+            cpu_secs: Some(1),
+            ..ResourceLimits::default()
+        };
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("ulimit -n; ulimit -t");
+        apply(&mut cmd, &limits).unwrap();
+        let output = cmd.output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "64\n1\n");
+
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("while :; do :; done");
+        apply(&mut cmd, &limits).unwrap();
+        let output = cmd.output().unwrap();
+        assert_eq!(
+            limits.kill_reason(&output.status, &output.stderr),
+            Some(KillReason::CpuLimit)
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sandboxes_block_writes_and_network() {
+        let limits = ResourceLimits {
+            read_only: Some(true),
+            no_network: Some(true),
+            ..ResourceLimits::default()
+        };
+        let tmp = tempfile::tempdir().unwrap();
+        let target = tmp.path().join("written");
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg(format!(
+            "echo x > {} 2>/dev/null || echo refused; cat /sys/class/net/lo/operstate; ls /sys/class/net",
+            target.display()
+        ));
+        apply(&mut cmd, &limits).unwrap();
+        let output = match cmd.output() {
+            Ok(output) => output,
+            // Containers may forbid namespaces altogether
+            Err(err) if err.raw_os_error() == Some(libc::EPERM) => return,
+            Err(err) => panic!("sandboxed command failed to start: {err}"),
+        };
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.starts_with("refused\n"), "{stdout}");
+        assert!(!target.exists());
+        assert!(stdout.ends_with("lo\n"), "{stdout}");
+
+        if crate::escalation::is_root() {
+            let mut cmd = Command::new("/bin/sh");
+            cmd.arg("-c")
+                .arg("mount -o remount,rw / 2>/dev/null && echo remounted || echo kept");
+            apply(&mut cmd, &limits).unwrap();
+            let output = cmd.output().unwrap();
+            assert_eq!(String::from_utf8_lossy(&output.stdout), "kept\n");
+        }
+    }
+
+    #[test]
+    fn shell_prefix_sets_the_same_limits() {
+        assert_eq!(ResourceLimits::default().shell_prefix(), None);
+        let limits = ResourceLimits {
+            open_files: Some(64),
+            cpu_secs: Some(1),
+            memory_mb: Some(512),
+            ..ResourceLimits::default()
+        };
+        let prefix = limits.shell_prefix().unwrap();
+        let output = Command::new("/bin/sh")
+            .arg("-c")
+            .arg(format!(
+                "{prefix}ulimit -n; ulimit -t; ulimit -H -t; ulimit -v"
+            ))
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "64\n1\n6\n524288\n"
+        );
+    }
+}
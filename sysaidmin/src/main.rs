@@ -1,16 +1,34 @@
+mod agent;
 mod allowlist;
 mod api;
 mod app;
+mod backup;
 mod config;
 mod conversation;
+mod diff;
+mod edit;
+mod environment;
+mod escalation;
 mod executor;
+mod fileops;
+mod http;
+mod limits;
 mod logger;
 mod models;
+mod output;
 mod parser;
+mod paths;
+mod pty;
+mod retry;
 mod session;
+mod shell;
+mod sse;
 mod task;
 mod tokenizer;
+mod tools;
 mod tui;
+mod undo;
+mod validate;
 
 use std::env;
 use std::panic;
@@ -20,7 +38,7 @@ use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use log::{debug, error, info, trace, warn};
 
 #[derive(Parser, Debug)]
@@ -29,6 +47,22 @@ struct Cli {
     /// Explicitly set the Anthropic model (skips interactive selection)
     #[arg(long)]
     model: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum CliCommand {
+    /// Restore files overwritten or deleted by sysaidmin from their backups
+    Undo {
+        /// Undo every edit from the session, newest first, instead of only the last one
+        #[arg(long)]
+        all: bool,
+        /// Session to undo (defaults to the most recent session that edited files)
+        #[arg(long)]
+        session: Option<String>,
+    },
 }
 
 static PANIC_OCCURRED: AtomicBool = AtomicBool::new(false);
@@ -66,8 +100,10 @@ fn setup_panic_handler() {
     }));
 }
 
+/// Register SIGTERM/SIGINT handlers. Returns the SIGINT flag, which the TUI
+/// polls to cancel the running command.
 #[cfg(unix)]
-fn setup_signal_handlers() {
+fn setup_signal_handlers() -> Arc<AtomicBool> {
     use signal_hook::consts::signal::*;
     use signal_hook::flag;
 
@@ -76,8 +112,13 @@ fn setup_signal_handlers() {
     // SIGABRT is also typically forbidden.
     // We can safely register SIGTERM and SIGINT for graceful shutdown.
     let signals = [SIGTERM, SIGINT];
+    let interrupted = Arc::new(AtomicBool::new(false));
     for sig in &signals {
-        let signal_occurred = Arc::new(AtomicBool::new(false));
+        let signal_occurred = if *sig == SIGINT {
+            interrupted.clone()
+        } else {
+            Arc::new(AtomicBool::new(false))
+        };
         let signal_occurred_clone = signal_occurred.clone();
 
         match flag::register(*sig, signal_occurred_clone) {
@@ -119,12 +160,14 @@ fn setup_signal_handlers() {
     info!(
         "Signal handlers registered (SIGTERM, SIGINT). Crashes will be logged via panic handler."
     );
+    interrupted
 }
 
 #[cfg(not(unix))]
-fn setup_signal_handlers() {
+fn setup_signal_handlers() -> Arc<AtomicBool> {
     // Signal handling not available on non-Unix platforms
     warn!("Signal handlers not available on this platform");
+    Arc::new(AtomicBool::new(false))
 }
 
 fn main() {
@@ -149,11 +192,11 @@ fn main() {
     info!("Panic handler installed");
 
     // Set up signal handlers
-    setup_signal_handlers();
+    let interrupted = setup_signal_handlers();
     info!("Signal handlers installed");
 
     // Run main logic with comprehensive error handling
-    let result = std::panic::catch_unwind(|| match run_main() {
+    let result = std::panic::catch_unwind(move || match run_main(interrupted) {
         Ok(()) => {
             info!("=== SYSAIDMIN EXITING NORMALLY ===");
             Ok(())
@@ -196,7 +239,43 @@ fn main() {
     }
 }
 
-fn run_main() -> Result<()> {
+fn run_undo(config: &config::AppConfig, all: bool, session_id: Option<&str>) -> Result<()> {
+    info!("Running undo (all={}, session={:?})", all, session_id);
+    let allowlist = allowlist::Allowlist::from_config(config.allowlist.clone())
+        .context("Failed to initialize allowlist")?;
+    let session = session::SessionStore::new(config.session_root.clone())
+        .context("Failed to create session store")?;
+    let executor = executor::Executor::new(config.dry_run)
+        .with_allowlist(allowlist)
+        .with_backups(session.backup_store(config.backup_retention));
+    let conversation = conversation::ConversationLogger::new(conversation::default_log_path())
+        .context("Failed to open conversation log")?;
+
+    let restored =
+        undo::undo_edits(&executor, &conversation, session_id, all).context("Undo failed")?;
+    if restored.is_empty() {
+        println!("Nothing to undo.");
+    }
+    for record in restored {
+        if record.created {
+            println!(
+                "Deleted {}, which task {} created",
+                record.original.display(),
+                record.task_id
+            );
+        } else {
+            println!(
+                "Restored {} from {} (task {})",
+                record.original.display(),
+                record.backup.display(),
+                record.task_id
+            );
+        }
+    }
+    Ok(())
+}
+
+fn run_main(interrupted: Arc<AtomicBool>) -> Result<()> {
     trace!("Parsing command line arguments");
     let cli = Cli::parse();
     debug!("CLI args parsed: model={:?}", cli.model);
@@ -210,6 +289,10 @@ fn run_main() -> Result<()> {
         config.dry_run, config.offline_mode, config.model
     );
 
+    if let Some(CliCommand::Undo { all, session }) = cli.command {
+        return run_undo(&config, all, session.as_deref());
+    }
+
     trace!("Selecting model");
     let selected_model =
         models::select_model(&config, cli.model).context("Failed to select model")?;
@@ -226,10 +309,6 @@ fn run_main() -> Result<()> {
     let client = api::AnthropicClient::new(&config).context("Failed to create API client")?;
     info!("API client created (offline_mode={})", config.offline_mode);
 
-    trace!("Creating executor");
-    let executor = executor::Executor::new(config.dry_run);
-    info!("Executor created (dry_run={})", config.dry_run);
-
     trace!("Creating session store");
     let session = session::SessionStore::new(config.session_root.clone())
         .context("Failed to create session store")?;
@@ -238,12 +317,42 @@ fn run_main() -> Result<()> {
         config.session_root.display()
     );
 
+    trace!("Creating executor");
+    let validators = validate::ValidatorRegistry::from_config(&config.validators)
+        .context("Failed to initialize validators")?;
+    let limit_policy = limits::LimitPolicy::from_config(&config.limits)
+        .context("Failed to initialize resource limits")?;
+    let executor = executor::Executor::new(config.dry_run)
+        .with_default_timeout(config.command_timeout_secs)
+        .with_allowlist(allowlist.clone())
+        .with_backups(session.backup_store(config.backup_retention))
+        .with_validators(validators)
+        .with_escalation(escalation::Escalation::new(config.escalation))
+        .with_interactive_mode(config.interactive_mode)
+        .with_output_caps(
+            output::OutputCaps {
+                max_bytes: config.output_max_bytes,
+                max_lines: config.output_max_lines,
+            },
+            session.output_dir(),
+        )
+        .with_limits(limit_policy)
+        .with_environment(environment::EnvPolicy::from_config(&config.environment));
+    info!(
+        "Executor created (dry_run={}, command_timeout_secs={}, backup_retention={}, escalation={}, interactive_mode={:?})",
+        config.dry_run,
+        config.command_timeout_secs,
+        config.backup_retention,
+        config.escalation.label(),
+        config.interactive_mode
+    );
+
     trace!("Creating application instance");
     let mut app = app::App::new(config, client, allowlist, executor, session);
     info!("Application instance created");
 
     trace!("Starting TUI");
-    tui::run(&mut app).context("TUI exited with error")?;
+    tui::run(&mut app, interrupted).context("TUI exited with error")?;
 
     info!("TUI completed successfully");
     Ok(())
@@ -5,6 +5,8 @@ use reqwest::blocking::Client;
 use serde::Deserialize;
 
 use crate::config::AppConfig;
+use crate::http;
+use crate::retry::{Failure, RetryPolicy};
 
 pub fn select_model(config: &AppConfig, cli_model: Option<String>) -> Result<String> {
     if let Some(m) = cli_model {
@@ -14,7 +16,7 @@ pub fn select_model(config: &AppConfig, cli_model: Option<String>) -> Result<Str
         return Ok(config.model.clone());
     }
 
-    let selector = ModelSelector::new(&config.api_key, &config.api_url)?;
+    let selector = ModelSelector::new(config)?;
     let stdin = io::stdin();
     let mut stdin_lock = stdin.lock();
     let mut stdout = io::stdout();
@@ -33,24 +35,29 @@ pub fn select_model(config: &AppConfig, cli_model: Option<String>) -> Result<Str
 struct ModelSelector {
     http: Client,
     endpoint: String,
+    retry: RetryPolicy,
 }
 
 impl ModelSelector {
-    fn new(api_key: &str, api_url: &str) -> Result<Self> {
-        let endpoint = build_models_endpoint(api_url)?;
+    fn new(config: &AppConfig) -> Result<Self> {
+        let endpoint = build_models_endpoint(&config.api_url)?;
         let mut headers = reqwest::header::HeaderMap::new();
         headers.insert(
             "x-api-key",
-            reqwest::header::HeaderValue::from_str(api_key)
+            reqwest::header::HeaderValue::from_str(&config.api_key)
                 .context("invalid API key header for models request")?,
         );
         headers.insert(
             "anthropic-version",
             reqwest::header::HeaderValue::from_static("2023-06-01"),
         );
-        let http = Client::builder().default_headers(headers).build()?;
+        let http = http::client(&config.http, headers)?;
 
-        Ok(Self { http, endpoint })
+        Ok(Self {
+            http,
+            endpoint,
+            retry: RetryPolicy::new(&config.retry),
+        })
     }
 
     fn prompt(
@@ -103,25 +110,32 @@ impl ModelSelector {
     }
 
     fn fetch_models(&self) -> Result<Vec<ModelInfo>> {
-        let resp = self
-            .http
-            .get(&self.endpoint)
-            .send()
-            .context("failed requesting model list from Anthropic")?;
-        let status = resp.status();
-        if !status.is_success() {
+        let mut on_retry = |notice: &_| eprintln!("Model list request failed; {notice}");
+        let body = self.retry.run(&mut on_retry, || {
+            let resp =
+                self.http.get(&self.endpoint).send().map_err(|err| {
+                    Failure::send(err, "failed requesting model list from Anthropic")
+                })?;
+            let status = resp.status();
+            if status.is_success() {
+                // Read within the attempt so a dropped download is retried
+                return resp
+                    .text()
+                    .map_err(|err| Failure::send(err, "failed reading Anthropic model list"));
+            }
+            let headers = resp.headers().clone();
             let body = resp
                 .text()
                 .unwrap_or_else(|_| "unable to read response body".into());
-            return Err(anyhow!(
+            let error = anyhow!(
                 "Anthropic model list failed with {}: {}",
                 status.as_u16(),
                 body
-            ));
-        }
-        let parsed: ModelsResponse = resp
-            .json()
-            .context("failed to parse Anthropic model list response")?;
+            );
+            Err(Failure::status(status, &headers, error))
+        })?;
+        let parsed: ModelsResponse =
+            serde_json::from_str(&body).context("failed to parse Anthropic model list response")?;
         Ok(parsed.data)
     }
 }
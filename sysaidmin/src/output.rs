@@ -0,0 +1,360 @@
+//! Keeping command output to a bounded size. Output within the caps is kept
+//! whole; beyond them only the head and the tail stay in memory, with a
+//! marker saying how much was cut, and the full output goes to a spill file.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::executor::OutputStream;
+use crate::fileops;
+
+/// Longest piece of a line read at once, so a huge line can't be held whole.
+const MAX_PIECE: usize = 8 * 1024;
+
+/// Limits on how much of each output stream is kept. 0 means no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutputCaps {
+    pub max_bytes: usize,
+    pub max_lines: usize,
+}
+
+impl OutputCaps {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_bytes == 0 && self.max_lines == 0
+    }
+
+    /// How many bytes to read at most before handing a piece on.
+    pub fn piece_len(&self) -> usize {
+        match self.max_bytes {
+            0 => MAX_PIECE,
+            bytes => (bytes - bytes / 2).clamp(1, MAX_PIECE),
+        }
+    }
+}
+
+/// How much of a stream was cut, and where the whole of it went.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Truncation {
+    pub stream: OutputStream,
+    pub omitted_lines: u64,
+    pub omitted_bytes: u64,
+    pub total_bytes: u64,
+    /// The complete output, unless the spill file couldn't be written.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub full_output: Option<PathBuf>,
+}
+
+impl Truncation {
+    fn marker(&self) -> String {
+        let whole = match &self.full_output {
+            Some(path) => format!("; full output in {}", path.display()),
+            None => String::new(),
+        };
+        format!(
+            "[... {} lines ({} bytes) of {} cut{} ...]\n",
+            self.omitted_lines,
+            self.omitted_bytes,
+            self.stream.label(),
+            whole
+        )
+    }
+}
+
+/// One stream's output as it arrives, in pieces of at most one line. Lines
+/// longer than a piece arrive split over several of them.
+pub struct CappedOutput {
+    stream: OutputStream,
+    caps: OutputCaps,
+    spill_dir: Option<PathBuf>,
+    /// Whether the last piece ended a line, so the next one starts a new one.
+    at_line_start: bool,
+    head: Vec<u8>,
+    head_lines: usize,
+    /// Pieces with the number of lines each one starts.
+    tail: VecDeque<(Vec<u8>, usize)>,
+    tail_bytes: usize,
+    tail_lines: usize,
+    total_bytes: u64,
+    omitted_lines: u64,
+    omitted_bytes: u64,
+    spill: Spill,
+}
+
+enum Spill {
+    NotNeeded,
+    Writing(PathBuf, BufWriter<File>),
+    Failed,
+}
+
+impl CappedOutput {
+    pub fn new(stream: OutputStream, caps: OutputCaps, spill_dir: Option<PathBuf>) -> Self {
+        Self {
+            stream,
+            caps,
+            spill_dir,
+            at_line_start: true,
+            head: Vec::new(),
+            head_lines: 0,
+            tail: VecDeque::new(),
+            tail_bytes: 0,
+            tail_lines: 0,
+            total_bytes: 0,
+            omitted_lines: 0,
+            omitted_bytes: 0,
+            spill: Spill::NotNeeded,
+        }
+    }
+
+    pub fn push(&mut self, piece: &[u8]) {
+        self.total_bytes += piece.len() as u64;
+        if self.caps.is_unlimited() {
+            self.head.extend_from_slice(piece);
+            return;
+        }
+        self.write_spill(piece);
+        let lines = self.lines_started(piece);
+
+        let head_full = exceeds(
+            self.head.len() + piece.len(),
+            head_budget(self.caps.max_bytes),
+        ) || exceeds(self.head_lines + lines, head_budget(self.caps.max_lines));
+        if self.tail.is_empty() && !head_full {
+            self.head.extend_from_slice(piece);
+            self.head_lines += lines;
+            return;
+        }
+
+        self.tail.push_back((piece.to_vec(), lines));
+        self.tail_bytes += piece.len();
+        self.tail_lines += lines;
+        let tail_bytes = tail_budget(self.caps.max_bytes);
+        let tail_lines = tail_budget(self.caps.max_lines);
+        // A line dropped for the line cap takes the rest of its pieces along.
+        let mut cut_line = false;
+        loop {
+            let over_lines = exceeds(self.tail_lines, tail_lines);
+            let rest_of_cut_line =
+                cut_line && self.tail.front().is_some_and(|(_, lines)| *lines == 0);
+            if !over_lines && !rest_of_cut_line && !exceeds(self.tail_bytes, tail_bytes) {
+                break;
+            }
+            if matches!(self.spill, Spill::NotNeeded) {
+                self.start_spill();
+            }
+            let Some((evicted, lines)) = self.tail.pop_front() else {
+                break;
+            };
+            self.tail_bytes -= evicted.len();
+            self.tail_lines -= lines;
+            self.omitted_bytes += evicted.len() as u64;
+            self.omitted_lines += lines as u64;
+            cut_line = over_lines || rest_of_cut_line;
+        }
+    }
+
+    /// How many lines begin in `piece`: one after every newline but a final
+    /// one, plus its first byte unless it carries on the previous line.
+    fn lines_started(&mut self, piece: &[u8]) -> usize {
+        let Some(&last) = piece.last() else {
+            return 0;
+        };
+        let newlines = piece.iter().filter(|&&byte| byte == b'\n').count();
+        let started = usize::from(self.at_line_start) + newlines - usize::from(last == b'\n');
+        self.at_line_start = last == b'\n';
+        started
+    }
+
+    /// The kept output, with a marker where anything was cut.
+    pub fn finish(mut self) -> (Vec<u8>, Option<Truncation>) {
+        let full_output = match std::mem::replace(&mut self.spill, Spill::NotNeeded) {
+            Spill::Writing(path, mut file) => match file.flush() {
+                Ok(()) => Some(path),
+                Err(err) => {
+                    warn!("Failed writing {}: {}", path.display(), err);
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let mut bytes = self.head;
+        let truncation = (self.omitted_bytes > 0).then_some(Truncation {
+            stream: self.stream,
+            omitted_lines: self.omitted_lines,
+            omitted_bytes: self.omitted_bytes,
+            total_bytes: self.total_bytes,
+            full_output,
+        });
+        if let Some(truncation) = &truncation {
+            if !bytes.is_empty() && !bytes.ends_with(b"\n") {
+                bytes.push(b'\n');
+            }
+            bytes.extend_from_slice(truncation.marker().as_bytes());
+            info!(
+                "Cut {} of {} bytes of {}",
+                truncation.omitted_bytes,
+                truncation.total_bytes,
+                self.stream.label()
+            );
+        }
+        for (piece, _) in self.tail {
+            bytes.extend_from_slice(&piece);
+        }
+        (bytes, truncation)
+    }
+
+    /// Open the spill file and write everything seen so far to it.
+    fn start_spill(&mut self) {
+        let Some(dir) = &self.spill_dir else {
+            self.spill = Spill::Failed;
+            return;
+        };
+        let opened = open_spill(dir, self.stream).and_then(|(path, mut file)| {
+            file.write_all(&self.head)?;
+            for (piece, _) in &self.tail {
+                file.write_all(piece)?;
+            }
+            Ok((path, file))
+        });
+        match opened {
+            Ok((path, file)) => self.spill = Spill::Writing(path, file),
+            Err(err) => {
+                warn!("Can't keep the full {}: {}", self.stream.label(), err);
+                self.spill = Spill::Failed;
+            }
+        }
+    }
+
+    fn write_spill(&mut self, bytes: &[u8]) {
+        if let Spill::Writing(path, file) = &mut self.spill
+            && let Err(err) = file.write_all(bytes)
+        {
+            warn!("Failed writing {}: {}", path.display(), err);
+            self.spill = Spill::Failed;
+        }
+    }
+}
+
+/// The head gets the first half of a cap, the tail the rest. A cap of 0
+/// is no limit, so neither gets a budget.
+fn head_budget(cap: usize) -> Option<usize> {
+    (cap > 0).then_some(cap / 2)
+}
+
+fn tail_budget(cap: usize) -> Option<usize> {
+    (cap > 0).then_some(cap - cap / 2)
+}
+
+fn exceeds(value: usize, budget: Option<usize>) -> bool {
+    budget.is_some_and(|budget| value > budget)
+}
+
+/// A new spill file in `dir`, readable only by its owner since root
+/// commands may print secrets.
+fn open_spill(dir: &Path, stream: OutputStream) -> std::io::Result<(PathBuf, BufWriter<File>)> {
+    fileops::private_dir(dir)?;
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let name = format!(
+        "{}-{}.{}",
+        Utc::now().format("%Y%m%d-%H%M%S"),
+        &id[..8],
+        stream.label()
+    );
+    let path = dir.join(name);
+    let file = fileops::private_file()
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    Ok((path, BufWriter::new(file)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn capture(caps: OutputCaps, dir: &Path, lines: usize) -> (String, Option<Truncation>) {
+        let mut output = CappedOutput::new(OutputStream::Stdout, caps, Some(dir.to_path_buf()));
+        for n in 0..lines {
+            output.push(format!("line {n}\n").as_bytes());
+        }
+        let (bytes, truncation) = output.finish();
+        (String::from_utf8(bytes).unwrap(), truncation)
+    }
+
+    #[test]
+    fn keeps_head_and_tail_and_spills_the_rest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let caps = OutputCaps {
+            max_bytes: 0,
+            max_lines: 4,
+        };
+
+        let (text, truncation) = capture(caps, tmp.path(), 4);
+        assert_eq!(text, "line 0\nline 1\nline 2\nline 3\n");
+        assert!(truncation.is_none());
+        assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 0);
+
+        let (text, truncation) = capture(caps, tmp.path(), 100);
+        let truncation = truncation.expect("output was cut");
+        assert_eq!(truncation.omitted_lines, 96);
+        assert!(text.starts_with("line 0\nline 1\n[... 96 lines"), "{text}");
+        assert!(text.ends_with("line 98\nline 99\n"), "{text}");
+        let spill = truncation.full_output.unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&spill).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let full = fs::read_to_string(spill).unwrap();
+        assert_eq!(full.lines().count(), 100);
+        assert_eq!(full.len() as u64, truncation.total_bytes);
+    }
+
+    #[test]
+    fn long_lines_count_once_however_they_are_read() {
+        let tmp = tempfile::tempdir().unwrap();
+        let caps = OutputCaps {
+            max_bytes: 0,
+            max_lines: 4,
+        };
+        let long = format!("{}\n", "x".repeat(3 * MAX_PIECE));
+        let mut output = CappedOutput::new(OutputStream::Stdout, caps, Some(tmp.path().into()));
+        for _ in 0..10 {
+            for piece in long.as_bytes().chunks(MAX_PIECE) {
+                output.push(piece);
+            }
+        }
+        let (bytes, truncation) = output.finish();
+        let truncation = truncation.expect("output was cut");
+        assert_eq!(truncation.omitted_lines, 6);
+        assert_eq!(truncation.omitted_bytes, 6 * long.len() as u64);
+        let text = String::from_utf8(bytes).unwrap();
+        assert!(text.contains("[... 6 lines"), "{}", &text[..100]);
+        assert_eq!(text.len() - truncation.marker().len(), 4 * long.len());
+    }
+
+    #[test]
+    fn byte_caps_bound_what_is_kept() {
+        let tmp = tempfile::tempdir().unwrap();
+        let caps = OutputCaps {
+            max_bytes: 64,
+            max_lines: 0,
+        };
+        let (text, truncation) = capture(caps, tmp.path(), 1000);
+        let truncation = truncation.expect("output was cut");
+        let kept = text.len() - truncation.marker().len();
+        assert!(kept <= 64, "{text}");
+        assert_eq!(
+            kept as u64 + truncation.omitted_bytes,
+            truncation.total_bytes
+        );
+    }
+}
@@ -1,7 +1,13 @@
-use anyhow::{Result, anyhow};
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result, anyhow};
 use serde::Deserialize;
 
-use crate::task::{CommandTask, FileEditTask, Task, TaskDetail};
+use crate::environment;
+use crate::fileops;
+use crate::pty;
+use crate::task::{CommandTask, EditOperation, FileEditTask, Task, TaskDetail};
+use crate::tools::{self, ToolCall};
 
 #[derive(Debug)]
 pub struct ParsedPlan {
@@ -47,8 +53,45 @@ pub fn parse_plan(raw: &str, default_shell: &str) -> Result<ParsedPlan> {
         anyhow!(error_msg)
     })?;
 
+    build_plan(llm_plan.summary, llm_plan.plan, default_shell)
+}
+
+/// Build a plan from the `tool_use` blocks of a response, one task per
+/// call. `summary` is whatever text the model wrote alongside them.
+pub fn plan_from_tool_calls(
+    summary: Option<String>,
+    calls: &[ToolCall],
+    default_shell: &str,
+) -> Result<ParsedPlan> {
+    let items = calls
+        .iter()
+        .map(|call| {
+            let kind = tools::kind_for(&call.name)
+                .ok_or_else(|| anyhow!("model called unknown tool '{}'", call.name))?;
+            let mut input = call.input.clone();
+            let Some(fields) = input.as_object_mut() else {
+                return Err(anyhow!("input for tool '{}' is not an object", call.name));
+            };
+            fields.insert("kind".into(), kind.into());
+            serde_json::from_value(input)
+                .with_context(|| format!("invalid input for tool '{}'", call.name))
+        })
+        .collect::<Result<Vec<LlmPlanItem>>>()?;
+    let mut plan = build_plan(summary, items, default_shell)?;
+    // build_plan makes exactly one task per item, in order
+    for (task, call) in plan.tasks.iter_mut().zip(calls) {
+        task.tool_use_id = Some(call.id.clone());
+    }
+    Ok(plan)
+}
+
+fn build_plan(
+    summary: Option<String>,
+    items: Vec<LlmPlanItem>,
+    default_shell: &str,
+) -> Result<ParsedPlan> {
     let mut tasks = Vec::new();
-    for entry in llm_plan.plan {
+    for entry in items {
         match entry.kind.as_deref().unwrap_or("note") {
             "command" => {
                 let description = entry
@@ -59,14 +102,23 @@ pub fn parse_plan(raw: &str, default_shell: &str) -> Result<ParsedPlan> {
                     .command
                     .clone()
                     .ok_or_else(|| anyhow!("command task missing 'command' field"))?;
+                let interactive =
+                    entry.interactive.unwrap_or(false) || pty::needs_terminal(&command);
+                let env = entry.env.clone().unwrap_or_default();
+                if let Some(name) = env.keys().find(|name| !environment::is_valid_name(name)) {
+                    return Err(anyhow!("command task has invalid environment variable '{name}'"));
+                }
                 let detail = TaskDetail::Command(CommandTask {
                     shell: entry
                         .shell
                         .clone()
                         .unwrap_or_else(|| default_shell.to_string()),
-                    command,
                     cwd: entry.cwd.clone(),
                     requires_root: entry.requires_root.unwrap_or(false),
+                    timeout_secs: entry.timeout_secs,
+                    interactive,
+                    env,
+                    command,
                 });
                 tasks.push(Task::new(description, detail));
             }
@@ -76,17 +128,33 @@ pub fn parse_plan(raw: &str, default_shell: &str) -> Result<ParsedPlan> {
                     .clone()
                     .unwrap_or_else(|| "File edit".into());
                 let path = entry.path.clone();
-                let new_text = entry
-                    .new_text
-                    .clone()
-                    .ok_or_else(|| anyhow!("file_edit task missing 'new_text'"))?;
+                let operation = edit_operation(&entry)?;
+                let new_text = match operation {
+                    EditOperation::Rewrite => entry
+                        .new_text
+                        .clone()
+                        .ok_or_else(|| anyhow!("file_edit task missing 'new_text'"))?,
+                    _ => String::new(),
+                };
                 let detail = TaskDetail::FileEdit(FileEditTask {
                     path,
                     new_text,
                     description: entry.details.clone(),
+                    operation,
+                    diff: None,
+                    planned: None,
                 });
                 tasks.push(Task::new(description, detail));
             }
+            kind @ ("delete" | "move" | "chmod" | "chown" | "mkdir" | "symlink") => {
+                let detail = file_op(kind, &entry)?;
+                let description = entry
+                    .description
+                    .clone()
+                    .or_else(|| detail.file_op_summary())
+                    .unwrap_or_else(|| "File operation".into());
+                tasks.push(Task::new(description, detail));
+            }
             _ => {
                 let details = entry
                     .details
@@ -123,9 +191,90 @@ pub fn parse_plan(raw: &str, default_shell: &str) -> Result<ParsedPlan> {
         return Err(anyhow!("SYSAIDMIN response did not include any plan items"));
     }
 
-    Ok(ParsedPlan {
-        summary: llm_plan.summary,
-        tasks,
+    Ok(ParsedPlan { summary, tasks })
+}
+
+/// Build the edit operation named by `op`; a missing `op` means a rewrite
+/// with `new_text`.
+fn edit_operation(entry: &LlmPlanItem) -> Result<EditOperation> {
+    let op = entry.op.as_deref().unwrap_or("rewrite");
+    let field = |value: &Option<String>, name: &str| {
+        value
+            .clone()
+            .ok_or_else(|| anyhow!("file_edit op '{op}' missing '{name}'"))
+    };
+    Ok(match op {
+        "rewrite" => EditOperation::Rewrite,
+        "patch" => EditOperation::Patch {
+            patch: field(&entry.patch, "patch")?,
+        },
+        "replace" => EditOperation::Replace {
+            search: field(&entry.search, "search")?,
+            replace: field(&entry.replace, "replace")?,
+        },
+        "insert_before" => EditOperation::InsertBefore {
+            anchor: field(&entry.anchor, "anchor")?,
+            text: field(&entry.text, "text")?,
+        },
+        "insert_after" => EditOperation::InsertAfter {
+            anchor: field(&entry.anchor, "anchor")?,
+            text: field(&entry.text, "text")?,
+        },
+        "append" => EditOperation::Append {
+            text: field(&entry.text, "text")?,
+        },
+        "regex_replace" => EditOperation::RegexReplace {
+            pattern: field(&entry.pattern, "pattern")?,
+            replacement: field(&entry.replacement, "replacement")?,
+        },
+        other => return Err(anyhow!("unknown file_edit op '{other}'")),
+    })
+}
+
+/// Build a delete, move, chmod, chown, mkdir or symlink task. Modes are
+/// checked here so a bad one fails the plan rather than the task.
+fn file_op(kind: &str, entry: &LlmPlanItem) -> Result<TaskDetail> {
+    let field = |value: &Option<String>, name: &str| {
+        value
+            .clone()
+            .ok_or_else(|| anyhow!("{kind} task missing '{name}'"))
+    };
+    let path = field(&entry.path, "path")?;
+    Ok(match kind {
+        "delete" => TaskDetail::Delete { path },
+        "move" => TaskDetail::Move {
+            path,
+            to: field(&entry.to, "to")?,
+        },
+        "chmod" => {
+            let mode = field(&entry.mode, "mode")?;
+            fileops::parse_mode(&mode)?;
+            TaskDetail::Chmod { path, mode }
+        }
+        "chown" => {
+            if entry.owner.is_none() && entry.group.is_none() {
+                return Err(anyhow!("chown task needs 'owner' or 'group'"));
+            }
+            TaskDetail::Chown {
+                path,
+                owner: entry.owner.clone(),
+                group: entry.group.clone(),
+            }
+        }
+        "mkdir" => {
+            if let Some(mode) = &entry.mode {
+                fileops::parse_mode(mode)?;
+            }
+            TaskDetail::Mkdir {
+                path,
+                mode: entry.mode.clone(),
+            }
+        }
+        "symlink" => TaskDetail::Symlink {
+            path,
+            target: field(&entry.target, "target")?,
+        },
+        other => return Err(anyhow!("unknown file operation '{other}'")),
     })
 }
 
@@ -162,6 +311,7 @@ fn strip_code_fence(raw: &str) -> String {
     trimmed.to_string()
 }
 
+#[allow(clippy::collapsible_match)]
 fn extract_json_segment(raw: &str) -> Option<String> {
     let mut depth = 0usize;
     let mut start_idx = None;
@@ -211,8 +361,24 @@ struct LlmPlanItem {
     shell: Option<String>,
     requires_root: Option<bool>,
     cwd: Option<String>,
+    timeout_secs: Option<u64>,
+    interactive: Option<bool>,
+    env: Option<BTreeMap<String, String>>,
     path: Option<String>,
     new_text: Option<String>,
+    op: Option<String>,
+    patch: Option<String>,
+    search: Option<String>,
+    replace: Option<String>,
+    anchor: Option<String>,
+    text: Option<String>,
+    pattern: Option<String>,
+    replacement: Option<String>,
+    to: Option<String>,
+    mode: Option<String>,
+    owner: Option<String>,
+    group: Option<String>,
+    target: Option<String>,
     details: Option<String>,
 }
 
@@ -245,6 +411,87 @@ mod tests {
         assert_eq!(parsed.tasks.len(), 2);
     }
 
+    #[test]
+    fn marks_commands_that_need_a_terminal() {
+        let input = r#"{
+            "summary": "Look around",
+            "plan": [
+                {"kind": "command", "command": "htop"},
+                {"kind": "command", "command": "top -b -n 1"},
+                {"kind": "command", "command": "./menu.sh", "interactive": true}
+            ]
+        }"#;
+
+        let parsed = parse_plan(input, "/bin/bash").expect("plan parses");
+        let interactive: Vec<bool> = parsed
+            .tasks
+            .iter()
+            .map(|task| match &task.detail {
+                TaskDetail::Command(cmd) => cmd.interactive,
+                other => panic!("unexpected task {other:?}"),
+            })
+            .collect();
+        assert_eq!(interactive, [true, false, true]);
+    }
+
+    #[test]
+    fn reads_per_task_environment() {
+        let input = r#"{
+            "summary": "Upgrade",
+            "plan": [
+                {"kind": "command", "command": "apt-get -y upgrade",
+                 "env": {"DEBIAN_FRONTEND": "noninteractive"}}
+            ]
+        }"#;
+        let parsed = parse_plan(input, "/bin/bash").expect("plan parses");
+        match &parsed.tasks[0].detail {
+            TaskDetail::Command(cmd) => {
+                assert_eq!(cmd.env["DEBIAN_FRONTEND"], "noninteractive")
+            }
+            other => panic!("unexpected task {other:?}"),
+        }
+
+        let bad = r#"{"plan": [{"kind": "command", "command": "ls", "env": {"A B": "x"}}]}"#;
+        assert!(parse_plan(bad, "/bin/bash").is_err());
+    }
+
+    #[test]
+    fn builds_tasks_from_tool_calls() {
+        let call = |name: &str, input: serde_json::Value| ToolCall {
+            id: format!("toolu_{name}"),
+            name: name.into(),
+            input,
+        };
+        let calls = [
+            call(
+                "run_command",
+                serde_json::json!({"command": "df -h", "description": "Disk usage"}),
+            ),
+            call(
+                "chmod",
+                serde_json::json!({"path": "/etc/app.conf", "mode": "0640", "description": "x"}),
+            ),
+        ];
+        let parsed = plan_from_tool_calls(Some("Check disks".into()), &calls, "/bin/sh")
+            .expect("plan builds");
+        assert_eq!(parsed.summary.as_deref(), Some("Check disks"));
+        match &parsed.tasks[0].detail {
+            TaskDetail::Command(cmd) => {
+                assert_eq!((cmd.shell.as_str(), cmd.command.as_str()), ("/bin/sh", "df -h"))
+            }
+            other => panic!("unexpected task {other:?}"),
+        }
+        assert!(matches!(
+            &parsed.tasks[1].detail,
+            TaskDetail::Chmod { mode, .. } if mode == "0640"
+        ));
+        assert_eq!(parsed.tasks[1].tool_use_id.as_deref(), Some("toolu_chmod"));
+
+        let unknown = [call("rm_rf", serde_json::json!({"path": "/"}))];
+        let err = plan_from_tool_calls(None, &unknown, "/bin/sh").unwrap_err();
+        assert!(err.to_string().contains("unknown tool"), "{err}");
+    }
+
     #[test]
     fn parses_code_fenced_plan() {
         let input = r#"```json
@@ -265,4 +512,86 @@ mod tests {
         let segment = extract_json_segment(raw).expect("segment");
         assert!(segment.contains("\"summary\""));
     }
+
+    #[test]
+    fn parses_targeted_file_edits() {
+        let input = r#"{
+            "summary": "Harden sshd",
+            "plan": [
+                {
+                    "kind": "file_edit",
+                    "description": "Disable root login",
+                    "path": "/etc/ssh/sshd_config",
+                    "op": "replace",
+                    "search": "PermitRootLogin yes",
+                    "replace": "PermitRootLogin no"
+                },
+                {
+                    "kind": "file_edit",
+                    "path": "/etc/motd",
+                    "new_text": "hello\n"
+                }
+            ]
+        }"#;
+
+        let parsed = parse_plan(input, "/bin/bash").expect("plan parses");
+        let TaskDetail::FileEdit(edit) = &parsed.tasks[0].detail else {
+            panic!("expected a file edit");
+        };
+        assert_eq!(
+            edit.operation,
+            EditOperation::Replace {
+                search: "PermitRootLogin yes".into(),
+                replace: "PermitRootLogin no".into(),
+            }
+        );
+        let TaskDetail::FileEdit(rewrite) = &parsed.tasks[1].detail else {
+            panic!("expected a file edit");
+        };
+        assert_eq!(rewrite.operation, EditOperation::Rewrite);
+
+        let missing = r#"{"plan": [{"kind": "file_edit", "path": "/etc/x", "op": "insert_after", "text": "y"}]}"#;
+        let err = parse_plan(missing, "/bin/bash").unwrap_err().to_string();
+        assert!(err.contains("missing 'anchor'"), "{err}");
+    }
+
+    #[test]
+    fn parses_file_operations() {
+        let input = r#"{
+            "summary": "Retire old unit",
+            "plan": [
+                {"kind": "delete", "path": "/etc/systemd/system/old.service"},
+                {"kind": "move", "path": "/etc/app.conf", "to": "/etc/app.conf.old"},
+                {"kind": "chmod", "path": "/etc/app.key", "mode": "0600"},
+                {"kind": "chown", "path": "/var/lib/app", "owner": "app", "group": "app"},
+                {"kind": "mkdir", "path": "/etc/app/conf.d", "mode": "0755"},
+                {"kind": "symlink", "path": "/etc/nginx/sites-enabled/app", "target": "../sites-available/app"}
+            ]
+        }"#;
+
+        let parsed = parse_plan(input, "/bin/bash").expect("plan parses");
+        let summaries: Vec<_> = parsed
+            .tasks
+            .iter()
+            .map(|t| t.detail.file_op_summary().unwrap())
+            .collect();
+        assert_eq!(
+            summaries,
+            [
+                "rm /etc/systemd/system/old.service",
+                "mv /etc/app.conf /etc/app.conf.old",
+                "chmod 0600 /etc/app.key",
+                "chown app:app /var/lib/app",
+                "mkdir -p -m 0755 /etc/app/conf.d",
+                "ln -s ../sites-available/app /etc/nginx/sites-enabled/app",
+            ]
+        );
+        assert_eq!(parsed.tasks[2].description, "chmod 0600 /etc/app.key");
+
+        let bad_mode = r#"{"plan": [{"kind": "chmod", "path": "/etc/x", "mode": "rwx"}]}"#;
+        assert!(parse_plan(bad_mode, "/bin/bash").is_err());
+        let no_target = r#"{"plan": [{"kind": "move", "path": "/etc/x"}]}"#;
+        let err = parse_plan(no_target, "/bin/bash").unwrap_err().to_string();
+        assert!(err.contains("missing 'to'"), "{err}");
+    }
 }
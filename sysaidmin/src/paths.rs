@@ -0,0 +1,149 @@
+//! Path normalization used before allowlist matching, so that
+//! `/etc/../root/.ssh/authorized_keys` or a symlink under `/var/log` is judged
+//! by where it really points.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Remove `.` components and fold `..` into its parent without touching the
+/// filesystem. `..` never climbs above the root of an absolute path.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match out.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    out.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => out.push(".."),
+            },
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// Resolve symlinks in `path` the way the kernel does: component by
+/// component, so a `..` after a symlink climbs out of the symlink's target,
+/// not out of the directory holding the link. Once a component doesn't
+/// exist, the rest is folded lexically and appended, so a file that is about
+/// to be created still resolves through its symlinked parent directories.
+pub fn resolve(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    // Whether `out` exists, and so is canonical
+    let mut exists = true;
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if exists => {
+                if out.as_os_str().is_empty() {
+                    out.push("..");
+                    exists = match fs::canonicalize(&out) {
+                        Ok(canonical) => {
+                            out = canonical;
+                            true
+                        }
+                        Err(_) => false,
+                    };
+                } else {
+                    // Canonical, so its parent is where `..` really leads
+                    out.pop();
+                }
+            }
+            Component::ParentDir => match out.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    out.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => out.push(".."),
+            },
+            Component::Normal(name) if exists => {
+                out.push(name);
+                match fs::canonicalize(&out) {
+                    Ok(canonical) => out = canonical,
+                    Err(_) => exists = false,
+                }
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// Where the directory entry `path` names, without following it if it is a
+/// symlink itself: the parent is resolved, the last name kept. For tasks
+/// acting on the entry, like delete, move and symlink.
+pub fn resolve_entry(path: &Path) -> PathBuf {
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            resolve(parent).join(name)
+        }
+        // Ends in `..` or is the root: no entry of its own
+        _ => resolve(path),
+    }
+}
+
+/// True when `path` itself is a symlink (a dangling one included).
+pub fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .map(|meta| meta.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_dot_and_dotdot() {
+        assert_eq!(
+            normalize(Path::new("/etc/../root/./.ssh/authorized_keys")),
+            PathBuf::from("/root/.ssh/authorized_keys")
+        );
+        assert_eq!(normalize(Path::new("/../../etc")), PathBuf::from("/etc"));
+        assert_eq!(normalize(Path::new("a/../../b")), PathBuf::from("../b"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolves_symlinked_parents_of_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        fs::create_dir(root.join("real")).unwrap();
+        std::os::unix::fs::symlink(root.join("real"), root.join("link")).unwrap();
+
+        assert_eq!(
+            resolve(&root.join("link/new.conf")),
+            root.join("real/new.conf")
+        );
+        assert!(is_symlink(&root.join("link")));
+        assert!(!is_symlink(&root.join("real")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dotdot_after_a_symlink_climbs_out_of_its_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = fs::canonicalize(dir.path()).unwrap();
+        fs::create_dir_all(root.join("log")).unwrap();
+        fs::create_dir_all(root.join("secret/sub")).unwrap();
+        std::os::unix::fs::symlink(root.join("secret/sub"), root.join("log/evil")).unwrap();
+
+        let path = root.join("log/evil/../key");
+        assert_eq!(resolve(&path), root.join("secret/key"));
+        assert_eq!(resolve_entry(&path), root.join("secret/key"));
+        assert_eq!(
+            resolve(&root.join("log/evil/../missing/../key")),
+            root.join("secret/key")
+        );
+        // The entry itself isn't followed
+        assert_eq!(resolve_entry(&root.join("log/evil")), root.join("log/evil"));
+    }
+}
@@ -0,0 +1,433 @@
+//! Running commands that need a terminal (top, less, vim...) in a
+//! pseudo-terminal. Either the user gets the real screen until the command
+//! exits, or the command runs unseen for a few seconds and whatever it drew
+//! is kept. A terminal emulator follows the output either way, so the log
+//! gets the screen as the user would have seen it rather than raw escapes.
+
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow};
+use log::{debug, info, warn};
+use portable_pty::{Child, CommandBuilder, MasterPty, PtySize, native_pty_system};
+use serde::Deserialize;
+
+use crate::executor::{CancelToken, KillReason};
+use crate::shell;
+
+/// Programs that draw a full-screen UI or page their output.
+const FULL_SCREEN_PROGRAMS: &[&str] = &[
+    "top", "htop", "atop", "btop", "less", "more", "most", "vi", "vim", "nvim", "nano", "emacs",
+    "man", "watch", "mc", "nmtui", "iftop", "iotop", "nethogs", "ncdu", "tmux", "screen",
+];
+/// Size of the virtual terminal snapshots are taken in.
+const SNAPSHOT_ROWS: u16 = 40;
+const SNAPSHOT_COLS: u16 = 120;
+/// How long a snapshot runs when its task has no timeout of its own.
+pub const SNAPSHOT_WINDOW: Duration = Duration::from_secs(3);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long output is still collected once the command has exited.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// What to do with a task that needs a terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InteractiveMode {
+    /// Leave the TUI and give the command the real terminal until it exits.
+    #[default]
+    Handover,
+    /// Run it in a hidden terminal for a bounded time and keep the screen.
+    Snapshot,
+}
+
+/// How a pseudo-terminal run ended and what was on its screen.
+#[derive(Debug, Clone)]
+pub struct Capture {
+    /// Exit code, or `None` when the process was terminated by a signal.
+    pub status: Option<i32>,
+    pub killed: Option<KillReason>,
+    pub screen: String,
+}
+
+/// Whether `command` runs a program that won't work without a terminal.
+/// `top -b` and the like are batch forms and don't count.
+pub fn needs_terminal(command: &str) -> bool {
+    let Ok(parsed) = shell::parse(command) else {
+        return false;
+    };
+    parsed.commands.iter().any(|part| {
+        let mut words = part.split_whitespace().skip_while(|w| is_assignment(w));
+        let Some(program) = words.next() else {
+            return false;
+        };
+        let program = program.rsplit('/').next().unwrap_or(program);
+        match program {
+            "top" => !words.any(|w| w.starts_with('-') && !w.starts_with("--") && w.contains('b')),
+            _ => FULL_SCREEN_PROGRAMS.contains(&program),
+        }
+    })
+}
+
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| {
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+/// Run `cmd` in a hidden terminal until it exits, `window` elapses or
+/// `cancel` is set, and return what it drew.
+pub fn snapshot(cmd: &Command, window: Duration, cancel: Option<&CancelToken>) -> Result<Capture> {
+    let size = PtySize {
+        rows: SNAPSHOT_ROWS,
+        cols: SNAPSHOT_COLS,
+        pixel_width: 0,
+        pixel_height: 0,
+    };
+    let mut builder = builder(cmd)?;
+    // The emulator below is the terminal, whatever sysaidmin runs in
+    builder.env("TERM", "xterm-256color");
+    let mut session = Session::spawn(builder, size)?;
+    let mut screen = Screen::new(size);
+
+    let started = Instant::now();
+    let (status, killed) = session.run(
+        |bytes| screen.feed(bytes),
+        |_| {
+            if cancel.is_some_and(CancelToken::is_cancelled) {
+                Some(KillReason::Cancelled)
+            } else if started.elapsed() >= window {
+                Some(KillReason::SnapshotTaken)
+            } else {
+                None
+            }
+        },
+    )?;
+    info!(
+        "Snapshot finished after {:?}: status={:?}, killed={:?}",
+        started.elapsed(),
+        status,
+        killed
+    );
+    Ok(Capture {
+        status,
+        killed,
+        screen: screen.capture(),
+    })
+}
+
+/// Run `cmd` on the real terminal, passing keystrokes through, until it
+/// exits. The caller must have left the alternate screen, with raw mode
+/// still on so keys reach the command unprocessed.
+#[cfg(unix)]
+pub fn handover(cmd: &Command) -> Result<Capture> {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let (cols, rows) = crossterm::terminal::size().context("failed reading the terminal size")?;
+    let mut size = PtySize {
+        rows,
+        cols,
+        pixel_width: 0,
+        pixel_height: 0,
+    };
+    let mut session = Session::spawn(builder(cmd)?, size)?;
+    // Both output and resizes update the emulator
+    let screen = std::cell::RefCell::new(Screen::new(size));
+
+    let done = Arc::new(AtomicBool::new(false));
+    let writer = session
+        .master
+        .take_writer()
+        .context("failed opening the pseudo-terminal for input")?;
+    let input = forward_input(writer, done.clone());
+
+    let mut stdout = std::io::stdout().lock();
+    let result = session.run(
+        |bytes| {
+            if let Err(err) = stdout.write_all(bytes).and_then(|()| stdout.flush()) {
+                warn!("Failed writing command output to the terminal: {}", err);
+            }
+            screen.borrow_mut().feed(bytes);
+        },
+        |master| {
+            // Follow the real terminal when the user resizes it
+            if let Ok((cols, rows)) = crossterm::terminal::size()
+                && (cols, rows) != (size.cols, size.rows)
+            {
+                size.cols = cols;
+                size.rows = rows;
+                if let Err(err) = master.resize(size) {
+                    warn!("Failed resizing the pseudo-terminal: {}", err);
+                }
+                screen.borrow_mut().resize(size);
+            }
+            None
+        },
+    );
+    // Stop reading stdin before the TUI wants its keys back
+    done.store(true, Ordering::SeqCst);
+    if input.join().is_err() {
+        warn!("Terminal input thread panicked");
+    }
+
+    let (status, killed) = result?;
+    info!("Handed-over command exited: status={:?}", status);
+    Ok(Capture {
+        status,
+        killed,
+        screen: screen.into_inner().capture(),
+    })
+}
+
+#[cfg(not(unix))]
+pub fn handover(_cmd: &Command) -> Result<Capture> {
+    Err(anyhow!(
+        "handing the terminal to a command is not supported on this platform"
+    ))
+}
+
+/// Copy stdin to the pseudo-terminal until `done` is set. stdin is polled
+/// rather than read outright so no keystroke meant for the TUI is swallowed
+/// after the command exits.
+#[cfg(unix)]
+fn forward_input(
+    mut writer: Box<dyn Write + Send>,
+    done: std::sync::Arc<std::sync::atomic::AtomicBool>,
+) -> thread::JoinHandle<()> {
+    use std::sync::atomic::Ordering;
+
+    thread::spawn(move || {
+        let mut buf = [0u8; 1024];
+        while !done.load(Ordering::SeqCst) {
+            let mut fds = libc::pollfd {
+                fd: libc::STDIN_FILENO,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: fds is a single valid pollfd for the duration of the call.
+            let ready = unsafe { libc::poll(&mut fds, 1, POLL_INTERVAL.as_millis() as i32) };
+            if ready <= 0 {
+                continue;
+            }
+            // SAFETY: buf is valid for writes of buf.len() bytes.
+            let read =
+                unsafe { libc::read(libc::STDIN_FILENO, buf.as_mut_ptr().cast(), buf.len()) };
+            if read <= 0 {
+                break;
+            }
+            if writer.write_all(&buf[..read as usize]).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// Carry a std `Command` over to the pseudo-terminal's own builder.
+fn builder(cmd: &Command) -> Result<CommandBuilder> {
+    let mut builder = CommandBuilder::new(cmd.get_program());
+    builder.args(cmd.get_args());
+    // CommandBuilder falls back to $HOME, not the current directory
+    let cwd = match cmd.get_current_dir() {
+        Some(dir) => dir.to_path_buf(),
+        None => std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/")),
+    };
+    if !cwd.is_dir() {
+        return Err(anyhow!(
+            "working directory {} does not exist",
+            cwd.display()
+        ));
+    }
+    builder.cwd(cwd);
+    for (key, value) in cmd.get_envs() {
+        match value {
+            Some(value) => builder.env(key, value),
+            None => builder.env_remove(key),
+        }
+    }
+    Ok(builder)
+}
+
+struct Session {
+    master: Box<dyn MasterPty + Send>,
+    child: Box<dyn Child + Send + Sync>,
+    output: Receiver<Vec<u8>>,
+}
+
+impl Session {
+    fn spawn(builder: CommandBuilder, size: PtySize) -> Result<Self> {
+        let pair = native_pty_system()
+            .openpty(size)
+            .map_err(|err| anyhow!("failed opening a pseudo-terminal: {err}"))?;
+        let child = pair
+            .slave
+            .spawn_command(builder)
+            .map_err(|err| anyhow!("failed starting the command: {err}"))?;
+        // Only the child should hold the terminal open, so reads end when it exits
+        drop(pair.slave);
+
+        let mut reader = pair
+            .master
+            .try_clone_reader()
+            .map_err(|err| anyhow!("failed reading the pseudo-terminal: {err}"))?;
+        let (tx, output) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; 8192];
+            loop {
+                match reader.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if tx.send(buf[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        debug!(
+            "Spawned command in a {}x{} pseudo-terminal",
+            size.cols, size.rows
+        );
+        Ok(Self {
+            master: pair.master,
+            child,
+            output,
+        })
+    }
+
+    /// Pass output to `on_output` until the child exits, or until `check`
+    /// gives a reason to kill it.
+    fn run(
+        &mut self,
+        mut on_output: impl FnMut(&[u8]),
+        mut check: impl FnMut(&dyn MasterPty) -> Option<KillReason>,
+    ) -> Result<(Option<i32>, Option<KillReason>)> {
+        loop {
+            match self.output.recv_timeout(POLL_INTERVAL) {
+                Ok(bytes) => on_output(&bytes),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => thread::sleep(POLL_INTERVAL),
+            }
+            if let Some(status) = self.child.try_wait()? {
+                self.drain(&mut on_output);
+                return Ok((exit_code(&status), None));
+            }
+            if let Some(reason) = check(self.master.as_ref()) {
+                self.drain(&mut on_output);
+                self.kill();
+                let status = self.child.wait()?;
+                return Ok((exit_code(&status), Some(reason)));
+            }
+        }
+    }
+
+    fn drain(&self, on_output: &mut impl FnMut(&[u8])) {
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        while let Ok(bytes) = self.output.recv_timeout(DRAIN_TIMEOUT) {
+            on_output(&bytes);
+            if Instant::now() >= deadline {
+                break;
+            }
+        }
+    }
+
+    fn kill(&mut self) {
+        // The command leads its own session, so its pid is its process group
+        #[cfg(unix)]
+        if let Some(pid) = self.child.process_id() {
+            // SAFETY: killpg has no memory-safety preconditions.
+            unsafe { libc::killpg(pid as libc::pid_t, libc::SIGHUP) };
+        }
+        if let Err(err) = self.child.kill() {
+            warn!("Failed to kill pseudo-terminal command: {}", err);
+        }
+    }
+}
+
+fn exit_code(status: &portable_pty::ExitStatus) -> Option<i32> {
+    match status.signal() {
+        Some(_) => None,
+        None => Some(status.exit_code() as i32),
+    }
+}
+
+/// A terminal emulator fed the command's output. Full-screen programs
+/// switch back to the main screen as they exit, so the last alternate
+/// screen is kept as what they showed.
+struct Screen {
+    parser: vt100::Parser,
+    alternate: Option<String>,
+}
+
+impl Screen {
+    fn new(size: PtySize) -> Self {
+        Self {
+            parser: vt100::Parser::new(size.rows, size.cols, 0),
+            alternate: None,
+        }
+    }
+
+    fn resize(&mut self, size: PtySize) {
+        self.parser.screen_mut().set_size(size.rows, size.cols);
+    }
+
+    fn feed(&mut self, bytes: &[u8]) {
+        self.parser.process(bytes);
+        if self.parser.screen().alternate_screen() {
+            self.alternate = Some(self.parser.screen().contents());
+        }
+    }
+
+    fn capture(self) -> String {
+        let screen = self.parser.screen();
+        let contents = match self.alternate {
+            Some(alternate) if !screen.alternate_screen() => alternate,
+            _ => screen.contents(),
+        };
+        let trimmed = contents.trim_end();
+        if trimmed.is_empty() {
+            String::new()
+        } else {
+            format!("{trimmed}\n")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_commands_that_need_a_terminal() {
+        assert!(needs_terminal("top"));
+        assert!(needs_terminal("htop -d 10"));
+        assert!(needs_terminal("journalctl -u nginx | less"));
+        assert!(needs_terminal("TERM=xterm /usr/bin/vim /etc/hosts"));
+        assert!(!needs_terminal("top -b -n 1"));
+        assert!(!needs_terminal("top -bn1 | head -20"));
+        assert!(!needs_terminal("df -h"));
+        assert!(!needs_terminal("echo less"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn snapshots_keep_the_screen_a_command_drew() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c")
+            .arg("[ -t 1 ] && printf 'on a tty\\n'; printf 'a\\rb\\n'");
+        let capture = snapshot(&cmd, Duration::from_secs(10), None).unwrap();
+        assert_eq!(capture.status, Some(0));
+        assert_eq!(capture.killed, None);
+        assert_eq!(capture.screen, "on a tty\nb\n");
+
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c")
+            .arg("printf '\\033[?1049hfull screen'; sleep 30");
+        let capture = snapshot(&cmd, Duration::from_millis(500), None).unwrap();
+        assert_eq!(capture.killed, Some(KillReason::SnapshotTaken));
+        assert_eq!(capture.screen, "full screen\n");
+    }
+}
@@ -0,0 +1,273 @@
+//! Retrying API requests that failed for reasons that tend to pass: rate
+//! limits, overloaded or briefly broken servers, and dropped connections.
+//! Waits grow exponentially with jitter, unless the server says how long to
+//! wait in `retry-after`.
+
+use std::collections::hash_map::RandomState;
+use std::error::Error as StdError;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use log::warn;
+use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use serde::Deserialize;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 4;
+const DEFAULT_BASE_DELAY_MS: u64 = 1000;
+const DEFAULT_MAX_DELAY_SECS: u64 = 30;
+
+/// Statuses worth another try: rate limited, server errors that are usually
+/// brief, and 529 for an overloaded API.
+const RETRYABLE_STATUSES: &[u16] = &[429, 500, 502, 503, 529];
+
+/// The `[retry]` config table.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RetryConfig {
+    /// Attempts per request, the first included. 1 disables retries.
+    pub max_attempts: Option<u32>,
+    /// Wait before the first retry; it doubles with each one after.
+    pub base_delay_ms: Option<u64>,
+    /// Longest wait between attempts, `retry-after` included.
+    pub max_delay_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+/// A failed attempt, and whether another one might succeed.
+#[derive(Debug)]
+pub struct Failure {
+    error: anyhow::Error,
+    retryable: bool,
+    retry_after: Option<Duration>,
+}
+
+/// A retry about to happen, for showing while it waits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryNotice {
+    /// The attempt that comes after the wait.
+    pub attempt: u32,
+    pub max_attempts: u32,
+    pub delay: Duration,
+    /// Why the last attempt failed.
+    pub reason: String,
+}
+
+impl RetryPolicy {
+    pub fn new(cfg: &RetryConfig) -> Self {
+        Self {
+            max_attempts: cfg.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1),
+            base_delay: Duration::from_millis(cfg.base_delay_ms.unwrap_or(DEFAULT_BASE_DELAY_MS)),
+            max_delay: Duration::from_secs(cfg.max_delay_secs.unwrap_or(DEFAULT_MAX_DELAY_SECS)),
+        }
+    }
+
+    /// Run `attempt` until it succeeds, fails for good, or the attempt
+    /// budget runs out. `on_retry` hears about each retry before its wait.
+    pub fn run<T>(
+        &self,
+        on_retry: &mut dyn FnMut(&RetryNotice),
+        mut attempt: impl FnMut() -> Result<T, Failure>,
+    ) -> anyhow::Result<T> {
+        let mut attempts = 1;
+        loop {
+            let failure = match attempt() {
+                Ok(value) => return Ok(value),
+                Err(failure) => failure,
+            };
+            if !failure.retryable {
+                return Err(failure.error);
+            }
+            if attempts >= self.max_attempts {
+                return Err(if attempts > 1 {
+                    failure
+                        .error
+                        .context(format!("gave up after {attempts} attempts"))
+                } else {
+                    failure.error
+                });
+            }
+            let delay = failure
+                .retry_after
+                .unwrap_or_else(|| self.backoff(attempts))
+                .min(self.max_delay);
+            attempts += 1;
+            let notice = RetryNotice {
+                attempt: attempts,
+                max_attempts: self.max_attempts,
+                delay,
+                reason: format!("{:#}", failure.error),
+            };
+            warn!("{notice}");
+            on_retry(&notice);
+            thread::sleep(delay);
+        }
+    }
+
+    /// The wait after `failures` failed attempts: the base delay doubled
+    /// for each failure after the first, capped, then jittered down by up
+    /// to half so clients that failed together don't retry together.
+    fn backoff(&self, failures: u32) -> Duration {
+        let exponential = self
+            .base_delay
+            .saturating_mul(1 << (failures - 1).min(16))
+            .min(self.max_delay);
+        let half = exponential / 2;
+        half + half.mul_f64(jitter())
+    }
+}
+
+impl Failure {
+    /// The request never got a response. Refused and reset connections are
+    /// retried; anything else, a timeout included, is not.
+    pub fn send(error: reqwest::Error, context: &str) -> Self {
+        let retryable = error.is_connect() || is_connection_reset(&error);
+        Self {
+            error: anyhow::Error::new(error).context(context.to_string()),
+            retryable,
+            retry_after: None,
+        }
+    }
+
+    /// The server answered with an error `status`, described by `error`.
+    pub fn status(status: StatusCode, headers: &HeaderMap, error: anyhow::Error) -> Self {
+        Self {
+            error,
+            retryable: RETRYABLE_STATUSES.contains(&status.as_u16()),
+            retry_after: retry_after(headers),
+        }
+    }
+}
+
+impl fmt::Display for RetryNotice {
+    /// E.g. "retry 2/4 in 3s: Anthropic API 529: overloaded".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason: String = self.reason.chars().take(80).collect();
+        write!(
+            f,
+            "retry {}/{} in {}s: {}",
+            self.attempt,
+            self.max_attempts,
+            self.delay.as_secs_f64().ceil(),
+            reason
+        )
+    }
+}
+
+/// The wait a `retry-after` header asks for, in seconds. The HTTP-date
+/// form isn't used by the API and is ignored.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let secs: f64 = value.parse().ok()?;
+    (secs.is_finite() && secs >= 0.0).then(|| Duration::from_secs_f64(secs))
+}
+
+fn is_connection_reset(error: &(dyn StdError + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(err) = source {
+        if let Some(io) = err.downcast_ref::<io::Error>()
+            && matches!(
+                io.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof
+            )
+        {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// A number in `[0, 1)`, from the randomly keyed std hasher.
+fn jitter() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy::new(&RetryConfig {
+            max_attempts: Some(max_attempts),
+            base_delay_ms: Some(0),
+            max_delay_secs: None,
+        })
+    }
+
+    fn status_failure(code: u16, retry_after: Option<&str>) -> Failure {
+        let mut headers = HeaderMap::new();
+        if let Some(value) = retry_after {
+            headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+        }
+        let status = StatusCode::from_u16(code).unwrap();
+        Failure::status(status, &headers, anyhow::anyhow!("Anthropic API {code}"))
+    }
+
+    #[test]
+    fn retryable_failures_are_retried_until_the_budget_runs_out() {
+        let mut notices = Vec::new();
+        let mut calls = 0;
+        let result: anyhow::Result<()> = policy(3).run(&mut |n| notices.push(n.clone()), || {
+            calls += 1;
+            Err(status_failure(529, Some("0")))
+        });
+        assert_eq!(calls, 3);
+        assert_eq!(notices.len(), 2);
+        assert_eq!(notices[1].attempt, 3);
+        assert_eq!(notices[1].delay, Duration::ZERO);
+        assert!(format!("{:#}", result.unwrap_err()).starts_with("gave up after 3 attempts"));
+
+        let mut calls = 0;
+        let result = policy(3).run(&mut |_| {}, || {
+            calls += 1;
+            if calls < 2 {
+                Err(status_failure(503, None))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 2);
+
+        let mut calls = 0;
+        let result: anyhow::Result<()> = policy(3).run(&mut |_| {}, || {
+            calls += 1;
+            Err(status_failure(400, None))
+        });
+        assert_eq!(calls, 1);
+        assert_eq!(result.unwrap_err().to_string(), "Anthropic API 400");
+    }
+
+    #[test]
+    fn backoff_doubles_within_jitter_and_respects_retry_after() {
+        let policy = RetryPolicy::new(&RetryConfig {
+            max_attempts: None,
+            base_delay_ms: Some(1000),
+            max_delay_secs: Some(5),
+        });
+        for (failures, full) in [(1, 1000), (2, 2000), (3, 4000), (4, 5000), (30, 5000)] {
+            let delay = policy.backoff(failures).as_millis();
+            assert!((full / 2..=full).contains(&delay), "{failures}: {delay}");
+        }
+
+        assert_eq!(
+            status_failure(429, Some("12")).retry_after,
+            Some(Duration::from_secs(12))
+        );
+        assert_eq!(status_failure(429, Some("soon")).retry_after, None);
+        assert!(!status_failure(404, None).retryable);
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::fs::{self, OpenOptions};
 use std::io::Write;
 use std::path::PathBuf;
@@ -6,31 +7,51 @@ use anyhow::{Context, Result};
 use chrono::{DateTime, Utc};
 use serde::Serialize;
 
+use crate::backup::BackupStore;
 use crate::task::Task;
 
 #[derive(Clone)]
 pub struct SessionStore {
+    root: PathBuf,
+    session_id: String,
     plan_path: PathBuf,
     log_path: PathBuf,
 }
 
 impl SessionStore {
     pub fn new(root: PathBuf) -> Result<Self> {
-        let timestamp = Utc::now().format("%Y%m%d-%H%M%S");
+        let timestamp = Utc::now().format("%Y%m%d-%H%M%S").to_string();
         fs::create_dir_all(&root)
             .with_context(|| format!("failed to create session root {}", root.display()))?;
         let plan_path = root.join(format!("plan-{timestamp}.json"));
         let log_path = root.join(format!("session-{timestamp}.log"));
         Ok(Self {
+            root,
+            session_id: timestamp,
             plan_path,
             log_path,
         })
     }
 
+    /// Backups for this session, kept in `backups/` under the session root.
+    pub fn backup_store(&self, retention: usize) -> BackupStore {
+        BackupStore::new(self.root.join("backups"), &self.session_id, retention)
+    }
+
+    /// Where command output too big to keep in the log is written whole.
+    pub fn output_dir(&self) -> PathBuf {
+        self.root.join("output")
+    }
+
     pub fn write_plan(&self, summary: Option<&str>, tasks: &[Task]) -> Result<()> {
+        let mut status_counts = BTreeMap::new();
+        for task in tasks {
+            *status_counts.entry(task.status.label()).or_insert(0) += 1;
+        }
         let payload = PlanExport {
             summary: summary.map(|s| s.to_string()),
             generated_at: Utc::now(),
+            status_counts,
             tasks: tasks.to_vec(),
         };
         let data = serde_json::to_string_pretty(&payload)?;
@@ -53,13 +74,15 @@ impl SessionStore {
 struct PlanExport {
     summary: Option<String>,
     generated_at: DateTime<Utc>,
+    /// Tasks per status label, so failed and skipped work stands out.
+    status_counts: BTreeMap<&'static str, usize>,
     tasks: Vec<Task>,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::task::{Task, TaskDetail};
+    use crate::task::{Task, TaskDetail, TaskStatus};
 
     #[test]
     fn writes_plan_and_logs() {
@@ -86,4 +109,24 @@ mod tests {
             .count();
         assert_eq!(plan_files, 1);
     }
+
+    #[test]
+    fn plan_export_distinguishes_failed_tasks() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = SessionStore::new(tmp.path().to_path_buf()).unwrap();
+        let mut ok = Task::new("ok", TaskDetail::Note { details: "".into() });
+        ok.status = TaskStatus::Complete;
+        let mut failed = Task::new("bad", TaskDetail::Note { details: "".into() });
+        failed.status = TaskStatus::Failed { exit_code: Some(3) };
+        let mut skipped = Task::new("later", TaskDetail::Note { details: "".into() });
+        skipped.status = TaskStatus::Skipped;
+        store.write_plan(None, &[ok, failed, skipped]).unwrap();
+
+        let exported: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&store.plan_path).unwrap()).unwrap();
+        assert_eq!(exported["status_counts"]["complete"], 1);
+        assert_eq!(exported["status_counts"]["failed"], 1);
+        assert_eq!(exported["status_counts"]["skipped"], 1);
+        assert_eq!(exported["tasks"][1]["status"]["Failed"]["exit_code"], 3);
+    }
 }
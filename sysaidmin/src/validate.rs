@@ -0,0 +1,304 @@
+//! Checks run against a staged file edit before it replaces the real file,
+//! so a broken `sshd_config` or sudoers file never gets installed.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{Result, anyhow};
+use log::{debug, info, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Stands for the staged file in a validator's command line.
+const FILE_PLACEHOLDER: &str = "{file}";
+
+/// A validator from the config file. The first of `command`'s words is the
+/// program; `{file}` is replaced with the path of the staged file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValidatorConfig {
+    pub path_pattern: String,
+    pub command: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SyntaxFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+#[derive(Debug, Clone)]
+enum Check {
+    Command(Vec<String>),
+    Syntax(SyntaxFormat),
+}
+
+#[derive(Debug, Clone)]
+struct Validator {
+    path_regex: Regex,
+    check: Check,
+}
+
+/// What a validator said about a staged file.
+#[derive(Debug, Clone)]
+pub struct Validation {
+    pub validator: String,
+    pub status: Option<i32>,
+    pub output: String,
+}
+
+/// A staged edit that its validator rejected. Carried inside the
+/// `anyhow::Error` from `apply_file_edit` so the UI can show the output.
+#[derive(Debug, thiserror::Error)]
+#[error("{} rejected the edit of {path}: {}", .validation.validator, .validation.output.trim())]
+pub struct ValidationFailed {
+    pub path: String,
+    pub validation: Validation,
+}
+
+/// Validators keyed by path pattern; the first match wins.
+#[derive(Debug, Clone)]
+pub struct ValidatorRegistry {
+    validators: Vec<Validator>,
+}
+
+fn builtin_validators() -> Vec<(&'static str, Check)> {
+    let cmd = |words: &[&str]| Check::Command(words.iter().map(|w| w.to_string()).collect());
+    vec![
+        (
+            r"^/etc/ssh/sshd_config$",
+            cmd(&["sshd", "-t", "-f", FILE_PLACEHOLDER]),
+        ),
+        (
+            r"^/etc/nginx/nginx\.conf$",
+            cmd(&["nginx", "-t", "-c", FILE_PLACEHOLDER]),
+        ),
+        (
+            r"^/etc/sudoers(\.d/[^/]+)?$",
+            cmd(&["visudo", "-cf", FILE_PLACEHOLDER]),
+        ),
+        (
+            r"^/etc/(bind/)?named\.conf(\.[^/]+)?$",
+            cmd(&["named-checkconf", FILE_PLACEHOLDER]),
+        ),
+        (
+            r"^/(etc|lib|usr/lib)/systemd/(system|user)/[^/]+\.(service|socket|timer|path|mount|automount|target|slice)$",
+            cmd(&["systemd-analyze", "verify", FILE_PLACEHOLDER]),
+        ),
+        (r"\.toml$", Check::Syntax(SyntaxFormat::Toml)),
+        (r"\.json$", Check::Syntax(SyntaxFormat::Json)),
+        (r"\.ya?ml$", Check::Syntax(SyntaxFormat::Yaml)),
+    ]
+}
+
+impl Default for ValidatorRegistry {
+    fn default() -> Self {
+        Self::from_config(&[]).expect("built-in validator patterns are valid")
+    }
+}
+
+impl ValidatorRegistry {
+    /// Configured validators, tried before the built-in ones.
+    pub fn from_config(custom: &[ValidatorConfig]) -> Result<Self> {
+        let mut validators = Vec::new();
+        for cfg in custom {
+            if cfg.command.is_empty() {
+                return Err(anyhow!(
+                    "validator for '{}' has an empty command",
+                    cfg.path_pattern
+                ));
+            }
+            let path_regex = Regex::new(&cfg.path_pattern).map_err(|err| {
+                anyhow!("invalid validator pattern '{}': {err}", cfg.path_pattern)
+            })?;
+            validators.push(Validator {
+                path_regex,
+                check: Check::Command(cfg.command.clone()),
+            });
+        }
+        for (pattern, check) in builtin_validators() {
+            validators.push(Validator {
+                path_regex: Regex::new(pattern)?,
+                check,
+            });
+        }
+        Ok(Self { validators })
+    }
+
+    /// Validate `staged`, the new contents bound for `target`. `Ok(None)`
+    /// means no validator covers `target`, or its program isn't installed.
+    pub fn validate(&self, target: &Path, staged: &Path) -> Result<Option<Validation>> {
+        let target_str = target.to_string_lossy();
+        let Some(validator) = self
+            .validators
+            .iter()
+            .find(|v| v.path_regex.is_match(&target_str))
+        else {
+            debug!("No validator for {}", target_str);
+            return Ok(None);
+        };
+
+        let validation = match &validator.check {
+            Check::Command(words) => match run_command(words, staged)? {
+                Some(validation) => validation,
+                None => return Ok(None),
+            },
+            Check::Syntax(format) => check_syntax(*format, staged)?,
+        };
+        info!(
+            "Validated {} with {}: {:?}",
+            target_str, validation.validator, validation.status
+        );
+        if validation.status == Some(0) {
+            Ok(Some(validation))
+        } else {
+            Err(ValidationFailed {
+                path: target_str.into_owned(),
+                validation,
+            }
+            .into())
+        }
+    }
+}
+
+fn run_command(words: &[String], staged: &Path) -> Result<Option<Validation>> {
+    let staged = staged.to_string_lossy();
+    let args: Vec<String> = words
+        .iter()
+        .map(|w| w.replace(FILE_PLACEHOLDER, &staged))
+        .collect();
+    let label = words.join(" ").replace(FILE_PLACEHOLDER, "<staged>");
+    let output = match Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::null())
+        .output()
+    {
+        Ok(output) => output,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            warn!("Validator '{}' is not installed; skipping", args[0]);
+            return Ok(None);
+        }
+        Err(err) => return Err(anyhow!("failed running validator '{label}': {err}")),
+    };
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok(Some(Validation {
+        validator: label,
+        status: output.status.code(),
+        output: text,
+    }))
+}
+
+fn check_syntax(format: SyntaxFormat, staged: &Path) -> Result<Validation> {
+    let text = fs::read_to_string(staged)?;
+    let (name, result) = match format {
+        SyntaxFormat::Toml => (
+            "TOML syntax",
+            toml::from_str::<toml::Value>(&text)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        ),
+        SyntaxFormat::Json => (
+            "JSON syntax",
+            serde_json::from_str::<serde_json::Value>(&text)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        ),
+        SyntaxFormat::Yaml => (
+            "YAML syntax",
+            yaml_rust2::YamlLoader::load_from_str(&text)
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        ),
+    };
+    let (status, output) = match result {
+        Ok(()) => (0, String::new()),
+        Err(err) => (1, err),
+    };
+    Ok(Validation {
+        validator: name.to_string(),
+        status: Some(status),
+        output,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syntax_checks_reject_broken_files() {
+        let tmp = tempfile::tempdir().unwrap();
+        let staged = tmp.path().join("staged");
+        let registry = ValidatorRegistry::default();
+
+        fs::write(&staged, "{\"ok\": true}").unwrap();
+        let ok = registry
+            .validate(Path::new("/etc/app/config.json"), &staged)
+            .unwrap()
+            .expect("json validator applies");
+        assert_eq!(ok.status, Some(0));
+
+        fs::write(&staged, "key = [unterminated").unwrap();
+        let err = registry
+            .validate(Path::new("/etc/app/config.toml"), &staged)
+            .unwrap_err();
+        let failed = err.downcast_ref::<ValidationFailed>().unwrap();
+        assert_eq!(failed.validation.validator, "TOML syntax");
+
+        fs::write(&staged, "a: [1, 2").unwrap();
+        assert!(
+            registry
+                .validate(Path::new("/etc/app/values.yaml"), &staged)
+                .is_err()
+        );
+
+        assert!(
+            registry
+                .validate(Path::new("/etc/motd"), &staged)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn configured_validators_run_commands_on_the_staged_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let staged = tmp.path().join("staged");
+        fs::write(&staged, "bad line\n").unwrap();
+        let registry = ValidatorRegistry::from_config(&[ValidatorConfig {
+            path_pattern: r"^/etc/app\.conf$".into(),
+            command: vec!["grep".into(), "-q".into(), "^ok".into(), "{file}".into()],
+        }])
+        .unwrap();
+
+        let err = registry
+            .validate(Path::new("/etc/app.conf"), &staged)
+            .unwrap_err();
+        let failed = err.downcast_ref::<ValidationFailed>().unwrap();
+        assert_eq!(failed.validation.validator, "grep -q ^ok <staged>");
+        assert_eq!(failed.validation.status, Some(1));
+
+        fs::write(&staged, "ok\n").unwrap();
+        assert!(
+            registry
+                .validate(Path::new("/etc/app.conf"), &staged)
+                .unwrap()
+                .is_some()
+        );
+
+        let missing = ValidatorRegistry::from_config(&[ValidatorConfig {
+            path_pattern: ".*".into(),
+            command: vec!["sysaidmin-no-such-validator".into(), "{file}".into()],
+        }])
+        .unwrap();
+        assert!(
+            missing
+                .validate(Path::new("/etc/x"), &staged)
+                .unwrap()
+                .is_none()
+        );
+    }
+}